@@ -0,0 +1,206 @@
+use crate::{DecodingMeta, QueryContext, SampleMeta};
+use common::{upos, utok};
+use std::{error, fmt};
+use tensor::udim;
+
+/// 推理过程中的错误。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InferError {
+    /// 输入的 token 超出了词表。
+    TokenOutOfRange {
+        /// token 在所有输入中的序号。
+        index: usize,
+        /// 输入的 token。
+        token: utok,
+        /// 词表的大小。
+        vocab_size: usize,
+    },
+    /// 查询超出了模型支持的最大上下文长度。
+    PositionOverflow {
+        /// 查询的序号。
+        query: usize,
+        /// 查询的注意力长度。
+        att_len: upos,
+        /// 模型支持的最大上下文长度。
+        max_seq_len: upos,
+    },
+    /// 不带缓存的查询没有从位置 0 开始，或查询需要的位置已被滚动缓存归还。
+    MissingCache {
+        /// 查询的序号。
+        query: usize,
+    },
+    /// 页池没有足够的页容纳查询的缓存。
+    CacheExhausted {
+        /// 查询的序号。
+        query: usize,
+    },
+    /// 生成的上下文达到了模型支持的最大长度，生成被截断。
+    ContextFull {
+        /// 模型支持的最大上下文长度。
+        max_seq_len: upos,
+    },
+    /// 输入张量的形状与查询不符。
+    ShapeMismatch {
+        /// 期望的形状。
+        expected: Vec<udim>,
+        /// 实际的形状。
+        actual: Vec<udim>,
+    },
+}
+
+impl error::Error for InferError {}
+impl fmt::Display for InferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TokenOutOfRange {
+                index,
+                token,
+                vocab_size,
+            } => write!(
+                f,
+                "token {token} at {index} is out of vocabulary of size {vocab_size}"
+            ),
+            Self::PositionOverflow {
+                query,
+                att_len,
+                max_seq_len,
+            } => write!(
+                f,
+                "query {query} attends {att_len} positions, exceeding {max_seq_len}"
+            ),
+            Self::MissingCache { query } => {
+                write!(f, "query {query} misses the cache of previous positions")
+            }
+            Self::CacheExhausted { query } => {
+                write!(f, "no cache block available for query {query}")
+            }
+            Self::ContextFull { max_seq_len } => {
+                write!(f, "context is full at {max_seq_len} positions")
+            }
+            Self::ShapeMismatch { expected, actual } => {
+                write!(f, "expect shape {expected:?}, but got {actual:?}")
+            }
+        }
+    }
+}
+
+/// 检查所有 token 都在词表中。
+pub fn check_tokens(tokens: &[utok], vocab_size: usize) -> Result<(), InferError> {
+    match tokens.iter().position(|&t| t as usize >= vocab_size) {
+        Some(index) => Err(InferError::TokenOutOfRange {
+            index,
+            token: tokens[index],
+            vocab_size,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// 检查查询不超过最大上下文长度，并且查询需要的缓存都存在，返回查询的总长度。
+pub fn check_queries<S>(
+    queries: &[QueryContext<S>],
+    max_seq_len: upos,
+) -> Result<udim, InferError> {
+    let mut nt = 0;
+    for (query, q) in queries.iter().enumerate() {
+        if q.att_len() > max_seq_len {
+            return Err(InferError::PositionOverflow {
+                query,
+                att_len: q.att_len(),
+                max_seq_len,
+            });
+        }
+        let covered = match &q.cache {
+            Some(cache) => cache.covers(q.pos()),
+            None => q.pos() == 0,
+        };
+        if !covered {
+            return Err(InferError::MissingCache { query });
+        }
+        nt += q.seq_len();
+    }
+    Ok(nt)
+}
+
+/// 检查张量的形状。
+pub fn check_shape(expected: &[udim], actual: &[udim]) -> Result<(), InferError> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(InferError::ShapeMismatch {
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        })
+    }
+}
+
+/// 检查解码的要求，返回解码的总长度。
+pub fn check_decoding(
+    decoding: &[DecodingMeta],
+    hidden_state: &[udim],
+    hidden_size: udim,
+) -> Result<udim, InferError> {
+    let nt = decoding.iter().map(|d| d.num_query).sum::<usize>() as udim;
+    check_shape(&[nt, hidden_size], hidden_state)?;
+    match decoding.iter().find(|d| d.num_decode > d.num_query) {
+        Some(d) => Err(InferError::ShapeMismatch {
+            expected: vec![d.num_query as _],
+            actual: vec![d.num_decode as _],
+        }),
+        None => Ok(decoding.iter().map(|d| d.num_decode).sum::<usize>() as _),
+    }
+}
+
+/// 检查采样的要求与 logits 的形状。
+pub fn check_sample(
+    args: &[SampleMeta],
+    logits: &[udim],
+    vocab_size: udim,
+) -> Result<(), InferError> {
+    let n = args.iter().map(|a| a.num_decode).sum::<usize>() as udim;
+    check_shape(&[n, vocab_size], logits)
+}
+
+#[test]
+fn test_check() {
+    assert_eq!(check_tokens(&[1, 2, 3], 4), Ok(()));
+    assert_eq!(
+        check_tokens(&[1, 4, 3], 4),
+        Err(InferError::TokenOutOfRange {
+            index: 1,
+            token: 4,
+            vocab_size: 4,
+        }),
+    );
+
+    let queries = [
+        QueryContext::<Vec<u8>> {
+            cache: None,
+            range: 0..3,
+        },
+        QueryContext {
+            cache: None,
+            range: 2..5,
+        },
+    ];
+    assert_eq!(check_queries(&queries[..1], 4), Ok(3));
+    assert_eq!(
+        check_queries(&queries[..1], 2),
+        Err(InferError::PositionOverflow {
+            query: 0,
+            att_len: 3,
+            max_seq_len: 2,
+        }),
+    );
+    assert_eq!(
+        check_queries(&queries, 8),
+        Err(InferError::MissingCache { query: 1 }),
+    );
+
+    let decoding = [DecodingMeta {
+        num_query: 3,
+        num_decode: 1,
+    }];
+    assert_eq!(check_decoding(&decoding, &[3, 8], 8), Ok(1));
+    assert!(check_decoding(&decoding, &[2, 8], 8).is_err());
+}
@@ -0,0 +1,54 @@
+use common::upos;
+use std::ops::Range;
+
+/// 基于累计注意力权重的缓存淘汰策略（heavy-hitter）。
+///
+/// 缓存长度达到 `budget` 时，始终保留最近的 `recent` 个位置，
+/// 并在更早的位置中保留累计注意力权重最大的 `budget - 2 x recent` 个，
+/// 为之后的 `recent` 个新位置留出空间。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct HeavyHitter {
+    /// 缓存长度的上限。
+    pub budget: upos,
+    /// 始终保留的最近位置数量。
+    pub recent: upos,
+}
+
+impl HeavyHitter {
+    /// 根据每个缓存位置的累计注意力权重选择保留的范围，未达到上限时返回 `None`。
+    pub fn select(&self, importance: &[f32]) -> Option<Vec<Range<upos>>> {
+        assert!(self.budget > 2 * self.recent);
+        let len = importance.len() as upos;
+        if len < self.budget {
+            return None;
+        }
+
+        let split = len - self.recent;
+        let mut heavy = (0..split).collect::<Vec<_>>();
+        heavy.sort_unstable_by(|&a, &b| importance[b as usize].total_cmp(&importance[a as usize]));
+        heavy.truncate((self.budget - 2 * self.recent) as usize);
+        heavy.sort_unstable();
+
+        let mut ans = Vec::<Range<upos>>::new();
+        for i in heavy.into_iter().chain(split..len) {
+            match ans.last_mut() {
+                Some(r) if r.end == i => r.end += 1,
+                _ => ans.push(i..i + 1),
+            }
+        }
+        Some(ans)
+    }
+}
+
+#[test]
+fn test_select() {
+    let policy = HeavyHitter {
+        budget: 6,
+        recent: 2,
+    };
+    assert_eq!(policy.select(&[1.; 5]), None);
+    assert_eq!(
+        policy.select(&[9., 0., 1., 5., 0., 0., 0.]),
+        Some(vec![0..1, 3..4, 5..7]),
+    );
+}
@@ -0,0 +1,449 @@
+use common::upos;
+use std::{
+    error, fmt,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+use tensor::{udim, DataType, Tensor};
+
+/// K-V 缓存。
+///
+/// 缓存由若干页构成，每页的形状为 `num_layers x 2 x num_kv_head x block_size x head_dim`，
+/// 页表中第 `i` 页保存位置 `[i x block_size, (i + 1) x block_size)` 的缓存。
+/// 分页缓存的页从共享的 [BlockPool] 中按需取得，并在释放时归还；
+/// 连续缓存只有一页，页的长度即缓存的最大长度；分页缓存的最大长度在构造时给出。
+///
+/// 设置了滑动窗口的分页缓存是滚动的，完全落在窗口之前的页会归还页池，
+/// 此后页表中第 `i` 页保存位置 `[(released + i) x block_size, ...)` 的缓存。
+pub struct KVCache<Storage> {
+    blocks: Vec<Tensor<Storage>>,
+    pool: Option<Arc<BlockPool<Storage>>>,
+    max_len: udim,
+    window: Option<udim>,
+    released: usize,
+    importance: Option<Vec<f32>>,
+}
+
+impl<Storage> KVCache<Storage> {
+    /// 用一个 `num_layers x 2 x num_kv_head x max_seq_len x head_dim` 的张量构造连续缓存。
+    #[inline]
+    pub fn contiguous(tensor: Tensor<Storage>) -> Self {
+        assert_eq!(tensor.shape().len(), 5);
+        Self {
+            max_len: tensor.shape()[3],
+            blocks: vec![tensor],
+            pool: None,
+            window: None,
+            released: 0,
+            importance: None,
+        }
+    }
+
+    /// 构造一个从 `pool` 中取页的空分页缓存，最多容纳 `max_len` 个位置。
+    #[inline]
+    pub fn paged(pool: Arc<BlockPool<Storage>>, max_len: udim) -> Self {
+        Self {
+            blocks: Vec::new(),
+            pool: Some(pool),
+            max_len,
+            window: None,
+            released: 0,
+            importance: None,
+        }
+    }
+
+    /// 设置滑动窗口，使分页缓存只保留最后 `window` 个位置所在的页。
+    #[inline]
+    pub fn with_window(mut self, window: udim) -> Self {
+        if self.pool.is_some() {
+            self.window = Some(window);
+        }
+        self
+    }
+
+    /// 滚动缓存的滑动窗口。
+    #[inline]
+    pub fn window(&self) -> Option<udim> {
+        self.window
+    }
+
+    /// 缓存保留的第一个位置，此前的页已被滚动缓存归还。
+    #[inline]
+    pub fn start(&self) -> udim {
+        self.released as udim * self.block_size()
+    }
+
+    /// 缓存是否保留了从 `pos` 开始的查询需要关注的所有位置。
+    #[inline]
+    pub fn covers(&self, pos: udim) -> bool {
+        let start = self.window.map_or(0, |w| (pos + 1).saturating_sub(w));
+        start >= self.start()
+    }
+
+    /// 滚动缓存填充到 `len` 后，归还不再被任何查询关注的页。
+    ///
+    /// 保留最后 `window` 个位置，使从 `len - 1` 开始的查询仍可重新填充最后一个位置。
+    pub fn slide(&mut self, len: udim) {
+        if let (Some(pool), Some(window)) = (&self.pool, self.window) {
+            let n = (len.saturating_sub(window) / pool.block_size()) as usize;
+            let n = n.saturating_sub(self.released).min(self.blocks.len());
+            pool.put(self.blocks.drain(..n));
+            self.released += n;
+        }
+    }
+
+    /// 分页缓存使用的页池。
+    #[inline]
+    pub fn pool(&self) -> Option<&Arc<BlockPool<Storage>>> {
+        self.pool.as_ref()
+    }
+
+    /// 每页缓存的长度。
+    #[inline]
+    pub fn block_size(&self) -> udim {
+        match &self.pool {
+            Some(pool) => pool.block_size(),
+            None => self.blocks[0].shape()[3],
+        }
+    }
+
+    /// 缓存最多容纳的位置数量。
+    #[inline]
+    pub fn max_len(&self) -> udim {
+        self.max_len
+    }
+
+    /// 当前页表能容纳的缓存长度。
+    #[inline]
+    pub fn capacity(&self) -> udim {
+        (self.released + self.blocks.len()) as udim * self.block_size()
+    }
+
+    /// 页表，不含滚动缓存已归还的页。
+    #[inline]
+    pub fn blocks(&self) -> &[Tensor<Storage>] {
+        &self.blocks
+    }
+
+    /// 可变页表。
+    #[inline]
+    pub fn blocks_mut(&mut self) -> &mut [Tensor<Storage>] {
+        &mut self.blocks
+    }
+
+    /// 连续缓存的唯一页。
+    #[inline]
+    pub fn as_contiguous(&self) -> &Tensor<Storage> {
+        assert!(self.pool.is_none(), "paged cache is not contiguous");
+        &self.blocks[0]
+    }
+
+    /// 确保缓存至少能容纳 `len` 个位置，失败时缓存保持不变。
+    pub fn reserve(&mut self, len: udim) -> Result<(), ReserveError> {
+        if len > self.max_len {
+            return Err(ReserveError::Overflow {
+                len,
+                max_len: self.max_len,
+            });
+        }
+        if let Some(pool) = &self.pool {
+            let n = (len.div_ceil(pool.block_size()) as usize).saturating_sub(self.released);
+            let old = self.blocks.len();
+            while self.blocks.len() < n {
+                match pool.take() {
+                    Ok(block) => self.blocks.push(block),
+                    Err(e) => {
+                        pool.put(self.blocks.drain(old..));
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 只保留容纳 `len` 个位置所需的页，其余的页归还页池。
+    pub fn truncate(&mut self, len: udim) {
+        if let Some(pool) = &self.pool {
+            let n = (len.div_ceil(pool.block_size()) as usize).saturating_sub(self.released);
+            if self.blocks.len() > n {
+                pool.put(self.blocks.drain(n..));
+            }
+        }
+    }
+
+    /// 映射每一页的存储，生成一个不归还页的缓存视图。
+    pub fn map_blocks<'s, U>(
+        &'s mut self,
+        f: impl FnMut(&'s mut Tensor<Storage>) -> Tensor<U>,
+    ) -> KVCache<U> {
+        KVCache {
+            blocks: self.blocks.iter_mut().map(f).collect(),
+            pool: None,
+            max_len: self.max_len,
+            window: self.window,
+            released: self.released,
+            importance: None,
+        }
+    }
+
+    /// 开始记录每个缓存位置累计获得的注意力权重。
+    #[inline]
+    pub fn track_importance(&mut self) {
+        self.importance.get_or_insert_with(Vec::new);
+    }
+
+    /// 每个缓存位置累计获得的注意力权重，未开始记录时为 `None`。
+    #[inline]
+    pub fn importance(&self) -> Option<&[f32]> {
+        self.importance.as_deref()
+    }
+
+    /// 累计注意力权重，`scores` 的第 `i` 项累加到位置 `i` 上。
+    pub fn accumulate_importance(&mut self, scores: &[f32]) {
+        if let Some(importance) = &mut self.importance {
+            if importance.len() < scores.len() {
+                importance.resize(scores.len(), 0.);
+            }
+            importance.iter_mut().zip(scores).for_each(|(a, b)| *a += b);
+        }
+    }
+
+    /// 只保留 `retain` 中的各个范围并依次拼接，同步整理注意力权重并归还多余的页。
+    ///
+    /// 缓存内容的移动由 [`CausalLM::retain_cache`](crate::CausalLM::retain_cache) 完成。
+    pub fn retain(&mut self, retain: &[Range<upos>]) {
+        assert_eq!(self.released, 0, "rolling cache cannot be retained");
+        assert!(retain.windows(2).all(|w| w[0].end <= w[1].start));
+        if let Some(importance) = &mut self.importance {
+            let mut dst = 0;
+            for r in retain {
+                let end = (r.end as usize).min(importance.len());
+                let start = (r.start as usize).min(end);
+                importance.copy_within(start..end, dst);
+                dst += end - start;
+            }
+            importance.truncate(dst);
+        }
+        self.truncate(retain.iter().map(|r| r.len() as udim).sum());
+    }
+}
+
+impl<Storage> Drop for KVCache<Storage> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.put(self.blocks.drain(..));
+        }
+    }
+}
+
+/// 缓存无法容纳要求的长度。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReserveError {
+    /// 要求的长度超过了缓存的最大长度。
+    Overflow {
+        /// 要求的长度。
+        len: udim,
+        /// 缓存的最大长度。
+        max_len: udim,
+    },
+    /// 页池分配的页已达到容量上限。
+    Exhausted {
+        /// 页池的容量。
+        capacity: usize,
+    },
+}
+
+impl error::Error for ReserveError {}
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overflow { len, max_len } => {
+                write!(f, "cache overflow: {len} > {max_len} positions")
+            }
+            Self::Exhausted { capacity } => {
+                write!(f, "block pool exhausted: all {capacity} blocks in use")
+            }
+        }
+    }
+}
+
+/// 在所有缓存之间共享的空闲页池。
+///
+/// 页池分配的页数不超过容量；空闲页多于使用中的页时，多余的空闲页会被释放。
+pub struct BlockPool<Storage> {
+    data_type: DataType,
+    shape: [udim; 5],
+    capacity: Option<usize>,
+    alloc: Box<dyn Fn(usize) -> Storage + Send + Sync>,
+    blocks: Mutex<Blocks<Storage>>,
+}
+
+struct Blocks<Storage> {
+    free: Vec<Tensor<Storage>>,
+    allocated: usize,
+}
+
+impl<Storage> BlockPool<Storage> {
+    /// 创建页池，每页的形状为 `num_layers x 2 x num_kv_head x block_size x head_dim`，由 `alloc` 分配。
+    pub fn new(
+        data_type: DataType,
+        shape: [udim; 5],
+        alloc: impl Fn(usize) -> Storage + Send + Sync + 'static,
+    ) -> Self {
+        assert_eq!(shape[1], 2);
+        Self {
+            data_type,
+            shape,
+            capacity: None,
+            alloc: Box::new(alloc),
+            blocks: Mutex::new(Blocks {
+                free: Vec::new(),
+                allocated: 0,
+            }),
+        }
+    }
+
+    /// 限制页池最多分配 `capacity` 个页。
+    #[inline]
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// 页池的容量，`None` 表示不限制。
+    #[inline]
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// 页的数据类型。
+    #[inline]
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    /// 每页的形状。
+    #[inline]
+    pub fn shape(&self) -> &[udim] {
+        &self.shape
+    }
+
+    /// 每页缓存的长度。
+    #[inline]
+    pub fn block_size(&self) -> udim {
+        self.shape[3]
+    }
+
+    /// 空闲页的数量。
+    #[inline]
+    pub fn num_free(&self) -> usize {
+        self.blocks.lock().unwrap().free.len()
+    }
+
+    /// 已分配且未释放的页的数量，包括空闲页。
+    #[inline]
+    pub fn num_allocated(&self) -> usize {
+        self.blocks.lock().unwrap().allocated
+    }
+
+    /// 取一个页，没有空闲页时分配新页，已分配的页达到容量时失败。
+    fn take(&self) -> Result<Tensor<Storage>, ReserveError> {
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(block) = blocks.free.pop() {
+            return Ok(block);
+        }
+        if let Some(capacity) = self.capacity.filter(|&c| blocks.allocated >= c) {
+            return Err(ReserveError::Exhausted { capacity });
+        }
+        blocks.allocated += 1;
+        drop(blocks);
+        Ok(Tensor::alloc(self.data_type, &self.shape, &self.alloc))
+    }
+
+    /// 归还页，并释放多于使用中的页的空闲页。
+    fn put(&self, returned: impl IntoIterator<Item = Tensor<Storage>>) {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.free.extend(returned);
+        let in_use = blocks.allocated - blocks.free.len();
+        if blocks.free.len() > in_use {
+            blocks.allocated -= blocks.free.len() - in_use;
+            blocks.free.truncate(in_use);
+        }
+    }
+}
+
+#[test]
+fn test_paged() {
+    let pool = Arc::new(BlockPool::new(DataType::F16, [2, 2, 4, 16, 8], vec_u8));
+    let mut cache = KVCache::paged(pool.clone(), 64);
+    assert_eq!(cache.capacity(), 0);
+
+    cache.reserve(17).unwrap();
+    assert_eq!(cache.blocks().len(), 2);
+    assert_eq!(cache.capacity(), 32);
+    cache.reserve(32).unwrap();
+    assert_eq!(cache.blocks().len(), 2);
+    assert_eq!(
+        cache.reserve(65),
+        Err(ReserveError::Overflow {
+            len: 65,
+            max_len: 64
+        }),
+    );
+
+    cache.truncate(5);
+    assert_eq!(cache.blocks().len(), 1);
+    assert_eq!(pool.num_free(), 1);
+
+    let mut other = KVCache::paged(pool.clone(), 64);
+    other.reserve(1).unwrap();
+    assert_eq!(pool.num_free(), 0);
+
+    // 不再使用的空闲页被释放
+    drop(cache);
+    assert_eq!(pool.num_free(), 1);
+    drop(other);
+    assert_eq!(pool.num_free(), 0);
+    assert_eq!(pool.num_allocated(), 0);
+
+    let mut cache = KVCache::paged(pool.clone(), 64);
+    cache.track_importance();
+    cache.reserve(40).unwrap();
+    cache.accumulate_importance(&[1., 2., 3., 4., 5.]);
+    cache.accumulate_importance(&[1., 1.]);
+    cache.retain(&[0..1, 3..20]);
+    assert_eq!(cache.importance(), Some(&[2., 4., 5.][..]));
+    assert_eq!(cache.blocks().len(), 2);
+
+    let mut cache = KVCache::paged(pool.clone(), 64).with_window(20);
+    cache.reserve(40).unwrap();
+    cache.slide(35);
+    assert_eq!(cache.start(), 0);
+    cache.slide(36);
+    assert_eq!(cache.start(), 16);
+    assert_eq!(cache.blocks().len(), 2);
+    assert_eq!(cache.capacity(), 48);
+    assert!(cache.covers(35));
+    assert!(!cache.covers(34));
+    cache.reserve(50).unwrap();
+    assert_eq!(cache.blocks().len(), 3);
+
+    // 页池达到容量后分配失败，已取得的页不受影响
+    let pool = Arc::new(BlockPool::new(DataType::F16, [2, 2, 4, 16, 8], vec_u8).with_capacity(2));
+    let mut cache = KVCache::paged(pool.clone(), 64);
+    cache.reserve(16).unwrap();
+    assert_eq!(
+        cache.reserve(48),
+        Err(ReserveError::Exhausted { capacity: 2 }),
+    );
+    assert_eq!(cache.blocks().len(), 1);
+    assert_eq!(pool.num_free(), 1);
+    cache.reserve(32).unwrap();
+    assert_eq!(pool.num_free(), 0);
+
+    fn vec_u8(len: usize) -> Vec<u8> {
+        vec![0; len]
+    }
+}
@@ -2,14 +2,24 @@
 
 #![deny(warnings, missing_docs)]
 
+mod error;
+mod eviction;
+mod kv_cache;
+mod pooling;
 mod query_context;
 mod sample;
 
+pub use error::{
+    check_decoding, check_queries, check_sample, check_shape, check_tokens, InferError,
+};
+pub use eviction::HeavyHitter;
+pub use kv_cache::{BlockPool, KVCache, ReserveError};
+pub use pooling::Pooling;
 pub use query_context::QueryContext;
 pub use sample::SampleArgs;
 
 use common::{upos, utok};
-use std::path::Path;
+use std::{ops::Range, path::Path};
 use tensor::{udim, Tensor};
 
 /// 模型。
@@ -26,20 +36,44 @@ pub trait Model: Sized {
 pub trait CausalLM: Model {
     /// 存储中间结果的类型。
     type Storage;
-    /// 模型定义的句子结束符。
-    fn eos_token(&self) -> utok;
-    /// 创建一个新的缓存（`num_layers x 2 x num_kv_head x max_seq_len x head_dim`）。
-    fn new_cache(&self) -> Tensor<Self::Storage>;
-    /// 复制一个有效长度为 `pos` 的缓存。
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage>;
+    /// 模型定义的所有句子结束符，至少有一个。
+    fn eos_tokens(&self) -> &[utok];
+    /// 模型定义的主要句子结束符，用于结束对话中的句子。
+    #[inline]
+    fn eos_token(&self) -> utok {
+        self.eos_tokens()[0]
+    }
+    /// 模型支持的最大上下文长度。
+    fn max_seq_len(&self) -> upos;
+    /// 创建一个新的缓存（每页 `num_layers x 2 x num_kv_head x block_size x head_dim`）。
+    fn new_cache(&self) -> KVCache<Self::Storage>;
+    /// 复制一个有效长度为 `pos` 的缓存，无法为副本分配缓存时返回 `None`。
+    fn duplicate_cache(
+        &self,
+        cache: &KVCache<Self::Storage>,
+        pos: upos,
+    ) -> Option<KVCache<Self::Storage>>;
+    /// 将缓存的前 `pos` 个位置复制到主存（`num_layers x 2 x num_kv_head x pos x head_dim`），
+    /// 缓存容量不足 `pos` 或滚动缓存已归还开头的页时返回 `None`。
+    fn export_cache(&self, cache: &KVCache<Self::Storage>, pos: upos) -> Option<Tensor<Vec<u8>>>;
+    /// 从 [`export_cache`](Self::export_cache) 导出的张量恢复缓存，与模型的缓存格式不符时返回 `None`。
+    fn import_cache(&self, tensor: &Tensor<&[u8]>) -> Option<KVCache<Self::Storage>>;
+    /// 只保留缓存中 `retain` 的各个范围（升序且不相交），将它们依次前移拼接，
+    /// 并为前移的键重新施加旋转位置编码。返回保留后缓存的有效长度。
+    fn retain_cache(&self, cache: &mut KVCache<Self::Storage>, retain: &[Range<upos>]) -> upos;
     /// 对所有词执行词嵌入（`num_tokens x hidden_size`）。
-    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage>;
+    fn token_embed(
+        &self,
+        queries: impl IntoIterator<Item = utok>,
+    ) -> Result<Tensor<Self::Storage>, InferError>;
     /// 对词嵌入张量执行 Transformer 计算（`num_tokens x hidden_size`）。
+    ///
+    /// 不带缓存的查询只关注查询自身，因此必须从位置 0 开始。
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, InferError>
     where
         Self: 'a;
     /// 对词嵌入张量执行解码计算（`num_decoding_tokens` x `vocab_size`）。
@@ -47,13 +81,20 @@ pub trait CausalLM: Model {
         &self,
         decoding: impl IntoIterator<Item = DecodingMeta>,
         hidden_state: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>;
+    ) -> Result<Tensor<Self::Storage>, InferError>;
+    /// 对词嵌入张量执行不带缓存的 Transformer 计算，并将每个查询池化为一个句向量（`num_queries x hidden_size`）。
+    fn embed(
+        &self,
+        seq_len: impl IntoIterator<Item = udim>,
+        token_embedded: Tensor<Self::Storage>,
+        pooling: Pooling,
+    ) -> Result<Vec<Vec<f32>>, InferError>;
     /// 对 logits 进行采样。
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
-    ) -> Vec<utok>;
+    ) -> Result<Vec<utok>, InferError>;
 }
 
 /// 解码的要求。
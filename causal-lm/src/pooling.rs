@@ -0,0 +1,74 @@
+use common::BetweenF32;
+use std::iter::zip;
+use tensor::udim;
+
+/// 句向量的池化方式。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub enum Pooling {
+    /// 取最后一个词的隐藏状态。
+    #[default]
+    Last,
+    /// 取所有词的隐藏状态的均值。
+    Mean,
+    /// 取所有词的隐藏状态的均值，并进行 L2 归一化。
+    Normalized,
+}
+
+impl Pooling {
+    /// 将 `hidden_state`（`num_tokens x hidden_size`）按 `seq_len` 划分为多个查询，并将每个查询池化为一个句向量。
+    pub fn pool<T: BetweenF32>(
+        self,
+        hidden_state: &[T],
+        hidden_size: usize,
+        seq_len: &[udim],
+    ) -> Vec<Vec<f32>> {
+        let d = hidden_size;
+        let mut rows = hidden_state.chunks_exact(d);
+        seq_len
+            .iter()
+            .map(|&len| {
+                let query = rows.by_ref().take(len as usize);
+                let mut ans = match self {
+                    Self::Last => query
+                        .last()
+                        .map_or_else(|| vec![0.; d], |row| row.iter().map(T::get).collect()),
+                    Self::Mean | Self::Normalized => {
+                        let mut sum = vec![0.; d];
+                        for row in query {
+                            zip(&mut sum, row).for_each(|(s, x)| *s += x.get());
+                        }
+                        if len > 0 {
+                            sum.iter_mut().for_each(|s| *s /= len as f32);
+                        }
+                        sum
+                    }
+                };
+                if self == Self::Normalized {
+                    let norm = ans.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    if norm > 0. {
+                        ans.iter_mut().for_each(|x| *x /= norm);
+                    }
+                }
+                ans
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_pool() {
+    let hidden_state = [1., 2., 3., 4., 5., 6., 0., 4.];
+    let seq_len = [3, 1];
+    assert_eq!(
+        Pooling::Last.pool(&hidden_state, 2, &seq_len),
+        [vec![5., 6.], vec![0., 4.]],
+    );
+    assert_eq!(
+        Pooling::Mean.pool(&hidden_state, 2, &seq_len),
+        [vec![3., 4.], vec![0., 4.]],
+    );
+    assert_eq!(
+        Pooling::Normalized.pool(&hidden_state, 2, &seq_len),
+        [vec![0.6, 0.8], vec![0., 1.]],
+    );
+}
@@ -1,11 +1,12 @@
-﻿use common::upos;
+﻿use crate::KVCache;
+use common::upos;
 use std::ops::{DerefMut, Range};
 use tensor::{slice, split, udim, LocalSplitable, Tensor};
 
 /// 查询 Transformer 的的信息。
 pub struct QueryContext<'a, Storage> {
     /// K-V cache.
-    pub cache: Option<&'a mut Tensor<Storage>>,
+    pub cache: Option<&'a mut KVCache<Storage>>,
     /// 查询在上下文中的位置。
     pub range: Range<upos>,
 }
@@ -25,7 +26,7 @@ impl<'a, Storage> QueryContext<'a, Storage> {
     }
 }
 
-type KVCache<'a, T> = (
+type KVBlock<'a, T> = (
     Tensor<LocalSplitable<&'a mut [T]>>,
     Tensor<LocalSplitable<&'a mut [T]>>,
 );
@@ -34,27 +35,41 @@ impl<'a, Storage, T> QueryContext<'a, Storage>
 where
     Storage: DerefMut<Target = [T]>,
 {
-    /// 提取第 `layer` 层的 K-V 缓存。
-    pub fn cache(&mut self, layer: usize) -> Option<KVCache<T>> {
+    /// 提取连续缓存第 `layer` 层的 K-V 缓存。
+    pub fn cache(&mut self, layer: usize) -> Option<KVBlock<T>> {
+        self.blocks(layer).map(|mut blocks| {
+            assert_eq!(blocks.len(), 1, "paged cache is not contiguous");
+            blocks.pop().unwrap()
+        })
+    }
+
+    /// 提取所有缓存页第 `layer` 层的 K-V 缓存（`num_kv_head x block_size x head_dim`）。
+    pub fn blocks(&mut self, layer: usize) -> Option<Vec<KVBlock<'_, T>>> {
         self.cache.as_mut().map(|cache| {
-            let &[_, 2, nkvh, max_seq_len, dh] = cache.shape() else {
-                unreachable!()
-            };
-            let u = cache
-                .as_mut()
-                .map_physical(|u| LocalSplitable::from(&mut **u))
-                .slice(&[
-                    slice![=layer],
-                    slice![=>],
-                    slice![=>],
-                    slice![=>],
-                    slice![=>],
-                ]);
-            let (k, v) = split!(u; [1]: 1, 1);
-            (
-                k.reshape(&[nkvh, max_seq_len, dh]),
-                v.reshape(&[nkvh, max_seq_len, dh]),
-            )
+            cache
+                .blocks_mut()
+                .iter_mut()
+                .map(|block| {
+                    let &[_, 2, nkvh, block_size, dh] = block.shape() else {
+                        unreachable!()
+                    };
+                    let u = block
+                        .as_mut()
+                        .map_physical(|u| LocalSplitable::from(&mut **u))
+                        .slice(&[
+                            slice![=layer],
+                            slice![=>],
+                            slice![=>],
+                            slice![=>],
+                            slice![=>],
+                        ]);
+                    let (k, v) = split!(u; [1]: 1, 1);
+                    (
+                        k.reshape(&[nkvh, block_size, dh]),
+                        v.reshape(&[nkvh, block_size, dh]),
+                    )
+                })
+                .collect()
         })
     }
 }
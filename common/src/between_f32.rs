@@ -37,3 +37,33 @@ impl BetweenF32 for half::f16 {
         Self::to_f32(*self)
     }
 }
+
+impl BetweenF32 for crate::f8e4m3 {
+    #[inline]
+    fn zero() -> Self {
+        Self::from_bits(0)
+    }
+    #[inline]
+    fn cast(f: f32) -> Self {
+        Self::from_f32(f)
+    }
+    #[inline]
+    fn get(&self) -> f32 {
+        Self::to_f32(*self)
+    }
+}
+
+impl BetweenF32 for crate::f8e5m2 {
+    #[inline]
+    fn zero() -> Self {
+        Self::from_bits(0)
+    }
+    #[inline]
+    fn cast(f: f32) -> Self {
+        Self::from_f32(f)
+    }
+    #[inline]
+    fn get(&self) -> f32 {
+        Self::to_f32(*self)
+    }
+}
@@ -0,0 +1,136 @@
+//! 8 位浮点数，格式与 PyTorch 的 `float8_e4m3fn` 和 `float8_e5m2` 一致。
+
+/// 1 位符号、4 位指数、3 位尾数，没有无穷大，最大值为 448。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(transparent)]
+pub struct f8e4m3(u8);
+
+/// 1 位符号、5 位指数、2 位尾数，最大值为 57344。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[repr(transparent)]
+pub struct f8e5m2(u8);
+
+impl f8e4m3 {
+    /// 最大的有限值。
+    pub const MAX: f32 = 448.;
+
+    /// 从位模式构造。
+    #[inline]
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// 返回位模式。
+    #[inline]
+    pub const fn to_bits(self) -> u8 {
+        self.0
+    }
+
+    /// 就近舍入，超出范围的值饱和到最大值。
+    #[inline]
+    pub fn from_f32(x: f32) -> Self {
+        Self(encode(x, 4, 3, Self::MAX))
+    }
+
+    /// 转换为 f32。
+    #[inline]
+    pub fn to_f32(self) -> f32 {
+        let sign = ((self.0 & 0x80) as u32) << 24;
+        let e = ((self.0 >> 3) & 0xf) as u32;
+        let m = (self.0 & 0x7) as u32;
+        match (e, m) {
+            (0xf, 0x7) => f32::NAN,
+            // 次正规数
+            (0, _) => f32::from_bits(sign | (m as f32 * f32::powi(2., -9)).to_bits()),
+            _ => f32::from_bits(sign | (e + 127 - 7) << 23 | m << 20),
+        }
+    }
+}
+
+impl f8e5m2 {
+    /// 最大的有限值。
+    pub const MAX: f32 = 57344.;
+
+    /// 从位模式构造。
+    #[inline]
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// 返回位模式。
+    #[inline]
+    pub const fn to_bits(self) -> u8 {
+        self.0
+    }
+
+    /// 就近舍入，超出范围的值饱和到最大值。
+    #[inline]
+    pub fn from_f32(x: f32) -> Self {
+        Self(encode(x, 5, 2, Self::MAX))
+    }
+
+    /// 转换为 f32。
+    #[inline]
+    pub fn to_f32(self) -> f32 {
+        // 与 f16 的高 8 位相同
+        half::f16::from_bits((self.0 as u16) << 8).to_f32()
+    }
+}
+
+/// 按给定的指数位数和尾数位数编码，NaN 编码为全 1。
+fn encode(x: f32, exp_bits: i32, man_bits: i32, max: f32) -> u8 {
+    let sign = ((x.to_bits() >> 24) & 0x80) as u8;
+    if x.is_nan() {
+        return sign | 0x7f;
+    }
+    let a = x.abs().min(max);
+    if a == 0. {
+        return sign;
+    }
+    let bias = (1 << (exp_bits - 1)) - 1;
+    // 小于最小正规数时按次正规数的间距舍入
+    let e = ((a.to_bits() >> 23) as i32 - 127).max(1 - bias);
+    let mut q = (a * f32::powi(2., man_bits - e)).round() as i32;
+    let mut e = e;
+    if q == 2 << man_bits {
+        q >>= 1;
+        e += 1;
+    }
+    let bits = if q < 1 << man_bits {
+        q
+    } else {
+        (e + bias) << man_bits | (q - (1 << man_bits))
+    };
+    sign | bits as u8
+}
+
+#[test]
+fn test_f8e4m3() {
+    assert_eq!(f8e4m3::from_f32(1.).to_bits(), 0x38);
+    assert_eq!(f8e4m3::from_f32(-448.).to_bits(), 0xfe);
+    assert_eq!(f8e4m3::from_f32(1e6).to_f32(), 448.);
+    assert_eq!(f8e4m3::from_bits(0x01).to_f32(), f32::powi(2., -9));
+    assert!(f8e4m3::from_bits(0x7f).to_f32().is_nan());
+    for bits in 0..=u8::MAX {
+        let x = f8e4m3::from_bits(bits);
+        if !x.to_f32().is_nan() {
+            assert_eq!(f8e4m3::from_f32(x.to_f32()).to_f32(), x.to_f32());
+        }
+    }
+}
+
+#[test]
+fn test_f8e5m2() {
+    assert_eq!(f8e5m2::from_f32(1.).to_bits(), 0x3c);
+    assert_eq!(f8e5m2::from_f32(1e6).to_f32(), 57344.);
+    assert_eq!(f8e5m2::from_f32(1.3).to_f32(), 1.25);
+    assert_eq!(f8e5m2::from_bits(0x01).to_f32(), f32::powi(2., -16));
+    for bits in 0..=u8::MAX {
+        let x = f8e5m2::from_bits(bits);
+        if x.to_f32().is_finite() {
+            assert_eq!(f8e5m2::from_f32(x.to_f32()).to_f32(), x.to_f32());
+        }
+    }
+}
@@ -0,0 +1,678 @@
+//! gguf 文件的加载、访问和写入。
+
+use memmap2::Mmap;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Error, ErrorKind::InvalidData, Result, Write},
+    ops::Deref,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
+
+/// 未声明 `general.alignment` 时数据区的对齐。
+pub const DEFAULT_ALIGNMENT: usize = 32;
+
+/// 按文件头声明的数量预分配的上限，数量不可信，超出部分按需增长。
+const MAX_PREALLOC: usize = 1 << 12;
+
+/// gguf 文件的元数据和张量信息，张量数据直接映射自文件。
+pub struct Gguf {
+    mmap: Mmap,
+    version: u32,
+    metadata: HashMap<String, GgufValue>,
+    tensors: HashMap<String, GgufTensorInfo>,
+    data_offset: usize,
+}
+
+/// 元数据的值。
+#[allow(missing_docs)]
+#[derive(Clone, PartialEq, Debug)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+/// ggml 的张量数据类型。
+#[allow(missing_docs, non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u32)]
+pub enum GgmlType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q8_1 = 9,
+    Q2_K = 10,
+    Q3_K = 11,
+    Q4_K = 12,
+    Q5_K = 13,
+    Q6_K = 14,
+    Q8_K = 15,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    I64 = 27,
+    F64 = 28,
+    BF16 = 30,
+}
+
+/// 张量信息。
+#[derive(Clone, Debug)]
+pub struct GgufTensorInfo {
+    /// 数据类型。
+    pub ty: GgmlType,
+    /// 形状，按行优先排列，与文件中的维度顺序相反。
+    pub shape: Vec<usize>,
+    /// 数据在数据区中的偏移。
+    pub offset: usize,
+}
+
+/// 要写入文件的张量。
+#[derive(Clone, Debug)]
+pub struct GgufTensorRef<'a> {
+    /// 名字。
+    pub name: &'a str,
+    /// 数据类型。
+    pub ty: GgmlType,
+    /// 形状，按行优先排列。
+    pub shape: &'a [usize],
+    /// 连续存储的数据。
+    pub data: &'a [u8],
+}
+
+impl GgmlType {
+    /// 每块的元素数和字节数，非量化类型每块一个元素。
+    pub const fn block(self) -> (usize, usize) {
+        match self {
+            Self::F32 => (1, 4),
+            Self::F16 => (1, 2),
+            Self::Q4_0 => (32, 18),
+            Self::Q4_1 => (32, 20),
+            Self::Q5_0 => (32, 22),
+            Self::Q5_1 => (32, 24),
+            Self::Q8_0 => (32, 34),
+            Self::Q8_1 => (32, 36),
+            Self::Q2_K => (256, 84),
+            Self::Q3_K => (256, 110),
+            Self::Q4_K => (256, 144),
+            Self::Q5_K => (256, 176),
+            Self::Q6_K => (256, 210),
+            Self::Q8_K => (256, 292),
+            Self::I8 => (1, 1),
+            Self::I16 => (1, 2),
+            Self::I32 => (1, 4),
+            Self::I64 => (1, 8),
+            Self::F64 => (1, 8),
+            Self::BF16 => (1, 2),
+        }
+    }
+
+    /// 是否按块量化。
+    #[inline]
+    pub const fn is_quantized(self) -> bool {
+        self.block().0 > 1
+    }
+}
+
+impl TryFrom<u32> for GgmlType {
+    type Error = u32;
+
+    fn try_from(value: u32) -> std::result::Result<Self, Self::Error> {
+        const TYPES: &[GgmlType] = &[
+            GgmlType::F32,
+            GgmlType::F16,
+            GgmlType::Q4_0,
+            GgmlType::Q4_1,
+            GgmlType::Q5_0,
+            GgmlType::Q5_1,
+            GgmlType::Q8_0,
+            GgmlType::Q8_1,
+            GgmlType::Q2_K,
+            GgmlType::Q3_K,
+            GgmlType::Q4_K,
+            GgmlType::Q5_K,
+            GgmlType::Q6_K,
+            GgmlType::Q8_K,
+            GgmlType::I8,
+            GgmlType::I16,
+            GgmlType::I32,
+            GgmlType::I64,
+            GgmlType::F64,
+            GgmlType::BF16,
+        ];
+        TYPES
+            .iter()
+            .find(|&&ty| ty as u32 == value)
+            .copied()
+            .ok_or(value)
+    }
+}
+
+impl GgufTensorInfo {
+    /// 数据的字节数。
+    #[inline]
+    pub fn nbytes(&self) -> usize {
+        let (block, size) = self.ty.block();
+        self.shape.iter().product::<usize>() / block * size
+    }
+}
+
+impl GgufValue {
+    /// 以无符号整数读取任意非负的整数。
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as _),
+            Self::U16(v) => Some(v as _),
+            Self::U32(v) => Some(v as _),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => v.try_into().ok(),
+            Self::I16(v) => v.try_into().ok(),
+            Self::I32(v) => v.try_into().ok(),
+            Self::I64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// 读取浮点数。
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            Self::F32(v) => Some(v),
+            Self::F64(v) => Some(v as _),
+            _ => None,
+        }
+    }
+
+    /// 读取布尔值。
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Self::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// 读取字符串。
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// 读取数组。
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl GgufValue {
+    /// 文件中表示值类型的编号。
+    fn ty(&self) -> u32 {
+        match self {
+            Self::U8(_) => 0,
+            Self::I8(_) => 1,
+            Self::U16(_) => 2,
+            Self::I16(_) => 3,
+            Self::U32(_) => 4,
+            Self::I32(_) => 5,
+            Self::F32(_) => 6,
+            Self::Bool(_) => 7,
+            Self::String(_) => 8,
+            Self::Array(_) => 9,
+            Self::U64(_) => 10,
+            Self::I64(_) => 11,
+            Self::F64(_) => 12,
+        }
+    }
+
+    /// 按小端序写入值，不含类型编号；数组的元素类型由第一个元素决定。
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::U8(v) => buf.extend(v.to_le_bytes()),
+            Self::I8(v) => buf.extend(v.to_le_bytes()),
+            Self::U16(v) => buf.extend(v.to_le_bytes()),
+            Self::I16(v) => buf.extend(v.to_le_bytes()),
+            Self::U32(v) => buf.extend(v.to_le_bytes()),
+            Self::I32(v) => buf.extend(v.to_le_bytes()),
+            Self::F32(v) => buf.extend(v.to_le_bytes()),
+            Self::Bool(v) => buf.push(*v as u8),
+            Self::String(v) => write_string(buf, v),
+            Self::Array(v) => {
+                buf.extend(v.first().map_or(0, Self::ty).to_le_bytes());
+                buf.extend((v.len() as u64).to_le_bytes());
+                v.iter().for_each(|v| v.write_to(buf));
+            }
+            Self::U64(v) => buf.extend(v.to_le_bytes()),
+            Self::I64(v) => buf.extend(v.to_le_bytes()),
+            Self::F64(v) => buf.extend(v.to_le_bytes()),
+        }
+    }
+}
+
+impl Gguf {
+    /// 加载 `.gguf` 文件，支持第 2、3 版。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file) }?;
+
+        let mut reader = Reader(&mmap);
+        if reader.bytes(4)? != b"GGUF" {
+            return Err(invalid("not a gguf file"));
+        }
+        let version = reader.u32()?;
+        if !matches!(version, 2 | 3) {
+            return Err(invalid(format!("unsupported gguf version: {version}")));
+        }
+        let tensor_count = reader.u64()? as usize;
+        let kv_count = reader.u64()? as usize;
+
+        let mut metadata = HashMap::with_capacity(kv_count.min(MAX_PREALLOC));
+        for _ in 0..kv_count {
+            let key = reader.string()?;
+            let ty = reader.u32()?;
+            metadata.insert(key, reader.value(ty)?);
+        }
+
+        let mut tensors = HashMap::with_capacity(tensor_count.min(MAX_PREALLOC));
+        for _ in 0..tensor_count {
+            let name = reader.string()?;
+            let ndim = reader.u32()? as usize;
+            let mut shape = (0..ndim)
+                .map(|_| reader.u64().map(|d| d as usize))
+                .collect::<Result<Vec<_>>>()?;
+            shape.reverse();
+            let ty = reader.u32()?;
+            let ty = GgmlType::try_from(ty)
+                .map_err(|ty| invalid(format!("unknown ggml type {ty} of {name}")))?;
+            let offset = reader.u64()? as usize;
+            tensors.insert(name, GgufTensorInfo { ty, shape, offset });
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .map_or(DEFAULT_ALIGNMENT, |a| a as usize);
+        if alignment == 0 {
+            return Err(invalid("invalid general.alignment: 0"));
+        }
+        let data_offset = (mmap.len() - reader.0.len()).next_multiple_of(alignment);
+        for (name, info) in &tensors {
+            // 量化类型的元素数必须是整块，否则 `nbytes` 会向下取整
+            let (block, size) = info.ty.block();
+            let nbytes = info
+                .shape
+                .iter()
+                .try_fold(1usize, |acc, &d| acc.checked_mul(d))
+                .filter(|len| len % block == 0)
+                .and_then(|len| (len / block).checked_mul(size))
+                .ok_or_else(|| invalid(format!("invalid shape of tensor {name}")))?;
+            let end = data_offset
+                .checked_add(info.offset)
+                .and_then(|offset| offset.checked_add(nbytes));
+            if end.is_none_or(|end| end > mmap.len()) {
+                return Err(invalid(format!("tensor {name} out of range")));
+            }
+        }
+
+        Ok(Self {
+            mmap,
+            version,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+
+    /// 共享自身。
+    #[inline]
+    pub fn share(self) -> Pin<Arc<Self>> {
+        Pin::new(Arc::new(self))
+    }
+
+    /// 文件格式的版本。
+    #[inline]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// 所有元数据。
+    #[inline]
+    pub fn metadata(&self) -> &HashMap<String, GgufValue> {
+        &self.metadata
+    }
+
+    /// 获取元数据。
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    /// 获取字符串元数据。
+    #[inline]
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(GgufValue::as_str)
+    }
+
+    /// 检查张量是否存在。
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    /// 获取张量信息。
+    #[inline]
+    pub fn tensor_info(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.get(name)
+    }
+
+    /// 所有张量的名字和信息。
+    #[inline]
+    pub fn tensors(&self) -> impl Iterator<Item = (&str, &GgufTensorInfo)> {
+        self.tensors
+            .iter()
+            .map(|(name, info)| (name.as_str(), info))
+    }
+
+    /// 获取张量数量。
+    #[inline]
+    pub fn tensors_count(&self) -> usize {
+        self.tensors.len()
+    }
+
+    /// 获取张量数据。
+    #[inline]
+    pub fn tensor_data(&self, name: &str) -> Option<&[u8]> {
+        self.tensors
+            .get(name)
+            .map(|info| &self.mmap[self.data_offset + info.offset..][..info.nbytes()])
+    }
+
+    /// 从共享的 [Gguf] 中获取共享的张量。
+    pub fn share_tensor(self: &Pin<Arc<Self>>, name: &str) -> Option<GgufTensor> {
+        let info = self.tensors.get(name)?;
+        let data = self.tensor_data(name)?;
+        Some(GgufTensor {
+            gguf: self.clone(),
+            info: unsafe { &*(info as *const _) },
+            data: unsafe { &*(data as *const _) },
+        })
+    }
+}
+
+/// 以第 3 版格式写入 gguf 文件，张量按给出的顺序依次存放并按 [DEFAULT_ALIGNMENT] 对齐。
+pub fn write(
+    mut w: impl Write,
+    metadata: &[(String, GgufValue)],
+    tensors: &[GgufTensorRef],
+) -> Result<()> {
+    let mut header = Vec::new();
+    header.extend(b"GGUF");
+    header.extend(3u32.to_le_bytes());
+    header.extend((tensors.len() as u64).to_le_bytes());
+    header.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        write_string(&mut header, key);
+        header.extend(value.ty().to_le_bytes());
+        value.write_to(&mut header);
+    }
+
+    let mut offset = 0;
+    for t in tensors {
+        let (block, size) = t.ty.block();
+        let len = t.shape.iter().product::<usize>();
+        if len % block != 0 || t.data.len() != len / block * size {
+            return Err(invalid(format!("invalid data size of tensor {}", t.name)));
+        }
+        write_string(&mut header, t.name);
+        header.extend((t.shape.len() as u32).to_le_bytes());
+        t.shape
+            .iter()
+            .rev()
+            .for_each(|&d| header.extend((d as u64).to_le_bytes()));
+        header.extend((t.ty as u32).to_le_bytes());
+        header.extend((offset as u64).to_le_bytes());
+        offset = (offset + t.data.len()).next_multiple_of(DEFAULT_ALIGNMENT);
+    }
+    header.resize(header.len().next_multiple_of(DEFAULT_ALIGNMENT), 0);
+    w.write_all(&header)?;
+
+    const PADDING: [u8; DEFAULT_ALIGNMENT] = [0; DEFAULT_ALIGNMENT];
+    for t in tensors {
+        w.write_all(t.data)?;
+        let len = t.data.len();
+        w.write_all(&PADDING[..len.next_multiple_of(DEFAULT_ALIGNMENT) - len])?;
+    }
+    w.flush()
+}
+
+/// 共享的张量。
+#[derive(Clone)]
+pub struct GgufTensor {
+    #[allow(unused)]
+    gguf: Pin<Arc<Gguf>>,
+    info: &'static GgufTensorInfo,
+    data: &'static [u8],
+}
+
+impl Deref for GgufTensor {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl GgufTensor {
+    /// 数据类型。
+    #[inline]
+    pub fn ty(&self) -> GgmlType {
+        self.info.ty
+    }
+
+    /// 形状。
+    #[inline]
+    pub fn shape(&self) -> &[usize] {
+        &self.info.shape
+    }
+}
+
+#[inline]
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(InvalidData, msg.into())
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u64).to_le_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// 按小端序依次读取文件头。
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("unexpected end of gguf header"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn value(&mut self, ty: u32) -> Result<GgufValue> {
+        use GgufValue as V;
+        Ok(match ty {
+            0 => V::U8(self.array().map(u8::from_le_bytes)?),
+            1 => V::I8(self.array().map(i8::from_le_bytes)?),
+            2 => V::U16(self.array().map(u16::from_le_bytes)?),
+            3 => V::I16(self.array().map(i16::from_le_bytes)?),
+            4 => V::U32(self.u32()?),
+            5 => V::I32(self.array().map(i32::from_le_bytes)?),
+            6 => V::F32(self.array().map(f32::from_le_bytes)?),
+            7 => V::Bool(self.array::<1>()?[0] != 0),
+            8 => V::String(self.string()?),
+            9 => {
+                let ty = self.u32()?;
+                let len = self.u64()? as usize;
+                V::Array((0..len).map(|_| self.value(ty)).collect::<Result<_>>()?)
+            }
+            10 => V::U64(self.u64()?),
+            11 => V::I64(self.array().map(i64::from_le_bytes)?),
+            12 => V::F64(self.array().map(f64::from_le_bytes)?),
+            _ => return Err(invalid(format!("unknown gguf value type: {ty}"))),
+        })
+    }
+}
+
+#[test]
+fn test_load() {
+    use std::io::Write;
+
+    let string = |s: &str| [&(s.len() as u64).to_le_bytes()[..], s.as_bytes()].concat();
+    let mut header = Vec::new();
+    header.extend(b"GGUF");
+    header.extend(3u32.to_le_bytes());
+    header.extend(1u64.to_le_bytes());
+    header.extend(2u64.to_le_bytes());
+    header.extend(string("general.architecture"));
+    header.extend(8u32.to_le_bytes());
+    header.extend(string("llama"));
+    header.extend(string("general.alignment"));
+    header.extend(4u32.to_le_bytes());
+    header.extend(64u32.to_le_bytes());
+    header.extend(string("a"));
+    header.extend(2u32.to_le_bytes());
+    header.extend(3u64.to_le_bytes());
+    header.extend(2u64.to_le_bytes());
+    header.extend((GgmlType::F32 as u32).to_le_bytes());
+    header.extend(0u64.to_le_bytes());
+    header.resize(header.len().next_multiple_of(64), 0);
+    (0..6).for_each(|i| header.extend((i as f32).to_le_bytes()));
+
+    let path = std::env::temp_dir().join("common-test-load.gguf");
+    File::create(&path).unwrap().write_all(&header).unwrap();
+    let gguf = Gguf::load(&path).unwrap().share();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(gguf.version(), 3);
+    assert_eq!(gguf.get_str("general.architecture"), Some("llama"));
+    let a = gguf.share_tensor("a").unwrap();
+    assert_eq!(a.ty(), GgmlType::F32);
+    assert_eq!(a.shape(), &[2, 3]);
+    assert_eq!(&a[4..8], &1f32.to_le_bytes());
+    assert_eq!(a.len(), 24);
+}
+
+#[test]
+fn test_load_invalid() {
+    use std::io::Write;
+
+    let load = |count: u64, ty: GgmlType, dims: &[u64], offset: u64| {
+        let mut header = Vec::new();
+        header.extend(b"GGUF");
+        header.extend(3u32.to_le_bytes());
+        header.extend(count.to_le_bytes());
+        header.extend(0u64.to_le_bytes());
+        header.extend(1u64.to_le_bytes());
+        header.push(b'a');
+        header.extend((dims.len() as u32).to_le_bytes());
+        dims.iter().for_each(|d| header.extend(d.to_le_bytes()));
+        header.extend((ty as u32).to_le_bytes());
+        header.extend(offset.to_le_bytes());
+        header.resize(header.len().next_multiple_of(DEFAULT_ALIGNMENT) + 64, 0);
+
+        let path = std::env::temp_dir().join("common-test-load-invalid.gguf");
+        File::create(&path).unwrap().write_all(&header).unwrap();
+        let result = Gguf::load(&path).map(|_| ());
+        std::fs::remove_file(path).unwrap();
+        result
+    };
+
+    assert!(load(1, GgmlType::Q8_0, &[32], 0).is_ok());
+    // 不足一块
+    assert!(load(1, GgmlType::Q8_0, &[31], 0).is_err());
+    // 偏移溢出
+    assert!(load(1, GgmlType::F32, &[1], u64::MAX).is_err());
+    // 元素数溢出
+    assert!(load(1, GgmlType::F32, &[u64::MAX, 2], 0).is_err());
+    // 声明的张量数远多于实际
+    assert!(load(u64::MAX, GgmlType::F32, &[1], 0).is_err());
+}
+
+#[test]
+fn test_write() {
+    let data = (0..6)
+        .flat_map(|i| (i as f32).to_le_bytes())
+        .collect::<Vec<_>>();
+    let metadata = [
+        (
+            "general.architecture".into(),
+            GgufValue::String("llama".into()),
+        ),
+        (
+            "tokenizer.ggml.scores".into(),
+            GgufValue::Array(vec![GgufValue::F32(0.), GgufValue::F32(-1.)]),
+        ),
+    ];
+    let tensors = [
+        GgufTensorRef {
+            name: "a",
+            ty: GgmlType::F32,
+            shape: &[3],
+            data: &data[..12],
+        },
+        GgufTensorRef {
+            name: "b",
+            ty: GgmlType::F32,
+            shape: &[2, 3],
+            data: &data,
+        },
+    ];
+
+    let path = std::env::temp_dir().join("common-test-write.gguf");
+    write(File::create(&path).unwrap(), &metadata, &tensors).unwrap();
+    let gguf = Gguf::load(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(gguf.get_str("general.architecture"), Some("llama"));
+    assert_eq!(gguf.get("tokenizer.ggml.scores"), Some(&metadata[1].1));
+    assert_eq!(gguf.tensor_info("b").unwrap().shape, [2, 3]);
+    assert_eq!(gguf.tensor_info("b").unwrap().offset, 32);
+    assert_eq!(gguf.tensor_data("a"), Some(&data[..12]));
+    assert_eq!(gguf.tensor_data("b"), Some(&data[..]));
+}
@@ -12,9 +12,14 @@ pub type upos = u32;
 
 mod between_f32;
 mod blob;
+mod fp8;
+pub mod gguf;
+pub mod one_or_many;
+pub mod quant;
 pub mod safe_tensors;
 pub mod test_model;
 
 pub use between_f32::BetweenF32;
 pub use blob::Blob;
+pub use fp8::{f8e4m3, f8e5m2};
 pub use half::{bf16, f16};
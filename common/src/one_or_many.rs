@@ -0,0 +1,54 @@
+//! 读写 json 中可以是单个 token 或 token 列表的字段，用于 `#[serde(with = "common::one_or_many")]`。
+
+use crate::utok;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(utok),
+    Many(Vec<utok>),
+}
+
+/// 只有一个 token 时写为单个 token，否则写为列表。
+pub fn serialize<S: Serializer>(tokens: &[utok], s: S) -> Result<S::Ok, S::Error> {
+    match tokens {
+        &[token] => OneOrMany::One(token),
+        _ => OneOrMany::Many(tokens.to_vec()),
+    }
+    .serialize(s)
+}
+
+/// 读取单个 token 或非空的 token 列表。
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<utok>, D::Error> {
+    match OneOrMany::deserialize(d)? {
+        OneOrMany::One(token) => Ok(vec![token]),
+        OneOrMany::Many(tokens) if !tokens.is_empty() => Ok(tokens),
+        OneOrMany::Many(_) => Err(D::Error::invalid_length(0, &"at least one token")),
+    }
+}
+
+#[test]
+fn test_one_or_many() {
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Config {
+        #[serde(with = "crate::one_or_many")]
+        eos_token_id: Vec<utok>,
+    }
+
+    let one = serde_json::from_str::<Config>(r#"{"eos_token_id":2}"#).unwrap();
+    assert_eq!(one.eos_token_id, [2]);
+    assert_eq!(
+        serde_json::to_string(&one).unwrap(),
+        r#"{"eos_token_id":2}"#
+    );
+
+    let many = serde_json::from_str::<Config>(r#"{"eos_token_id":[1,2]}"#).unwrap();
+    assert_eq!(many.eos_token_id, [1, 2]);
+    assert_eq!(
+        serde_json::to_string(&many).unwrap(),
+        r#"{"eos_token_id":[1,2]}"#
+    );
+
+    assert!(serde_json::from_str::<Config>(r#"{"eos_token_id":[]}"#).is_err());
+}
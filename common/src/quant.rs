@@ -0,0 +1,360 @@
+//! 按块量化的数据格式，存储布局与 ggml 一致。
+
+use crate::f16;
+
+/// 按块量化的数据，每块包含 [Block::N] 个元素。
+pub trait Block: Copy + Send + Sync + 'static {
+    /// 每块的元素数。
+    const N: usize;
+
+    /// 量化一块数据，`x` 的长度为 [Block::N]。
+    fn quantize(x: &[f32]) -> Self;
+
+    /// 反量化一块数据，`y` 的长度为 [Block::N]。
+    fn dequantize(&self, y: &mut [f32]);
+
+    /// 与 `x` 做点积，`x` 的长度为 [Block::N]。
+    fn dot(&self, x: &[f32]) -> f32 {
+        let mut y = [0.; 256];
+        let y = &mut y[..Self::N];
+        self.dequantize(y);
+        y.iter().zip(x).map(|(y, x)| y * x).sum()
+    }
+}
+
+/// 32 个元素共用一个缩放系数，以 int8 存储。
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct Q8_0 {
+    d: [u8; 2],
+    qs: [i8; 32],
+}
+
+/// 32 个元素共用一个缩放系数，以偏移 8 的 4 位整数存储。
+///
+/// 第 `i` 个字节的低 4 位是第 `i` 个元素，高 4 位是第 `i + 16` 个元素。
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct Q4_0 {
+    d: [u8; 2],
+    qs: [u8; 16],
+}
+
+/// 256 个元素分为 8 个子块，每个子块以 6 位整数存储缩放系数和偏移，元素以 4 位整数存储。
+///
+/// 每 64 个元素占用 32 字节，低 4 位是前 32 个元素，高 4 位是后 32 个元素。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct Q4_K {
+    d: [u8; 2],
+    dmin: [u8; 2],
+    scales: [u8; 12],
+    qs: [u8; 128],
+}
+
+/// 与 [Q4_K] 相同，但元素以 5 位整数存储，第 5 位单独存放在 `qh` 中。
+///
+/// 只用于加载 llama.cpp 的混合量化模型，不支持量化。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct Q5_K {
+    d: [u8; 2],
+    dmin: [u8; 2],
+    scales: [u8; 12],
+    qh: [u8; 32],
+    qs: [u8; 128],
+}
+
+/// 256 个元素分为 16 个子块，每个子块以 int8 存储缩放系数，元素以偏移 32 的 6 位整数存储。
+///
+/// 低 4 位存放在 `ql` 中，高 2 位存放在 `qh` 中。只用于加载 llama.cpp 的混合量化模型，不支持量化。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct Q6_K {
+    ql: [u8; 128],
+    qh: [u8; 64],
+    scales: [i8; 16],
+    d: [u8; 2],
+}
+
+const _: () = assert!(std::mem::size_of::<Q8_0>() == 34);
+const _: () = assert!(std::mem::size_of::<Q4_0>() == 18);
+const _: () = assert!(std::mem::size_of::<Q4_K>() == 144);
+const _: () = assert!(std::mem::size_of::<Q5_K>() == 176);
+const _: () = assert!(std::mem::size_of::<Q6_K>() == 210);
+
+impl Block for Q8_0 {
+    const N: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        let amax = x.iter().map(|x| x.abs()).fold(0., f32::max);
+        let d = f16::from_f32(amax / i8::MAX as f32);
+        let id = recip(d.to_f32());
+        Self {
+            d: d.to_le_bytes(),
+            qs: std::array::from_fn(|i| (x[i] * id).round() as i8),
+        }
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        for (y, &q) in y.iter_mut().zip(&self.qs) {
+            *y = q as f32 * d;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        self.qs
+            .iter()
+            .zip(x)
+            .map(|(&q, x)| q as f32 * x)
+            .sum::<f32>()
+            * d
+    }
+}
+
+impl Block for Q4_0 {
+    const N: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        // 以绝对值最大的元素为 -8，使正负两侧都能充分利用
+        let max = x
+            .iter()
+            .copied()
+            .fold(0f32, |m, x| if x.abs() > m.abs() { x } else { m });
+        let d = f16::from_f32(max / -8.);
+        let id = recip(d.to_f32());
+        let q = |x: f32| (x * id + 8.5).clamp(0., 15.) as u8;
+        Self {
+            d: d.to_le_bytes(),
+            qs: std::array::from_fn(|i| q(x[i]) | q(x[i + 16]) << 4),
+        }
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        for (i, &q) in self.qs.iter().enumerate() {
+            y[i] = ((q & 0xf) as f32 - 8.) * d;
+            y[i + 16] = ((q >> 4) as f32 - 8.) * d;
+        }
+    }
+
+    fn dot(&self, x: &[f32]) -> f32 {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        let (lo, hi) = x.split_at(16);
+        let sum = self
+            .qs
+            .iter()
+            .zip(lo.iter().zip(hi))
+            .map(|(&q, (lo, hi))| ((q & 0xf) as f32 - 8.) * lo + ((q >> 4) as f32 - 8.) * hi);
+        sum.sum::<f32>() * d
+    }
+}
+
+impl Q4_K {
+    /// 第 `j` 个子块的缩放系数和偏移。
+    #[inline]
+    fn scale_min(&self, j: usize) -> (u8, u8) {
+        scale_min_k4(&self.scales, j)
+    }
+}
+
+/// 从 k-quants 以 6 位整数打包的 `scales` 中取出第 `j` 个子块的缩放系数和偏移。
+fn scale_min_k4(s: &[u8; 12], j: usize) -> (u8, u8) {
+    if j < 4 {
+        (s[j] & 63, s[j + 4] & 63)
+    } else {
+        (
+            (s[j + 4] & 0xf) | ((s[j - 4] >> 6) << 4),
+            (s[j + 4] >> 4) | ((s[j] >> 6) << 4),
+        )
+    }
+}
+
+impl Block for Q4_K {
+    const N: usize = 256;
+
+    fn quantize(x: &[f32]) -> Self {
+        // 每个子块按最小值和最大值线性量化，偏移只表示非正的最小值
+        let mut scales = [0f32; 8];
+        let mut mins = [0f32; 8];
+        for (j, x) in x.chunks_exact(32).enumerate() {
+            let min = x.iter().copied().fold(0., f32::min);
+            let max = x.iter().copied().fold(f32::MIN, f32::max);
+            scales[j] = (max - min) / 15.;
+            mins[j] = -min;
+        }
+        let d = f16::from_f32(scales.iter().copied().fold(0., f32::max) / 63.);
+        let dmin = f16::from_f32(mins.iter().copied().fold(0., f32::max) / 63.);
+        let (id, idmin) = (recip(d.to_f32()), recip(dmin.to_f32()));
+        let sc = scales.map(|s| (s * id).round().min(63.) as u8);
+        let m = mins.map(|m| (m * idmin).round().min(63.) as u8);
+
+        let mut ans = Self {
+            d: d.to_le_bytes(),
+            dmin: dmin.to_le_bytes(),
+            scales: [0; 12],
+            qs: [0; 128],
+        };
+        for j in 0..4 {
+            ans.scales[j] = sc[j] | (sc[j + 4] >> 4) << 6;
+            ans.scales[j + 4] = m[j] | (m[j + 4] >> 4) << 6;
+            ans.scales[j + 8] = (sc[j + 4] & 0xf) | (m[j + 4] & 0xf) << 4;
+        }
+        for (j, x) in x.chunks_exact(32).enumerate() {
+            let dj = d.to_f32() * sc[j] as f32;
+            let mj = dmin.to_f32() * m[j] as f32;
+            let idj = recip(dj);
+            let (qs, shift) = (&mut ans.qs[j / 2 * 32..][..32], j % 2 * 4);
+            for (q, &x) in qs.iter_mut().zip(x) {
+                *q |= (((x + mj) * idj).round().clamp(0., 15.) as u8) << shift;
+            }
+        }
+        ans
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        let dmin = f16::from_le_bytes(self.dmin).to_f32();
+        for (j, y) in y.chunks_exact_mut(32).enumerate() {
+            let (sc, m) = self.scale_min(j);
+            let (dj, mj) = (d * sc as f32, dmin * m as f32);
+            let (qs, shift) = (&self.qs[j / 2 * 32..][..32], j % 2 * 4);
+            for (y, &q) in y.iter_mut().zip(qs) {
+                *y = ((q >> shift) & 0xf) as f32 * dj - mj;
+            }
+        }
+    }
+}
+
+impl Q5_K {
+    /// 每块的元素数。
+    pub const N: usize = 256;
+
+    /// 反量化一块数据，`y` 的长度为 [Q5_K::N]。
+    pub fn dequantize(&self, y: &mut [f32]) {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        let dmin = f16::from_le_bytes(self.dmin).to_f32();
+        for (j, y) in y.chunks_exact_mut(32).enumerate() {
+            let (sc, m) = scale_min_k4(&self.scales, j);
+            let (dj, mj) = (d * sc as f32, dmin * m as f32);
+            let (qs, shift) = (&self.qs[j / 2 * 32..][..32], j % 2 * 4);
+            for ((y, &q), &h) in y.iter_mut().zip(qs).zip(&self.qh) {
+                let q = (q >> shift) & 0xf | ((h >> j) & 1) << 4;
+                *y = q as f32 * dj - mj;
+            }
+        }
+    }
+}
+
+impl Q6_K {
+    /// 每块的元素数。
+    pub const N: usize = 256;
+
+    /// 反量化一块数据，`y` 的长度为 [Q6_K::N]。
+    ///
+    /// 每 128 个元素为一组，组内第 `k` 个 32 元素段取 `ql` 的低 4 位或高 4 位和 `qh` 的第 `2k` 位起的 2 位。
+    pub fn dequantize(&self, y: &mut [f32]) {
+        let d = f16::from_le_bytes(self.d).to_f32();
+        for (n, y) in y.chunks_exact_mut(128).enumerate() {
+            let ql = &self.ql[n * 64..][..64];
+            let qh = &self.qh[n * 32..][..32];
+            for (k, y) in y.chunks_exact_mut(32).enumerate() {
+                let (ql, shift) = (&ql[k % 2 * 32..][..32], k / 2 * 4);
+                for (l, y) in y.iter_mut().enumerate() {
+                    let q = (ql[l] >> shift) & 0xf | ((qh[l] >> (2 * k)) & 3) << 4;
+                    let scale = self.scales[(n * 128 + k * 32 + l) / 16];
+                    *y = d * scale as f32 * (q as i32 - 32) as f32;
+                }
+            }
+        }
+    }
+}
+
+#[inline]
+fn recip(x: f32) -> f32 {
+    if x != 0. {
+        x.recip()
+    } else {
+        0.
+    }
+}
+
+#[cfg(test)]
+fn check<B: Block>(tolerance: f32) {
+    let x = (0..B::N * 2)
+        .map(|i| (i as f32 * 0.37).sin() * 2. + 0.5)
+        .collect::<Vec<_>>();
+    let mut y = vec![0.; x.len()];
+    for (x, y) in x.chunks_exact(B::N).zip(y.chunks_exact_mut(B::N)) {
+        let block = B::quantize(x);
+        block.dequantize(y);
+        let dot = y.iter().zip(x).map(|(y, x)| y * x).sum::<f32>();
+        assert!((block.dot(x) - dot).abs() < 1e-3 * dot.abs().max(1.));
+    }
+    for (x, y) in x.iter().zip(&y) {
+        assert!((x - y).abs() < tolerance, "{x} vs {y}");
+    }
+}
+
+#[test]
+fn test_q8_0() {
+    check::<Q8_0>(2.5 / 127.);
+}
+
+#[test]
+fn test_q4_0() {
+    check::<Q4_0>(2.5 / 8.);
+}
+
+#[test]
+fn test_q4_k() {
+    check::<Q4_K>(5. / 15.);
+}
+
+#[test]
+fn test_q5_k() {
+    let one = f16::ONE.to_le_bytes();
+    let mut scales = [0; 12];
+    // 所有子块的缩放系数为 1，第 0 个子块的偏移为 3
+    scales[..4].fill(1);
+    scales[8..].fill(1);
+    scales[4] = 3;
+    // 第 0 至 3 个子块的元素带有第 5 位
+    let block = Q5_K {
+        d: one,
+        dmin: one,
+        scales,
+        qh: [0x0f; 32],
+        qs: [0x21; 128],
+    };
+    let mut y = [0.; 256];
+    block.dequantize(&mut y);
+    for (i, y) in y.into_iter().enumerate() {
+        let j = i / 32;
+        let q = if j % 2 == 0 { 1. } else { 2. } + if j < 4 { 16. } else { 0. };
+        let m = if j == 0 { 3. } else { 0. };
+        assert_eq!(y, q - m, "{i}");
+    }
+}
+
+#[test]
+fn test_q6_k() {
+    // 每组 4 段的低 4 位依次为 1、1、2、2，高 2 位依次为 0、1、2、3
+    let block = Q6_K {
+        ql: [0x21; 128],
+        qh: [0b11_10_01_00; 64],
+        scales: std::array::from_fn(|i| i as i8 + 1),
+        d: f16::ONE.to_le_bytes(),
+    };
+    let mut y = [0.; 256];
+    block.dequantize(&mut y);
+    for (i, y) in y.into_iter().enumerate() {
+        let q = [1, 17, 34, 50][i % 128 / 32] - 32;
+        assert_eq!(y, ((i / 16 + 1) as i32 * q) as f32, "{i}");
+    }
+}
@@ -28,6 +28,8 @@ pub enum SafeTensorsError {
     Io(std::io::Error),
     /// Json 解析错误。
     Json(serde_json::Error),
+    /// gguf 文件的读取或解析错误。
+    Gguf(std::io::Error),
 }
 
 /// safetensors 文件中的张量映射。
@@ -1,8 +1,9 @@
 ﻿//!
 
+use crate::gguf::{self, GgmlType, GgufTensorRef, GgufValue};
 use std::{
-    env::var_os,
-    fs::canonicalize,
+    env::{temp_dir, var_os},
+    fs::{canonicalize, File},
     path::{Path, PathBuf},
     process::Command,
     str::from_utf8,
@@ -32,6 +33,62 @@ pub fn find() -> Option<PathBuf> {
     None
 }
 
+/// 在临时目录写入一个固定权重的单层 Llama 结构 `.gguf` 模型，用于不依赖真实模型的测试。
+///
+/// `hidden_size` 为 256，4 个注意力头，最大长度 1024，词表有 8 个词；`extra` 是额外写入的张量。
+pub fn tiny_gguf(name: &str, extra: &[GgufTensorRef]) -> PathBuf {
+    const D: usize = 256;
+    const VOCAB: usize = 8;
+
+    let uint = |v: usize| GgufValue::U32(v as _);
+    let metadata = [
+        ("general.architecture", GgufValue::String("llama".into())),
+        ("llama.embedding_length", uint(D)),
+        ("llama.feed_forward_length", uint(D)),
+        ("llama.attention.head_count", uint(4)),
+        ("llama.context_length", uint(1024)),
+        ("llama.block_count", uint(1)),
+    ]
+    .map(|(k, v)| (k.to_string(), v));
+
+    let data = |len: usize| {
+        (0..len)
+            .flat_map(|i| ((i % 17) as f32 / 64. - 0.125).to_le_bytes())
+            .collect::<Vec<_>>()
+    };
+    let norm = vec![1f32; D]
+        .into_iter()
+        .flat_map(f32::to_le_bytes)
+        .collect::<Vec<_>>();
+    let (embd, matrix) = (data(VOCAB * D), data(D * D));
+    let tensors = [
+        ("token_embd.weight", &[VOCAB, D][..], &embd),
+        ("blk.0.attn_norm.weight", &[D], &norm),
+        ("blk.0.attn_q.weight", &[D, D], &matrix),
+        ("blk.0.attn_k.weight", &[D, D], &matrix),
+        ("blk.0.attn_v.weight", &[D, D], &matrix),
+        ("blk.0.attn_output.weight", &[D, D], &matrix),
+        ("blk.0.ffn_norm.weight", &[D], &norm),
+        ("blk.0.ffn_gate.weight", &[D, D], &matrix),
+        ("blk.0.ffn_up.weight", &[D, D], &matrix),
+        ("blk.0.ffn_down.weight", &[D, D], &matrix),
+        ("output_norm.weight", &[D], &norm),
+    ]
+    .map(|(name, shape, data)| GgufTensorRef {
+        name,
+        ty: GgmlType::F32,
+        shape,
+        data,
+    })
+    .into_iter()
+    .chain(extra.iter().cloned())
+    .collect::<Vec<_>>();
+
+    let path = temp_dir().join(name).with_extension("gguf");
+    gguf::write(File::create(&path).unwrap(), &metadata, &tensors).unwrap();
+    path
+}
+
 #[test]
 fn test_find() {
     println!("{:?}", find());
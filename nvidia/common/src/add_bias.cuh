@@ -0,0 +1,9 @@
+template<class Tdata>
+static __device__ void add_bias(
+    Tdata *__restrict__ x_,
+    int const stride_x,
+    Tdata const *__restrict__ bias_) {
+    auto j = blockIdx.x * blockDim.x + threadIdx.x,
+         i = blockIdx.y * stride_x + j;
+    x_[i] = Tdata(float(x_[i]) + float(bias_[j]));
+}
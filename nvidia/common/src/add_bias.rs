@@ -0,0 +1,96 @@
+﻿use crate::PtxWapper;
+use cuda::{bindings::CUdeviceptr, ContextSpore, CudaDataType, DevByte, ModuleSpore, Ptx, Stream};
+use std::{
+    ffi::{c_uint, c_void, CString},
+    ops::{Deref, DerefMut},
+};
+use tensor::{udim, Tensor};
+
+pub struct AddBias {
+    ptx: Ptx,
+    f: CString,
+    block_size: c_uint,
+}
+
+impl PtxWapper for AddBias {
+    #[inline]
+    fn ptx(&self) -> &Ptx {
+        &self.ptx
+    }
+}
+
+impl AddBias {
+    pub fn new(data_type: CudaDataType, block_size: usize) -> Self {
+        let ty_arg = data_type.name();
+        let name = format!("add_bias_{ty_arg}");
+
+        const ADD_BIAS: &str = include_str!("add_bias.cuh");
+        let code = format!(
+            r#"{ADD_BIAS}
+
+extern "C" __global__ void {name}(
+    {ty_arg} *__restrict__ x,
+    int const stride_x,
+    {ty_arg} const *__restrict__ bias
+){{
+    add_bias(x, stride_x, bias);
+}}
+"#
+        );
+
+        let (ptx, log) = Ptx::compile(code);
+        if !log.is_empty() {
+            warn!("{log}");
+        }
+        Self {
+            ptx: ptx.unwrap(),
+            f: CString::new(name).unwrap(),
+            block_size: block_size as _,
+        }
+    }
+
+    pub fn launch<T, U>(
+        &self,
+        module: &ModuleSpore,
+        x: &mut Tensor<T>,
+        bias: &Tensor<U>,
+        stream: &Stream,
+    ) where
+        T: DerefMut<Target = [DevByte]>,
+        U: Deref<Target = [DevByte]>,
+    {
+        assert_eq!(x.data_type(), bias.data_type());
+
+        let &[seq_len, n] = x.shape() else {
+            panic!("x shape: {:?}", x.shape());
+        };
+        assert_eq!(bias.shape(), &[n]);
+        assert_eq!(x.strides()[1], 1);
+        assert!(bias.is_contiguous());
+
+        let x_ptr = (x.physical().as_ptr() as isize + x.bytes_offset()) as CUdeviceptr;
+        let bias_ptr = (bias.physical().as_ptr() as isize + bias.bytes_offset()) as CUdeviceptr;
+        let params: [*const c_void; 3] = [
+            (&x_ptr) as *const _ as _,
+            (&x.strides()[0]) as *const _ as _,
+            (&bias_ptr) as *const _ as _,
+        ];
+
+        #[inline]
+        fn gcd(mut a: udim, mut b: udim) -> u32 {
+            while b != 0 {
+                let rem = a % b;
+                a = b;
+                b = rem;
+            }
+            a
+        }
+
+        let block_dims = gcd(self.block_size, n);
+        let grid_dims = (seq_len, n / block_dims);
+
+        let module = unsafe { module.sprout(stream.ctx()) };
+        let kernel = module.get_kernel(&self.f);
+        kernel.launch(grid_dims, block_dims, params.as_ptr(), 0, Some(stream));
+    }
+}
@@ -4,6 +4,7 @@
 extern crate log;
 pub extern crate cuda;
 
+mod add_bias;
 mod fused_softmax;
 mod gather;
 mod mat_mul;
@@ -19,10 +20,11 @@ pub use common::{
 };
 pub use tensor::{slice, split, udim, DataType, LocalSplitable, Tensor};
 
+use add_bias::AddBias;
 use cublas::{Cublas, CublasSpore};
 use cuda::{
-    memcpy_d2h, ContextGuard, ContextResource, ContextSpore, CudaDataType, DevByte, ModuleSpore,
-    Ptx, Stream,
+    memcpy_d2h, ContextGuard, ContextResource, ContextSpore, CudaDataType, DevByte, DevMemSpore,
+    ModuleSpore, Ptx, Stream,
 };
 use fused_softmax::FusedSoftmax;
 use reform::Reform;
@@ -33,12 +35,13 @@ use std::{
     sync::Arc,
 };
 use swiglu::Swiglu;
-use transformer::{Kernels, Llama2};
+use transformer::{Kernels, Llama2, RopeTable};
 
 pub struct NvidiaKernelsPtx {
     epsilon: f32,
-    theta: f32,
+    rope: RopeTable,
     rms_norm: Arc<RmsNormalization>,
+    add_bias: Arc<AddBias>,
     rotary_embedding: Arc<Rope>,
     reform: Arc<Reform>,
     softmax: Arc<FusedSoftmax>,
@@ -49,12 +52,13 @@ impl NvidiaKernelsPtx {
     pub fn new(host: &dyn Llama2, block_size: usize) -> Self {
         Self {
             epsilon: host.rms_norm_eps(),
-            theta: host.rope_theta(),
+            rope: RopeTable::new(host),
             rms_norm: Arc::new(RmsNormalization::new(
                 CudaDataType::f16,
                 host.hidden_size(),
                 block_size,
             )),
+            add_bias: Arc::new(AddBias::new(CudaDataType::f16, block_size)),
             rotary_embedding: Arc::new(Rope::new(block_size)),
             reform: Arc::new(Reform::new(block_size, 32)),
             softmax: Arc::new(FusedSoftmax::new(
@@ -85,9 +89,11 @@ struct ModuleWapper<T> {
 
 pub struct NvidiaKernels {
     epsilon: f32,
-    theta: f32,
+    inv_freq: DevMemSpore,
+    dynamic: Option<(f32, f32)>,
     cublas: CublasSpore,
     rms_norm: ModuleWapper<RmsNormalization>,
+    add_bias: ModuleWapper<AddBias>,
     rotary_embedding: ModuleWapper<Rope>,
     reform: ModuleWapper<Reform>,
     softmax: ModuleWapper<FusedSoftmax>,
@@ -96,11 +102,16 @@ pub struct NvidiaKernels {
 
 impl NvidiaKernelsPtx {
     pub fn load(&self, ctx: &ContextGuard) -> NvidiaKernels {
+        let stream = ctx.stream();
+        let inv_freq = stream.from_host(self.rope.inv_freq()).sporulate();
+        stream.synchronize();
         NvidiaKernels {
             epsilon: self.epsilon,
-            theta: self.theta,
+            inv_freq,
+            dynamic: self.rope.dynamic(),
             cublas: Cublas::new(ctx).sporulate(),
             rms_norm: self.rms_norm.clone().load(ctx),
+            add_bias: self.add_bias.clone().load(ctx),
             rotary_embedding: self.rotary_embedding.clone().load(ctx),
             reform: self.reform.clone().load(ctx),
             softmax: self.softmax.clone().load(ctx),
@@ -113,7 +124,9 @@ impl NvidiaKernels {
     pub fn kill(&mut self, ctx: &ContextGuard) {
         unsafe {
             self.cublas.kill(ctx);
+            self.inv_freq.kill(ctx);
             self.rms_norm.module.kill(ctx);
+            self.add_bias.module.kill(ctx);
             self.rotary_embedding.module.kill(ctx);
             self.reform.module.kill(ctx);
             self.softmax.module.kill(ctx);
@@ -179,6 +192,16 @@ impl Kernels for KernelRuntime<'_> {
         mat_mul::mat_mul(&cublas, c, beta, a, b, alpha)
     }
 
+    #[inline]
+    fn add_bias<T, U>(&self, x: &mut Tensor<T>, bias: &Tensor<U>)
+    where
+        T: DerefMut<Target = Self::Storage>,
+        U: Deref<Target = Self::Storage>,
+    {
+        let ModuleWapper { module, kernel } = &self.kernels.add_bias;
+        kernel.launch(module, x, bias, self.stream);
+    }
+
     #[inline]
     fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>)
     where
@@ -186,7 +209,8 @@ impl Kernels for KernelRuntime<'_> {
         U: Deref<Target = Self::Storage>,
     {
         let ModuleWapper { module, kernel } = &self.kernels.rotary_embedding;
-        kernel.launch(module, t, pos, self.kernels.theta, self.stream);
+        let inv_freq = unsafe { self.kernels.inv_freq.sprout(self.stream.ctx()) };
+        kernel.launch(module, t, pos, &inv_freq, self.kernels.dynamic, self.stream);
     }
 
     #[inline]
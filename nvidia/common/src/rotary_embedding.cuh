@@ -1,9 +1,12 @@
 #include <cuda_fp16.h>
 
+template<class Tpos>
 static __device__ void padding(
     half2 *__restrict__ x_,
-    unsigned int const *__restrict__ pos_,
-    float const theta,
+    Tpos const *__restrict__ pos_,
+    float const *__restrict__ inv_freq,
+    float const dynamic_factor,
+    float const dynamic_original,
     unsigned int const leading_dim) {
     auto dh = blockDim.x;
     auto k = threadIdx.x;
@@ -11,8 +14,15 @@ static __device__ void padding(
     auto &x = x_[blockIdx.x * leading_dim + blockIdx.y * dh + k];
     auto pos = float(pos_[blockIdx.x]);
 
+    auto freq = inv_freq[k];
+    // 动态 NTK：位置超出原始上下文长度后放大底数
+    if (dynamic_factor > 0 && pos + 1 > dynamic_original) {
+        auto s = dynamic_factor * (pos + 1) / dynamic_original - (dynamic_factor - 1);
+        freq *= powf(s, -float(k) / float(dh - 1));
+    }
+
     float sin, cos;
-    sincosf(pos / powf(theta, float(k) / float(dh)), &sin, &cos);
+    sincosf(pos * freq, &sin, &cos);
 
     x = x * half2(cos, cos) + half2(-x.y, x.x) * half2(sin, sin);
 }
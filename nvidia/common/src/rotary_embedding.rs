@@ -2,6 +2,7 @@
 use cuda::{bindings::CUdeviceptr, ContextSpore, DevByte, ModuleSpore, Ptx, Stream};
 use std::{
     ffi::{c_uint, c_void, CString},
+    mem::size_of,
     ops::{Deref, DerefMut},
 };
 use tensor::{udim, DataType, Tensor};
@@ -9,6 +10,7 @@ use tensor::{udim, DataType, Tensor};
 pub struct Rope {
     ptx: Ptx,
     f: CString,
+    f_signed: CString,
     block_size: c_uint,
 }
 
@@ -22,6 +24,7 @@ impl PtxWapper for Rope {
 impl Rope {
     pub fn new(block_size: usize) -> Self {
         let name = "rotary_embedding_padding";
+        let name_signed = "rotary_embedding_padding_signed";
 
         const ROTARY_EMBEDDING: &str = include_str!("rotary_embedding.cuh");
         let code = format!(
@@ -30,10 +33,23 @@ impl Rope {
 extern "C" __global__ void {name}(
     half2              *__restrict__ x,
     unsigned int const *__restrict__ pos,
-    float theta,
+    float const *__restrict__ inv_freq,
+    float dynamic_factor,
+    float dynamic_original,
     unsigned int const leading_dim
 ){{
-    padding(x, pos, theta, leading_dim);
+    padding(x, pos, inv_freq, dynamic_factor, dynamic_original, leading_dim);
+}}
+
+extern "C" __global__ void {name_signed}(
+    half2     *__restrict__ x,
+    int const *__restrict__ pos,
+    float const *__restrict__ inv_freq,
+    float dynamic_factor,
+    float dynamic_original,
+    unsigned int const leading_dim
+){{
+    padding(x, pos, inv_freq, dynamic_factor, dynamic_original, leading_dim);
 }}
 "#
         );
@@ -45,6 +61,7 @@ extern "C" __global__ void {name}(
         Self {
             ptx: ptx.unwrap(),
             f: CString::new(name).unwrap(),
+            f_signed: CString::new(name_signed).unwrap(),
             block_size: block_size as _,
         }
     }
@@ -54,7 +71,8 @@ extern "C" __global__ void {name}(
         module: &ModuleSpore,
         t: &mut Tensor<T>,
         pos: &Tensor<U>,
-        theta: f32,
+        inv_freq: &[DevByte],
+        dynamic: Option<(f32, f32)>,
         stream: &Stream,
     ) where
         T: DerefMut<Target = [DevByte]>,
@@ -66,22 +84,31 @@ extern "C" __global__ void {name}(
 
         assert!(t.contiguous_len() >= 2);
         assert_eq!(t.data_type(), DataType::F16);
-        assert_eq!(pos.data_type(), DataType::U32);
+        let f = match pos.data_type() {
+            DataType::U32 => &self.f,
+            DataType::I32 => &self.f_signed,
+            _ => panic!("Invalid pos type"),
+        };
         assert_eq!(pos.shape(), &[nt]);
         assert!(dh < self.block_size);
+        assert_eq!(inv_freq.len(), dh as usize / 2 * size_of::<f32>());
 
         let t_ptr = (t.physical().as_ptr() as isize + t.bytes_offset()) as CUdeviceptr;
         let pos_ptr = (pos.physical().as_ptr() as isize + pos.bytes_offset()) as CUdeviceptr;
+        let freq_ptr = inv_freq.as_ptr() as CUdeviceptr;
+        let (dynamic_factor, dynamic_original) = dynamic.unwrap_or((0., 0.));
         let leading_dim = t.strides()[0] as udim / 2;
-        let params: [*const c_void; 4] = [
+        let params: [*const c_void; 6] = [
             (&t_ptr) as *const _ as _,
             (&pos_ptr) as *const _ as _,
-            (&theta) as *const _ as _,
+            (&freq_ptr) as *const _ as _,
+            (&dynamic_factor) as *const _ as _,
+            (&dynamic_original) as *const _ as _,
             (&leading_dim) as *const _ as _,
         ];
 
         let module = unsafe { module.sprout(stream.ctx()) };
-        let kernel = module.get_kernel(&self.f);
+        let kernel = module.get_kernel(f);
         kernel.launch((nh, nt), dh / 2, params.as_ptr(), 0, Some(stream))
     }
 }
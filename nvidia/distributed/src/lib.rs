@@ -5,7 +5,10 @@ mod parameters;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{
+    check_decoding, check_queries, check_sample, check_shape, check_tokens, CausalLM, DecodingMeta,
+    InferError, KVCache, Model, Pooling, QueryContext, SampleMeta,
+};
 use common_nv::{
     cast_dt,
     cuda::{
@@ -19,6 +22,7 @@ use nccl::CommunicatorGroup;
 use parameters::ParameterMatrix;
 use std::{
     iter::{repeat, zip},
+    ops::Range,
     path::Path,
     slice::from_raw_parts,
     sync::Arc,
@@ -86,11 +90,16 @@ impl CausalLM for Transformer {
     type Storage = Cache;
 
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.host.eos_token_id()
+    fn eos_tokens(&self) -> &[utok] {
+        self.host.eos_token_ids()
+    }
+
+    #[inline]
+    fn max_seq_len(&self) -> upos {
+        self.host.max_position_embeddings() as _
     }
 
-    fn new_cache(&self) -> Tensor<Self::Storage> {
+    fn new_cache(&self) -> KVCache<Self::Storage> {
         let dt = self.host.data_type();
         let nlayers = self.host.num_hidden_layers() as udim;
         let nkvh = self.host.num_key_value_heads() as udim;
@@ -100,18 +109,25 @@ impl CausalLM for Transformer {
 
         let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
         let n = contexts.len() as udim;
-        Tensor::alloc(dt, &[nlayers, 2, nkvh / n, max_seq_len, d / nh], |len| {
-            Cache {
+        KVCache::contiguous(Tensor::alloc(
+            dt,
+            &[nlayers, 2, nkvh / n, max_seq_len, d / nh],
+            |len| Cache {
                 mem: contexts
                     .iter()
                     .map(|context| context.apply(|ctx| ctx.malloc::<u8>(len).sporulate()))
                     .collect(),
                 contexts: contexts.clone(),
-            }
-        })
+            },
+        ))
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        cache: &KVCache<Self::Storage>,
+        pos: upos,
+    ) -> Option<KVCache<Self::Storage>> {
+        let cache = cache.as_contiguous();
         let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
             panic!()
         };
@@ -147,11 +163,197 @@ impl CausalLM for Transformer {
             })
             .collect();
 
-        Tensor::new(cache.data_type(), cache.shape(), Cache { contexts, mem })
+        Some(KVCache::contiguous(Tensor::new(
+            cache.data_type(),
+            cache.shape(),
+            Cache { contexts, mem },
+        )))
+    }
+
+    fn export_cache(&self, cache: &KVCache<Self::Storage>, pos: upos) -> Option<Tensor<Vec<u8>>> {
+        let cache = cache.as_contiguous();
+        let &[nlayers, 2, nkvh, max_seq_len, dh] = cache.shape() else {
+            panic!()
+        };
+        if pos > max_seq_len {
+            return None;
+        }
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ];
+
+        let n = self.kernels.len() as udim;
+        let dt = cache.data_type();
+        let mut ans = Tensor::alloc(dt, &[nlayers, 2, nkvh * n, pos, dh], |len| vec![0u8; len]);
+        for (i, context) in self.comms.contexts().enumerate() {
+            // 每张卡保存连续的一部分 kv 头
+            let mut host = Tensor::alloc(dt, &[nlayers, 2, nkvh, pos, dh], |len| vec![0u8; len]);
+            context.apply(|ctx| {
+                let stream = ctx.stream();
+                let mut tmp = Tensor::alloc(dt, host.shape(), |len| stream.malloc::<u8>(len));
+                let kernels = self.kernels[i].on(&stream);
+                kernels.reform(
+                    &mut tmp.as_mut().map_physical(|u| &mut **u),
+                    &cache
+                        .as_ref()
+                        .slice(&slice)
+                        .map_physical(|u| unsafe { u.mem[i].sprout(ctx) }),
+                );
+                stream.synchronize();
+                memcpy_d2h(host.physical_mut(), tmp.physical());
+            });
+            let heads = [
+                slice![=>],
+                slice![=>],
+                slice![i as udim * nkvh =>=> nkvh],
+                slice![=>],
+                slice![=>],
+            ];
+            host.as_ref()
+                .map_physical(|u| &**u)
+                .reform_to(&mut ans.as_mut().slice(&heads).map_physical(|u| &mut **u));
+        }
+        Some(ans)
+    }
+
+    fn import_cache(&self, tensor: &Tensor<&[u8]>) -> Option<KVCache<Self::Storage>> {
+        let mut cache = self.new_cache();
+        let block = &mut cache.blocks_mut()[0];
+        let &[nlayers, 2, nkvh, pos, dh] = tensor.shape() else {
+            return None;
+        };
+        let n = self.kernels.len() as udim;
+        let shape = block.shape();
+        if tensor.data_type() != block.data_type()
+            || [nlayers, 2, nkvh, dh] != [shape[0], 2, shape[2] * n, shape[4]]
+            || pos > shape[3]
+        {
+            return None;
+        }
+        let nkvh = shape[2];
+        let dt = block.data_type();
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ];
+
+        for (i, context) in self.comms.contexts().enumerate() {
+            let heads = [
+                slice![=>],
+                slice![=>],
+                slice![i as udim * nkvh =>=> nkvh],
+                slice![=>],
+                slice![=>],
+            ];
+            let mut host = Tensor::alloc(dt, &[nlayers, 2, nkvh, pos, dh], |len| vec![0u8; len]);
+            tensor
+                .as_ref()
+                .slice(&heads)
+                .map_physical(|u| &**u)
+                .reform_to(&mut host.as_mut().map_physical(|u| &mut **u));
+            context.apply(|ctx| {
+                let stream = ctx.stream();
+                let src = host.as_ref().map_physical(|u| stream.from_host(&u[..]));
+                let mut dst = block
+                    .as_mut()
+                    .map_physical(|u| unsafe { u.mem[i].sprout(ctx) });
+                let kernels = self.kernels[i].on(&stream);
+                kernels.reform(
+                    &mut dst.as_mut().slice(&slice).map_physical(|u| &mut **u),
+                    &src.as_ref().map_physical(|u| &**u),
+                );
+                stream.synchronize();
+            });
+        }
+        Some(cache)
     }
 
-    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
+    fn retain_cache(&self, cache: &mut KVCache<Self::Storage>, retain: &[Range<upos>]) -> upos {
+        let block = &mut cache.blocks_mut()[0];
+        let &[_nlayers, 2, nkvh, _max_seq_len, dh] = block.shape() else {
+            panic!()
+        };
+        let dt = block.data_type();
+
+        for (i, context) in self.comms.contexts().enumerate() {
+            context.apply(|ctx| {
+                let stream = ctx.stream();
+                let kernels = self.kernels[i].on(&stream);
+                let mut block = block
+                    .as_mut()
+                    .map_physical(|u| unsafe { u.mem[i].sprout(ctx) });
+
+                let mut dst = 0;
+                for range in retain {
+                    let n = range.len() as udim;
+                    let shift = range.start - dst;
+                    if shift == 0 || n == 0 {
+                        dst += n;
+                        continue;
+                    }
+                    // 前移的键旋转 -shift 个位置
+                    let mut tmp = Tensor::alloc(dt, &[n, nkvh, dh], |len| stream.malloc::<u8>(len));
+                    let pos = vec![-(shift as i32); n as usize];
+                    let pos = Tensor::new(DataType::I32, &[n], stream.from_host(&pos));
+
+                    for layer in 0..self.host.num_hidden_layers() {
+                        for kv in 0..2 {
+                            let slice = |start: udim| {
+                                [
+                                    slice![=layer],
+                                    slice![=kv],
+                                    slice![=>],
+                                    slice![start =>=> n],
+                                    slice![=>],
+                                ]
+                            };
+                            let src = block
+                                .as_ref()
+                                .slice(&slice(range.start))
+                                .map_physical(|u| &**u)
+                                .reshape(&[nkvh, n, dh]);
+                            kernels.reform(
+                                &mut tmp
+                                    .as_mut()
+                                    .transpose(&[1, 0, 2])
+                                    .map_physical(|u| &mut **u),
+                                &src,
+                            );
+                            if kv == 0 {
+                                kernels.rotary_embedding(&mut tmp, &pos);
+                            }
+                            let mut dst = block
+                                .as_mut()
+                                .slice(&slice(dst))
+                                .map_physical(|u| &mut **u)
+                                .reshape(&[nkvh, n, dh]);
+                            kernels.reform(
+                                &mut dst,
+                                &tmp.as_ref().transpose(&[1, 0, 2]).map_physical(|u| &**u),
+                            );
+                        }
+                    }
+                    dst += n;
+                }
+            });
+        }
+        cache.retain(retain);
+        retain.iter().map(|r| r.len() as upos).sum()
+    }
+
+    fn token_embed(
+        &self,
+        queries: impl IntoIterator<Item = utok>,
+    ) -> Result<Tensor<Self::Storage>, InferError> {
         let tokens = queries.into_iter().collect::<Vec<_>>();
+        check_tokens(&tokens, self.host.vocab_size())?;
         let nt = tokens.len() as udim;
 
         let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
@@ -172,19 +374,21 @@ impl CausalLM for Transformer {
                 comm.broadcast(&mut dst, None, 0, &stream);
             });
         }
-        x.map_physical(|mem| Cache { contexts, mem })
+        Ok(x.map_physical(|mem| Cache { contexts, mem }))
     }
 
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, InferError>
     where
         Self: 'a,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
-        let mut nt = 0;
+        let nt = check_queries(&queries, self.max_seq_len())?;
+        check_shape(&[nt, self.host.hidden_size() as _], token_embedded.shape())?;
+
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
         let seq_len = queries
@@ -192,7 +396,6 @@ impl CausalLM for Transformer {
             .map(|q| {
                 let seq = q.seq_len();
                 let att = q.att_len();
-                nt += seq;
                 max_seq_len = max_seq_len.max(seq);
                 max_att_len = max_att_len.max(att);
                 seq
@@ -207,7 +410,8 @@ impl CausalLM for Transformer {
         let dkv = nkvh * dh;
         let di = self.host.intermediate_size() as udim;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
+        let head_div = (dh as f32).sqrt().recip()
+            * self.host.rope_scaling().map_or(1., |s| s.attention_scale());
 
         let contexts = self.comms.contexts().collect::<Vec<_>>();
         let n = contexts.len() as udim;
@@ -226,6 +430,14 @@ impl CausalLM for Transformer {
             &contexts,
             (nh / n * max_seq_len * max_att_len) as usize * dt.size(),
         );
+        let mut kv_buf = malloc_all(
+            &contexts,
+            if queries.iter().any(|q| q.cache.is_none()) {
+                (2 * nkvh / n * max_seq_len * dh) as usize * dt.size()
+            } else {
+                0
+            },
+        );
         let pos = causal_lm::pos(&queries, nt);
         let mut pos = pos.as_ref().map_physical(|u| {
             contexts
@@ -242,7 +454,8 @@ impl CausalLM for Transformer {
         });
 
         let mut x = token_embedded;
-        for layer in 0..self.host.num_hidden_layers() {
+        let mut missing = None;
+        'layers: for layer in 0..self.host.num_hidden_layers() {
             let (mut x1, qkv) = state!();
             let mut qkv = qkv.slice(&[slice![=>], slice![=> (d + dkv + dkv) / n]]);
 
@@ -259,6 +472,9 @@ impl CausalLM for Transformer {
                     let mut qkv = qkv.as_mut().map_physical(|u| unsafe { ctx.sprout(&u[i]) });
                     kernels.rms_norm(&mut x1, &x, &params.input_layernorm());
                     kernels.mat_mul(&mut qkv, 0., &x1, &params.w_qkv(), 1.);
+                    if let Some(b_qkv) = params.b_qkv() {
+                        kernels.add_bias(&mut qkv, &b_qkv);
+                    }
                 });
             }
 
@@ -287,20 +503,38 @@ impl CausalLM for Transformer {
             let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
             let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
 
-            for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
+            for (i, (query, q, k, v, mut o)) in izip!(&mut queries, q, k, v, o).enumerate() {
                 let pos = query.pos();
                 let seq_len = query.seq_len();
                 let att_len = query.att_len();
                 let mut cache = query
                     .cache
                     .as_mut()
-                    .map(|t| t.as_mut().map_physical(|u| &mut *u.mem));
+                    .map(|c| c.map_blocks(|t| t.as_mut().map_physical(|u| &mut *u.mem)));
                 let mut query = QueryContext {
                     cache: cache.as_mut(),
                     range: query.range.clone(),
                 };
-                let Some((mut k_cache, mut v_cache)) = query.cache(layer) else {
-                    continue;
+                let (mut k_cache, mut v_cache) = match query.cache(layer) {
+                    Some(kv) => kv,
+                    // 不带缓存的查询只关注自身
+                    None if pos == 0 => {
+                        let kv = Tensor::new(
+                            dt,
+                            &[2, nkvh / n, seq_len, dh],
+                            LocalSplitable::from(&mut kv_buf[..]),
+                        );
+                        let (k, v) = split!(kv; [0]: 1, 1);
+                        (
+                            k.reshape(&[nkvh / n, seq_len, dh]),
+                            v.reshape(&[nkvh / n, seq_len, dh]),
+                        )
+                    }
+                    // 跳出循环，释放显存后再报错
+                    None => {
+                        missing = Some(i);
+                        break 'layers;
+                    }
                 };
 
                 let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
@@ -435,24 +669,31 @@ impl CausalLM for Transformer {
                 ctx.kill(&mut state_buf.physical_mut()[i]);
                 ctx.kill(&mut q_buf[i]);
                 ctx.kill(&mut att_buf[i]);
+                ctx.kill(&mut kv_buf[i]);
                 ctx.kill(&mut pos.physical_mut()[i]);
             });
         }
 
-        x
+        match missing {
+            Some(query) => Err(InferError::MissingCache { query }),
+            None => Ok(x),
+        }
     }
 
     fn decode(
         &self,
         decoding: impl IntoIterator<Item = DecodingMeta>,
         mut hidden_state: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage> {
+    ) -> Result<Tensor<Self::Storage>, InferError> {
         let dt = self.host.data_type();
         let d = self.host.hidden_size();
         let voc = self.host.vocab_size() as udim;
 
+        let decoding = decoding.into_iter().collect::<Vec<_>>();
+        check_decoding(&decoding, hidden_state.shape(), d as _)?;
+
         let contexts = Arc::new(vec![self.comms.contexts().next().unwrap()]);
-        contexts[0].apply(|ctx| {
+        Ok(contexts[0].apply(|ctx| {
             let mut x = hidden_state
                 .as_mut()
                 .map_physical(|u| unsafe { u.mem[0].sprout(ctx) });
@@ -498,8 +739,9 @@ impl CausalLM for Transformer {
                 }
             }
 
-            if dst == begin {
-                return Tensor::alloc(dt, &[0, d as _], |_| Cache {
+            // 没有需要解码的 token
+            if dst <= begin {
+                return Tensor::alloc(dt, &[0, voc], |_| Cache {
                     contexts: contexts.clone(),
                     mem: vec![stream.malloc::<u8>(0).sporulate()],
                 });
@@ -528,27 +770,68 @@ impl CausalLM for Transformer {
                 contexts: contexts.clone(),
                 mem: vec![u.sporulate()],
             })
-        })
+        }))
+    }
+
+    fn embed(
+        &self,
+        seq_len: impl IntoIterator<Item = udim>,
+        token_embedded: Tensor<Self::Storage>,
+        pooling: Pooling,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        let d = self.host.hidden_size();
+
+        let seq_len = seq_len.into_iter().collect::<Vec<_>>();
+        let queries = seq_len.iter().map(|&len| QueryContext {
+            cache: None,
+            range: 0..len,
+        });
+        let mut hidden_state = self.forward(queries, token_embedded)?;
+
+        let mut host = vec![f16::ZERO; hidden_state.size()];
+        let contexts = self.comms.contexts().collect::<Vec<_>>();
+        contexts[0].apply(|ctx| {
+            let stream = unsafe { self.streams[0].sprout(ctx) };
+            let kernels = self.kernels[0].on(&stream);
+
+            let mut x = hidden_state
+                .as_mut()
+                .map_physical(|u| unsafe { u.mem[0].sprout(ctx) });
+            let model_norm = self
+                .model_norm
+                .as_ref()
+                .map_physical(|u| unsafe { u.sprout(ctx) });
+            // 复制一个 x 以实现原地归一化
+            let x_ = x
+                .as_ref()
+                .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+            kernels.rms_norm(&mut x, &x_, &model_norm);
+            stream.synchronize();
+            memcpy_d2h(&mut host, x.physical());
+        });
+        Ok(pooling.pool(&host, d, &seq_len))
     }
 
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
-    ) -> Vec<utok> {
+    ) -> Result<Vec<utok>, InferError> {
         assert_eq!(logits.data_type(), DataType::F16);
-        let &[_, voc] = logits.shape() else { panic!() };
-        let voc = voc as usize;
+        let voc = self.host.vocab_size();
+        let args = args.into_iter().collect::<Vec<_>>();
+        check_sample(&args, logits.shape(), voc as _)?;
 
         let mut host = vec![f16::ZERO; logits.size()];
         let Cache { contexts, mem } = logits.physical();
         contexts[0].apply(|ctx| memcpy_d2h(&mut host, unsafe { &mem[0].sprout(ctx) }));
 
-        args.into_iter()
+        Ok(args
+            .into_iter()
             .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
             .enumerate()
             .map(|(i, args)| args.random(&host[i * voc..][..voc]))
-            .collect()
+            .collect())
     }
 }
 
@@ -626,25 +909,25 @@ fn test_infer() {
     let mut pos = 0;
 
     while prompt != &[model.eos_token()] {
-        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied());
+        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied()).unwrap();
 
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
         }];
-        let hidden_state = CausalLM::forward(&model, queries, token_embedded);
+        let hidden_state = CausalLM::forward(&model, queries, token_embedded).unwrap();
 
         let decoding = [DecodingMeta {
             num_query: prompt.len(),
             num_decode: 1,
         }];
-        let logits = CausalLM::decode(&model, decoding, hidden_state);
+        let logits = CausalLM::decode(&model, decoding, hidden_state).unwrap();
 
         let args = [SampleMeta {
             num_decode: 1,
             args: causal_lm::SampleArgs::default(),
         }];
-        let tokens = CausalLM::sample(&model, args, logits);
+        let tokens = CausalLM::sample(&model, args, logits).unwrap();
 
         println!("{:?}", tokens);
         pos += prompt.len() as upos;
@@ -95,6 +95,21 @@ impl Layer<'_> {
         .transpose(&[1, 0])
     }
 
+    #[inline]
+    pub fn b_qkv(&self) -> Option<Tensor<&[DevByte]>> {
+        let nh = self.scheme.nh;
+        let nkvh = self.scheme.nkvh;
+        let dh = self.scheme.dh;
+        let n = self.scheme.n as udim;
+        self.scheme.b_qkv.map(|offset| {
+            Tensor::new(
+                self.scheme.dt,
+                &[(nh + nkvh + nkvh) / n * dh],
+                &self.mem[offset..],
+            )
+        })
+    }
+
     #[inline]
     pub fn w_o(&self) -> Tensor<&[DevByte]> {
         let d = self.scheme.nh * self.scheme.dh;
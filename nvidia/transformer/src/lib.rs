@@ -5,7 +5,10 @@ mod parameters;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{
+    check_decoding, check_queries, check_sample, check_shape, check_tokens, CausalLM, DecodingMeta,
+    InferError, KVCache, Model, Pooling, QueryContext, SampleMeta,
+};
 use common_nv::{
     cuda::{memcpy_d2h, DevMemSpore},
     f16, slice, split, udim, upos, utok, DataType, LocalSplitable, NvidiaKernels, NvidiaKernelsPtx,
@@ -16,6 +19,7 @@ use itertools::izip;
 use parameters::{LayersParameters, ModelParameters};
 use std::{
     iter::repeat,
+    ops::Range,
     path::Path,
     slice::from_raw_parts,
     sync::{Arc, Mutex},
@@ -48,6 +52,23 @@ impl Model for Transformer {
             Some(|l| context.apply(|ctx| ctx.malloc_host::<u8>(l).sporulate())),
         )?;
         info!("load host: {:?}", time.elapsed());
+        assert_eq!(host.num_experts(), 0, "mixture of experts is not supported");
+        assert!(host.is_llama_like(), "only llama-style layers are supported");
+        assert!(!host.alibi(), "alibi is not supported");
+        assert!(
+            host.sliding_window().is_none(),
+            "sliding window is not supported"
+        );
+        assert!(
+            host.quantization().is_none(),
+            "quantized weights are not supported"
+        );
+        assert!(
+            host.scale_emb().is_none()
+                && host.scale_depth().is_none()
+                && host.dim_model_base().is_none(),
+            "muP scaling is not supported"
+        );
         let load_layers = host.num_hidden_layers();
 
         let (model, layers, kernels, transfer, compute) = context.apply(|ctx| {
@@ -78,11 +99,16 @@ impl CausalLM for Transformer {
     type Storage = Cache;
 
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.host.eos_token_id()
+    fn eos_tokens(&self) -> &[utok] {
+        self.host.eos_token_ids()
+    }
+
+    #[inline]
+    fn max_seq_len(&self) -> upos {
+        self.host.max_position_embeddings() as _
     }
 
-    fn new_cache(&self) -> Tensor<Self::Storage> {
+    fn new_cache(&self) -> KVCache<Self::Storage> {
         let dt = self.host.data_type();
         let nlayers = self.host.num_hidden_layers() as udim;
         let nkvh = self.host.num_key_value_heads() as udim;
@@ -90,13 +116,22 @@ impl CausalLM for Transformer {
         let d = self.host.hidden_size() as udim;
         let nh = self.host.num_attention_heads() as udim;
 
-        Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, d / nh], |len| Cache {
-            context: self.context.clone(),
-            mem: self.context.apply(|ctx| ctx.malloc::<u8>(len).sporulate()),
-        })
+        KVCache::contiguous(Tensor::alloc(
+            dt,
+            &[nlayers, 2, nkvh, max_seq_len, d / nh],
+            |len| Cache {
+                context: self.context.clone(),
+                mem: self.context.apply(|ctx| ctx.malloc::<u8>(len).sporulate()),
+            },
+        ))
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
+    fn duplicate_cache(
+        &self,
+        cache: &KVCache<Self::Storage>,
+        pos: upos,
+    ) -> Option<KVCache<Self::Storage>> {
+        let cache = cache.as_contiguous();
         let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
             panic!()
         };
@@ -122,22 +157,172 @@ impl CausalLM for Transformer {
                     .slice(&slice)
                     .map_physical(|u| unsafe { u.mem.sprout(ctx) }),
             );
-            ans.map_physical(|u| Cache {
+            Some(KVCache::contiguous(ans.map_physical(|u| Cache {
                 context: self.context.clone(),
                 mem: u.sporulate(),
-            })
+            })))
         })
     }
 
-    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
+    fn export_cache(&self, cache: &KVCache<Self::Storage>, pos: upos) -> Option<Tensor<Vec<u8>>> {
+        let cache = cache.as_contiguous();
+        let &[nlayers, 2, nkvh, max_seq_len, dh] = cache.shape() else {
+            panic!()
+        };
+        if pos > max_seq_len {
+            return None;
+        }
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ];
+
+        let mut ans = Tensor::alloc(cache.data_type(), &[nlayers, 2, nkvh, pos, dh], |len| {
+            vec![0u8; len]
+        });
+        self.context.apply(|ctx| {
+            let stream = ctx.stream();
+            let mut tmp =
+                Tensor::alloc(ans.data_type(), ans.shape(), |len| stream.malloc::<u8>(len));
+            let kernels = self.kernels.on(&stream);
+            kernels.reform(
+                &mut tmp.as_mut().map_physical(|u| &mut **u),
+                &cache
+                    .as_ref()
+                    .slice(&slice)
+                    .map_physical(|u| unsafe { u.mem.sprout(ctx) }),
+            );
+            stream.synchronize();
+            memcpy_d2h(ans.physical_mut(), tmp.physical());
+        });
+        Some(ans)
+    }
+
+    fn import_cache(&self, tensor: &Tensor<&[u8]>) -> Option<KVCache<Self::Storage>> {
+        let mut cache = self.new_cache();
+        let block = &mut cache.blocks_mut()[0];
+        let &[nlayers, 2, nkvh, pos, dh] = tensor.shape() else {
+            return None;
+        };
+        if tensor.data_type() != block.data_type()
+            || [nlayers, 2, nkvh, dh] != [block.shape()[0], 2, block.shape()[2], block.shape()[4]]
+            || pos > block.shape()[3]
+        {
+            return None;
+        }
+        let slice = [
+            slice![=>],
+            slice![=>],
+            slice![=>],
+            slice![=>pos],
+            slice![=>],
+        ];
+
+        self.context.apply(|ctx| {
+            let stream = ctx.stream();
+            let src = tensor.as_ref().map_physical(|u| stream.from_host(&u[..]));
+            let mut dst = block
+                .as_mut()
+                .map_physical(|u| unsafe { u.mem.sprout(ctx) });
+            let kernels = self.kernels.on(&stream);
+            kernels.reform(
+                &mut dst.as_mut().slice(&slice).map_physical(|u| &mut **u),
+                &src.as_ref().map_physical(|u| &**u),
+            );
+            stream.synchronize();
+        });
+        Some(cache)
+    }
+
+    fn retain_cache(&self, cache: &mut KVCache<Self::Storage>, retain: &[Range<upos>]) -> upos {
         let dt = self.host.data_type();
         let d = self.host.hidden_size() as udim;
+        let nh = self.host.num_attention_heads() as udim;
+        let nkvh = self.host.num_key_value_heads() as udim;
+        let dh = d / nh;
+
+        let mut dst = 0;
+        let block = &mut cache.blocks_mut()[0];
         self.context.apply(|ctx| {
             let compute = unsafe { self.compute.sprout(ctx) };
             let kernels = self.kernels.on(&compute);
+            let mut block = block
+                .as_mut()
+                .map_physical(|u| unsafe { u.mem.sprout(ctx) });
 
-            let tokens = queries.into_iter().collect::<Vec<_>>();
-            let nt = tokens.len() as udim;
+            for range in retain {
+                let n = range.len() as udim;
+                let shift = range.start - dst;
+                if shift == 0 || n == 0 {
+                    dst += n;
+                    continue;
+                }
+                // 前移的键旋转 -shift 个位置
+                let mut tmp = Tensor::alloc(dt, &[n, nkvh, dh], |len| compute.malloc::<u8>(len));
+                let pos = vec![-(shift as i32); n as usize];
+                let pos = Tensor::new(DataType::I32, &[n], compute.from_host(&pos));
+
+                for layer in 0..self.host.num_hidden_layers() {
+                    for i in 0..2 {
+                        let slice = |start: udim| {
+                            [
+                                slice![=layer],
+                                slice![=i],
+                                slice![=>],
+                                slice![start =>=> n],
+                                slice![=>],
+                            ]
+                        };
+                        let src = block
+                            .as_ref()
+                            .slice(&slice(range.start))
+                            .map_physical(|u| &**u)
+                            .reshape(&[nkvh, n, dh]);
+                        kernels.reform(
+                            &mut tmp
+                                .as_mut()
+                                .transpose(&[1, 0, 2])
+                                .map_physical(|u| &mut **u),
+                            &src,
+                        );
+                        if i == 0 {
+                            kernels.rotary_embedding(&mut tmp, &pos);
+                        }
+                        let mut dst = block
+                            .as_mut()
+                            .slice(&slice(dst))
+                            .map_physical(|u| &mut **u)
+                            .reshape(&[nkvh, n, dh]);
+                        kernels.reform(
+                            &mut dst,
+                            &tmp.as_ref().transpose(&[1, 0, 2]).map_physical(|u| &**u),
+                        );
+                    }
+                }
+                dst += n;
+            }
+        });
+        cache.retain(retain);
+        dst
+    }
+
+    fn token_embed(
+        &self,
+        queries: impl IntoIterator<Item = utok>,
+    ) -> Result<Tensor<Self::Storage>, InferError> {
+        let dt = self.host.data_type();
+        let d = self.host.hidden_size() as udim;
+
+        let tokens = queries.into_iter().collect::<Vec<_>>();
+        check_tokens(&tokens, self.host.vocab_size())?;
+        let nt = tokens.len() as udim;
+
+        Ok(self.context.apply(|ctx| {
+            let compute = unsafe { self.compute.sprout(ctx) };
+            let kernels = self.kernels.on(&compute);
 
             let mut x = Tensor::alloc(dt, &[nt, d], |len| compute.malloc::<u8>(len));
             kernels.gather(&mut x, &self.host.embed_tokens(), tokens);
@@ -145,19 +330,21 @@ impl CausalLM for Transformer {
                 context: self.context.clone(),
                 mem: u.sporulate(),
             })
-        })
+        }))
     }
 
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, InferError>
     where
         Self: 'a,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
-        let mut nt = 0;
+        let nt = check_queries(&queries, self.max_seq_len())?;
+        check_shape(&[nt, self.host.hidden_size() as _], token_embedded.shape())?;
+
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
         let seq_len = queries
@@ -165,7 +352,6 @@ impl CausalLM for Transformer {
             .map(|q| {
                 let seq = q.seq_len();
                 let att = q.att_len();
-                nt += seq;
                 max_seq_len = max_seq_len.max(seq);
                 max_att_len = max_att_len.max(att);
                 seq
@@ -180,7 +366,8 @@ impl CausalLM for Transformer {
         let dkv = nkvh * dh;
         let di = self.host.intermediate_size() as udim;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
+        let head_div = (dh as f32).sqrt().recip()
+            * self.host.rope_scaling().map_or(1., |s| s.attention_scale());
 
         let mut x_ = token_embedded;
         self.context.apply(|ctx| {
@@ -197,6 +384,11 @@ impl CausalLM for Transformer {
 
             let mut q_buf = compute.malloc::<u8>((nh * max_seq_len * dh) as usize * dt.size());
             let mut att_buf = compute.malloc::<u8>((nh * max_seq_len * max_att_len) as usize * dt.size());
+            let mut kv_buf = compute.malloc::<u8>(if queries.iter().any(|q| q.cache.is_none()) {
+                (2 * nkvh * max_seq_len * dh) as usize * dt.size()
+            } else {
+                0
+            });
             let pos = causal_lm::pos(&queries, nt);
             let pos = pos.as_ref().map_physical(|u| compute.from_host(u));
 
@@ -215,6 +407,9 @@ impl CausalLM for Transformer {
 
                 kernels.rms_norm(&mut x1, &x, &params.input_layernorm(ctx));
                 kernels.mat_mul(&mut qkv, 0., &x1, &params.w_qkv(ctx), 1.);
+                if let Some(b_qkv) = params.b_qkv(ctx) {
+                    kernels.add_bias(&mut qkv, &b_qkv);
+                }
 
                 let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
                 let mut q = q.reshape(&[nt, nh, dh]);
@@ -230,14 +425,21 @@ impl CausalLM for Transformer {
                 let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
                 let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
 
-                for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
+                for (i, (query, q, k, v, mut o)) in izip!(&mut queries, q, k, v, o).enumerate() {
                     let pos = query.pos();
                     let seq_len = query.seq_len();
                     let att_len = query.att_len();
-                    let mut cache = query.cache.as_mut().map(|t|t.as_mut().map_physical(|u| unsafe { u.mem.sprout(ctx) }));
+                    let mut cache = query.cache.as_mut().map(|c| c.map_blocks(|t| t.as_mut().map_physical(|u| unsafe { u.mem.sprout(ctx) })));
                     let mut  query = QueryContext{ cache:cache.as_mut(), range: query.range.clone() };
-                    let Some((mut k_cache, mut v_cache)) = query.cache(layer) else {
-                        continue;
+                    let (mut k_cache, mut v_cache) = match query.cache(layer) {
+                        Some(kv) => kv,
+                        // 不带缓存的查询只关注自身
+                        None if pos == 0 => {
+                            let kv = Tensor::new(dt, &[2, nkvh, seq_len, dh], LocalSplitable::from(&mut kv_buf[..]));
+                            let (k, v) = split!(kv; [0]: 1, 1);
+                            (k.reshape(&[nkvh, seq_len, dh]), v.reshape(&[nkvh, seq_len, dh]))
+                        }
+                        None => return Err(InferError::MissingCache { query: i }),
                     };
 
                     let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
@@ -278,20 +480,24 @@ impl CausalLM for Transformer {
                 kernels.swiglu(&mut gate, &up);
                 kernels.mat_mul(&mut x, 1., &gate, &params.mlp_down(ctx), 1.);
             }
-        });
-        x_
+            Ok(())
+        })?;
+        Ok(x_)
     }
 
     fn decode(
         &self,
         decoding: impl IntoIterator<Item = DecodingMeta>,
         mut hidden_state: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage> {
+    ) -> Result<Tensor<Self::Storage>, InferError> {
         let dt = self.host.data_type();
         let d = self.host.hidden_size();
         let voc = self.host.vocab_size() as udim;
 
-        self.context.apply(|ctx| {
+        let decoding = decoding.into_iter().collect::<Vec<_>>();
+        check_decoding(&decoding, hidden_state.shape(), d as _)?;
+
+        Ok(self.context.apply(|ctx| {
             let mut x = hidden_state
                 .as_mut()
                 .map_physical(|u| unsafe { u.mem.sprout(ctx) });
@@ -338,8 +544,9 @@ impl CausalLM for Transformer {
                 }
             }
 
-            if dst == begin {
-                return Tensor::alloc(dt, &[0, d as _], |_| Cache {
+            // 没有需要解码的 token
+            if dst <= begin {
+                return Tensor::alloc(dt, &[0, voc], |_| Cache {
                     context: self.context.clone(),
                     mem: compute.malloc::<u8>(0).sporulate(),
                 });
@@ -361,27 +568,64 @@ impl CausalLM for Transformer {
                 context: self.context.clone(),
                 mem: u.sporulate(),
             })
-        })
+        }))
+    }
+
+    fn embed(
+        &self,
+        seq_len: impl IntoIterator<Item = udim>,
+        token_embedded: Tensor<Self::Storage>,
+        pooling: Pooling,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        let d = self.host.hidden_size();
+
+        let seq_len = seq_len.into_iter().collect::<Vec<_>>();
+        let queries = seq_len.iter().map(|&len| QueryContext {
+            cache: None,
+            range: 0..len,
+        });
+        let mut hidden_state = self.forward(queries, token_embedded)?;
+
+        let mut host = vec![f16::ZERO; hidden_state.size()];
+        self.context.apply(|ctx| {
+            let compute = unsafe { self.compute.sprout(ctx) };
+            let kernels = self.kernels.on(&compute);
+
+            let mut x = hidden_state
+                .as_mut()
+                .map_physical(|u| unsafe { u.mem.sprout(ctx) });
+            let (model_norm, _) = unsafe { self.model.release(&compute) };
+            // 复制一个 x 以实现原地归一化
+            let x_ = x
+                .as_ref()
+                .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+            kernels.rms_norm(&mut x, &x_, &model_norm);
+            compute.synchronize();
+            memcpy_d2h(&mut host, x.physical());
+        });
+        Ok(pooling.pool(&host, d, &seq_len))
     }
 
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
         mut logits: Tensor<Self::Storage>,
-    ) -> Vec<utok> {
+    ) -> Result<Vec<utok>, InferError> {
         assert_eq!(logits.data_type(), DataType::F16);
-        let &[_, voc] = logits.shape() else { panic!() };
-        let voc = voc as usize;
+        let voc = self.host.vocab_size();
+        let args = args.into_iter().collect::<Vec<_>>();
+        check_sample(&args, logits.shape(), voc as _)?;
 
         let mut host = vec![f16::ZERO; logits.size()];
         let Cache { context, mem } = logits.physical_mut();
         context.apply(|ctx| memcpy_d2h(&mut host, unsafe { &mem.sprout(ctx) }));
 
-        args.into_iter()
+        Ok(args
+            .into_iter()
             .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
             .enumerate()
             .map(|(i, args)| args.random(&host[i * voc..][..voc]))
-            .collect()
+            .collect())
     }
 }
 
@@ -438,25 +682,25 @@ fn test_infer() {
     let mut pos = 0;
 
     while prompt != &[model.eos_token()] {
-        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied());
+        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied()).unwrap();
 
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
         }];
-        let hidden_state = CausalLM::forward(&model, queries, token_embedded);
+        let hidden_state = CausalLM::forward(&model, queries, token_embedded).unwrap();
 
         let decoding = [DecodingMeta {
             num_query: prompt.len(),
             num_decode: 1,
         }];
-        let logits = CausalLM::decode(&model, decoding, hidden_state);
+        let logits = CausalLM::decode(&model, decoding, hidden_state).unwrap();
 
         let args = [SampleMeta {
             num_decode: 1,
             args: causal_lm::SampleArgs::default(),
         }];
-        let tokens = CausalLM::sample(&model, args, logits);
+        let tokens = CausalLM::sample(&model, args, logits).unwrap();
 
         println!("{:?}", tokens);
         pos += prompt.len() as upos;
@@ -83,6 +83,9 @@ impl LayersParameters {
         for layer in &mut self.layers {
             layer.input_layernorm.physical_mut().kill(ctx);
             layer.w_qkv.physical_mut().kill(ctx);
+            if let Some(b_qkv) = &mut layer.b_qkv {
+                b_qkv.physical_mut().kill(ctx);
+            }
             layer.self_attn_o_proj.physical_mut().kill(ctx);
             layer.post_attention_layernorm.physical_mut().kill(ctx);
             layer.mlp_gate_up.physical_mut().kill(ctx);
@@ -95,6 +98,7 @@ impl LayersParameters {
 pub(crate) struct LayerParameter {
     pub input_layernorm: Tensor<DevMemSpore>,
     pub w_qkv: Tensor<DevMemSpore>,
+    pub b_qkv: Option<Tensor<DevMemSpore>>,
     pub self_attn_o_proj: Tensor<DevMemSpore>,
     pub post_attention_layernorm: Tensor<DevMemSpore>,
     pub mlp_gate_up: Tensor<DevMemSpore>,
@@ -119,6 +123,13 @@ impl LayerParameter {
         unsafe { self.w_qkv.as_ref().map_physical(|s| s.sprout(ctx)) }
     }
 
+    #[inline]
+    pub fn b_qkv<'ctx>(&self, ctx: &'ctx ContextGuard) -> Option<Tensor<DevMem<'ctx>>> {
+        self.b_qkv
+            .as_ref()
+            .map(|b| unsafe { b.as_ref().map_physical(|s| s.sprout(ctx)) })
+    }
+
     #[inline]
     pub fn w_o<'ctx>(&self, ctx: &'ctx ContextGuard) -> Tensor<DevMem<'ctx>> {
         unsafe {
@@ -158,6 +169,10 @@ impl LayerParameter {
         Self {
             input_layernorm: map!(input_layernorm),
             w_qkv: map!(w_qkv).transpose(&[1, 0]),
+            b_qkv: host.b_qkv(layer).map(|b| {
+                b.as_ref()
+                    .map_physical(|slice| stream.from_host(slice).sporulate())
+            }),
             self_attn_o_proj: map!(self_attn_o_proj).transpose(&[1, 0]),
             post_attention_layernorm: map!(post_attention_layernorm),
             mlp_gate_up: map!(mlp_gate_up).transpose(&[1, 0]),
@@ -183,6 +198,12 @@ impl LayerParameter {
         }
         update!(input_layernorm);
         update!(w_qkv);
+        if let (Some(dst), Some(src)) = (&mut self.b_qkv, host.b_qkv(layer)) {
+            stream.memcpy_h2d(
+                unsafe { &mut dst.physical_mut().sprout(ctx) },
+                src.as_slice(),
+            );
+        }
         update!(self_attn_o_proj);
         update!(post_attention_layernorm);
         update!(mlp_gate_up);
@@ -0,0 +1,124 @@
+//! 会话存档的读写。
+//!
+//! 存档由 8 字节小端的头部长度、JSON 格式的头部和缓存数据依次组成。
+
+use common::{upos, utok};
+use std::{
+    fs,
+    io::{self, ErrorKind::InvalidData, Write},
+    path::Path,
+};
+use tensor::{udim, DataType};
+
+/// 存档的头部。
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct Header {
+    /// 保存会话的模型的指纹。
+    pub fingerprint: u64,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub sink: usize,
+    pub dialog: Vec<SentenceRecord>,
+    pub tail: Vec<utok>,
+    /// 头部之后保存的缓存，`None` 表示未保存缓存。
+    pub cache: Option<CacheRecord>,
+}
+
+impl Header {
+    /// 对话的总长度，句子不是从 0 开始首尾相接或者来自上一句的后续超过句子本身时返回 `None`。
+    pub fn dialog_len(&self) -> Option<upos> {
+        self.dialog.iter().try_fold(0, |pos, s| {
+            (s.pos == pos && s.head_len <= s.tokens.len()).then(|| pos + s.tokens.len() as upos)
+        })
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct SentenceRecord {
+    pub pos: upos,
+    pub head_len: usize,
+    pub tokens: Vec<utok>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct CacheRecord {
+    pub data_type: DataType,
+    pub shape: Vec<udim>,
+}
+
+pub(crate) fn write(path: impl AsRef<Path>, header: &Header, cache: &[u8]) -> io::Result<()> {
+    let header = serde_json::to_string(header)?;
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    file.write_all(&(header.len() as u64).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    file.write_all(cache)?;
+    file.flush()
+}
+
+pub(crate) fn read(path: impl AsRef<Path>) -> io::Result<(Header, Vec<u8>)> {
+    let mut data = fs::read(path)?;
+    let len = data
+        .get(..8)
+        .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+        .filter(|&len| len <= data.len() - 8)
+        .ok_or_else(|| io::Error::new(InvalidData, "broken session archive"))?;
+    let header = serde_json::from_slice(&data[8..][..len])?;
+    data.drain(..8 + len);
+    Ok((header, data))
+}
+
+/// FNV-1a 哈希的初始值。
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// 计算数据的 FNV-1a 哈希，结果在不同的进程间保持一致。
+pub(crate) fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter()
+        .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[test]
+fn test_archive() {
+    let path = std::env::temp_dir().join(format!("session-{}.bin", std::process::id()));
+    let header = Header {
+        fingerprint: fnv1a(FNV_OFFSET, b"model"),
+        temperature: 0.,
+        top_k: usize::MAX,
+        top_p: 1.,
+        sink: 4,
+        dialog: vec![SentenceRecord {
+            pos: 0,
+            head_len: 0,
+            tokens: vec![1, 2, 3],
+        }],
+        tail: vec![2],
+        cache: Some(CacheRecord {
+            data_type: DataType::U8,
+            shape: vec![1, 2, 1, 2, 1],
+        }),
+    };
+    assert_eq!(header.dialog_len(), Some(3));
+    write(&path, &header, &[1, 2, 3, 4]).unwrap();
+    let (read, cache) = read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(read.fingerprint, header.fingerprint);
+    assert_eq!(read.top_k, usize::MAX);
+    assert_eq!(read.dialog[0].tokens, [1, 2, 3]);
+    assert_eq!(read.tail, [2]);
+    assert_eq!(read.cache.unwrap().shape, [1, 2, 1, 2, 1]);
+    assert_eq!(cache, [1, 2, 3, 4]);
+
+    let sentence = |pos, head_len| SentenceRecord {
+        pos,
+        head_len,
+        tokens: vec![1, 2],
+    };
+    let mut header = header;
+    header.dialog = vec![sentence(0, 0), sentence(2, 2)];
+    assert_eq!(header.dialog_len(), Some(4));
+    header.dialog = vec![sentence(0, 0), sentence(3, 0)];
+    assert_eq!(header.dialog_len(), None);
+    header.dialog = vec![sentence(0, 3)];
+    assert_eq!(header.dialog_len(), None);
+}
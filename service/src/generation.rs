@@ -0,0 +1,65 @@
+use causal_lm::SampleArgs;
+use common::utok;
+use std::{fs::File, path::Path};
+
+/// generation_config.json 中生成的默认参数。
+#[derive(serde::Deserialize, Default, Debug)]
+pub(crate) struct GenerationConfig {
+    /// 覆盖模型定义的句子结束符，为空时使用模型的定义。
+    #[serde(default, deserialize_with = "common::one_or_many::deserialize")]
+    pub eos_token_id: Vec<utok>,
+    #[serde(default)]
+    pub do_sample: bool,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+}
+
+impl GenerationConfig {
+    /// 读取模型目录中的 generation_config.json，文件不存在或无法解析时使用默认值。
+    pub fn load(model_dir: impl AsRef<Path>) -> Self {
+        File::open(model_dir.as_ref().join("generation_config.json"))
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    /// 默认的采样参数，缺省值与 transformers 一致，不采样时使用贪心解码。
+    pub fn sample_args(&self) -> SampleArgs {
+        if self.do_sample {
+            SampleArgs {
+                temperature: self.temperature.unwrap_or(1.),
+                // top_k 为 0 表示不限制
+                top_k: match self.top_k.unwrap_or(50) {
+                    0 => usize::MAX,
+                    k => k,
+                },
+                top_p: self.top_p.unwrap_or(1.),
+            }
+        } else {
+            SampleArgs::default()
+        }
+    }
+}
+
+#[test]
+fn test_generation_config() {
+    let config: GenerationConfig = serde_json::from_str(
+        r#"{"bos_token_id":128000,"do_sample":true,"eos_token_id":[128001,128008,128009],"temperature":0.6,"top_p":0.9}"#,
+    )
+    .unwrap();
+    assert_eq!(config.eos_token_id, [128001, 128008, 128009]);
+    assert_eq!(
+        config.sample_args(),
+        SampleArgs {
+            temperature: 0.6,
+            top_k: 50,
+            top_p: 0.9,
+        },
+    );
+
+    let config: GenerationConfig =
+        serde_json::from_str(r#"{"eos_token_id":2,"temperature":0.7}"#).unwrap();
+    assert_eq!(config.eos_token_id, [2]);
+    assert_eq!(config.sample_args(), SampleArgs::default());
+}
@@ -1,15 +1,27 @@
 #![deny(warnings)]
 
+mod archive;
 mod batcher;
+mod generation;
+mod prefix;
 mod session;
 mod template;
 
-use causal_lm::{CausalLM, SampleArgs};
-use session::{Generator, HandleComponent};
-use std::{fmt::Debug, path::Path, sync::Arc};
+use causal_lm::{CausalLM, InferError, Pooling, SampleArgs};
+use common::gguf::Gguf;
+use generation::GenerationConfig;
+use serde_json::json;
+use session::{EmbedTask, Generator, HandleComponent, Request};
+use std::{
+    fmt::Debug,
+    path::Path,
+    sync::{atomic::Ordering::Relaxed, Arc},
+    time::UNIX_EPOCH,
+};
 use template::Template;
-use tokenizer::{BPECommonNormalizer, Normalizer, Tokenizer, VocabTxt, BPE};
+use tokenizer::{BPECommonNormalizer, GgufTokenizer, Normalizer, Tokenizer, VocabTxt, BPE};
 use tokio::task::JoinHandle;
+use transformer::{Architecture, ChatTemplate};
 
 pub use session::{BusySession, ChatError, Session};
 
@@ -27,6 +39,8 @@ struct ServiceComponent<M: CausalLM> {
     tokenizer: Box<dyn Tokenizer + Send + Sync>,
     normalizer: Box<dyn Normalizer + Send + Sync>,
     template: Box<dyn template::Template + Send + Sync>,
+    /// 模型的指纹，用于检查恢复的会话是否由同一个模型保存。
+    fingerprint: u64,
 }
 
 impl<M: CausalLM> Drop for ServiceComponent<M> {
@@ -44,20 +58,54 @@ where
     M::Error: Debug,
 {
     pub fn load(model_dir: impl AsRef<Path>, meta: M::Meta) -> (Self, JoinHandle<()>) {
-        let handle = Arc::new(HandleComponent::from(M::load(&model_dir, meta).unwrap()));
+        let generation = GenerationConfig::load(&model_dir);
+        let mut handle = HandleComponent::from(M::load(&model_dir, meta).unwrap());
+        if !generation.eos_token_id.is_empty() {
+            handle.eos = generation.eos_token_id.clone();
+        }
+        let handle = Arc::new(handle);
         (
             Self {
                 component: Arc::new(ServiceComponent {
                     handle: handle.clone(),
                     tokenizer: tokenizer(&model_dir),
                     normalizer: normalizer(&model_dir),
-                    template: template(model_dir),
+                    template: template(&model_dir),
+                    fingerprint: fingerprint(model_dir),
                 }),
-                default_sample: Default::default(),
+                default_sample: generation.sample_args(),
             },
             tokio::task::spawn_blocking(move || handle.run()),
         )
     }
+
+    /// 复用对话模型计算每段文本的句向量。
+    pub async fn embed(
+        &self,
+        texts: impl IntoIterator<Item = impl AsRef<str>>,
+        pooling: Pooling,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        let component = &self.component;
+        let tokens = texts
+            .into_iter()
+            .map(|text| {
+                let text = component.template.normalize(text.as_ref());
+                let text = component.normalizer.encode(&text);
+                component.tokenizer.encode(&text)
+            })
+            .collect::<Vec<_>>();
+        if tokens.is_empty() {
+            return Ok(vec![]);
+        }
+        // 经由推理线程计算，避免与其他推理同时使用模型
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        component.handle.batcher.enq(Request::Embed(EmbedTask {
+            tokens,
+            pooling,
+            sender,
+        }));
+        receiver.await.unwrap()
+    }
 }
 
 impl<M: CausalLM> Service<M> {
@@ -69,6 +117,20 @@ impl<M: CausalLM> Service<M> {
         session
     }
 
+    /// 设置跨会话共享的前缀缓存最多保存的条目数量，为 0 时不缓存。
+    #[inline]
+    pub fn set_prefix_cache(&self, capacity: usize) {
+        let mut prefix = self.component.handle.prefix.lock().unwrap();
+        prefix.set_capacity(capacity);
+    }
+
+    /// 设置每次推理最多计算的 token 数量，更长的填充将分块计算，以限制推理的峰值内存。
+    #[inline]
+    pub fn set_token_budget(&self, budget: usize) {
+        let handle = &self.component.handle;
+        handle.token_budget.store(budget, Relaxed);
+    }
+
     /// 从对话服务启动一个文本生成器。
     #[inline]
     pub fn generate(&self, prompt: impl AsRef<str>, sample: Option<SampleArgs>) -> Generator<M> {
@@ -91,7 +153,8 @@ fn test() {
     let runtime = Builder::new_current_thread().build().unwrap();
     let _rt = runtime.enter();
 
-    let (service, _handle) = Service::<transformer_cpu::Transformer>::load(model_dir, ());
+    let (service, _handle) =
+        Service::<transformer_cpu::Transformer>::load(model_dir, Default::default());
 
     let mut set = JoinSet::new();
     let tasks = vec![
@@ -116,18 +179,78 @@ fn test() {
     runtime.shutdown_background();
 }
 
+/// 由模型配置、词表和权重文件计算模型的指纹。
+///
+/// 权重文件以文件名、大小和修改时间代替内容，使同一结构和词表的不同权重具有不同的指纹。
+fn fingerprint(model_dir: impl AsRef<Path>) -> u64 {
+    let model_dir = model_dir.as_ref();
+    let file = |hash: u64, path: &Path| {
+        let meta = std::fs::metadata(path).ok();
+        let len = meta.as_ref().map_or(0, |m| m.len());
+        let modified = meta
+            .and_then(|m| m.modified().ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as u64);
+        let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+        let hash = archive::fnv1a(hash, name);
+        let hash = archive::fnv1a(hash, &len.to_le_bytes());
+        archive::fnv1a(hash, &modified.to_le_bytes())
+    };
+    // gguf 把配置和词表都保存在元数据中
+    if is_gguf(model_dir) {
+        return file(archive::FNV_OFFSET, model_dir);
+    }
+    let hash = ["config.json", "tokenizer.model", "vocabs.txt"]
+        .iter()
+        .filter_map(|name| std::fs::read(model_dir.join(name)).ok())
+        .fold(archive::FNV_OFFSET, |hash, data| {
+            archive::fnv1a(hash, &data)
+        });
+    let mut weights = std::fs::read_dir(model_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "safetensors"))
+        .collect::<Vec<_>>();
+    weights.sort_unstable();
+    weights.iter().fold(hash, |hash, path| file(hash, path))
+}
+
+/// 按 config.json 识别模型族，使用其默认的对话模板。
 fn template(model_dir: impl AsRef<Path>) -> Box<dyn Template + Send + Sync> {
-    let path: String = model_dir.as_ref().display().to_string();
-    let path = path.to_ascii_lowercase();
-    if path.contains("tinyllama") {
-        Box::new(template::ChatTinyLlama)
+    let config = if is_gguf(&model_dir) {
+        Gguf::load(&model_dir)
+            .ok()
+            .and_then(|gguf| {
+                gguf.get_str("general.architecture")
+                    .map(|a| json!({ "model_type": a }))
+            })
+            .unwrap_or_default()
     } else {
-        Box::new(template::ChatCPM)
+        std::fs::File::open(model_dir.as_ref().join("config.json"))
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    };
+    match Architecture::find(&config).template {
+        ChatTemplate::ChatCPM => Box::new(template::ChatCPM),
+        ChatTemplate::ChatTinyLlama => Box::new(template::ChatTinyLlama),
+        ChatTemplate::ChatInst => Box::new(template::ChatInst),
+        ChatTemplate::ChatML => Box::new(template::ChatML),
+        ChatTemplate::ChatGemma => Box::new(template::ChatGemma),
+        ChatTemplate::Plain => Box::new(template::Plain),
     }
 }
 
 fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync> {
     use std::io::ErrorKind::NotFound;
+    if is_gguf(&model_dir) {
+        return match GgufTokenizer::from_gguf_file(model_dir) {
+            Ok(t) if t.is_byte_level() => Box::new(()),
+            Ok(_) => Box::new(BPECommonNormalizer {}),
+            Err(e) => panic!("{e:?}"),
+        };
+    }
     match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
         Ok(_) => return Box::new(BPECommonNormalizer {}),
         Err(e) if e.kind() == NotFound => {}
@@ -143,6 +266,9 @@ fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync>
 
 fn tokenizer(model_dir: impl AsRef<Path>) -> Box<dyn Tokenizer + Send + Sync> {
     use std::io::ErrorKind::NotFound;
+    if is_gguf(&model_dir) {
+        return Box::new(GgufTokenizer::from_gguf_file(model_dir).unwrap());
+    }
     match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
         Ok(bpe) => return Box::new(bpe),
         Err(e) if e.kind() == NotFound => {}
@@ -155,3 +281,11 @@ fn tokenizer(model_dir: impl AsRef<Path>) -> Box<dyn Tokenizer + Send + Sync> {
     }
     panic!("Tokenizer file not found");
 }
+
+/// 模型以单个 `.gguf` 文件提供，分词器等都保存在其元数据中。
+fn is_gguf(model_dir: impl AsRef<Path>) -> bool {
+    model_dir
+        .as_ref()
+        .extension()
+        .is_some_and(|ext| ext == "gguf")
+}
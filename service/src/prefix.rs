@@ -0,0 +1,158 @@
+use causal_lm::KVCache;
+use common::{upos, utok};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+};
+
+/// 前缀按 token 数量对齐的粒度。
+const ALIGN: usize = 16;
+
+/// 跨会话共享的前缀缓存，以 token 前缀的哈希为键保存已填充的缓存。
+///
+/// 每个条目保存按 [`ALIGN`] 对齐的前缀，并以其所有对齐的子前缀为键索引，
+/// 新的推理可从最长的已缓存前缀开始填充。
+pub(crate) struct PrefixCache<S> {
+    /// 最多保存的条目数量，为 0 时不缓存。
+    capacity: usize,
+    hasher: RandomState,
+    index: HashMap<u64, usize>,
+    entries: HashMap<usize, Entry<S>>,
+    next: usize,
+    clock: usize,
+}
+
+struct Entry<S> {
+    tokens: Vec<utok>,
+    cache: KVCache<S>,
+    last_used: usize,
+}
+
+impl<S> Default for PrefixCache<S> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            hasher: RandomState::new(),
+            index: Default::default(),
+            entries: Default::default(),
+            next: 0,
+            clock: 0,
+        }
+    }
+}
+
+impl<S> PrefixCache<S> {
+    /// 设置最多保存的条目数量，淘汰超出的条目。
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink(capacity);
+    }
+
+    /// `tokens` 中可以保存的最长前缀长度。
+    #[inline]
+    pub fn aligned(&self, tokens: &[utok]) -> usize {
+        if self.capacity == 0 {
+            0
+        } else {
+            tokens.len() / ALIGN * ALIGN
+        }
+    }
+
+    /// 查找 `tokens` 的最长已缓存前缀，至少留下一个 token 用于填充。
+    pub fn lookup(&mut self, tokens: &[utok]) -> Option<(&KVCache<S>, upos)> {
+        let len = (1..tokens.len())
+            .rev()
+            .filter(|len| len % ALIGN == 0)
+            .find(|&len| self.find(&tokens[..len]).is_some())?;
+        let id = self.find(&tokens[..len]).unwrap();
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&id).unwrap();
+        entry.last_used = self.clock;
+        Some((&entry.cache, len as upos))
+    }
+
+    /// 判断 `tokens` 是否已被缓存。
+    #[inline]
+    pub fn contains(&self, tokens: &[utok]) -> bool {
+        self.find(tokens).is_some()
+    }
+
+    /// 保存有效长度为 `tokens.len()` 的缓存，`tokens` 必须已对齐。
+    pub fn insert(&mut self, tokens: Vec<utok>, cache: KVCache<S>) {
+        assert_eq!(tokens.len() % ALIGN, 0);
+        if self.capacity == 0 || tokens.is_empty() {
+            return;
+        }
+        self.shrink(self.capacity - 1);
+
+        let id = self.next;
+        self.next += 1;
+        for len in (ALIGN..=tokens.len()).step_by(ALIGN) {
+            let key = self.hasher.hash_one(&tokens[..len]);
+            self.index.insert(key, id);
+        }
+        self.clock += 1;
+        self.entries.insert(
+            id,
+            Entry {
+                tokens,
+                cache,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn find(&self, tokens: &[utok]) -> Option<usize> {
+        let id = *self.index.get(&self.hasher.hash_one(tokens))?;
+        self.entries
+            .get(&id)
+            .filter(|e| e.tokens.starts_with(tokens))
+            .map(|_| id)
+    }
+
+    /// 按最近最少使用的顺序淘汰条目，直到不超过 `len` 个。
+    fn shrink(&mut self, len: usize) {
+        while self.entries.len() > len {
+            let (&id, _) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .unwrap();
+            self.entries.remove(&id);
+            self.index.retain(|_, v| *v != id);
+            // 被淘汰条目覆盖的共同前缀重新指向其他条目
+            for (&id, e) in &self.entries {
+                for len in (ALIGN..=e.tokens.len()).step_by(ALIGN) {
+                    let key = self.hasher.hash_one(&e.tokens[..len]);
+                    self.index.entry(key).or_insert(id);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_prefix_cache() {
+    use tensor::{DataType, Tensor};
+
+    let cache = || KVCache::contiguous(Tensor::new(DataType::U8, &[1; 5], vec![0u8]));
+    let mut prefix = PrefixCache::<Vec<u8>>::default();
+    let tokens = (0..40).collect::<Vec<utok>>();
+    assert_eq!(prefix.aligned(&tokens), 0);
+
+    prefix.set_capacity(1);
+    assert_eq!(prefix.aligned(&tokens), 32);
+    prefix.insert(tokens[..32].to_vec(), cache());
+    assert!(prefix.contains(&tokens[..16]));
+    assert_eq!(prefix.lookup(&tokens).map(|(_, len)| len), Some(32));
+    assert_eq!(prefix.lookup(&tokens[..32]).map(|(_, len)| len), Some(16));
+
+    let mut other = tokens.clone();
+    other[20] = 0;
+    assert_eq!(prefix.lookup(&other).map(|(_, len)| len), Some(16));
+    prefix.insert(other[..32].to_vec(), cache());
+    assert!(!prefix.contains(&tokens[..32]));
+    assert!(prefix.contains(&other[..32]));
+}
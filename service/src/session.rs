@@ -1,23 +1,48 @@
-﻿use crate::{batcher::Batcher, ServiceComponent};
-use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleArgs, SampleMeta};
+﻿use crate::{
+    archive::{self, CacheRecord, Header, SentenceRecord},
+    batcher::Batcher,
+    prefix::PrefixCache,
+    ServiceComponent,
+};
+use causal_lm::{
+    CausalLM, DecodingMeta, HeavyHitter, InferError, KVCache, Pooling, QueryContext, SampleArgs,
+    SampleMeta,
+};
 use common::{upos, utok};
 use std::{
     borrow::Cow,
     cmp::Ordering::{Equal, Greater, Less},
     error, fmt,
+    io::{self, ErrorKind::InvalidData},
     iter::zip,
     mem::{replace, take},
     ops::Range,
-    sync::{Arc, Mutex},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+use tensor::{udim, Tensor};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
 };
-use tensor::Tensor;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// 默认保留的开头 token 数量。
+const DEFAULT_SINK: usize = 4;
+/// 默认每次推理最多计算的 token 数量。
+const DEFAULT_TOKEN_BUDGET: usize = 512;
 
 /// 会话。
 pub struct Session<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     pub sample: SampleArgs,
-    cache: Option<Tensor<M::Storage>>,
+    /// 上下文溢出时始终保留的开头 token 数量，包含这些 token 的句子不会被丢弃。
+    pub sink: usize,
+    /// 推理过程中缓存的淘汰策略，`None` 表示不淘汰。
+    pub eviction: Option<HeavyHitter>,
+    cache: Option<KVCache<M::Storage>>,
     dialog: Vec<Arc<Sentence>>,
     tail: Vec<utok>,
 }
@@ -28,6 +53,8 @@ impl<M: CausalLM> From<Arc<ServiceComponent<M>>> for Session<M> {
         Self {
             component,
             sample: Default::default(),
+            sink: DEFAULT_SINK,
+            eviction: None,
             cache: Default::default(),
             dialog: Default::default(),
             tail: Default::default(),
@@ -46,7 +73,10 @@ impl<M: CausalLM> Session<M> {
         Self {
             component: self.component.clone(),
             sample: Default::default(),
-            cache: self.cache.as_ref().map(|cache| {
+            sink: self.sink,
+            eviction: self.eviction,
+            // 无法复制缓存时，复制的会话将在下一次对话时重新填充
+            cache: self.cache.as_ref().and_then(|cache| {
                 self.component
                     .handle
                     .model
@@ -57,12 +87,94 @@ impl<M: CausalLM> Session<M> {
         }
     }
 
+    /// 将对话、采样参数保存到 `path`，`with_cache` 时同时保存缓存的有效部分。
+    pub fn save(&self, path: impl AsRef<Path>, with_cache: bool) -> io::Result<()> {
+        let model = &self.component.handle.model;
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|_| with_cache)
+            // 滚动缓存已归还了前部的页，只保存对话
+            .and_then(|cache| model.export_cache(cache, self.pos().min(cache.capacity())));
+        let header = Header {
+            fingerprint: self.component.fingerprint,
+            temperature: self.sample.temperature,
+            top_k: self.sample.top_k,
+            top_p: self.sample.top_p,
+            sink: self.sink,
+            dialog: self
+                .dialog
+                .iter()
+                .map(|s| SentenceRecord {
+                    pos: s.pos,
+                    head_len: s.head_len,
+                    tokens: s.tokens.clone(),
+                })
+                .collect(),
+            tail: self.tail.clone(),
+            cache: cache.as_ref().map(|cache| CacheRecord {
+                data_type: cache.data_type(),
+                shape: cache.shape().to_vec(),
+            }),
+        };
+        archive::write(path, &header, cache.as_ref().map_or(&[], |c| c.as_slice()))
+    }
+
+    /// 从 `path` 恢复保存的会话，保存会话的模型与当前模型不同或对话已损坏时返回错误。
+    ///
+    /// 未保存缓存、缓存的格式与模型不符或缓存不足以覆盖对话时，将在下一次对话时重新填充整个对话。
+    pub fn load(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let (header, data) = archive::read(path)?;
+        if header.fingerprint != self.component.fingerprint {
+            return Err(io::Error::new(
+                InvalidData,
+                "session is saved by another model",
+            ));
+        }
+        let pos = header
+            .dialog_len()
+            .ok_or_else(|| io::Error::new(InvalidData, "session dialog is broken"))?;
+        self.cache = header
+            .cache
+            .and_then(|c| {
+                let tensor = Tensor::new(c.data_type, &c.shape, &*data);
+                (tensor.bytes_size() == data.len())
+                    .then(|| self.component.handle.model.import_cache(&tensor))
+                    .flatten()
+            })
+            .filter(|cache| cache.capacity() >= pos);
+        self.sample = SampleArgs {
+            temperature: header.temperature,
+            top_k: header.top_k,
+            top_p: header.top_p,
+        };
+        self.sink = header.sink;
+        self.dialog = header
+            .dialog
+            .into_iter()
+            .map(|s| {
+                Arc::new(Sentence {
+                    pos: s.pos,
+                    head_len: s.head_len,
+                    tokens: s.tokens,
+                })
+            })
+            .collect();
+        self.tail = header.tail;
+        Ok(())
+    }
+
     /// 回滚对话到第 `dialog_pos` 个句子。
     pub fn revert(&mut self, dialog_pos: usize) -> Result<(), ChatError> {
         match dialog_pos.cmp(&self.dialog.len()) {
             Less => {
                 self.tail = self.dialog[dialog_pos].head().to_vec();
                 self.dialog.truncate(dialog_pos);
+                // 归还回滚部分占用的缓存页
+                let pos = self.pos();
+                if let Some(cache) = &mut self.cache {
+                    cache.truncate(pos);
+                }
                 Ok(())
             }
             Equal => Ok(()),
@@ -71,13 +183,25 @@ impl<M: CausalLM> Session<M> {
     }
 
     /// 用 dialog 重置会话，启动推理并返回忙会话。
+    ///
+    /// 如果上下文将超过模型的最大长度，会保留开头的 `sink` 个 token 所在的句子，
+    /// 并清空其后最早的句子，直到上下文不超过最大长度的一半。
     pub fn chat<'s, 'a>(
         &'s mut self,
         dialog: impl IntoIterator<Item = &'a str>,
     ) -> BusySession<'s, M> {
-        let eos = self.component.handle.model.eos_token();
-        let pos = self.pos();
+        let eos = self.component.handle.eos_token();
+        let mut pos = self.pos();
         let mut prefill = vec![];
+        if let Some(&last) = self.dialog.last().and_then(|s| s.tokens.last()) {
+            // 中途停止接收时最后一个生成的词可能尚未写入缓存，重新填充它
+            pos -= 1;
+            prefill.push(last);
+        }
+        // 滚动缓存已归还回滚后的对话需要的页时，丢弃缓存
+        if self.cache.as_ref().is_some_and(|c| !c.covers(pos)) {
+            self.cache = None;
+        }
         let mut prompt = self.dialog.is_empty() || !self.tail.is_empty();
         // 填充对话
         for s in dialog {
@@ -96,24 +220,134 @@ impl<M: CausalLM> Session<M> {
             }
             prompt = !prompt;
         }
+        let mut pos = self.shift_context(pos, prefill.len() as upos);
+        if self.cache.is_none() {
+            // 没有缓存时从头填充整个对话
+            pos = 0;
+            prefill = self
+                .dialog
+                .iter()
+                .flat_map(|s| &s.tokens)
+                .copied()
+                .collect();
+        }
         // 生成推理任务与会话的交互管道
         let (sender, receiver) = unbounded_channel();
-        let cache = Arc::new(Mutex::new(Some(
-            self.cache
-                .take()
-                .unwrap_or_else(|| self.component.handle.model.new_cache()),
-        )));
-        self.component.handle.batcher.enq(Task {
+        let handle = &self.component.handle;
+        let mut cache = self.cache.take();
+        let mut prefix = None;
+        if pos == 0 {
+            // 从其他会话缓存的最长前缀开始填充
+            if let Some((shared, len)) = handle.reuse_prefix(&prefill) {
+                cache = Some(shared);
+                pos = len;
+            }
+            let tokens = take(&mut prefill);
+            prefill = tokens[pos as usize..].to_vec();
+            prefix = Some(tokens);
+        }
+        let mut cache = cache.unwrap_or_else(|| handle.model.new_cache());
+        // 滚动缓存自行丢弃滑动窗口之前的位置，不需要淘汰
+        let eviction = self.eviction.filter(|_| cache.window().is_none());
+        if eviction.is_some() {
+            cache.track_importance();
+        }
+        let cache = Arc::new(Mutex::new(Some(cache)));
+        let retained = Arc::new(Mutex::new(Vec::new()));
+        handle.batcher.enq(Request::Infer(Task {
             tokens: prefill,
             pos,
             sample: self.sample.clone(),
+            eviction,
             cache: cache.clone(),
+            retained: retained.clone(),
+            prefix,
+            rest: vec![],
             sender,
-        });
+        }));
         BusySession {
             session: self,
             receiver: Some(receiver),
             cache,
+            retained,
+            error: None,
+        }
+    }
+
+    /// 在有效长度为 `cached` 的缓存后填充 `prefill` 个 token 前检查上下文是否溢出，
+    /// 溢出时丢弃最早的句子并前移缓存，返回前移后的缓存长度。
+    fn shift_context(&mut self, cached: upos, prefill: upos) -> upos {
+        let max_seq_len = self.component.handle.model.max_seq_len();
+        if cached + prefill < max_seq_len {
+            return cached;
+        }
+        let Some(cache) = self.cache.as_mut() else {
+            return cached;
+        };
+        let rolled = cache.start() > 0;
+        // 保留 sink 所在的句子，丢弃的句子必须都已缓存
+        let first = self
+            .dialog
+            .iter()
+            .position(|s| s.pos as usize >= self.sink)
+            .unwrap_or(self.dialog.len());
+        let Some(start) = self
+            .dialog
+            .get(first)
+            .map(|s| s.pos)
+            .filter(|&p| p < cached)
+        else {
+            return cached;
+        };
+        let target = max_seq_len / 2;
+        let last = (first + 1..self.dialog.len())
+            .take_while(|&i| self.dialog[i].pos <= cached)
+            .find(|&i| cached + prefill - (self.dialog[i].pos - start) <= target)
+            .unwrap_or_else(|| {
+                (first + 1..self.dialog.len())
+                    .take_while(|&i| self.dialog[i].pos <= cached)
+                    .last()
+                    .unwrap_or(first)
+            });
+
+        let end = self.dialog[last].pos;
+        if end == start {
+            return cached;
+        }
+        if rolled {
+            // 滚动缓存无法原地前移，丢弃缓存并重新填充剩余的对话
+            self.cache = None;
+            self.retain_dialog(&[0..start, end..upos::MAX]);
+            return 0;
+        }
+        self.component
+            .handle
+            .model
+            .retain_cache(cache, &[0..start, end..cached]);
+        self.retain_dialog(&[0..start, end..upos::MAX]);
+        cached - (end - start)
+    }
+
+    /// 按缓存保留的范围（升序且不相交）删除对话中被丢弃的 token，句子的序号保持不变。
+    fn retain_dialog(&mut self, retain: &[Range<upos>]) {
+        let mut pos = 0;
+        for s in &mut self.dialog {
+            let kept = |i: usize| {
+                let p = s.pos + i as upos;
+                retain.iter().any(|r| r.contains(&p))
+            };
+            let head_len = (0..s.head_len).filter(|&i| kept(i)).count();
+            let tokens = (0..s.tokens.len())
+                .filter(|&i| kept(i))
+                .map(|i| s.tokens[i])
+                .collect::<Vec<_>>();
+            let len = tokens.len() as upos;
+            *s = Arc::new(Sentence {
+                pos,
+                head_len,
+                tokens,
+            });
+            pos += len;
         }
     }
 
@@ -152,24 +386,37 @@ impl fmt::Display for ChatError {
 /// 忙会话，表示会话正在处理推理任务，并可接收推理结果。
 pub struct BusySession<'a, M: CausalLM> {
     session: &'a mut Session<M>,
-    receiver: Option<UnboundedReceiver<utok>>,
-    cache: Arc<Mutex<Option<Tensor<M::Storage>>>>,
+    receiver: Option<UnboundedReceiver<Result<utok, InferError>>>,
+    cache: Arc<Mutex<Option<KVCache<M::Storage>>>>,
+    retained: Arc<Mutex<Vec<Vec<Range<upos>>>>>,
+    error: Option<InferError>,
 }
 
 impl<M: CausalLM> BusySession<'_, M> {
-    /// 接收模型解码产生的文本。
+    /// 接收模型解码产生的文本，推理失败时结束接收，错误可由 [`Self::error`] 取得。
     pub async fn decode(&mut self) -> Option<Cow<str>> {
-        self.receiver.as_mut().unwrap().recv().await.map(|token| {
-            // 记录 token
-            self.session.tail.push(token);
-            // detokenize and denormalize the token
-            let ServiceComponent {
-                normalizer,
-                tokenizer,
-                ..
-            } = &*self.session.component;
-            normalizer.decode(tokenizer.decode(token))
-        })
+        let token = match self.receiver.as_mut().unwrap().recv().await? {
+            Ok(token) => token,
+            Err(e) => {
+                self.error = Some(e);
+                return None;
+            }
+        };
+        // 记录 token
+        self.session.tail.push(token);
+        // detokenize and denormalize the token
+        let ServiceComponent {
+            normalizer,
+            tokenizer,
+            ..
+        } = &*self.session.component;
+        Some(normalizer.decode(tokenizer.decode(token)))
+    }
+
+    /// 导致推理失败的错误。
+    #[inline]
+    pub fn error(&self) -> Option<&InferError> {
+        self.error.as_ref()
     }
 }
 
@@ -180,23 +427,30 @@ impl<M: CausalLM> Drop for BusySession<'_, M> {
         let _ = self.receiver.take();
         // 回收 cache
         s.cache = self.cache.lock().unwrap().take();
+        // 推理过程中淘汰的缓存也从对话中删除
+        let retained = take(&mut *self.retained.lock().unwrap());
         if !s.tail.is_empty() {
             // 只要忙会话收集到任何 token，就生成一个新的句子
             let answer = take(&mut s.tail);
             s.push_sentence(answer);
+            retained.iter().for_each(|r| s.retain_dialog(r));
             // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
-            s.tail = vec![s.component.handle.model.eos_token()];
-        } else if let Some(last) = s.dialog.pop() {
-            // 否则回滚句子
-            s.tail = last.head().to_vec();
+            s.tail = vec![s.component.handle.eos_token()];
+        } else {
+            retained.iter().for_each(|r| s.retain_dialog(r));
+            if let Some(last) = s.dialog.pop() {
+                // 否则回滚句子
+                s.tail = last.head().to_vec();
+            }
         }
     }
 }
 
 pub struct Generator<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
-    receiver: Option<UnboundedReceiver<utok>>,
-    cache: Arc<Mutex<Option<Tensor<M::Storage>>>>,
+    receiver: Option<UnboundedReceiver<Result<utok, InferError>>>,
+    cache: Arc<Mutex<Option<KVCache<M::Storage>>>>,
+    error: Option<InferError>,
 }
 
 impl<M: CausalLM> Generator<M> {
@@ -210,31 +464,51 @@ impl<M: CausalLM> Generator<M> {
         let tokens = component.tokenizer.encode(&prompt);
         // 生成推理任务与会话的交互管道
         let (sender, receiver) = unbounded_channel();
-        let cache = Arc::new(Mutex::new(Some(component.handle.model.new_cache())));
-        component.handle.batcher.enq(Task {
-            tokens,
-            pos: 0,
+        let handle = &component.handle;
+        let (cache, pos) = handle
+            .reuse_prefix(&tokens)
+            .unwrap_or_else(|| (handle.model.new_cache(), 0));
+        let cache = Arc::new(Mutex::new(Some(cache)));
+        handle.batcher.enq(Request::Infer(Task {
+            tokens: tokens[pos as usize..].to_vec(),
+            pos,
             sample,
+            eviction: None,
             cache: cache.clone(),
+            retained: Default::default(),
+            prefix: Some(tokens),
+            rest: vec![],
             sender,
-        });
+        }));
         Self {
             component,
             receiver: Some(receiver),
             cache,
+            error: None,
         }
     }
 
     pub async fn decode(&mut self) -> Option<Cow<str>> {
-        self.receiver.as_mut().unwrap().recv().await.map(|token| {
-            // detokenize and denormalize the token
-            let ServiceComponent {
-                normalizer,
-                tokenizer,
-                ..
-            } = &*self.component;
-            normalizer.decode(tokenizer.decode(token))
-        })
+        let token = match self.receiver.as_mut().unwrap().recv().await? {
+            Ok(token) => token,
+            Err(e) => {
+                self.error = Some(e);
+                return None;
+            }
+        };
+        // detokenize and denormalize the token
+        let ServiceComponent {
+            normalizer,
+            tokenizer,
+            ..
+        } = &*self.component;
+        Some(normalizer.decode(tokenizer.decode(token)))
+    }
+
+    /// 导致推理失败的错误。
+    #[inline]
+    pub fn error(&self) -> Option<&InferError> {
+        self.error.as_ref()
     }
 }
 
@@ -248,16 +522,24 @@ impl<M: CausalLM> Drop for Generator<M> {
 }
 
 pub(crate) struct HandleComponent<M: CausalLM> {
-    model: M,
-    pub batcher: Batcher<Task<M::Storage>>,
+    pub model: M,
+    /// 停止生成的所有句子结束符，第一个用于结束对话中的句子。
+    pub eos: Vec<utok>,
+    pub batcher: Batcher<Request<M::Storage>>,
+    pub prefix: Mutex<PrefixCache<M::Storage>>,
+    /// 每次推理最多计算的 token 数量，更长的填充将分块计算。
+    pub token_budget: AtomicUsize,
 }
 
 impl<M: CausalLM> From<M> for HandleComponent<M> {
     #[inline]
     fn from(model: M) -> Self {
         Self {
+            eos: model.eos_tokens().to_vec(),
             model,
             batcher: Batcher::new(),
+            prefix: Default::default(),
+            token_budget: AtomicUsize::new(DEFAULT_TOKEN_BUDGET),
         }
     }
 }
@@ -268,6 +550,100 @@ impl<M: CausalLM> HandleComponent<M> {
     pub fn stop(&self) {
         self.batcher.shutdown();
     }
+
+    /// 结束对话中句子的结束符。
+    #[inline]
+    pub fn eos_token(&self) -> utok {
+        self.eos[0]
+    }
+
+    /// 查找 `tokens` 的最长已缓存前缀，返回复制的缓存及其有效长度。
+    fn reuse_prefix(&self, tokens: &[utok]) -> Option<(KVCache<M::Storage>, upos)> {
+        let mut prefix = self.prefix.lock().unwrap();
+        let (cache, len) = prefix.lookup(tokens)?;
+        Some((self.model.duplicate_cache(cache, len)?, len))
+    }
+
+    /// 按 token 预算选出本次推理的任务，解码任务优先，超出预算的填充只计算一部分，
+    /// 完全超出预算的任务放回队列。
+    ///
+    /// 淘汰缓存的任务每次填充后缓存不超过淘汰策略的上限，以便在填充的间隙淘汰。
+    fn schedule(&self, mut tasks: Vec<Task<M::Storage>>) -> Vec<Task<M::Storage>> {
+        tasks.sort_by_key(|t| t.tokens.len());
+        let mut budget = self.token_budget.load(Relaxed).max(1);
+        let mut ans = Vec::with_capacity(tasks.len());
+        for mut task in tasks {
+            if budget == 0 {
+                self.batcher.enq(Request::Infer(task));
+                continue;
+            }
+            let limit = match task.eviction {
+                Some(policy) => budget.min(policy.budget.saturating_sub(task.pos).max(1) as _),
+                None => budget,
+            };
+            if task.tokens.len() > limit {
+                let mut rest = task.tokens.split_off(limit);
+                rest.append(&mut task.rest);
+                task.rest = rest;
+            }
+            budget -= task.tokens.len();
+            ans.push(task);
+        }
+        ans
+    }
+
+    /// 向导致推理失败的请求报告错误，并将其他请求放回队列；无法确定出错的请求时全部失败。
+    fn fail(&self, tasks: Vec<Task<M::Storage>>, e: InferError) {
+        let culprit = match e {
+            InferError::TokenOutOfRange { index, .. } => tasks
+                .iter()
+                .scan(0, |end, t| {
+                    *end += t.tokens.len();
+                    Some(*end)
+                })
+                .position(|end| index < end),
+            InferError::PositionOverflow { query, .. }
+            | InferError::MissingCache { query }
+            | InferError::CacheExhausted { query } => Some(query),
+            InferError::ContextFull { .. } | InferError::ShapeMismatch { .. } => None,
+        };
+        for (i, task) in tasks.into_iter().enumerate() {
+            if culprit.is_none_or(|c| c == i) {
+                let _ = task.sender.send(Err(e.clone()));
+            } else {
+                self.batcher.enq(Request::Infer(task));
+            }
+        }
+    }
+
+    /// 计算每段文本的句向量并发送给请求者。
+    fn embed(&self, task: EmbedTask) {
+        let EmbedTask {
+            tokens,
+            pooling,
+            sender,
+        } = task;
+        let result = self
+            .model
+            .token_embed(tokens.iter().flatten().copied())
+            .and_then(|token_embedded| {
+                let seq_len = tokens.iter().map(|t| t.len() as udim);
+                self.model.embed(seq_len, token_embedded, pooling)
+            });
+        let _ = sender.send(result);
+    }
+
+    /// 共享从位置 0 开始填充完成的缓存中对齐的前缀。
+    fn share_prefix(&self, tokens: &[utok], cache: &KVCache<M::Storage>) {
+        let mut prefix = self.prefix.lock().unwrap();
+        let len = prefix.aligned(tokens);
+        // 滚动缓存已归还了前缀的页
+        if len > 0 && cache.start() == 0 && !prefix.contains(&tokens[..len]) {
+            if let Some(shared) = self.model.duplicate_cache(cache, len as upos) {
+                prefix.insert(tokens[..len].to_vec(), shared);
+            }
+        }
+    }
 }
 
 impl<M> HandleComponent<M>
@@ -276,53 +652,146 @@ where
     M::Storage: Send,
 {
     pub fn run(self: Arc<Self>) {
-        while let Some(tasks) = Some(self.batcher.deq()).filter(|t| !t.is_empty()) {
-            let token_embedded = {
-                let queries = tasks.iter().flat_map(|t| &t.tokens).copied();
-                self.model.token_embed(queries)
-            };
-            // 锁定所有请求的 cache
-            let hidden_state = {
-                let mut queries = tasks
+        while let Some(requests) = Some(self.batcher.deq()).filter(|r| !r.is_empty()) {
+            // 句向量请求与推理任务在同一个线程中依次计算
+            let mut tasks = Vec::with_capacity(requests.len());
+            for request in requests {
+                match request {
+                    Request::Infer(task) => tasks.push(task),
+                    Request::Embed(task) => self.embed(task),
+                }
+            }
+            if tasks.is_empty() {
+                continue;
+            }
+            let tasks = self.schedule(tasks);
+            // 锁定所有请求的 cache，跳过缓存已被会话收回的请求
+            let (tasks, result) = {
+                let mut locks = tasks
                     .iter()
-                    .map(|t| (t, t.cache.lock().unwrap()))
+                    .map(|t| t.cache.lock().unwrap())
                     .collect::<Vec<_>>();
-                let queries = queries.iter_mut().map(|(task, lock)| QueryContext {
-                    cache: lock.as_mut(),
-                    range: task.range(),
+                let alive = locks.iter().map(|l| l.is_some()).collect::<Vec<_>>();
+                if !alive.contains(&true) {
+                    continue;
+                }
+
+                let token_embedded = {
+                    let queries = zip(&tasks, &alive)
+                        .filter(|(_, alive)| **alive)
+                        .flat_map(|(t, _)| &t.tokens)
+                        .copied();
+                    self.model.token_embed(queries)
+                };
+                let result = token_embedded.and_then(|token_embedded| {
+                    let queries = zip(&tasks, &mut locks)
+                        .filter(|(_, lock)| lock.is_some())
+                        .map(|(task, lock)| QueryContext {
+                            cache: lock.as_mut(),
+                            range: task.range(),
+                        });
+                    self.model.forward(queries, token_embedded)
                 });
-                self.model.forward(queries, token_embedded)
+                // 填充完成后共享前缀
+                if result.is_ok() {
+                    for (task, lock) in zip(&tasks, &locks).filter(|(t, _)| t.rest.is_empty()) {
+                        if let (Some(tokens), Some(cache)) = (&task.prefix, lock.as_ref()) {
+                            self.share_prefix(tokens, cache);
+                        }
+                    }
+                }
+                drop(locks);
+
+                let tasks = zip(tasks, alive)
+                    .filter_map(|(t, alive)| alive.then_some(t))
+                    .collect::<Vec<_>>();
+                (tasks, result)
+            };
+            let hidden_state = match result {
+                Ok(hidden_state) => hidden_state,
+                Err(e) => {
+                    self.fail(tasks, e);
+                    continue;
+                }
             };
             // 为每次推理启动一个任务执行解码工作
             let self_ = self.clone();
             tokio::task::spawn_blocking(move || {
                 let num_decode = tasks
                     .iter()
-                    .map(|t| if !t.sender.is_closed() { 1 } else { 0 })
+                    .map(|t| (t.rest.is_empty() && !t.sender.is_closed()) as usize)
                     .collect::<Vec<_>>();
 
                 let decoding = zip(&tasks, &num_decode).map(|(t, num_decode)| DecodingMeta {
                     num_query: t.tokens.len(),
                     num_decode: *num_decode,
                 });
-                let logits = self_.model.decode(decoding, hidden_state);
-
                 let args = zip(&tasks, &num_decode).map(|(t, num_decode)| SampleMeta {
                     num_decode: *num_decode,
                     args: t.sample.clone(),
                 });
-                let tokens = self_.model.sample(args, logits);
-
-                let eos = self_.model.eos_token();
-                zip(tasks, num_decode)
-                    .filter(|(_, n)| *n > 0)
-                    .map(|(t, _)| t)
-                    .zip(tokens)
-                    .filter(|(task, token)| *token != eos && task.sender.send(*token).is_ok())
-                    .for_each(|(mut task, token)| {
+                let tokens = match self_
+                    .model
+                    .decode(decoding, hidden_state)
+                    .and_then(|logits| self_.model.sample(args, logits))
+                {
+                    Ok(tokens) => tokens,
+                    Err(e) => {
+                        // 无法确定出错的请求，整批失败
+                        for task in tasks {
+                            let _ = task.sender.send(Err(e.clone()));
+                        }
+                        return;
+                    }
+                };
+
+                let max_seq_len = self_.model.max_seq_len();
+                let mut tokens = tokens.into_iter();
+                for (mut task, num_decode) in zip(tasks, num_decode) {
+                    if !task.rest.is_empty() {
+                        // 分块填充未完成，继续填充剩余的部分
+                        if task.sender.is_closed() {
+                            continue;
+                        }
+                        let rest = take(&mut task.rest);
+                        task.pos += replace(&mut task.tokens, rest).len() as upos;
+                    } else {
+                        if num_decode == 0 {
+                            continue;
+                        }
+                        let token = tokens.next().unwrap();
+                        if self_.eos.contains(&token) || task.sender.send(Ok(token)).is_err() {
+                            continue;
+                        }
                         task.pos += replace(&mut task.tokens, vec![token]).len() as upos;
-                        self_.batcher.enq(task);
-                    });
+                        task.prefix = None;
+                    }
+                    // 缓存达到上限时淘汰不重要的位置
+                    if let Some(policy) = task.eviction {
+                        let mut lock = task.cache.lock().unwrap();
+                        if let Some(cache) = lock.as_mut() {
+                            let cached = task.pos;
+                            if let Some(mut retain) =
+                                cache.importance().and_then(|i| policy.select(i))
+                            {
+                                task.pos = self_.model.retain_cache(cache, &retain);
+                                // 淘汰后的缓存不再对应完整的前缀，不能共享
+                                task.prefix = None;
+                                // 尚未缓存的词不受淘汰影响
+                                retain.push(cached..upos::MAX);
+                                task.retained.lock().unwrap().push(retain);
+                            }
+                        }
+                    }
+                    // 上下文已满时停止生成并通知接收者，由会话在下一次对话前移动上下文
+                    if task.pos < max_seq_len {
+                        self_.batcher.enq(Request::Infer(task));
+                    } else {
+                        let _ = task
+                            .sender
+                            .send(Err(InferError::ContextFull { max_seq_len }));
+                    }
+                }
             });
         }
     }
@@ -357,12 +826,34 @@ impl Sentence {
     }
 }
 
+/// 推理线程处理的请求。
+pub(crate) enum Request<Cache> {
+    /// 对话或文本生成的推理任务。
+    Infer(Task<Cache>),
+    /// 计算句向量的请求。
+    Embed(EmbedTask),
+}
+
+/// 计算句向量的请求，每段文本池化为一个向量。
+pub(crate) struct EmbedTask {
+    pub tokens: Vec<Vec<utok>>,
+    pub pooling: Pooling,
+    pub sender: oneshot::Sender<Result<Vec<Vec<f32>>, InferError>>,
+}
+
 pub(crate) struct Task<Cache> {
     tokens: Vec<utok>,
     pos: upos,
     sample: SampleArgs,
-    cache: Arc<Mutex<Option<Tensor<Cache>>>>,
-    sender: UnboundedSender<utok>,
+    eviction: Option<HeavyHitter>,
+    cache: Arc<Mutex<Option<KVCache<Cache>>>>,
+    /// 每次淘汰后保留的范围，用于同步会话的对话。
+    retained: Arc<Mutex<Vec<Vec<Range<upos>>>>>,
+    /// 从位置 0 开始填充的完整 token 序列，填充后共享给其他会话。
+    prefix: Option<Vec<utok>>,
+    /// 超出 token 预算而留待之后填充的部分。
+    rest: Vec<utok>,
+    sender: UnboundedSender<Result<utok, InferError>>,
 }
 
 impl<Cache> Task<Cache> {
@@ -9,6 +9,15 @@ pub struct ChatCPM;
 
 pub struct ChatTinyLlama;
 
+pub struct ChatInst;
+
+pub struct ChatML;
+
+pub struct ChatGemma;
+
+/// 没有对话模板的模型，原样续写。
+pub struct Plain;
+
 impl Template for ChatCPM {
     #[inline]
     fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
@@ -32,3 +41,57 @@ impl Template for ChatTinyLlama {
         Cow::Owned(format!("<|user|>\n{prompt}</s><|assistant|>\n"))
     }
 }
+
+impl Template for ChatInst {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    #[inline]
+    fn apply_chat<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(format!("<s>[INST] {} [/INST]", prompt.trim()))
+    }
+}
+
+impl Template for ChatML {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    #[inline]
+    fn apply_chat<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(format!(
+            "<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
+            prompt.trim()
+        ))
+    }
+}
+
+impl Template for ChatGemma {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    #[inline]
+    fn apply_chat<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(format!(
+            "<bos><start_of_turn>user\n{}<end_of_turn>\n<start_of_turn>model\n",
+            prompt.trim()
+        ))
+    }
+}
+
+impl Template for Plain {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    #[inline]
+    fn apply_chat<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+}
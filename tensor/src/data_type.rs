@@ -4,6 +4,7 @@
 };
 use std::fmt;
 
+#[allow(non_camel_case_types)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum DataType {
@@ -20,6 +21,16 @@ pub enum DataType {
     BF16,
     F32,
     F64,
+    /// 8 位浮点数，4 位指数、3 位尾数。
+    F8_E4M3,
+    /// 8 位浮点数，5 位指数、2 位尾数。
+    F8_E5M2,
+    /// 每 32 个元素一块，块内以 f16 缩放系数和 int8 存储。
+    Q8_0,
+    /// 每 32 个元素一块，块内以 f16 缩放系数和 4 位整数存储。
+    Q4_0,
+    /// 每 256 个元素一块，分为 8 个子块，子块的缩放系数和偏移以 6 位整数存储。
+    Q4_K,
 }
 
 impl DataType {
@@ -44,8 +55,34 @@ impl DataType {
             Self::BF16 => <half::bf16 as Ty>::SIZE,
             Self::F32 => <f32 as Ty>::SIZE,
             Self::F64 => <f64 as Ty>::SIZE,
+            Self::F8_E4M3 | Self::F8_E5M2 => 1,
+            Self::Q8_0 => 34,
+            Self::Q4_0 => 18,
+            Self::Q4_K => 144,
         }
     }
+
+    /// 每个元素包含的数据个数，按块量化的类型以一块为一个元素。
+    #[inline]
+    pub const fn block_size(&self) -> usize {
+        match self {
+            Self::Q8_0 | Self::Q4_0 => 32,
+            Self::Q4_K => 256,
+            _ => 1,
+        }
+    }
+
+    /// 是否按块量化的类型。
+    #[inline]
+    pub const fn is_quantized(&self) -> bool {
+        self.block_size() > 1
+    }
+
+    /// 是否 8 位浮点类型。
+    #[inline]
+    pub const fn is_fp8(&self) -> bool {
+        matches!(self, Self::F8_E4M3 | Self::F8_E5M2)
+    }
 }
 
 pub trait Ty: Sized {
@@ -98,12 +135,26 @@ impl Serialize for DataType {
     where
         S: Serializer,
     {
-        match self {
-            Self::F16 => serializer.serialize_str("float16"),
-            Self::BF16 => serializer.serialize_str("bfloat16"),
-            Self::F32 => serializer.serialize_str("float32"),
-            _ => todo!(),
-        }
+        serializer.serialize_str(match self {
+            Self::Bool => "bool",
+            Self::I8 => "int8",
+            Self::I16 => "int16",
+            Self::I32 => "int32",
+            Self::I64 => "int64",
+            Self::U8 => "uint8",
+            Self::U16 => "uint16",
+            Self::U32 => "uint32",
+            Self::U64 => "uint64",
+            Self::F16 => "float16",
+            Self::BF16 => "bfloat16",
+            Self::F32 => "float32",
+            Self::F64 => "float64",
+            Self::F8_E4M3 => "float8_e4m3fn",
+            Self::F8_E5M2 => "float8_e5m2",
+            Self::Q8_0 => "q8_0",
+            Self::Q4_0 => "q4_0",
+            Self::Q4_K => "q4_k",
+        })
     }
 }
 
@@ -122,10 +173,7 @@ impl<'de> Visitor<'de> for DataTypeVisitor {
     type Value = DataType;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            formatter,
-            "pytorch dtype string: \"float16\", \"bfloat16\", or \"float32\""
-        )
+        write!(formatter, "pytorch dtype string, such as \"float16\"")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -133,12 +181,27 @@ impl<'de> Visitor<'de> for DataTypeVisitor {
         E: serde::de::Error,
     {
         match v {
+            "bool" => Ok(DataType::Bool),
+            "int8" => Ok(DataType::I8),
+            "int16" => Ok(DataType::I16),
+            "int32" => Ok(DataType::I32),
+            "int64" => Ok(DataType::I64),
+            "uint8" => Ok(DataType::U8),
+            "uint16" => Ok(DataType::U16),
+            "uint32" => Ok(DataType::U32),
+            "uint64" => Ok(DataType::U64),
             "float16" => Ok(DataType::F16),
             "bfloat16" => Ok(DataType::BF16),
             "float32" => Ok(DataType::F32),
+            "float64" => Ok(DataType::F64),
+            "float8_e4m3fn" => Ok(DataType::F8_E4M3),
+            "float8_e5m2" => Ok(DataType::F8_E5M2),
+            "q8_0" => Ok(DataType::Q8_0),
+            "q4_0" => Ok(DataType::Q4_0),
+            "q4_k" => Ok(DataType::Q4_K),
             _ => Err(E::invalid_value(
                 Unexpected::Str(v),
-                &"\"float16\", \"bfloat16\", or \"float32\"",
+                &"pytorch dtype string",
             )),
         }
     }
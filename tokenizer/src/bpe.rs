@@ -1,5 +1,5 @@
 ﻿use crate::{ByteDecoder, Tokenizer};
-use common::utok;
+use common::{gguf::GgufValue, utok};
 use std::{io::Result, path::Path};
 
 /// 由 tokenizer.model 文件定义的 bpe 分词器。
@@ -51,6 +51,13 @@ impl BPE {
         })
     }
 
+    /// 词表和评分写为 gguf 元数据。
+    pub fn gguf_metadata(&self) -> Vec<(String, GgufValue)> {
+        let pieces =
+            (0..self.offsets.len() as utok).map(|i| (self.get_piece(i), self.get_score(i)));
+        crate::gguf::spm_metadata(pieces)
+    }
+
     /// 根据词汇查找代码。
     #[inline]
     fn find_piece(&self, piece: &str) -> Option<utok> {
@@ -0,0 +1,338 @@
+﻿use crate::{pre_tokenizer::PreTokenizer, ByteDecoder, Tokenizer};
+use common::{
+    gguf::{Gguf, GgufValue},
+    utok,
+};
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+};
+
+/// 由 gguf 元数据中的词表定义的分词器。
+///
+/// `tokenizer.ggml.model` 为 `llama` 时按词汇评分合并（sentencepiece），
+/// 为 `gpt2` 时按 `tokenizer.ggml.pre` 预分词，再在每个片段内按 `tokenizer.ggml.merges` 的顺序合并字节级的词汇。
+pub struct GgufTokenizer {
+    /// 词表。
+    pieces: Vec<String>,
+    /// 从词汇查询序号。
+    indices: HashMap<String, utok>,
+    /// 相邻两个词汇合并的优先级和结果，优先级越大越先合并。
+    merges: HashMap<(utok, utok), (f32, utok)>,
+    /// 字节级词表的预分词，sentencepiece 词表为 `None`。
+    pre: Option<PreTokenizer>,
+    /// 词汇的最大长度。
+    max_piece_len: usize,
+    /// 单字节词汇转义。
+    byte_pieces: ByteDecoder,
+}
+
+impl GgufTokenizer {
+    /// 从 `.gguf` 文件中读取分词器。
+    #[inline]
+    pub fn from_gguf_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_gguf(&Gguf::load(path)?)
+    }
+
+    /// 从 gguf 元数据中读取分词器。
+    pub fn from_gguf(gguf: &Gguf) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(InvalidData, msg);
+        let strings = |key: &str| {
+            gguf.get(key)
+                .and_then(GgufValue::as_array)
+                .map(|a| a.iter().filter_map(GgufValue::as_str).collect::<Vec<_>>())
+        };
+
+        let tokens = strings("tokenizer.ggml.tokens")
+            .ok_or_else(|| invalid("missing tokenizer.ggml.tokens"))?;
+        match gguf.get_str("tokenizer.ggml.model") {
+            Some("llama") | None => {
+                let scores = gguf
+                    .get("tokenizer.ggml.scores")
+                    .and_then(GgufValue::as_array)
+                    .ok_or_else(|| invalid("missing tokenizer.ggml.scores"))?
+                    .iter()
+                    .map(|s| s.as_f32().unwrap_or(0.))
+                    .collect::<Vec<_>>();
+                Ok(Self::new_spm(&tokens, &scores))
+            }
+            Some("gpt2") => {
+                let merges = strings("tokenizer.ggml.merges")
+                    .ok_or_else(|| invalid("missing tokenizer.ggml.merges"))?;
+                let pre = gguf.get_str("tokenizer.ggml.pre");
+                let pre = PreTokenizer::from_name(pre).ok_or_else(|| {
+                    Error::new(
+                        InvalidData,
+                        format!(
+                            "unsupported tokenizer.ggml.pre: {}",
+                            pre.unwrap_or_default()
+                        ),
+                    )
+                })?;
+                Ok(Self::new_bpe(&tokens, &merges, pre))
+            }
+            Some(model) => Err(Error::new(
+                InvalidData,
+                format!("unsupported tokenizer model: {model}"),
+            )),
+        }
+    }
+
+    /// 是否字节级的词表，这种词表自行处理空格，不需要额外的规范化。
+    #[inline]
+    pub fn is_byte_level(&self) -> bool {
+        self.pre.is_some()
+    }
+
+    /// sentencepiece 词表，合并的结果越高分越先合并。
+    fn new_spm(tokens: &[&str], scores: &[f32]) -> Self {
+        let mut ans = Self::new(tokens, None);
+        for (i, piece) in tokens.iter().enumerate() {
+            let chars = piece.char_indices().skip(1);
+            for (split, _) in chars {
+                let (a, b) = piece.split_at(split);
+                if let (Some(&a), Some(&b)) = (ans.indices.get(a), ans.indices.get(b)) {
+                    let score = scores.get(i).copied().unwrap_or(0.);
+                    ans.merges.insert((a, b), (score, i as _));
+                }
+            }
+        }
+        ans
+    }
+
+    /// 字节级 bpe 词表，越靠前的合并规则越先合并。
+    fn new_bpe(tokens: &[&str], merges: &[&str], pre: PreTokenizer) -> Self {
+        let mut ans = Self::new(tokens, Some(pre));
+        for (rank, merge) in merges.iter().enumerate() {
+            let Some((a, b)) = merge.split_once(' ') else {
+                continue;
+            };
+            let pair = (ans.indices.get(a), ans.indices.get(b));
+            let merged = ans.indices.get(&format!("{a}{b}"));
+            if let ((Some(&a), Some(&b)), Some(&merged)) = (pair, merged) {
+                ans.merges.entry((a, b)).or_insert((-(rank as f32), merged));
+            }
+        }
+        ans
+    }
+
+    fn new(tokens: &[&str], pre: Option<PreTokenizer>) -> Self {
+        let unicode_to_byte = unicode_to_byte();
+        let mut max_piece_len = 0;
+        let pieces = tokens
+            .iter()
+            .map(|piece| {
+                if pre.is_none() {
+                    max_piece_len = max_piece_len.max(piece.len());
+                    return piece.to_string();
+                }
+                // 字节级的词汇以可见字符表示每个字节，还原为原始字节
+                let bytes = piece
+                    .chars()
+                    .map(|c| unicode_to_byte.get(&c).copied().unwrap_or(b'?'))
+                    .collect::<Vec<_>>();
+                max_piece_len = max_piece_len.max(bytes.len());
+                match String::from_utf8(bytes) {
+                    Ok(piece) => piece,
+                    // 单个字节可能是多字节字符的一部分，与 sentencepiece 的字节词汇一样解码
+                    Err(e) if e.as_bytes().len() == 1 => format!("<0x{:02X}>", e.as_bytes()[0]),
+                    // 多个字节的残缺字符无法表示为字符串
+                    Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        Self {
+            max_piece_len,
+            pieces,
+            indices: tokens
+                .iter()
+                .enumerate()
+                .map(|(i, piece)| (piece.to_string(), i as _))
+                .collect(),
+            merges: HashMap::new(),
+            pre,
+            byte_pieces: ByteDecoder::new(),
+        }
+    }
+}
+
+impl Tokenizer for GgufTokenizer {
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        self.max_piece_len
+    }
+
+    fn encode(&self, text: &str) -> Vec<utok> {
+        let Some(pre) = self.pre else {
+            let mut tokens = Vec::new();
+            for c in text.chars() {
+                if let Some(&index) = self.indices.get(c.encode_utf8(&mut [0; 4]) as &str) {
+                    tokens.push(index);
+                } else {
+                    tokens.extend(
+                        c.to_string()
+                            .bytes()
+                            .filter_map(|b| self.indices.get(&format!("<0x{b:02X}>"))),
+                    );
+                }
+            }
+            return self.merge(tokens);
+        };
+
+        let byte_to_unicode = byte_to_unicode();
+        let mut ans = Vec::new();
+        for piece in pre.split(text) {
+            let tokens = piece
+                .bytes()
+                .filter_map(|b| self.indices.get(&byte_to_unicode[b as usize].to_string()))
+                .copied()
+                .collect();
+            ans.extend(self.merge(tokens));
+        }
+        ans
+    }
+
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        self.byte_pieces.decode(&self.pieces[token as usize])
+    }
+}
+
+impl GgufTokenizer {
+    /// 每次合并优先级最高的一对相邻词汇，优先级相同时合并靠前的。
+    fn merge(&self, mut tokens: Vec<utok>) -> Vec<utok> {
+        while let Some((i, merged)) = tokens
+            .windows(2)
+            .enumerate()
+            .filter_map(|(i, pair)| self.merges.get(&(pair[0], pair[1])).map(|m| (i, m)))
+            .max_by(|(i, (a, _)), (j, (b, _))| a.total_cmp(b).then(j.cmp(i)))
+            .map(|(i, &(_, merged))| (i, merged))
+        {
+            tokens[i] = merged;
+            tokens.remove(i + 1);
+        }
+        tokens
+    }
+}
+
+/// 把 sentencepiece 风格的词表和评分写为 gguf 元数据。
+pub(crate) fn spm_metadata<'a>(
+    pieces: impl IntoIterator<Item = (&'a str, f32)>,
+) -> Vec<(String, GgufValue)> {
+    let (mut tokens, mut scores, mut types) = (Vec::new(), Vec::new(), Vec::new());
+    for (piece, score) in pieces {
+        // 与 llama.cpp 的 token 类型一致：1 普通，2 未知，3 控制，6 字节
+        let ty = match piece {
+            "<unk>" => 2,
+            "<s>" | "</s>" => 3,
+            _ if piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>') => 6,
+            _ => 1,
+        };
+        tokens.push(GgufValue::String(piece.into()));
+        scores.push(GgufValue::F32(score));
+        types.push(GgufValue::I32(ty));
+    }
+    vec![
+        (
+            "tokenizer.ggml.model".into(),
+            GgufValue::String("llama".into()),
+        ),
+        ("tokenizer.ggml.tokens".into(), GgufValue::Array(tokens)),
+        ("tokenizer.ggml.scores".into(), GgufValue::Array(scores)),
+        ("tokenizer.ggml.token_type".into(), GgufValue::Array(types)),
+    ]
+}
+
+/// GPT-2 把每个字节映射为一个可见字符。
+fn byte_to_unicode() -> [char; 256] {
+    let mut ans = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        ans[b as usize] = if matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff) {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    ans
+}
+
+fn unicode_to_byte() -> HashMap<char, u8> {
+    byte_to_unicode()
+        .iter()
+        .enumerate()
+        .map(|(b, &c)| (c, b as u8))
+        .collect()
+}
+
+#[test]
+fn test_spm() {
+    let tokens = ["<unk>", "<0x21>", "▁", "a", "b", "ab", "▁ab"];
+    let scores = [0., 0., -1., -1., -1., -2., -3.];
+    let spm = GgufTokenizer::new_spm(&tokens, &scores);
+    assert_eq!(spm.encode("▁ab!"), [6, 1]);
+    assert_eq!(spm.encode("ba"), [4, 3]);
+    assert_eq!(spm.decode(1), "!");
+}
+
+#[test]
+fn test_spm_metadata() {
+    let metadata = spm_metadata([("<unk>", 0.), ("<s>", 0.), ("<0x0A>", 0.), ("▁a", -1.)]);
+    let map = metadata.into_iter().collect::<HashMap<_, _>>();
+    let types = map["tokenizer.ggml.token_type"].as_array().unwrap();
+    assert_eq!(
+        types
+            .iter()
+            .filter_map(GgufValue::as_u64)
+            .collect::<Vec<_>>(),
+        [2, 3, 6, 1]
+    );
+    let tokens = map["tokenizer.ggml.tokens"].as_array().unwrap();
+    let scores = map["tokenizer.ggml.scores"].as_array().unwrap();
+    let spm = GgufTokenizer::new_spm(
+        &tokens
+            .iter()
+            .filter_map(GgufValue::as_str)
+            .collect::<Vec<_>>(),
+        &scores
+            .iter()
+            .filter_map(GgufValue::as_f32)
+            .collect::<Vec<_>>(),
+    );
+    assert_eq!(spm.decode(2), "\n");
+}
+
+#[test]
+fn test_bpe() {
+    let tokens = ["a", "b", "Ġ", "ab", "Ġab"];
+    let merges = ["a b", "Ġ ab"];
+    let bpe = GgufTokenizer::new_bpe(&tokens, &merges, PreTokenizer::Gpt2);
+    assert_eq!(bpe.encode(" ab"), [4]);
+    assert_eq!(bpe.encode("ab a"), [3, 2, 0]);
+
+    // 合并不跨越预分词的边界
+    let tokens = ["1", "2", "12"];
+    let gpt2 = GgufTokenizer::new_bpe(&tokens, &["1 2"], PreTokenizer::Gpt2);
+    let qwen2 = GgufTokenizer::new_bpe(&tokens, &["1 2"], PreTokenizer::Qwen2);
+    assert_eq!(gpt2.encode("12"), [2]);
+    assert_eq!(qwen2.encode("12"), [0, 1]);
+    assert_eq!(bpe.decode(4), " ab");
+}
+
+#[test]
+fn test_bpe_bytes() {
+    // "Ã" 是字节 0xC3，"ä¸" 是 "中" 的前两个字节
+    let tokens = ["Ã", "ä¸", "ä¸Ń", "a"];
+    let bpe = GgufTokenizer::new_bpe(&tokens, &["ä¸ Ń"], PreTokenizer::Qwen2);
+    assert_eq!(bpe.decode(0).as_bytes(), [0xC3]);
+    assert_eq!(bpe.decode(1), "\u{FFFD}");
+    assert_eq!(bpe.decode(2), "中");
+    assert_eq!(bpe.decode(3), "a");
+    assert_eq!(bpe.max_piece_len(), 3);
+}
@@ -1,5 +1,8 @@
 mod bpe;
+mod gguf;
 mod normalizer;
+mod pre_tokenizer;
+mod tokenizer_json;
 mod vocab_txt;
 
 use common::utok;
@@ -12,7 +15,9 @@ pub trait Tokenizer {
 }
 
 pub use bpe::BPE;
+pub use gguf::GgufTokenizer;
 pub use normalizer::{BPECommonNormalizer, Normalizer};
+pub use tokenizer_json::TokenizerJson;
 pub use vocab_txt::VocabTxt;
 
 struct ByteDecoder([u8; 256]);
@@ -0,0 +1,178 @@
+/// 字节级词表合并之前的预分词，与 `tokenizer.ggml.pre` 对应的正则表达式（[PreTokenizer::pattern]）等价。
+///
+/// 合并只发生在同一片段之内，因此不会跨越单词、数字和标点的边界。
+/// `\p{L}` 和 `\p{N}` 分别以 [char::is_alphabetic] 和 [char::is_numeric] 判断。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum PreTokenizer {
+    /// GPT-2，空格与后面的单词、数字或标点合并。
+    Gpt2,
+    /// Llama 3，数字每 3 个切分，标点带上后面的换行。
+    Llama3,
+    /// 与 [PreTokenizer::Llama3] 相同，但数字逐个切分。
+    Qwen2,
+}
+
+impl PreTokenizer {
+    /// 按 `tokenizer.ggml.pre` 的取值选择预分词，没有声明时按 GPT-2 处理。
+    pub fn from_name(name: Option<&str>) -> Option<Self> {
+        match name {
+            None | Some("gpt-2") => Some(Self::Gpt2),
+            Some("llama3" | "llama-v3" | "llama-bpe") => Some(Self::Llama3),
+            Some("qwen2") => Some(Self::Qwen2),
+            Some(_) => None,
+        }
+    }
+
+    /// 写入 `tokenizer.ggml.pre` 的名字。
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gpt2 => "gpt-2",
+            Self::Llama3 => "llama-bpe",
+            Self::Qwen2 => "qwen2",
+        }
+    }
+
+    /// 按 `tokenizer.json` 中 `Split` 预分词的正则表达式选择预分词。
+    pub fn from_pattern(pattern: &str) -> Option<Self> {
+        [Self::Gpt2, Self::Llama3, Self::Qwen2]
+            .into_iter()
+            .find(|pre| pre.pattern() == pattern)
+    }
+
+    /// 对应的正则表达式。
+    fn pattern(self) -> &'static str {
+        match self {
+            Self::Gpt2 => {
+                r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+"
+            }
+            Self::Llama3 => {
+                r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+"
+            }
+            Self::Qwen2 => {
+                r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+"
+            }
+        }
+    }
+
+    /// 把文本切分为依次相连的片段。
+    pub fn split(self, text: &str) -> Vec<&str> {
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut offset = 0;
+        let mut i = 0;
+        let mut ans = Vec::new();
+        while i < chars.len() {
+            let end = self.next(&chars, i);
+            let len = chars[i..end].iter().map(|c| c.len_utf8()).sum::<usize>();
+            ans.push(&text[offset..][..len]);
+            offset += len;
+            i = end;
+        }
+        ans
+    }
+
+    /// 从第 `i` 个字符开始的片段的结束位置，按正则表达式各分支的顺序尝试。
+    fn next(self, chars: &[char], i: usize) -> usize {
+        let at = |j: usize| chars.get(j).copied();
+        let is = |j: usize, f: fn(char) -> bool| at(j).is_some_and(f);
+        let run =
+            |j: usize, f: fn(char) -> bool| j + chars[j..].iter().take_while(|&&c| f(c)).count();
+        let space = (at(i) == Some(' ')) as usize;
+        let gpt2 = self == Self::Gpt2;
+
+        // 缩写
+        if at(i) == Some('\'') {
+            for suffix in ["s", "t", "re", "ve", "m", "ll", "d"] {
+                let matches = suffix
+                    .chars()
+                    .enumerate()
+                    .all(|(k, s)| match at(i + 1 + k) {
+                        Some(c) if gpt2 => c == s,
+                        Some(c) => c.to_ascii_lowercase() == s,
+                        None => false,
+                    });
+                if matches {
+                    return i + 1 + suffix.len();
+                }
+            }
+        }
+        // 单词
+        if gpt2 {
+            if is(i + space, is_letter) {
+                return run(i + space, is_letter);
+            }
+        } else if is(i, is_letter) {
+            return run(i, is_letter);
+        } else if is(i, |c| {
+            !matches!(c, '\r' | '\n') && !is_letter(c) && !is_number(c)
+        }) && is(i + 1, is_letter)
+        {
+            return run(i + 1, is_letter);
+        }
+        // 数字
+        match self {
+            Self::Gpt2 if is(i + space, is_number) => return run(i + space, is_number),
+            Self::Llama3 if is(i, is_number) => return run(i, is_number).min(i + 3),
+            Self::Qwen2 if is(i, is_number) => return i + 1,
+            _ => {}
+        }
+        // 标点
+        if is(i + space, is_other) {
+            let end = run(i + space, is_other);
+            return if gpt2 { end } else { run(end, is_newline) };
+        }
+        // 空白
+        let end = run(i, char::is_whitespace);
+        if !gpt2 {
+            if let Some(last) = (i..end).rev().find(|&j| is(j, is_newline)) {
+                return last + 1;
+            }
+        }
+        if end < chars.len() && end - i > 1 {
+            // 保留最后一个空白与后面的非空白字符合并
+            end - 1
+        } else {
+            end.max(i + 1)
+        }
+    }
+}
+
+fn is_letter(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+fn is_number(c: char) -> bool {
+    c.is_numeric()
+}
+
+fn is_other(c: char) -> bool {
+    !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric()
+}
+
+fn is_newline(c: char) -> bool {
+    matches!(c, '\r' | '\n')
+}
+
+#[test]
+fn test_split() {
+    let text = "Hello world's  12345 apples!!\n\n  ok";
+    assert_eq!(
+        PreTokenizer::Gpt2.split(text),
+        ["Hello", " world", "'s", " ", " 12345", " apples", "!!", "\n\n ", " ok"],
+    );
+    assert_eq!(
+        PreTokenizer::Llama3.split(text),
+        ["Hello", " world", "'s", " ", " ", "123", "45", " apples", "!!\n\n", " ", " ok"],
+    );
+    assert_eq!(
+        PreTokenizer::Qwen2.split("I'M 2024年"),
+        ["I", "'M", " ", "2", "0", "2", "4", "年"],
+    );
+    for pre in [
+        PreTokenizer::Gpt2,
+        PreTokenizer::Llama3,
+        PreTokenizer::Qwen2,
+    ] {
+        assert_eq!(PreTokenizer::from_pattern(pre.pattern()), Some(pre));
+        assert_eq!(PreTokenizer::from_name(Some(pre.name())), Some(pre));
+    }
+}
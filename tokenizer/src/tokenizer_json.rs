@@ -0,0 +1,219 @@
+use crate::pre_tokenizer::PreTokenizer;
+use common::{gguf::GgufValue, utok};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+};
+
+/// 由 HuggingFace tokenizer.json 文件定义的字节级 bpe 词表，用于导出 gguf。
+///
+/// 只支持 gguf 能够表示的词表：`BPE` 模型、`ByteLevel` 解码，
+/// 预分词的正则表达式是 [PreTokenizer] 已知的一种。
+pub struct TokenizerJson {
+    /// 词表和 llama.cpp 的 token 类型。
+    tokens: Vec<(String, i32)>,
+    /// 按优先级排列的合并规则。
+    merges: Vec<String>,
+    pre: PreTokenizer,
+}
+
+#[derive(Deserialize)]
+struct Json {
+    model: Model,
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    #[serde(default)]
+    pre_tokenizer: Value,
+    #[serde(default)]
+    decoder: Value,
+}
+
+#[derive(Deserialize)]
+struct Model {
+    #[serde(rename = "type")]
+    ty: String,
+    vocab: HashMap<String, utok>,
+    merges: Vec<Merge>,
+}
+
+/// 较新的 tokenizers 把合并规则保存为字符串对。
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Merge {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Deserialize)]
+struct AddedToken {
+    id: utok,
+    content: String,
+    special: bool,
+}
+
+impl TokenizerJson {
+    /// 打开 tokenizer.json 文件并读取词表。
+    #[inline]
+    pub fn from_json_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(&read_to_string(path)?)
+    }
+
+    fn from_json(text: &str) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(InvalidData, msg);
+        let json = serde_json::from_str::<Json>(text)?;
+        if json.model.ty != "BPE" || json.decoder["type"] != "ByteLevel" {
+            return Err(invalid("only byte-level bpe tokenizers can be exported"));
+        }
+        let pre = pre_tokenizer(&json.pre_tokenizer)
+            .ok_or_else(|| invalid("unsupported pre-tokenizer"))?;
+
+        // 与 llama.cpp 的 token 类型一致：1 普通，3 控制，4 用户定义，5 未使用
+        let len = json
+            .model
+            .vocab
+            .values()
+            .chain(json.added_tokens.iter().map(|t| &t.id))
+            .max()
+            .map_or(0, |&max| max as usize + 1);
+        let mut tokens = (0..len)
+            .map(|i| (format!("[PAD{i}]"), 5))
+            .collect::<Vec<_>>();
+        for (piece, id) in json.model.vocab {
+            tokens[id as usize] = (piece, 1);
+        }
+        for t in json.added_tokens {
+            tokens[t.id as usize] = (t.content, if t.special { 3 } else { 4 });
+        }
+        let merges = json
+            .model
+            .merges
+            .into_iter()
+            .map(|m| match m {
+                Merge::Joined(m) => m,
+                Merge::Pair(a, b) => format!("{a} {b}"),
+            })
+            .collect();
+        Ok(Self {
+            tokens,
+            merges,
+            pre,
+        })
+    }
+
+    /// 词表、合并规则和预分词写为 gguf 元数据。
+    pub fn gguf_metadata(&self) -> Vec<(String, GgufValue)> {
+        let string = |s: &String| GgufValue::String(s.clone());
+        vec![
+            (
+                "tokenizer.ggml.model".into(),
+                GgufValue::String("gpt2".into()),
+            ),
+            (
+                "tokenizer.ggml.pre".into(),
+                GgufValue::String(self.pre.name().into()),
+            ),
+            (
+                "tokenizer.ggml.tokens".into(),
+                GgufValue::Array(self.tokens.iter().map(|(s, _)| string(s)).collect()),
+            ),
+            (
+                "tokenizer.ggml.token_type".into(),
+                GgufValue::Array(
+                    self.tokens
+                        .iter()
+                        .map(|&(_, ty)| GgufValue::I32(ty))
+                        .collect(),
+                ),
+            ),
+            (
+                "tokenizer.ggml.merges".into(),
+                GgufValue::Array(self.merges.iter().map(string).collect()),
+            ),
+        ]
+    }
+}
+
+/// 识别 `Split` 加不带正则的 `ByteLevel`，或者单独的 `ByteLevel`（GPT-2）。
+fn pre_tokenizer(value: &Value) -> Option<PreTokenizer> {
+    let flag = |v: &Value, key: &str, default: bool| v[key].as_bool().unwrap_or(default);
+    match value["type"].as_str()? {
+        "ByteLevel"
+            if flag(value, "use_regex", true) && !flag(value, "add_prefix_space", false) =>
+        {
+            Some(PreTokenizer::Gpt2)
+        }
+        "Sequence" => match value["pretokenizers"].as_array()?.as_slice() {
+            [split, byte_level]
+                if split["type"] == "Split"
+                    && byte_level["type"] == "ByteLevel"
+                    && !flag(byte_level, "use_regex", true)
+                    && !flag(byte_level, "add_prefix_space", false) =>
+            {
+                PreTokenizer::from_pattern(split["pattern"]["Regex"].as_str()?)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[test]
+fn test_tokenizer_json() {
+    let json = r#"{
+        "added_tokens": [{ "id": 4, "content": "<|endoftext|>", "special": true }],
+        "pre_tokenizer": {
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": { "Regex": "(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\\r\\n\\p{L}\\p{N}]?\\p{L}+|\\p{N}| ?[^\\s\\p{L}\\p{N}]+[\\r\\n]*|\\s*[\\r\\n]+|\\s+(?!\\S)|\\s+" },
+                    "behavior": "Isolated",
+                    "invert": false
+                },
+                { "type": "ByteLevel", "add_prefix_space": false, "use_regex": false }
+            ]
+        },
+        "decoder": { "type": "ByteLevel" },
+        "model": {
+            "type": "BPE",
+            "vocab": { "a": 0, "b": 1, "ab": 2 },
+            "merges": ["a b", ["ab", "b"]]
+        }
+    }"#;
+    let tokenizer = TokenizerJson::from_json(json).unwrap();
+    assert_eq!(tokenizer.pre, PreTokenizer::Qwen2);
+    assert_eq!(tokenizer.merges, ["a b", "ab b"]);
+
+    let map = tokenizer
+        .gguf_metadata()
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(map["tokenizer.ggml.pre"].as_str(), Some("qwen2"));
+    let tokens = map["tokenizer.ggml.tokens"].as_array().unwrap();
+    assert_eq!(
+        tokens
+            .iter()
+            .filter_map(GgufValue::as_str)
+            .collect::<Vec<_>>(),
+        ["a", "b", "ab", "[PAD3]", "<|endoftext|>"]
+    );
+    let types = map["tokenizer.ggml.token_type"].as_array().unwrap();
+    assert_eq!(
+        types
+            .iter()
+            .filter_map(GgufValue::as_u64)
+            .collect::<Vec<_>>(),
+        [1, 1, 1, 5, 3]
+    );
+
+    // sentencepiece 风格的 tokenizer.json 无法表示为字节级词表
+    let spm = json.replace(
+        r#""decoder": { "type": "ByteLevel" }"#,
+        r#""decoder": null"#,
+    );
+    assert!(TokenizerJson::from_json(&spm).is_err());
+}
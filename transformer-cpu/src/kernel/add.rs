@@ -0,0 +1,43 @@
+use common::{f16, BetweenF32};
+use std::ops::{Deref, DerefMut};
+use tensor::{idim, DVector, DataType, Tensor};
+
+/// 将 `y` 逐元素累加到 `x` 上。
+pub fn add<T, U>(x: &mut Tensor<T>, y: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let dt = x.data_type();
+    assert_eq!(y.data_type(), dt);
+
+    let &[seq_len, n] = x.shape() else {
+        panic!("x shape: {:?}", x.shape());
+    };
+    assert_eq!(y.shape(), &[seq_len, n]);
+    assert!(x.contiguous_len() >= 1);
+    assert!(y.contiguous_len() >= 1);
+
+    for i in 0..seq_len {
+        let indices = DVector::from_vec(vec![i as idim, 0, 1]);
+        let x = x.locate_mut(&indices.as_view()).unwrap();
+        let y = y.locate(&indices.as_view()).unwrap();
+
+        match dt {
+            DataType::F16 => typed::<f16>(x, y, n as _),
+            DataType::F32 => typed::<f32>(x, y, n as _),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn typed<T>(x: *mut u8, y: *const u8, n: usize)
+where
+    T: BetweenF32,
+{
+    let x = unsafe { std::slice::from_raw_parts_mut(x.cast::<T>(), n) };
+    let y = unsafe { std::slice::from_raw_parts(y.cast::<T>(), n) };
+    for (x, y) in x.iter_mut().zip(y) {
+        *x = T::cast(x.get() + y.get());
+    }
+}
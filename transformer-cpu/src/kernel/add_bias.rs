@@ -0,0 +1,64 @@
+use common::{f16, BetweenF32};
+use std::ops::{Deref, DerefMut};
+use tensor::{idim, DVector, DataType, Tensor};
+
+pub fn add_bias<T, U>(x: &mut Tensor<T>, bias: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let dt = x.data_type();
+    assert_eq!(bias.data_type(), dt);
+
+    let &[seq_len, n] = x.shape() else {
+        panic!("x shape: {:?}", x.shape());
+    };
+    assert_eq!(bias.shape(), &[n]);
+    assert!(x.contiguous_len() >= 1);
+    assert!(bias.is_contiguous());
+
+    let bias = bias.as_slice();
+    for i in 0..seq_len {
+        let indices = DVector::from_vec(vec![i as idim, 0, 1]);
+        let x = x.locate_mut(&indices.as_view()).unwrap();
+
+        match dt {
+            DataType::F16 => typed::<f16>(x, bias.as_ptr(), n as _),
+            DataType::F32 => typed::<f32>(x, bias.as_ptr(), n as _),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn typed<T>(x: *mut u8, bias: *const u8, n: usize)
+where
+    T: BetweenF32,
+{
+    let x = unsafe { std::slice::from_raw_parts_mut(x.cast::<T>(), n) };
+    let bias = unsafe { std::slice::from_raw_parts(bias.cast::<T>(), n) };
+    for (x, b) in x.iter_mut().zip(bias) {
+        *x = T::cast(x.get() + b.get());
+    }
+}
+
+#[test]
+fn test_add_bias() {
+    use tensor::{reslice, slice};
+
+    let data = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+    let mut x = Tensor::new(DataType::F32, &[2, 6], reslice(&data).to_vec());
+    let bias = [10f32, 20., 30.];
+    let bias = Tensor::new(DataType::F32, &[3], reslice(&bias));
+    // 只对每行的前 3 个元素加偏置
+    add_bias(
+        &mut x
+            .as_mut()
+            .slice(&[slice![=>], slice![=>3]])
+            .map_physical(|u| &mut **u),
+        &bias,
+    );
+    assert_eq!(
+        reslice::<u8, f32>(x.as_slice()),
+        [10., 21., 32., 3., 4., 5., 16., 27., 38., 9., 10., 11.],
+    );
+}
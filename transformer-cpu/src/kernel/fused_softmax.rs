@@ -1,21 +1,24 @@
 ﻿use super::slice;
 use common::{f16, BetweenF32};
 use std::ops::DerefMut;
-use tensor::{expand_indices, idx_strides, DataType, Tensor};
+use tensor::{expand_indices, idx_strides, udim, DataType, Tensor};
 
 /// - x: [N0, N1, ... , N_, seq_len, att_len]
-pub fn softmax<T>(x: &mut Tensor<T>)
+/// - window: 每行最多关注的位置数，包括自身
+/// - slopes: ALiBi 斜率，按 `x` 除最后两维外的序号依次对应各注意力头
+pub fn softmax<T>(x: &mut Tensor<T>, window: Option<udim>, slopes: Option<&[f32]>)
 where
     T: DerefMut<Target = [u8]>,
 {
+    let window = window.map_or(usize::MAX, |w| w as usize);
     match x.data_type() {
-        DataType::F16 => typed::<T, f16>(x),
-        DataType::F32 => typed::<T, f32>(x),
+        DataType::F16 => typed::<T, f16>(x, window, slopes),
+        DataType::F32 => typed::<T, f32>(x, window, slopes),
         _ => unreachable!(),
     }
 }
 
-fn typed<T, U>(x: &mut Tensor<T>)
+fn typed<T, U>(x: &mut Tensor<T>, window: usize, slopes: Option<&[f32]>)
 where
     T: DerefMut<Target = [u8]>,
     U: BetweenF32 + PartialOrd + Clone,
@@ -32,9 +35,20 @@ where
             .unwrap()
             .cast::<U>();
         let slice = unsafe { std::slice::from_raw_parts_mut(ptr, seq_len * att_len) };
+        let slope = slopes.map_or(0., |s| s[i as usize % s.len()]);
         for r in 0..seq_len {
             let slice = &mut slice!(slice; att_len; [r]);
             let (att, tail) = slice.split_at_mut(att_len - seq_len + r + 1);
+            // 滑动窗口之前的位置与未来的位置一样被遮蔽
+            let (head, att) = att.split_at_mut(att.len().saturating_sub(window));
+            head.fill(U::zero());
+            // ALiBi 按与自身的距离线性降低注意力分数
+            if slope != 0. {
+                let last = att.len() - 1;
+                for (j, x) in att.iter_mut().enumerate() {
+                    *x = U::cast(x.get() - slope * (last - j) as f32);
+                }
+            }
 
             let max = att
                 .iter()
@@ -55,3 +69,18 @@ where
         }
     }
 }
+
+#[test]
+fn test_alibi() {
+    use tensor::reslice;
+
+    let mut x = Tensor::new(DataType::F32, &[2, 1, 3], vec![0u8; 24]);
+    softmax(&mut x, None, Some(&[2f32.ln(), 0.]));
+
+    // 第一个头的分数依次减去 2ln2、ln2、0
+    let expected = [1. / 7., 2. / 7., 4. / 7., 1. / 3., 1. / 3., 1. / 3.];
+    let ans = reslice::<u8, f32>(x.as_slice());
+    for (a, e) in ans.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,61 @@
+use super::gelu::{gelu_erf, gelu_tanh};
+use common::{f16, BetweenF32};
+use std::ops::{Deref, DerefMut};
+use tensor::{idim, DVector, DataType, Tensor};
+
+/// `exact` 为真时使用精确的 GELU，否则使用 tanh 近似。
+pub fn geglu<T, U>(gate: &mut Tensor<T>, up: &Tensor<U>, exact: bool)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let dt = gate.data_type();
+    assert_eq!(up.data_type(), dt);
+
+    let &[seq_len, di] = gate.shape() else {
+        panic!("gate shape: {:?}", gate.shape());
+    };
+    assert_eq!(up.shape(), &[seq_len, di]);
+    assert!(gate.contiguous_len() >= 1);
+    assert!(up.contiguous_len() >= 1);
+
+    let gelu = if exact { gelu_erf } else { gelu_tanh };
+    for i in 0..seq_len {
+        let indices = DVector::from_vec(vec![i as idim, 0, 1]);
+        let gate = gate.locate_mut(&indices.as_view()).unwrap();
+        let up = up.locate(&indices.as_view()).unwrap();
+
+        match dt {
+            DataType::F16 => typed::<f16>(gate, up, di as _, gelu),
+            DataType::F32 => typed::<f32>(gate, up, di as _, gelu),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn typed<T>(gate: *mut u8, up: *const u8, di: usize, gelu: fn(f32) -> f32)
+where
+    T: BetweenF32,
+{
+    let gate = unsafe { std::slice::from_raw_parts_mut(gate.cast::<T>(), di) };
+    let up = unsafe { std::slice::from_raw_parts(up.cast::<T>(), di) };
+    for (gate, up) in gate.iter_mut().zip(up) {
+        *gate = T::cast(gelu(gate.get()) * up.get());
+    }
+}
+
+#[test]
+fn test_geglu() {
+    use tensor::reslice;
+
+    let gate = [0f32, 1., -1.];
+    let mut gate = Tensor::new(DataType::F32, &[1, 3], reslice(&gate).to_vec());
+    let up = [1f32, 2., 3.];
+    let up = Tensor::new(DataType::F32, &[1, 3], reslice(&up));
+    geglu(&mut gate, &up, false);
+
+    let ans = reslice::<u8, f32>(gate.as_slice());
+    assert_eq!(ans[0], 0.);
+    assert!((ans[1] - 0.841192 * 2.).abs() < 1e-5);
+    assert!((ans[2] + 0.158808 * 3.).abs() < 1e-5);
+}
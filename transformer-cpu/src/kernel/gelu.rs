@@ -0,0 +1,82 @@
+use common::{f16, BetweenF32};
+use std::ops::DerefMut;
+use tensor::{idim, DVector, DataType, Tensor};
+
+/// 原地计算 `x` 的 GELU，`exact` 为假时使用 tanh 近似。
+pub fn gelu<T>(x: &mut Tensor<T>, exact: bool)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    let &[seq_len, n] = x.shape() else {
+        panic!("x shape: {:?}", x.shape());
+    };
+    assert!(x.contiguous_len() >= 1);
+
+    let dt = x.data_type();
+    let gelu = if exact { gelu_erf } else { gelu_tanh };
+    for i in 0..seq_len {
+        let indices = DVector::from_vec(vec![i as idim, 0, 1]);
+        let x = x.locate_mut(&indices.as_view()).unwrap();
+
+        match dt {
+            DataType::F16 => typed::<f16>(x, n as _, gelu),
+            DataType::F32 => typed::<f32>(x, n as _, gelu),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn typed<T: BetweenF32>(x: *mut u8, n: usize, gelu: fn(f32) -> f32) {
+    let x = unsafe { std::slice::from_raw_parts_mut(x.cast::<T>(), n) };
+    for x in x {
+        *x = T::cast(gelu(x.get()));
+    }
+}
+
+/// GELU 的 tanh 近似。
+#[inline(always)]
+pub(super) fn gelu_tanh(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1. + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// 精确的 GELU，`0.5 * x * (1 + erf(x / sqrt(2)))`。
+#[inline(always)]
+pub(super) fn gelu_erf(x: f32) -> f32 {
+    0.5 * x * (1. + erf(x * std::f32::consts::FRAC_1_SQRT_2))
+}
+
+/// Abramowitz-Stegun 7.1.26 近似，误差不超过 1.5e-7。
+#[inline(always)]
+fn erf(x: f32) -> f32 {
+    const P: f32 = 0.327_591_1;
+    const A: [f32; 5] = [
+        0.254_829_6,
+        -0.284_496_74,
+        1.421_413_8,
+        -1.453_152,
+        1.061_405_4,
+    ];
+    let t = (1. + P * x.abs()).recip();
+    let poly = t * (A[0] + t * (A[1] + t * (A[2] + t * (A[3] + t * A[4]))));
+    (1. - poly * (-x * x).exp()).copysign(x)
+}
+
+#[test]
+fn test_gelu() {
+    use tensor::reslice;
+
+    let x = [0f32, 1., -1., 3.];
+    let mut exact = Tensor::new(DataType::F32, &[1, 4], reslice(&x).to_vec());
+    let mut tanh = Tensor::new(DataType::F32, &[1, 4], reslice(&x).to_vec());
+    gelu(&mut exact, true);
+    gelu(&mut tanh, false);
+
+    let exact = reslice::<u8, f32>(exact.as_slice());
+    let tanh = reslice::<u8, f32>(tanh.as_slice());
+    assert_eq!(exact[0], 0.);
+    assert!((exact[1] - 0.841_344_7).abs() < 1e-6);
+    assert!((exact[2] + 0.158_655_3).abs() < 1e-6);
+    assert!((exact[3] - 2.995_950_5).abs() < 1e-6);
+    assert!((tanh[1] - 0.841_192).abs() < 1e-5);
+}
@@ -0,0 +1,113 @@
+use common::{f16, BetweenF32};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+use tensor::{DataType, Tensor};
+
+/// 带偏置的 LayerNorm，`(x - E[x]) / sqrt(Var[x] + ε) * w + b`。
+pub fn layer_norm<T, U, V, W>(
+    o: &mut Tensor<T>,
+    x: &Tensor<U>,
+    w: &Tensor<V>,
+    b: &Tensor<W>,
+    epsilon: f32,
+) where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+    V: Deref<Target = [u8]>,
+    W: Deref<Target = [u8]>,
+{
+    let &[n, d] = o.shape() else { panic!() };
+    let dt = o.data_type();
+
+    assert_eq!(x.data_type(), dt);
+    assert_eq!(w.data_type(), dt);
+    assert_eq!(b.data_type(), dt);
+    assert_eq!(o.shape(), x.shape());
+    assert_eq!(w.shape(), &[d]);
+    assert_eq!(b.shape(), &[d]);
+    assert!(o.contiguous_len() >= 1);
+    assert!(x.contiguous_len() >= 1);
+    assert!(w.is_contiguous());
+    assert!(b.is_contiguous());
+
+    let ptr_o = o.locate_start_mut();
+    let ptr_x = x.locate_start();
+    let ptr_w = w.locate_start();
+    let ptr_b = b.locate_start();
+
+    let stride_o = o.strides()[0] as usize;
+    let stride_x = x.strides()[0] as usize;
+    let n = n as usize;
+    let d = d as usize;
+
+    match dt {
+        DataType::F16 => layer_norm_op::<f16>(
+            ptr_o, stride_o, ptr_x, stride_x, ptr_w, ptr_b, n, d, epsilon,
+        ),
+        DataType::F32 => layer_norm_op::<f32>(
+            ptr_o, stride_o, ptr_x, stride_x, ptr_w, ptr_b, n, d, epsilon,
+        ),
+        _ => unreachable!("unsupported data type \"{dt:?}\""),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn layer_norm_op<T: BetweenF32>(
+    o: *mut u8,
+    stride_o: usize,
+    x: *const u8,
+    stride_x: usize,
+    w: *const u8,
+    b: *const u8,
+    n: usize,
+    d: usize,
+    epsilon: f32,
+) {
+    let o = o.cast::<T>();
+    let x = x.cast::<T>();
+    let w = unsafe { from_raw_parts(w.cast::<T>(), d) };
+    let b = unsafe { from_raw_parts(b.cast::<T>(), d) };
+    for i in 0..n {
+        let o = unsafe { from_raw_parts_mut(o.add(stride_o * i), d) };
+        let x = unsafe { from_raw_parts(x.add(stride_x * i), d) };
+
+        let mean = x.iter().map(T::get).sum::<f32>() / d as f32;
+        let var = x
+            .iter()
+            .map(|x| {
+                let x = x.get() - mean;
+                x * x
+            })
+            .sum::<f32>()
+            / d as f32;
+        let k = (var + epsilon).sqrt().recip();
+
+        zip(o, zip(x, zip(w, b)))
+            .for_each(|(o, (x, (w, b)))| *o = T::cast((x.get() - mean) * k * w.get() + b.get()));
+    }
+}
+
+#[test]
+fn test_layer_norm() {
+    use tensor::reslice;
+
+    let x = [1f32, 2., 3., 4.];
+    let x = Tensor::new(DataType::F32, &[1, 4], reslice(&x));
+    let w = [1f32, 1., 2., 2.];
+    let w = Tensor::new(DataType::F32, &[4], reslice(&w));
+    let b = [0f32, 1., 0., 1.];
+    let b = Tensor::new(DataType::F32, &[4], reslice(&b));
+    let mut o = Tensor::new(DataType::F32, &[1, 4], vec![0u8; 16]);
+    layer_norm(&mut o, &x, &w, &b, 0.);
+
+    // 均值 2.5，方差 1.25
+    let k = 1.25f32.sqrt().recip();
+    let ans = reslice::<u8, f32>(o.as_slice());
+    let expected = [-1.5 * k, -0.5 * k + 1., 1. * k, 3. * k + 1.];
+    for (a, e) in ans.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-6);
+    }
+}
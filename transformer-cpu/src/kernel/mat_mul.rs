@@ -1,9 +1,15 @@
-﻿use common::{f16, BetweenF32};
+﻿use common::{
+    f16, f8e4m3, f8e5m2,
+    quant::{Block, Q4_0, Q4_K, Q8_0},
+    BetweenF32,
+};
 use gemm::gemm;
+use rayon::prelude::*;
 use std::{
-    ffi::{c_longlong, c_void},
+    ffi::{c_int, c_longlong, c_void},
     mem::swap,
     ops::{Deref, DerefMut},
+    slice::from_raw_parts,
 };
 use tensor::{DataType, Tensor};
 use transformer::Matrix;
@@ -28,7 +34,30 @@ where
 
     let mut c = Matrix::new(c, base);
     let mut a = Matrix::new(a, base);
+    // 量化权重以块为元素，不能交给 gemm
+    let qt = b.data_type();
     let mut b = Matrix::new(b, base);
+    if qt.is_quantized() {
+        assert_eq!(a.c, b.r * qt.block_size() as c_int); // k
+        return match qt {
+            DataType::Q8_0 => mat_mul_quantized::<Q8_0>(dt, c, beta, alpha, a, b),
+            DataType::Q4_0 => mat_mul_quantized::<Q4_0>(dt, c, beta, alpha, a, b),
+            DataType::Q4_K => mat_mul_quantized::<Q4_K>(dt, c, beta, alpha, a, b),
+            _ => unreachable!(),
+        };
+    }
+    // FP8 权重每 32 个元素视为一块，计算时转换为 f32
+    if qt.is_fp8() {
+        assert_eq!(a.c, b.r); // k
+        assert!(b.r % 32 == 0 && b.cs % 32 == 0);
+        b.r /= 32;
+        b.cs /= 32;
+        return match qt {
+            DataType::F8_E4M3 => mat_mul_quantized::<Fp8<f8e4m3>>(dt, c, beta, alpha, a, b),
+            DataType::F8_E5M2 => mat_mul_quantized::<Fp8<f8e5m2>>(dt, c, beta, alpha, a, b),
+            _ => unreachable!(),
+        };
+    }
     assert_eq!(c.r, a.r); // m
     assert_eq!(c.c, b.c); // n
     assert_eq!(a.c, b.r); // k
@@ -131,6 +160,104 @@ fn gemm_as_blas<T: 'static + BetweenF32>(c: Matrix, beta: f32, alpha: f32, a: Ma
     }
 }
 
+/// 连续的 32 个 FP8 元素。
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+struct Fp8<T>([T; 32]);
+
+impl<T: BetweenF32 + Copy + Send + Sync + 'static> Block for Fp8<T> {
+    const N: usize = 32;
+
+    fn quantize(x: &[f32]) -> Self {
+        Self(std::array::from_fn(|i| T::cast(x[i])))
+    }
+
+    fn dequantize(&self, y: &mut [f32]) {
+        for (y, x) in y.iter_mut().zip(&self.0) {
+            *y = x.get();
+        }
+    }
+}
+
+/// c = beta * c + alpha * a x b，b 是按块量化的权重的转置。
+///
+/// - c: [m, n]
+/// - a: [m, k]
+/// - b: [k / B::N, n]，每列是权重的一行
+fn mat_mul_quantized<B: Block>(
+    dt: DataType,
+    c: Matrix,
+    beta: f32,
+    alpha: f32,
+    a: Matrix,
+    b: Matrix,
+) {
+    assert!(c.batch == 1 && a.batch == 1 && b.batch == 1);
+    assert!(c.cs == 1 && a.cs == 1 && b.rs == 1);
+    match dt {
+        DataType::F16 => mat_mul_quantized_::<B, f16>(c, beta, alpha, a, b),
+        DataType::F32 => mat_mul_quantized_::<B, f32>(c, beta, alpha, a, b),
+        _ => unreachable!(),
+    }
+}
+
+fn mat_mul_quantized_<B: Block, T: BetweenF32>(
+    c: Matrix,
+    beta: f32,
+    alpha: f32,
+    a: Matrix,
+    b: Matrix,
+) {
+    let m = c.r as usize;
+    let n = c.c as usize;
+    let k = a.c as usize;
+    let nb = b.r as usize;
+
+    // a 先统一转为 f32
+    let a = (0..m)
+        .flat_map(|i| {
+            let row = unsafe { from_raw_parts(a.base.cast::<T>().add(i * a.rs as usize), k) };
+            row.iter().map(BetweenF32::get)
+        })
+        .collect::<Vec<_>>();
+    let ld = b.cs as usize;
+    let b = unsafe { from_raw_parts(b.base.cast::<B>(), (n - 1) * ld + nb) };
+    // 按权重的行并行，单行输入直接点积，多行输入每块只反量化一次
+    let ab = (0..n)
+        .into_par_iter()
+        .flat_map_iter(|j| {
+            let w = &b[j * ld..][..nb];
+            let mut sum = vec![0f32; m];
+            if m == 1 {
+                sum[0] = w
+                    .iter()
+                    .zip(a.chunks_exact(B::N))
+                    .map(|(w, x)| w.dot(x))
+                    .sum();
+            } else {
+                let mut y = vec![0f32; B::N];
+                for (l, w) in w.iter().enumerate() {
+                    w.dequantize(&mut y);
+                    for (sum, x) in sum.iter_mut().zip(a.chunks_exact(k)) {
+                        let x = &x[l * B::N..][..B::N];
+                        *sum += y.iter().zip(x).map(|(y, x)| y * x).sum::<f32>();
+                    }
+                }
+            }
+            sum
+        })
+        .collect::<Vec<_>>();
+
+    let c_ = c.base.cast::<T>();
+    for i in 0..m {
+        for j in 0..n {
+            let y = unsafe { &mut *c_.add(i * c.rs as usize + j) };
+            let ab = alpha * ab[j * m + i];
+            *y = T::cast(if beta == 0. { ab } else { beta * y.get() + ab });
+        }
+    }
+}
+
 #[cfg(detected_mkl)]
 mod mkl {
     use gemm::f16;
@@ -265,3 +392,83 @@ mod mkl {
         }
     }
 }
+
+#[test]
+fn test_mat_mul_quantized() {
+    use tensor::reslice;
+
+    let (m, n, k) = (3, 4, 64);
+    let w = (0..n * k)
+        .map(|i| (i as f32 * 0.13).sin())
+        .collect::<Vec<_>>();
+    let x = (0..m * k)
+        .map(|i| (i as f32 * 0.29).cos())
+        .collect::<Vec<_>>();
+    let blocks = w
+        .chunks_exact(Q8_0::N)
+        .map(Q8_0::quantize)
+        .collect::<Vec<_>>();
+    let blocks = unsafe {
+        from_raw_parts(
+            blocks.as_ptr().cast::<u8>(),
+            std::mem::size_of_val(blocks.as_slice()),
+        )
+    };
+    let b = Tensor::new(DataType::Q8_0, &[n as _, (k / Q8_0::N) as _], blocks);
+    let b = b.transpose(&[1, 0]);
+
+    for m in [1, m] {
+        let a = Tensor::new(DataType::F32, &[m as _, k as _], reslice(&x[..m * k]));
+        let mut c = Tensor::new(DataType::F32, &[m as _, n as _], vec![0u8; m * n * 4]);
+        mat_mul(&mut c, 0., &a, &b, 2.);
+        let c = reslice::<u8, f32>(c.as_slice());
+        for i in 0..m {
+            for j in 0..n {
+                let x = &x[i * k..][..k];
+                let w = &w[j * k..][..k];
+                let ans = 2. * x.iter().zip(w).map(|(x, w)| x * w).sum::<f32>();
+                assert!(
+                    (c[i * n + j] - ans).abs() < 0.05,
+                    "{} vs {ans}",
+                    c[i * n + j]
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_mat_mul_fp8() {
+    use tensor::reslice;
+
+    let (m, n, k) = (2, 3, 64);
+    let w = (0..n * k)
+        .map(|i| f8e4m3::from_f32((i as f32 * 0.13).sin() * 100.))
+        .collect::<Vec<_>>();
+    let x = (0..m * k)
+        .map(|i| (i as f32 * 0.29).cos())
+        .collect::<Vec<_>>();
+    let b = Tensor::new(
+        DataType::F8_E4M3,
+        &[n as _, k as _],
+        w.iter().map(|w| w.to_bits()).collect::<Vec<_>>(),
+    );
+    let b = b.transpose(&[1, 0]);
+
+    let a = Tensor::new(DataType::F32, &[m as _, k as _], reslice(&x));
+    let mut c = Tensor::new(DataType::F32, &[m as _, n as _], vec![0u8; m * n * 4]);
+    mat_mul(&mut c, 0., &a, &b, 0.01);
+    let c = reslice::<u8, f32>(c.as_slice());
+    for i in 0..m {
+        for j in 0..n {
+            let x = &x[i * k..][..k];
+            let w = &w[j * k..][..k];
+            let ans = 0.01 * x.iter().zip(w).map(|(x, w)| x * w.to_f32()).sum::<f32>();
+            assert!(
+                (c[i * n + j] - ans).abs() < 1e-4,
+                "{} vs {ans}",
+                c[i * n + j]
+            );
+        }
+    }
+}
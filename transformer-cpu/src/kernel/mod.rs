@@ -1,8 +1,17 @@
-﻿mod fused_softmax;
+﻿mod add;
+mod add_bias;
+mod fused_softmax;
 mod gather;
+mod geglu;
+mod gelu;
+mod layer_norm;
 mod mat_mul;
+mod moe;
+mod quantize;
 mod rms_norm;
 mod rotary_embedding;
+mod scale;
+mod soft_cap;
 mod swiglu;
 
 macro_rules! slice {
@@ -11,16 +20,21 @@ macro_rules! slice {
     };
 }
 
+pub(super) use quantize::SCALE_SIZE;
 pub(super) use slice;
 
 use common::utok;
 use std::ops::{Deref, DerefMut};
-use tensor::Tensor;
-use transformer::{Kernels, Llama2};
+use tensor::{udim, Tensor};
+use transformer::{Activation, Kernels, Llama2, RopeTable};
 
 pub struct CpuKernels {
     epsilon: f32,
-    theta: f32,
+    norm_offset: f32,
+    hidden_act: Activation,
+    rope: RopeTable,
+    sliding_window: Option<udim>,
+    alibi_slopes: Option<Vec<f32>>,
 }
 
 impl CpuKernels {
@@ -28,9 +42,158 @@ impl CpuKernels {
     pub fn new(model: &dyn Llama2) -> Self {
         Self {
             epsilon: model.rms_norm_eps(),
-            theta: model.rope_theta(),
+            norm_offset: model.rms_norm_offset(),
+            hidden_act: model.hidden_act(),
+            rope: RopeTable::new(model),
+            sliding_window: model.sliding_window().map(|w| w as _),
+            alibi_slopes: model.alibi_slopes(),
         }
     }
+
+    /// 按给定的滑动窗口计算因果 softmax，用于各层窗口不同的模型。
+    #[inline]
+    pub fn softmax_window<T>(&self, att: &mut Tensor<T>, window: Option<usize>)
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        fused_softmax::softmax(att, window.map(|w| w as _), self.alibi_slopes.as_deref());
+    }
+
+    /// 将 F16 的 K-V 量化写入 int8 缓存。
+    #[inline]
+    pub fn quantize<T, U>(&self, dst: &mut Tensor<T>, src: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        quantize::quantize(dst, src);
+    }
+
+    /// 将 int8 缓存反量化为 F16 的 K-V。
+    #[inline]
+    pub fn dequantize<T, U>(&self, dst: &mut Tensor<T>, src: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        quantize::dequantize(dst, src);
+    }
+
+    /// 按模型的激活函数计算门控前馈网络的激活，结果写回 `gate`。
+    #[inline]
+    pub fn gated_act<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        match self.hidden_act {
+            Activation::Silu => swiglu::swiglu(gate, up),
+            Activation::GeluTanh => geglu::geglu(gate, up, false),
+            Activation::Gelu => geglu::geglu(gate, up, true),
+        }
+    }
+
+    /// 带偏置的 LayerNorm。
+    #[inline]
+    pub fn layer_norm<T, U, V, W>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        b: &Tensor<W>,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        V: Deref<Target = [u8]>,
+        W: Deref<Target = [u8]>,
+    {
+        layer_norm::layer_norm(y, x, w, b, self.epsilon);
+    }
+
+    /// 按模型的激活函数原地计算 GELU。
+    #[inline]
+    pub fn gelu<T>(&self, x: &mut Tensor<T>)
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        gelu::gelu(x, self.hidden_act != Activation::GeluTanh);
+    }
+
+    /// 有偏置时做 LayerNorm，否则做 RMSNorm。
+    #[inline]
+    pub fn norm<T, U, V>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &Tensor<U>,
+        w: &Tensor<V>,
+        b: Option<&Tensor<V>>,
+    ) where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+        V: Deref<Target = [u8]>,
+    {
+        match b {
+            Some(b) => self.layer_norm(y, x, w, b),
+            None => self.rms_norm(y, x, w),
+        }
+    }
+
+    /// 将 `x` 原地软截断到 `(-cap, cap)`。
+    #[inline]
+    pub fn soft_cap<T>(&self, x: &mut Tensor<T>, cap: f32)
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        soft_cap::soft_cap(x, cap);
+    }
+
+    /// 将 `x` 原地乘以 `k`。
+    #[inline]
+    pub fn scale<T>(&self, x: &mut Tensor<T>, k: f32)
+    where
+        T: DerefMut<Target = [u8]>,
+    {
+        scale::scale(x, k);
+    }
+
+    /// 将 `y` 累加到 `x` 上。
+    #[inline]
+    pub fn add<T, U>(&self, x: &mut Tensor<T>, y: &Tensor<U>)
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        add::add(x, y);
+    }
+
+    /// 为每个 token 选择 `k` 个专家，按专家分组返回 token 序号和权重。
+    #[inline]
+    pub fn route<T>(&self, logits: &Tensor<T>, k: usize) -> Vec<Vec<(udim, f32)>>
+    where
+        T: Deref<Target = [u8]>,
+    {
+        moe::route(logits, k)
+    }
+
+    /// 将 `x` 中选中的行依次复制到 `y`。
+    #[inline]
+    pub fn gather_rows<T, U>(&self, y: &mut Tensor<T>, x: &Tensor<U>, rows: &[(udim, f32)])
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        moe::gather_rows(y, x, rows.iter().map(|&(row, _)| row));
+    }
+
+    /// 将 `y` 的各行按权重累加到 `x` 中对应的行。
+    #[inline]
+    pub fn scatter_add<T, U>(&self, x: &mut Tensor<T>, y: &Tensor<U>, rows: &[(udim, f32)])
+    where
+        T: DerefMut<Target = [u8]>,
+        U: Deref<Target = [u8]>,
+    {
+        moe::scatter_add(x, y, rows);
+    }
 }
 
 impl Kernels for CpuKernels {
@@ -53,7 +216,7 @@ impl Kernels for CpuKernels {
         U: Deref<Target = Self::Storage>,
         V: Deref<Target = Self::Storage>,
     {
-        rms_norm::rms_norm(y, x, w, self.epsilon);
+        rms_norm::rms_norm(y, x, w, self.epsilon, self.norm_offset);
     }
 
     #[inline]
@@ -72,13 +235,22 @@ impl Kernels for CpuKernels {
         mat_mul::mat_mul(c, beta, a, b, alpha);
     }
 
+    #[inline]
+    fn add_bias<T, U>(&self, x: &mut Tensor<T>, bias: &Tensor<U>)
+    where
+        T: DerefMut<Target = Self::Storage>,
+        U: Deref<Target = Self::Storage>,
+    {
+        add_bias::add_bias(x, bias);
+    }
+
     #[inline]
     fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>)
     where
         T: DerefMut<Target = Self::Storage>,
         U: Deref<Target = Self::Storage>,
     {
-        rotary_embedding::rotary_embedding(t, pos, self.theta);
+        rotary_embedding::rotary_embedding(t, pos, &self.rope);
     }
 
     #[inline]
@@ -95,7 +267,7 @@ impl Kernels for CpuKernels {
     where
         T: DerefMut<Target = Self::Storage>,
     {
-        fused_softmax::softmax(att);
+        self.softmax_window(att, self.sliding_window.map(|w| w as _));
     }
 
     #[inline]
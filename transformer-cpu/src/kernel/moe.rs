@@ -0,0 +1,128 @@
+use common::{f16, BetweenF32};
+use std::ops::{Deref, DerefMut};
+use tensor::{idim, udim, DVector, DataType, Tensor};
+
+/// 为每个 token 选择路由分数最高的 `k` 个专家，按专家分组返回 token 序号和归一化的权重。
+pub fn route<T>(logits: &Tensor<T>, k: usize) -> Vec<Vec<(udim, f32)>>
+where
+    T: Deref<Target = [u8]>,
+{
+    let &[nt, ne] = logits.shape() else {
+        panic!("logits shape: {:?}", logits.shape());
+    };
+    assert!(logits.is_contiguous());
+    assert!(0 < k && k <= ne as usize);
+
+    let scores = match logits.data_type() {
+        DataType::F16 => typed::<f16>(logits.as_slice()),
+        DataType::F32 => typed::<f32>(logits.as_slice()),
+        _ => unreachable!(),
+    };
+
+    let mut experts = vec![vec![]; ne as usize];
+    let mut selected = Vec::with_capacity(ne as usize);
+    for (token, row) in scores.chunks_exact(ne as usize).enumerate() {
+        selected.clear();
+        selected.extend(row.iter().copied().enumerate());
+        selected.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+        selected.truncate(k);
+        // 在选中的专家上做 softmax，等价于全体 softmax 后对前 k 个重新归一化
+        let max = selected[0].1;
+        selected.iter_mut().for_each(|(_, s)| *s = (*s - max).exp());
+        let sum = selected.iter().map(|(_, s)| s).sum::<f32>();
+        for &(expert, s) in &selected {
+            experts[expert].push((token as udim, s / sum));
+        }
+    }
+    assert_eq!(experts.iter().map(Vec::len).sum::<usize>(), nt as usize * k);
+    experts
+}
+
+/// 将 `x` 中选中的行依次复制到 `y`。
+pub fn gather_rows<T, U>(y: &mut Tensor<T>, x: &Tensor<U>, rows: impl IntoIterator<Item = udim>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    assert_eq!(y.data_type(), x.data_type());
+    let &[_, d] = x.shape() else {
+        panic!("x shape: {:?}", x.shape());
+    };
+    assert_eq!(y.shape()[1], d);
+    assert!(x.contiguous_len() >= 1);
+    assert!(y.contiguous_len() >= 1);
+
+    let len = d as usize * x.data_type().size();
+    for (i, row) in rows.into_iter().enumerate() {
+        let src = x
+            .locate(&DVector::from_vec(vec![row as idim, 0, 1]).as_view())
+            .unwrap();
+        let dst = y
+            .locate_mut(&DVector::from_vec(vec![i as idim, 0, 1]).as_view())
+            .unwrap();
+        unsafe { std::ptr::copy_nonoverlapping(src, dst, len) };
+    }
+}
+
+/// 将 `y` 的各行按权重累加到 `x` 中对应的行。
+pub fn scatter_add<T, U>(x: &mut Tensor<T>, y: &Tensor<U>, rows: &[(udim, f32)])
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let dt = x.data_type();
+    assert_eq!(y.data_type(), dt);
+    let &[_, d] = x.shape() else {
+        panic!("x shape: {:?}", x.shape());
+    };
+    assert_eq!(y.shape()[1], d);
+    assert!(x.contiguous_len() >= 1);
+    assert!(y.contiguous_len() >= 1);
+
+    for (i, &(row, weight)) in rows.iter().enumerate() {
+        let dst = x
+            .locate_mut(&DVector::from_vec(vec![row as idim, 0, 1]).as_view())
+            .unwrap();
+        let src = y
+            .locate(&DVector::from_vec(vec![i as idim, 0, 1]).as_view())
+            .unwrap();
+        match dt {
+            DataType::F16 => axpy::<f16>(dst, src, weight, d as _),
+            DataType::F32 => axpy::<f32>(dst, src, weight, d as _),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn typed<T: BetweenF32>(logits: &[u8]) -> Vec<f32> {
+    let (&[], logits, &[]) = (unsafe { logits.align_to::<T>() }) else {
+        panic!("unaligned logits");
+    };
+    logits.iter().map(BetweenF32::get).collect()
+}
+
+fn axpy<T: BetweenF32>(x: *mut u8, y: *const u8, a: f32, n: usize) {
+    let x = unsafe { std::slice::from_raw_parts_mut(x.cast::<T>(), n) };
+    let y = unsafe { std::slice::from_raw_parts(y.cast::<T>(), n) };
+    for (x, y) in x.iter_mut().zip(y) {
+        *x = T::cast(x.get() + a * y.get());
+    }
+}
+
+#[test]
+fn test_route() {
+    use tensor::reslice;
+
+    let logits = [1f32, 3., 2., 0., 0., 0., 5., 5.];
+    let logits = Tensor::new(DataType::F32, &[2, 4], reslice(&logits));
+    let experts = route(&logits, 2);
+
+    let w = 1. / (1. + (-1f32).exp());
+    assert!(experts[0].is_empty());
+    assert_eq!(experts[1], [(0, w)]);
+    assert_eq!(experts[2].len(), 2);
+    assert_eq!(experts[2][0].0, 0);
+    assert!((experts[2][0].1 - (1. - w)).abs() < 1e-6);
+    assert_eq!(experts[2][1], (1, 0.5));
+    assert_eq!(experts[3], [(1, 0.5)]);
+}
@@ -0,0 +1,102 @@
+use common::f16;
+use std::{
+    ops::{Deref, DerefMut},
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+use tensor::{expand_indices, idx_strides, DataType, Tensor};
+
+/// 每行量化数据后附加的缩放系数的字节数。
+pub const SCALE_SIZE: usize = 2;
+
+/// - dst: [num_head, seq_len, head_dim + 2]，I8
+/// - src: [num_head, seq_len, head_dim]，F16
+///
+/// 每行按绝对值最大值对称量化为 int8，行尾以 f16 存放缩放系数。
+pub fn quantize<T, U>(dst: &mut Tensor<T>, src: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let &[nh, seq_len, dh] = src.shape() else {
+        panic!()
+    };
+    assert_eq!(src.data_type(), DataType::F16);
+    assert_eq!(dst.data_type(), DataType::I8);
+    assert_eq!(dst.shape(), &[nh, seq_len, dh + SCALE_SIZE as u32]);
+    assert!(src.contiguous_len() >= 1);
+    assert!(dst.contiguous_len() >= 1);
+
+    let dh = dh as usize;
+    let (n, idx_strides) = idx_strides(&[nh, seq_len]);
+    for i in 0..n {
+        let idx = expand_indices(i, &idx_strides, &[0, 1]);
+        let x = src.locate(&idx.as_view()).unwrap().cast::<f16>();
+        let x = unsafe { from_raw_parts(x, dh) };
+        let y = dst.locate_mut(&idx.as_view()).unwrap();
+        let y = unsafe { from_raw_parts_mut(y, dh + SCALE_SIZE) };
+
+        let amax = x.iter().map(|x| x.to_f32().abs()).fold(0., f32::max);
+        let scale = f16::from_f32(amax / i8::MAX as f32);
+        let recip = if amax > 0. {
+            scale.to_f32().recip()
+        } else {
+            0.
+        };
+        for (y, x) in y.iter_mut().zip(x) {
+            *y = (x.to_f32() * recip).round() as i8 as u8;
+        }
+        y[dh..].copy_from_slice(&scale.to_bits().to_le_bytes());
+    }
+}
+
+/// - dst: [num_head, seq_len, head_dim]，F16
+/// - src: [num_head, seq_len, head_dim + 2]，I8
+pub fn dequantize<T, U>(dst: &mut Tensor<T>, src: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let &[nh, seq_len, dh] = dst.shape() else {
+        panic!()
+    };
+    assert_eq!(dst.data_type(), DataType::F16);
+    assert_eq!(src.data_type(), DataType::I8);
+    assert_eq!(src.shape(), &[nh, seq_len, dh + SCALE_SIZE as u32]);
+    assert!(dst.contiguous_len() >= 1);
+    assert!(src.contiguous_len() >= 1);
+
+    let dh = dh as usize;
+    let (n, idx_strides) = idx_strides(&[nh, seq_len]);
+    for i in 0..n {
+        let idx = expand_indices(i, &idx_strides, &[0, 1]);
+        let x = src.locate(&idx.as_view()).unwrap();
+        let x = unsafe { from_raw_parts(x, dh + SCALE_SIZE) };
+        let y = dst.locate_mut(&idx.as_view()).unwrap().cast::<f16>();
+        let y = unsafe { from_raw_parts_mut(y, dh) };
+
+        let scale = f16::from_bits(u16::from_le_bytes([x[dh], x[dh + 1]])).to_f32();
+        for (y, &x) in y.iter_mut().zip(x) {
+            *y = f16::from_f32(x as i8 as f32 * scale);
+        }
+    }
+}
+
+#[test]
+fn test_quantize() {
+    let data = [0.5f32, -1., 0.25, 0.0, 0., 0., 0., 0.]
+        .map(f16::from_f32)
+        .iter()
+        .flat_map(|x| x.to_bits().to_le_bytes())
+        .collect::<Vec<_>>();
+    let src = Tensor::new(DataType::F16, &[1, 2, 4], &data[..]);
+    let mut q = Tensor::new(DataType::I8, &[1, 2, 6], vec![0u8; 12]);
+    quantize(&mut q, &src);
+    let mut y = Tensor::new(DataType::F16, &[1, 2, 4], vec![0u8; 16]);
+    dequantize(&mut y, &q);
+
+    for (a, b) in data.chunks_exact(2).zip(y.as_slice().chunks_exact(2)) {
+        let a = f16::from_bits(u16::from_le_bytes([a[0], a[1]])).to_f32();
+        let b = f16::from_bits(u16::from_le_bytes([b[0], b[1]])).to_f32();
+        assert!((a - b).abs() < 1e-2);
+    }
+}
@@ -6,7 +6,8 @@ use std::{
 };
 use tensor::{DataType, Tensor};
 
-pub fn rms_norm<T, U, V>(o: &mut Tensor<T>, x: &Tensor<U>, w: &Tensor<V>, epsilon: f32)
+/// `offset` 加在权重上，Gemma 以 `1 + w` 作为权重。
+pub fn rms_norm<T, U, V>(o: &mut Tensor<T>, x: &Tensor<U>, w: &Tensor<V>, epsilon: f32, offset: f32)
 where
     T: DerefMut<Target = [u8]>,
     U: Deref<Target = [u8]>,
@@ -33,8 +34,12 @@ where
     let d = d as usize;
 
     match dt {
-        DataType::F16 => rms_norm_op::<f16>(ptr_o, stride_o, ptr_x, stride_x, ptr_w, n, d, epsilon),
-        DataType::F32 => rms_norm_op::<f32>(ptr_o, stride_o, ptr_x, stride_x, ptr_w, n, d, epsilon),
+        DataType::F16 => rms_norm_op::<f16>(
+            ptr_o, stride_o, ptr_x, stride_x, ptr_w, n, d, epsilon, offset,
+        ),
+        DataType::F32 => rms_norm_op::<f32>(
+            ptr_o, stride_o, ptr_x, stride_x, ptr_w, n, d, epsilon, offset,
+        ),
         _ => unreachable!("unsupported data type \"{dt:?}\""),
     }
 }
@@ -49,6 +54,7 @@ fn rms_norm_op<T: Mul<Output = T> + BetweenF32 + Copy>(
     n: usize,
     d: usize,
     epsilon: f32,
+    offset: f32,
 ) {
     let o = o.cast::<T>();
     let x = x.cast::<T>();
@@ -64,8 +70,14 @@ fn rms_norm_op<T: Mul<Output = T> + BetweenF32 + Copy>(
             len += 1;
             sum += x * x;
         }
-        let k = T::cast((sum / (len as f32) + epsilon).sqrt().recip());
+        let k = (sum / (len as f32) + epsilon).sqrt().recip();
 
-        zip(o, zip(x, w)).for_each(|(o, (x, w))| *o = *w * (k * *x));
+        if offset == 0. {
+            let k = T::cast(k);
+            zip(o, zip(x, w)).for_each(|(o, (x, w))| *o = *w * (k * *x));
+        } else {
+            zip(o, zip(x, w))
+                .for_each(|(o, (x, w))| *o = T::cast((w.get() + offset) * k * x.get()));
+        }
     }
 }
@@ -0,0 +1,23 @@
+use common::{f16, BetweenF32};
+use std::ops::DerefMut;
+use tensor::{DataType, Tensor};
+
+/// 将 `x` 的每个元素原地乘以 `k`。
+pub fn scale<T>(x: &mut Tensor<T>, k: f32)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    assert!(x.is_contiguous());
+    match x.data_type() {
+        DataType::F16 => typed::<f16>(x.as_mut_slice(), k),
+        DataType::F32 => typed::<f32>(x.as_mut_slice(), k),
+        _ => unreachable!(),
+    }
+}
+
+fn typed<T: BetweenF32>(x: &mut [u8], k: f32) {
+    let (&mut [], x, &mut []) = (unsafe { x.align_to_mut::<T>() }) else {
+        panic!("unaligned tensor");
+    };
+    x.iter_mut().for_each(|x| *x = T::cast(x.get() * k));
+}
@@ -0,0 +1,39 @@
+use common::{f16, BetweenF32};
+use std::ops::DerefMut;
+use tensor::{DataType, Tensor};
+
+/// 将 `x` 的每个元素原地替换为 `cap * tanh(x / cap)`。
+pub fn soft_cap<T>(x: &mut Tensor<T>, cap: f32)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    assert!(x.is_contiguous());
+    match x.data_type() {
+        DataType::F16 => typed::<f16>(x.as_mut_slice(), cap),
+        DataType::F32 => typed::<f32>(x.as_mut_slice(), cap),
+        _ => unreachable!(),
+    }
+}
+
+fn typed<T: BetweenF32>(x: &mut [u8], cap: f32) {
+    let (&mut [], x, &mut []) = (unsafe { x.align_to_mut::<T>() }) else {
+        panic!("unaligned tensor");
+    };
+    x.iter_mut()
+        .for_each(|x| *x = T::cast(cap * (x.get() / cap).tanh()));
+}
+
+#[test]
+fn test_soft_cap() {
+    use tensor::reslice;
+
+    let data = [0f32, 1., 100., -100.];
+    let mut x = Tensor::new(DataType::F32, &[2, 2], reslice(&data).to_vec());
+    soft_cap(&mut x, 30.);
+
+    let ans = reslice::<u8, f32>(x.as_slice());
+    assert_eq!(ans[0], 0.);
+    assert!((ans[1] - 30. * (1f32 / 30.).tanh()).abs() < 1e-6);
+    assert!(ans[2] < 30. && ans[2] > 29.);
+    assert_eq!(ans[3], -ans[2]);
+}
@@ -1,28 +1,86 @@
 mod kernel;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{
+    check_decoding, check_queries, check_sample, check_shape, check_tokens, BlockPool, CausalLM,
+    DecodingMeta, InferError, KVCache, Model, Pooling, QueryContext, ReserveError, SampleMeta,
+};
 use common::{safe_tensors::SafeTensorsError, upos, utok, Blob};
 use gemm::f16;
 use itertools::izip;
-use kernel::CpuKernels;
-use std::{iter::repeat, path::Path, slice::from_raw_parts};
-use tensor::{reslice, slice, split, udim, DataType, LocalSplitable, Tensor};
+use kernel::{CpuKernels, SCALE_SIZE};
+use std::{
+    iter::{repeat, zip},
+    mem::size_of,
+    ops::Range,
+    path::Path,
+    slice::from_raw_parts,
+    sync::Arc,
+};
+use tensor::{reslice, reslice_mut, slice, split, udim, DataType, LocalSplitable, Tensor};
 use transformer::{Kernels, Llama2, Memory};
 
-pub struct Transformer(Memory);
+pub struct Transformer {
+    host: Memory,
+    pool: Arc<BlockPool<Blob>>,
+}
+
+/// K-V 缓存每页的长度。
+const BLOCK_SIZE: udim = 64;
+
+/// 加载模型的选项。
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Meta {
+    /// K-V 缓存的存储类型。
+    pub kv_cache: KVCacheType,
+    /// K-V 缓存页池最多分配的页数，`None` 表示不限制。
+    pub cache_blocks: Option<usize>,
+}
+
+/// K-V 缓存的存储类型。
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum KVCacheType {
+    /// 与模型相同的 F16。
+    #[default]
+    F16,
+    /// 逐行量化的 int8，每行附带一个 f16 缩放系数。
+    I8,
+}
 
 impl Model for Transformer {
-    type Meta = ();
+    type Meta = Meta;
     type Error = SafeTensorsError;
 
     #[inline]
-    fn load(model_dir: impl AsRef<Path>, _meta: Self::Meta) -> Result<Self, Self::Error> {
-        let memory = Memory::load_safetensors(model_dir)?;
-        if memory.data_type() == DataType::F16 {
-            Ok(Self(memory))
+    fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
+        let model_dir = model_dir.as_ref();
+        let memory = if model_dir.extension().is_some_and(|ext| ext == "gguf") {
+            Memory::load_gguf(model_dir).map_err(SafeTensorsError::Gguf)?
         } else {
-            Ok(Self(Memory::cast(&memory, DataType::F16)))
-        }
+            Memory::load_safetensors(model_dir)?
+        };
+        let host = if memory.data_type() == DataType::F16 {
+            memory
+        } else {
+            Memory::cast(&memory, DataType::F16)
+        };
+
+        let nlayers = host.num_hidden_layers() as udim;
+        let nkvh = host.num_key_value_heads() as udim;
+        let dh = host.head_dim() as udim;
+        let (dt, row) = match meta.kv_cache {
+            KVCacheType::F16 => (host.data_type(), dh),
+            KVCacheType::I8 => (DataType::I8, dh + SCALE_SIZE as udim),
+        };
+        let pool = BlockPool::new(dt, [nlayers, 2, nkvh, BLOCK_SIZE, row], Blob::new);
+        let pool = match meta.cache_blocks {
+            Some(capacity) => pool.with_capacity(capacity),
+            None => pool,
+        };
+
+        Ok(Self {
+            host,
+            pool: Arc::new(pool),
+        })
     }
 }
 
@@ -30,66 +88,233 @@ impl CausalLM for Transformer {
     type Storage = Blob;
 
     #[inline]
-    fn eos_token(&self) -> utok {
-        self.0.eos_token_id()
+    fn eos_tokens(&self) -> &[utok] {
+        self.host.eos_token_ids()
     }
 
-    fn new_cache(&self) -> Tensor<Self::Storage> {
-        let dt = self.0.data_type();
-        let nlayers = self.0.num_hidden_layers() as udim;
-        let nkvh = self.0.num_key_value_heads() as udim;
-        let max_seq_len = self.0.max_position_embeddings() as udim;
-        let d = self.0.hidden_size() as udim;
-        let nh = self.0.num_attention_heads() as udim;
+    #[inline]
+    fn max_seq_len(&self) -> upos {
+        self.host.max_position_embeddings() as _
+    }
 
-        Tensor::alloc(dt, &[nlayers, 2, nkvh, max_seq_len, d / nh], Blob::new)
+    #[inline]
+    fn new_cache(&self) -> KVCache<Self::Storage> {
+        let cache = KVCache::paged(self.pool.clone(), self.max_seq_len());
+        match self.host.cache_window() {
+            Some(window) => cache.with_window(window as _),
+            None => cache,
+        }
     }
 
-    fn duplicate_cache(&self, cache: &Tensor<Self::Storage>, pos: upos) -> Tensor<Self::Storage> {
-        let &[_nlayers, 2, _nkvh, max_seq_len, _dh] = cache.shape() else {
-            panic!()
+    fn duplicate_cache(
+        &self,
+        cache: &KVCache<Self::Storage>,
+        pos: upos,
+    ) -> Option<KVCache<Self::Storage>> {
+        // 滚动缓存已归还开头的页，无法复制
+        if cache.start() != 0 {
+            return None;
+        }
+        let mut ans = match cache.pool() {
+            Some(_) => self.new_cache(),
+            None => {
+                let src = cache.as_contiguous();
+                KVCache::contiguous(Tensor::alloc(src.data_type(), src.shape(), Blob::new))
+            }
         };
-        assert!(pos <= max_seq_len);
-        let slice = [
-            slice![=>],
-            slice![=>],
-            slice![=>],
-            slice![=>pos],
-            slice![=>],
-        ];
+        ans.reserve(pos).ok()?;
+
+        let block_size = cache.block_size();
+        for (i, (src, dst)) in zip(cache.blocks(), ans.blocks_mut()).enumerate() {
+            let len = (pos - i as udim * block_size).min(block_size);
+            let slice = [
+                slice![=>],
+                slice![=>],
+                slice![=>],
+                slice![=>len],
+                slice![=>],
+            ];
+            src.as_ref()
+                .slice(&slice)
+                .map_physical(|u| &**u)
+                .reform_to(&mut dst.as_mut().slice(&slice).map_physical(|u| &mut **u));
+        }
+        Some(ans)
+    }
 
-        let mut ans = Tensor::alloc(cache.data_type(), cache.shape(), Blob::new);
-        cache
-            .as_ref()
-            .slice(&slice)
-            .map_physical(|u| &**u)
-            .reform_to(&mut ans.as_mut().slice(&slice).map_physical(|u| &mut **u));
-        ans
+    fn export_cache(&self, cache: &KVCache<Self::Storage>, pos: upos) -> Option<Tensor<Vec<u8>>> {
+        if pos > cache.capacity() || cache.start() != 0 {
+            return None;
+        }
+        let block_size = cache.block_size();
+        let (dt, mut shape) = match cache.blocks().first() {
+            Some(block) => (block.data_type(), block.shape().to_vec()),
+            None => (self.pool.data_type(), self.pool.shape().to_vec()),
+        };
+        shape[3] = pos;
+        let mut ans = Tensor::alloc(dt, &shape, |len| vec![0; len]);
+
+        for (i, src) in cache.blocks().iter().enumerate() {
+            let start = i as udim * block_size;
+            if start >= pos {
+                break;
+            }
+            let len = (pos - start).min(block_size);
+            let slice = |start: udim| {
+                [
+                    slice![=>],
+                    slice![=>],
+                    slice![=>],
+                    slice![start =>=> len],
+                    slice![=>],
+                ]
+            };
+            src.as_ref()
+                .slice(&slice(0))
+                .map_physical(|u| &**u)
+                .reform_to(&mut ans.as_mut().slice(&slice(start)).map_physical(|u| &mut **u));
+        }
+        Some(ans)
     }
 
-    fn token_embed(&self, queries: impl IntoIterator<Item = utok>) -> Tensor<Self::Storage> {
-        let dt = self.0.data_type();
-        let d = self.0.hidden_size() as udim;
-        let kernels = CpuKernels::new(&self.0);
+    fn import_cache(&self, tensor: &Tensor<&[u8]>) -> Option<KVCache<Self::Storage>> {
+        let &[nlayers, 2, nkvh, pos, row] = tensor.shape() else {
+            return None;
+        };
+        let shape = self.pool.shape();
+        if tensor.data_type() != self.pool.data_type()
+            || [nlayers, nkvh, row] != [shape[0], shape[2], shape[4]]
+        {
+            return None;
+        }
+
+        let mut ans = self.new_cache();
+        ans.reserve(pos).ok()?;
+        let block_size = ans.block_size();
+        for (i, dst) in ans.blocks_mut().iter_mut().enumerate() {
+            let start = i as udim * block_size;
+            let len = (pos - start).min(block_size);
+            let slice = |start: udim| {
+                [
+                    slice![=>],
+                    slice![=>],
+                    slice![=>],
+                    slice![start =>=> len],
+                    slice![=>],
+                ]
+            };
+            tensor
+                .as_ref()
+                .slice(&slice(start))
+                .map_physical(|u| &**u)
+                .reform_to(&mut dst.as_mut().slice(&slice(0)).map_physical(|u| &mut **u));
+        }
+        Some(ans)
+    }
+
+    fn retain_cache(&self, cache: &mut KVCache<Self::Storage>, retain: &[Range<upos>]) -> upos {
+        let dt = self.host.data_type();
+        let nkvh = self.host.num_key_value_heads() as udim;
+        let dh = self.host.head_dim() as udim;
+        let rot = self.host.rotary_dim() as udim;
+        let block_size = cache.block_size();
+        let kernels = CpuKernels::new(&self.host);
+
+        let mut buf = Blob::new((block_size * nkvh * dh) as usize * dt.size());
+        let mut pos = Blob::new(block_size as usize * size_of::<i32>());
+
+        let mut dst = 0;
+        for range in retain {
+            let shift = range.start - dst;
+            if shift == 0 {
+                dst = range.end;
+                continue;
+            }
+            // 前移的键旋转 -shift 个位置
+            reslice_mut::<u8, i32>(&mut pos).fill(-(shift as i32));
+
+            // 逐段前移，每段不跨越源页和目标页的边界，目标总在源之前，因此可以原地移动
+            let mut src = range.start;
+            while src < range.end {
+                let n = (block_size - src % block_size)
+                    .min(block_size - dst % block_size)
+                    .min(range.end - src);
+                let mut tmp = Tensor::new(dt, &[n, nkvh, dh], &mut buf[..]);
+                let pos = Tensor::new(DataType::I32, &[n], &pos[..n as usize * size_of::<i32>()]);
+                for layer in 0..self.host.num_hidden_layers() {
+                    for i in 0..2 {
+                        let block = &cache.blocks()[(src / block_size) as usize];
+                        let kv = kv_slice(block.as_ref(), layer, i, src % block_size, n)
+                            .map_physical(|u| &**u);
+                        let mut tmp_t = tmp
+                            .as_mut()
+                            .transpose(&[1, 0, 2])
+                            .map_physical(|u| &mut **u);
+                        if kv.data_type() == DataType::I8 {
+                            kernels.dequantize(&mut tmp_t, &kv);
+                        } else {
+                            kernels.reform(&mut tmp_t, &kv);
+                        }
+                        if i == 0 && rot > 0 {
+                            let mut tmp = tmp
+                                .as_mut()
+                                .slice(&[slice![=>], slice![=>], slice![=>rot]])
+                                .map_physical(|u| &mut **u);
+                            kernels.rotary_embedding(&mut tmp, &pos);
+                        }
+
+                        let block = &mut cache.blocks_mut()[(dst / block_size) as usize];
+                        let mut kv = kv_slice(block.as_mut(), layer, i, dst % block_size, n)
+                            .map_physical(|u| &mut **u);
+                        let tmp_t = tmp.as_ref().transpose(&[1, 0, 2]).map_physical(|u| &**u);
+                        if kv.data_type() == DataType::I8 {
+                            kernels.quantize(&mut kv, &tmp_t);
+                        } else {
+                            kernels.reform(&mut kv, &tmp_t);
+                        }
+                    }
+                }
+                src += n;
+                dst += n;
+            }
+        }
+        cache.retain(retain);
+        dst
+    }
+
+    fn token_embed(
+        &self,
+        queries: impl IntoIterator<Item = utok>,
+    ) -> Result<Tensor<Self::Storage>, InferError> {
+        let dt = self.host.data_type();
+        let d = self.host.hidden_size() as udim;
+        let kernels = CpuKernels::new(&self.host);
 
         let tokens = queries.into_iter().collect::<Vec<_>>();
+        check_tokens(&tokens, self.host.vocab_size())?;
         let nt = tokens.len() as udim;
 
         let mut x = Tensor::alloc(dt, &[nt, d], Blob::new);
-        kernels.gather(&mut x, &self.0.embed_tokens(), tokens);
-        x
+        kernels.gather(&mut x, &self.host.embed_tokens(), tokens);
+        let embed_scale = self.host.embed_scale();
+        if embed_scale != 1. {
+            kernels.scale(&mut x, embed_scale);
+        }
+        Ok(x)
     }
 
     fn forward<'a>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'a, Self::Storage>>,
         token_embedded: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage>
+    ) -> Result<Tensor<Self::Storage>, InferError>
     where
         Self: 'a,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
-        let mut nt = 0;
+        let nt = check_queries(&queries, self.max_seq_len())?;
+        check_shape(&[nt, self.host.hidden_size() as _], token_embedded.shape())?;
+
         let mut max_seq_len = 0;
         let mut max_att_len = 0;
         let seq_len = queries
@@ -97,129 +322,420 @@ impl CausalLM for Transformer {
             .map(|q| {
                 let seq = q.seq_len();
                 let att = q.att_len();
-                nt += seq;
                 max_seq_len = max_seq_len.max(seq);
                 max_att_len = max_att_len.max(att);
                 seq
             })
             .collect::<Vec<_>>();
 
-        let dt = self.0.data_type();
-        let d = self.0.hidden_size() as udim;
-        let nh = self.0.num_attention_heads() as udim;
-        let nkvh = self.0.num_key_value_heads() as udim;
-        let dh = d / nh;
+        let dt = self.host.data_type();
+        let d = self.host.hidden_size() as udim;
+        let nh = self.host.num_attention_heads() as udim;
+        let nkvh = self.host.num_key_value_heads() as udim;
+        let dh = self.host.head_dim() as udim;
+        let dq = nh * dh;
         let dkv = nkvh * dh;
-        let di = self.0.intermediate_size() as udim;
+        let di = self.host.intermediate_size() as udim;
+        let rot = self.host.rotary_dim() as udim;
         let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
-        let kernels = CpuKernels::new(&self.0);
-
-        let reusing = (d + dkv + dkv).max(di + di);
-        let mut state_buf = Tensor::alloc(dt, &[nt, d + reusing], Blob::new);
+        let head_div = (self.host.query_pre_attn_scalar() as f32).sqrt().recip()
+            * self.host.rope_scaling().map_or(1., |s| s.attention_scale());
+        // MiniCPM 的注意力和前馈网络输出缩放后再加回残差
+        let residual_scale = self.host.residual_scale();
+        let kernels = CpuKernels::new(&self.host);
+
+        let dx = d.max(dq);
+        let reusing = (dq + dkv + dkv).max(di + di).max(d);
+        let mut state_buf = Tensor::alloc(dt, &[nt, dx + reusing], Blob::new);
+        // 注意力输出的宽度可能与隐藏层不同，二者共用一块空间
         macro_rules! state {
-            () => {
-                split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing)
-            };
+            () => {{
+                let (x1, reusing) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
+                let (o,) = split!(x1; [1]: dq);
+                let (x1,) = split!(x1; [1]: d);
+                (x1, o, reusing)
+            }};
         }
 
         let mut q_buf = Blob::new((nh * max_seq_len * dh) as usize * dt.size());
         let mut att_buf = Blob::new((nh * max_seq_len * max_att_len) as usize * dt.size());
+        let mut kv_buf = if queries.iter().any(|q| q.cache.is_none()) {
+            Blob::new((2 * nkvh * max_seq_len * dh) as usize * dt.size())
+        } else {
+            Blob::new(0)
+        };
+        // int8 缓存逐页反量化的暂存空间
+        let mut deq_buf = if self.pool.data_type() == DataType::I8 {
+            Blob::new((nkvh * BLOCK_SIZE * dh) as usize * dt.size())
+        } else {
+            Blob::new(0)
+        };
+        // 混合专家模型的路由分数和逐专家计算的暂存空间
+        let ne = self.host.num_experts();
+        let (mut router_buf, mut moe_buf) = if ne > 0 {
+            (
+                Blob::new(nt as usize * ne * dt.size()),
+                Blob::new((nt * (d + di + di)) as usize * dt.size()),
+            )
+        } else {
+            (Blob::new(0), Blob::new(0))
+        };
         let pos = causal_lm::pos(&queries, nt);
         let pos = pos.as_ref().map_physical(|u| reslice(u));
 
-        let mut x = token_embedded;
-        for layer in 0..self.0.num_hidden_layers() {
-            let (mut x1, qkv) = state!();
-            let mut qkv = qkv.slice(&[slice![=>], slice![=> d + dkv + dkv]]);
-
-            let input_layernorm = self.0.input_layernorm(layer);
-            kernels.rms_norm(&mut x1, &x, &input_layernorm);
+        for (i, query) in queries.iter_mut().enumerate() {
+            let att_len = query.att_len();
+            if let Some(cache) = query.cache.as_mut() {
+                cache.reserve(att_len).map_err(|e| match e {
+                    ReserveError::Overflow { len, max_len } => InferError::PositionOverflow {
+                        query: i,
+                        att_len: len,
+                        max_seq_len: max_len,
+                    },
+                    ReserveError::Exhausted { .. } => InferError::CacheExhausted { query: i },
+                })?;
+            }
+        }
+        // 记录注意力权重的缓存在所有层上累计每个位置获得的权重
+        let mut importance = queries
+            .iter()
+            .map(|q| {
+                q.cache
+                    .as_ref()
+                    .and_then(|c| c.importance())
+                    .map(|_| vec![0.; q.att_len() as usize])
+            })
+            .collect::<Vec<_>>();
 
-            let w_qkv = self.0.w_qkv(layer).transpose(&[1, 0]);
-            kernels.mat_mul(&mut qkv, 0., &x1, &w_qkv, 1.);
+        let mut x = token_embedded;
+        // 可学习的绝对位置编码按位置取出后加到词嵌入上
+        if let Some(embed_positions) = self.host.embed_positions() {
+            let mut pe = Tensor::alloc(dt, &[nt, d], Blob::new);
+            let pos = reslice::<u8, upos>(pos.physical());
+            kernels.gather(&mut pe, &embed_positions, pos.iter().copied());
+            kernels.add(&mut x, &pe);
+        }
+        for layer in 0..self.host.num_hidden_layers() {
+            let (mut x1, o, qkv) = state!();
+            let mut qkv = qkv.slice(&[slice![=>], slice![=> dq + dkv + dkv]]);
+            // FP8 权重的缩放系数并入矩阵乘的系数
+            let scales = self.host.linear_scales(layer);
+
+            let input_layernorm = self.host.input_layernorm(layer);
+            let input_layernorm_bias = self.host.input_layernorm_bias(layer);
+            kernels.norm(&mut x1, &x, &input_layernorm, input_layernorm_bias.as_ref());
+
+            let w_qkv = self.host.w_qkv(layer).transpose(&[1, 0]);
+            kernels.mat_mul(&mut qkv, 0., &x1, &w_qkv, scales.qkv);
+            if let Some(b_qkv) = self.host.b_qkv(layer) {
+                kernels.add_bias(&mut qkv, &b_qkv);
+            }
 
-            let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
+            let (q, k, v) = split!(qkv; [1]: dq, dkv, dkv);
             let mut q = q.reshape(&[nt, nh, dh]);
             let mut k = k.reshape(&[nt, nkvh, dh]);
             let v = v.reshape(&[nt, nkvh, dh]);
-            let o = x1.reshape(&[nt, nh, dh]);
-
-            kernels.rotary_embedding(&mut q, &pos);
-            kernels.rotary_embedding(&mut k, &pos);
+            let o = o.reshape(&[nt, nh, dh]);
+
+            // 只对每个头的前 `rot` 维做旋转位置编码
+            if rot > 0 {
+                let slice = [slice![=>], slice![=>], slice![=>rot]];
+                let mut q = q.as_mut().slice(&slice).map_physical(|u| &mut **u);
+                let mut k = k.as_mut().slice(&slice).map_physical(|u| &mut **u);
+                kernels.rotary_embedding(&mut q, &pos);
+                kernels.rotary_embedding(&mut k, &pos);
+            }
 
             let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
             let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
             let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
             let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
 
-            for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
+            for (i, (query, importance, q, k, v, mut o)) in
+                izip!(&mut queries, &mut importance, q, k, v, o).enumerate()
+            {
                 let pos = query.pos();
                 let seq_len = query.seq_len();
                 let att_len = query.att_len();
-                let Some((mut k_cache, mut v_cache)) = query.cache(layer) else {
-                    continue;
+                let (block_size, released) = query.cache.as_ref().map_or((seq_len, 0), |c| {
+                    (c.block_size(), c.start() / c.block_size())
+                });
+                // 滑动窗口之前的页不参与注意力，注意力从第一个参与的页开始
+                let window = self.host.layer_window(layer);
+                let first = window
+                    .map_or(0, |w| (pos + 1).saturating_sub(w as _) / block_size)
+                    .max(released);
+                let att_start = first * block_size;
+                let mut blocks = match query.blocks(layer) {
+                    Some(blocks) => blocks,
+                    // 不带缓存的查询只关注自身
+                    None if pos == 0 => {
+                        let kv = Tensor::new(
+                            dt,
+                            &[2, nkvh, seq_len, dh],
+                            LocalSplitable::from(&mut kv_buf[..]),
+                        );
+                        let (k, v) = split!(kv; [0]: 1, 1);
+                        let kv = (
+                            k.reshape(&[nkvh, seq_len, dh]),
+                            v.reshape(&[nkvh, seq_len, dh]),
+                        );
+                        vec![kv]
+                    }
+                    None => return Err(InferError::MissingCache { query: i }),
                 };
 
-                let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
-                let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
                 let shape_q0 = &[nkvh * head_group, seq_len, dh];
                 let shape_q1 = &[nkvh, head_group * seq_len, dh];
-                let shape_att0 = &[nkvh, head_group * seq_len, att_len];
-                let shape_att1 = &[nkvh * head_group, seq_len, att_len];
+                let shape_att0 = &[nkvh, head_group * seq_len, att_len - att_start];
+                let shape_att1 = &[nkvh * head_group, seq_len, att_len - att_start];
 
                 let mut q_att = Tensor::new(dt, shape_q0, &mut q_buf[..]);
-                let mut k_cat = k_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
-                let mut v_cat = v_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
                 kernels.reform(&mut q_att, &q);
-                kernels.reform(&mut k_cat, &k);
-                kernels.reform(&mut v_cat, &v);
-
                 let q_att = q_att.reshape(shape_q1);
-                let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
-                let v_att = v_cache.slice(slice_att);
 
+                // 逐页写入新的 K-V，并计算各页的注意力分数
                 let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
-                kernels.mat_mul(&mut att, 0., &q_att, &k_att, head_div);
+                let skip = (first - released) as usize;
+                for (i, (k_cache, v_cache)) in blocks.iter_mut().enumerate().skip(skip) {
+                    let start = (released + i as udim) * block_size;
+                    let end = (start + block_size).min(att_len);
+                    if start >= end {
+                        break;
+                    }
+                    if end > pos {
+                        let cat = start.max(pos)..end;
+                        let slice_src = &[
+                            slice![=>],
+                            slice![cat.start - pos => cat.end - pos],
+                            slice![=>],
+                        ];
+                        let slice_dst = &[
+                            slice![=>],
+                            slice![cat.start - start => cat.end - start],
+                            slice![=>],
+                        ];
+                        let mut k_cat =
+                            k_cache.as_mut().slice(slice_dst).map_physical(|u| &mut **u);
+                        let mut v_cat =
+                            v_cache.as_mut().slice(slice_dst).map_physical(|u| &mut **u);
+                        let k = k.as_ref().slice(slice_src).map_physical(|u| &**u);
+                        let v = v.as_ref().slice(slice_src).map_physical(|u| &**u);
+                        if k_cat.data_type() == DataType::I8 {
+                            kernels.quantize(&mut k_cat, &k);
+                            kernels.quantize(&mut v_cat, &v);
+                        } else {
+                            kernels.reform(&mut k_cat, &k);
+                            kernels.reform(&mut v_cat, &v);
+                        }
+                    }
+
+                    let k_att = k_cache
+                        .as_ref()
+                        .slice(&[slice![=>], slice![=> end - start], slice![=>]])
+                        .map_physical(|u| &**u);
+                    let mut k_deq = Tensor::new(dt, &[nkvh, end - start, dh], &mut deq_buf[..]);
+                    let k_att = if k_att.data_type() == DataType::I8 {
+                        kernels.dequantize(&mut k_deq, &k_att);
+                        k_deq.as_ref().map_physical(|u| &**u)
+                    } else {
+                        k_att
+                    };
+                    let k_att = k_att.transpose(&[0, 2, 1]);
+                    let mut att = att
+                        .as_mut()
+                        .slice(&[
+                            slice![=>],
+                            slice![=>],
+                            slice![start - att_start => end - att_start],
+                        ])
+                        .map_physical(|u| &mut **u);
+                    kernels.mat_mul(&mut att, 0., &q_att, &k_att, head_div);
+                }
                 let mut att = att.reshape(shape_att1);
-                kernels.softmax(&mut att);
+                if let Some(cap) = self.host.attn_logit_softcapping() {
+                    kernels.soft_cap(&mut att, cap);
+                }
+                kernels.softmax_window(&mut att, window);
+                if let Some(importance) = importance {
+                    let att = reslice::<u8, f16>(att.as_slice());
+                    for row in att.chunks_exact((att_len - att_start) as usize) {
+                        zip(&mut importance[att_start as usize..], row)
+                            .for_each(|(a, b)| *a += b.to_f32());
+                    }
+                }
+                let att = att.reshape(shape_att0);
+
+                // 逐页累加注意力加权的 V
                 let mut x2 = q_att;
-                kernels.mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1.);
+                for (i, (_, v_cache)) in blocks.iter().enumerate().skip(skip) {
+                    let start = (released + i as udim) * block_size;
+                    let end = (start + block_size).min(att_len);
+                    if start >= end {
+                        break;
+                    }
+                    let att = att
+                        .as_ref()
+                        .slice(&[
+                            slice![=>],
+                            slice![=>],
+                            slice![start - att_start => end - att_start],
+                        ])
+                        .map_physical(|u| &**u);
+                    let v_att = v_cache
+                        .as_ref()
+                        .slice(&[slice![=>], slice![=> end - start], slice![=>]])
+                        .map_physical(|u| &**u);
+                    let mut v_deq = Tensor::new(dt, &[nkvh, end - start, dh], &mut deq_buf[..]);
+                    let v_att = if v_att.data_type() == DataType::I8 {
+                        kernels.dequantize(&mut v_deq, &v_att);
+                        v_deq.as_ref().map_physical(|u| &**u)
+                    } else {
+                        v_att
+                    };
+                    let beta = if i == skip { 0. } else { 1. };
+                    kernels.mat_mul(&mut x2, beta, &att, &v_att, 1.);
+                }
 
                 kernels.reform(&mut o, &x2.reshape(shape_q0));
             }
 
-            let (mut x1, gate_up) = state!();
-            let mut gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
+            let (mut x1, o, gate_up) = state!();
+
+            let wo = self.host.self_attn_o_proj(layer).transpose(&[1, 0]);
+            let b_o = self.host.b_o(layer);
+            let post_layernorm = self.host.post_attention_layernorm(layer);
+            let post_layernorm_bias = self.host.post_attention_layernorm_bias(layer);
+            let attn_output_layernorm = self.host.attn_output_layernorm(layer);
+            if attn_output_layernorm.is_none() && !self.host.parallel_residual() {
+                kernels.mat_mul(&mut x, 1., &o, &wo, residual_scale * scales.o);
+                if let Some(b_o) = &b_o {
+                    kernels.add_bias(&mut x, b_o);
+                }
+                kernels.norm(&mut x1, &x, &post_layernorm, post_layernorm_bias.as_ref());
+            } else {
+                let (mut y,) = split!(gate_up; [1]: d);
+                kernels.mat_mul(&mut y, 0., &o, &wo, residual_scale * scales.o);
+                if let Some(b_o) = &b_o {
+                    kernels.add_bias(&mut y, b_o);
+                }
+                match attn_output_layernorm {
+                    // 注意力输出先归一化再加回残差
+                    Some(attn_output_layernorm) => {
+                        kernels.rms_norm(&mut x1, &y, &attn_output_layernorm);
+                        kernels.add(&mut x, &x1);
+                        kernels.norm(&mut x1, &x, &post_layernorm, post_layernorm_bias.as_ref());
+                    }
+                    // 并行残差的前馈网络与注意力使用同一个输入
+                    None => {
+                        kernels.norm(&mut x1, &x, &post_layernorm, post_layernorm_bias.as_ref());
+                        kernels.add(&mut x, &y);
+                    }
+                }
+            }
 
-            let wo = self.0.self_attn_o_proj(layer).transpose(&[1, 0]);
-            kernels.mat_mul(&mut x, 1., &x1, &wo, 1.);
+            let Some(moe_gate) = self.host.moe_gate(layer) else {
+                let w_gate_up = self.host.mlp_gate_up(layer).transpose(&[1, 0]);
+                let b_gate_up = self.host.b_mlp_gate_up(layer);
+                let act = if self.host.gated_mlp() {
+                    let (mut gate_up,) = split!(gate_up; [1]: di + di);
+                    kernels.mat_mul(&mut gate_up, 0., &x1, &w_gate_up, scales.gate_up);
+                    if let Some(b_gate_up) = &b_gate_up {
+                        kernels.add_bias(&mut gate_up, b_gate_up);
+                    }
+
+                    let (mut gate, up) = split!(gate_up; [1]: di, di);
+                    kernels.gated_act(&mut gate, &up);
+                    gate
+                } else {
+                    // 不带门控的前馈网络直接激活 up
+                    let (mut up,) = split!(gate_up; [1]: di);
+                    kernels.mat_mul(&mut up, 0., &x1, &w_gate_up, scales.gate_up);
+                    if let Some(b_up) = &b_gate_up {
+                        kernels.add_bias(&mut up, b_up);
+                    }
+                    kernels.gelu(&mut up);
+                    up
+                };
 
-            let post_layernorm = self.0.post_attention_layernorm(layer);
-            kernels.rms_norm(&mut x1, &x, &post_layernorm);
+                let mlp_down = self.host.mlp_down(layer).transpose(&[1, 0]);
+                let b_down = self.host.b_mlp_down(layer);
+                match self.host.mlp_output_layernorm(layer) {
+                    // 前馈网络输出先归一化再加回残差
+                    Some(mlp_output_layernorm) => {
+                        kernels.mat_mul(&mut x1, 0., &act, &mlp_down, residual_scale * scales.down);
+                        if let Some(b_down) = &b_down {
+                            kernels.add_bias(&mut x1, b_down);
+                        }
+                        let (mut y,) = split!(gate_up; [1]: d);
+                        kernels.rms_norm(&mut y, &x1, &mlp_output_layernorm);
+                        kernels.add(&mut x, &y);
+                    }
+                    None => {
+                        kernels.mat_mul(&mut x, 1., &act, &mlp_down, residual_scale * scales.down);
+                        if let Some(b_down) = &b_down {
+                            kernels.add_bias(&mut x, b_down);
+                        }
+                    }
+                }
+                continue;
+            };
 
-            let w_gate_up = self.0.mlp_gate_up(layer).transpose(&[1, 0]);
-            kernels.mat_mul(&mut gate_up, 0., &x1, &w_gate_up, 1.);
+            let mut router = Tensor::new(dt, &[nt, ne as udim], &mut router_buf[..]);
+            kernels.mat_mul(&mut router, 0., &x1, &moe_gate.transpose(&[1, 0]), 1.);
 
-            let (mut gate, up) = split!(gate_up; [1]: di, di);
-            kernels.swiglu(&mut gate, &up);
+            // 逐专家收集分到的 token 批量计算，再按路由权重累加到残差上
+            let experts = kernels.route(&router, self.host.num_experts_per_tok());
+            for (expert, rows) in experts.iter().enumerate() {
+                if rows.is_empty() {
+                    continue;
+                }
+                let n = rows.len() as udim;
+                let buf = Tensor::new(
+                    dt,
+                    &[n, d + di + di],
+                    LocalSplitable::from(&mut moe_buf[..]),
+                );
+                let (mut xe, mut gate_up) = split!(buf; [1]: d, di + di);
+                kernels.gather_rows(&mut xe, &x1, rows);
+
+                let w_gate_up = self.host.expert_gate_up(layer, expert).transpose(&[1, 0]);
+                kernels.mat_mul(&mut gate_up, 0., &xe, &w_gate_up, scales.gate_up);
+
+                let (mut gate, up) = split!(gate_up; [1]: di, di);
+                kernels.gated_act(&mut gate, &up);
+
+                let w_down = self.host.expert_down(layer, expert).transpose(&[1, 0]);
+                kernels.mat_mul(&mut xe, 0., &gate, &w_down, residual_scale * scales.down);
+                kernels.scatter_add(&mut x, &xe, rows);
+            }
+        }
 
-            let mlp_down = self.0.mlp_down(layer).transpose(&[1, 0]);
-            kernels.mat_mul(&mut x, 1., &gate, &mlp_down, 1.);
+        for (query, importance) in zip(&mut queries, importance) {
+            let att_len = query.att_len();
+            if let Some(cache) = query.cache.as_mut() {
+                if let Some(importance) = importance {
+                    cache.accumulate_importance(&importance);
+                }
+                cache.slide(att_len);
+            }
         }
 
-        x
+        Ok(x)
     }
 
     fn decode(
         &self,
         decoding: impl IntoIterator<Item = DecodingMeta>,
         mut hidden_state: Tensor<Self::Storage>,
-    ) -> Tensor<Self::Storage> {
-        let dt = self.0.data_type();
-        let d = self.0.hidden_size();
-        let voc = self.0.vocab_size() as udim;
-        let kernels = CpuKernels::new(&self.0);
+    ) -> Result<Tensor<Self::Storage>, InferError> {
+        let dt = self.host.data_type();
+        let d = self.host.hidden_size();
+        let voc = self.host.vocab_size() as udim;
+        let kernels = CpuKernels::new(&self.host);
+
+        let decoding = decoding.into_iter().collect::<Vec<_>>();
+        check_decoding(&decoding, hidden_state.shape(), d as _)?;
 
         let buf = hidden_state.as_mut_slice();
         let len = d * dt.size();
@@ -259,8 +775,9 @@ impl CausalLM for Transformer {
             }
         }
 
-        if dst == begin {
-            return Tensor::alloc(dt, &[0, d as _], Blob::new);
+        // 没有需要解码的 token
+        if dst <= begin {
+            return Ok(Tensor::alloc(dt, &[0, voc], Blob::new));
         }
 
         let mut x = hidden_state.slice(&[slice![begin => dst], slice![=>]]);
@@ -270,29 +787,110 @@ impl CausalLM for Transformer {
         let x_ = x
             .as_ref()
             .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
-        kernels.rms_norm(&mut x, &x_, &self.0.model_norm());
+        let model_norm_bias = self.host.model_norm_bias();
+        kernels.norm(
+            &mut x,
+            &x_,
+            &self.host.model_norm(),
+            model_norm_bias.as_ref(),
+        );
+
+        let lm_head = self.host.lm_head().transpose(&[1, 0]);
+        kernels.mat_mul(&mut logits, 0., &x, &lm_head, self.host.logits_scale());
+        if let Some(cap) = self.host.final_logit_softcapping() {
+            kernels.soft_cap(&mut logits, cap);
+        }
 
-        let lm_head = self.0.lm_head().transpose(&[1, 0]);
-        kernels.mat_mul(&mut logits, 0., &x, &lm_head, 1.);
+        Ok(logits)
+    }
 
-        logits
+    fn embed(
+        &self,
+        seq_len: impl IntoIterator<Item = udim>,
+        token_embedded: Tensor<Self::Storage>,
+        pooling: Pooling,
+    ) -> Result<Vec<Vec<f32>>, InferError> {
+        let d = self.host.hidden_size();
+        let kernels = CpuKernels::new(&self.host);
+
+        let seq_len = seq_len.into_iter().collect::<Vec<_>>();
+        let queries = seq_len.iter().map(|&len| QueryContext {
+            cache: None,
+            range: 0..len,
+        });
+        let mut x = self.forward(queries, token_embedded)?;
+
+        // 复制一个 x 以实现原地归一化
+        let x_ = x
+            .as_ref()
+            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+        let model_norm_bias = self.host.model_norm_bias();
+        kernels.norm(
+            &mut x,
+            &x_,
+            &self.host.model_norm(),
+            model_norm_bias.as_ref(),
+        );
+
+        let x: &[f16] = reslice(x.as_slice());
+        Ok(pooling.pool(x, d, &seq_len))
     }
 
     fn sample(
         &self,
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
-    ) -> Vec<utok> {
-        let &[_, voc] = logits.shape() else { panic!() };
+    ) -> Result<Vec<utok>, InferError> {
+        let voc = self.host.vocab_size() as udim;
+        let args = args.into_iter().collect::<Vec<_>>();
+        check_sample(&args, logits.shape(), voc)?;
+
         let logits: &[f16] = reslice(logits.as_slice());
-        args.into_iter()
+        Ok(args
+            .into_iter()
             .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
             .enumerate()
             .map(|(i, args)| args.random(&kernel::slice!(logits; voc; [i])))
-            .collect()
+            .collect())
     }
 }
 
+/// 取出缓存页第 `layer` 层的 K（`i = 0`）或 V（`i = 1`）中从 `start` 开始的 `len` 个位置（`num_kv_head x len x head_dim`）。
+fn kv_slice<T>(block: Tensor<T>, layer: usize, i: udim, start: udim, len: udim) -> Tensor<T> {
+    let &[_, 2, nkvh, _, row] = block.shape() else {
+        unreachable!()
+    };
+    block
+        .slice(&[
+            slice![=layer],
+            slice![=i],
+            slice![=>],
+            slice![start =>=> len],
+            slice![=>],
+        ])
+        .reshape(&[nkvh, len, row])
+}
+
+#[test]
+fn test_duplicate_after_slide() {
+    let path = common::test_model::tiny_gguf("transformer-cpu-test-duplicate", &[]);
+    let model = <Transformer as Model>::load(&path, Default::default()).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    let mut cache = model.new_cache().with_window(BLOCK_SIZE);
+    cache.reserve(3 * BLOCK_SIZE).unwrap();
+    assert!(model.duplicate_cache(&cache, 2 * BLOCK_SIZE).is_some());
+    let exported = model.export_cache(&cache, 2 * BLOCK_SIZE).unwrap();
+    assert_eq!(exported.shape()[3], 2 * BLOCK_SIZE);
+    assert!(model.export_cache(&cache, 4 * BLOCK_SIZE).is_none());
+
+    // 滑动后开头的页已归还，复制和导出失败而不是崩溃
+    cache.slide(3 * BLOCK_SIZE);
+    assert_ne!(cache.start(), 0);
+    assert!(model.duplicate_cache(&cache, 3 * BLOCK_SIZE).is_none());
+    assert!(model.export_cache(&cache, 3 * BLOCK_SIZE).is_none());
+}
+
 #[test]
 fn test_infer() {
     use std::time::Instant;
@@ -303,7 +901,7 @@ fn test_infer() {
     println!("model_dir: {}", model_dir.display());
 
     let t0 = Instant::now();
-    let model = <Transformer as Model>::load(model_dir, ()).unwrap();
+    let model = <Transformer as Model>::load(model_dir, Default::default()).unwrap();
     let t1 = Instant::now();
     println!("load {:?}", t1 - t0);
 
@@ -316,25 +914,25 @@ fn test_infer() {
     let mut pos = 0;
 
     while prompt != &[model.eos_token()] {
-        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied());
+        let token_embedded = CausalLM::token_embed(&model, prompt.iter().copied()).unwrap();
 
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
         }];
-        let hidden_state = CausalLM::forward(&model, queries, token_embedded);
+        let hidden_state = CausalLM::forward(&model, queries, token_embedded).unwrap();
 
         let decoding = [DecodingMeta {
             num_query: prompt.len(),
             num_decode: 1,
         }];
-        let logits = CausalLM::decode(&model, decoding, hidden_state);
+        let logits = CausalLM::decode(&model, decoding, hidden_state).unwrap();
 
         let args = [SampleMeta {
             num_decode: 1,
             args: causal_lm::SampleArgs::default(),
         }];
-        let tokens = CausalLM::sample(&model, args, logits);
+        let tokens = CausalLM::sample(&model, args, logits).unwrap();
 
         println!("{:?}", tokens);
         pos += prompt.len() as upos;
@@ -0,0 +1,29 @@
+//! ALiBi 注意力偏置的斜率。
+
+/// 每个注意力头的 ALiBi 斜率，第 `h` 个头的注意力分数减去 `slope[h] * 距离`。
+///
+/// 头数为 2 的幂 `n` 时斜率为 `2^(-8/n)` 的 1 到 n 次幂，否则不足的部分从 `2n` 个头的斜率中隔一个取一个。
+pub fn alibi_slopes(num_heads: usize) -> Vec<f32> {
+    let n = 1 << num_heads.ilog2();
+    let base = 2f32.powf(-8. / n as f32);
+    let extra = 2f32.powf(-4. / n as f32);
+    (1..=n)
+        .map(|i| base.powi(i as _))
+        .chain((0..num_heads - n).map(|i| extra.powi(i as i32 * 2 + 1)))
+        .collect()
+}
+
+#[test]
+fn test_alibi_slopes() {
+    let slopes = alibi_slopes(8);
+    let expected = (1..=8).map(|i| 0.5f32.powi(i)).collect::<Vec<_>>();
+    assert_eq!(slopes, expected);
+
+    let slopes = alibi_slopes(12);
+    assert_eq!(slopes.len(), 12);
+    assert_eq!(&slopes[..8], &expected[..]);
+    let expected = [1, 3, 5, 7].map(|i| 2f32.powf(-0.5 * i as f32));
+    for (a, b) in slopes[8..].iter().zip(expected) {
+        assert!((a - b).abs() < 1e-6);
+    }
+}
@@ -29,6 +29,11 @@ pub trait Kernels {
         U: Deref<Target = Self::Storage>,
         V: Deref<Target = Self::Storage>;
 
+    fn add_bias<T, U>(&self, x: &mut Tensor<T>, bias: &Tensor<U>)
+    where
+        T: DerefMut<Target = Self::Storage>,
+        U: Deref<Target = Self::Storage>;
+
     fn rotary_embedding<T, U>(&self, t: &mut Tensor<T>, pos: &Tensor<U>)
     where
         T: DerefMut<Target = Self::Storage>,
@@ -2,10 +2,17 @@
 
 #![deny(warnings)]
 
+mod alibi;
 mod blas;
 mod kernels;
 mod parameters;
+mod rope;
 
+pub use alibi::alibi_slopes;
 pub use blas::Matrix;
 pub use kernels::Kernels;
-pub use parameters::{save, DistributeScheme, DistributedLayer, Distributer, Llama2, Memory};
+pub use parameters::{
+    save, save_gguf, Activation, Architecture, ChatTemplate, DistributeScheme, DistributedLayer,
+    Distributer, LinearScales, Llama2, Memory,
+};
+pub use rope::{RopeScaling, RopeTable};
@@ -0,0 +1,390 @@
+﻿use serde_json::{json, Map, Value};
+
+/// 一个模型族：如何识别、如何读取配置和权重、默认使用哪种对话模板。
+pub struct Architecture {
+    pub name: &'static str,
+    /// config.json 中 `architectures` 可能的取值。
+    architectures: &'static [&'static str],
+    /// config.json 中 `model_type` 可能的取值。
+    model_types: &'static [&'static str],
+    pub(super) tensors: TensorNames,
+    /// 在解析之前把 config.json 调整为 Llama 的字段和语义。
+    pub(super) adapt: fn(&mut Map<String, Value>),
+    pub template: ChatTemplate,
+    /// 词嵌入是否按 `sqrt(hidden_size)` 缩放。
+    pub scale_embed: bool,
+    /// 归一化权重的偏移，以 `norm_offset + w` 作为权重。
+    pub norm_offset: f32,
+    /// 滑动窗口是否只用于偶数层，奇数层为全局注意力。
+    pub alternating_window: bool,
+}
+
+/// 模型族默认的对话模板。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChatTemplate {
+    /// `<用户>...<AI>`。
+    ChatCPM,
+    /// Zephyr 风格的 `<|user|>...<|assistant|>`。
+    ChatTinyLlama,
+    /// `[INST] ... [/INST]`。
+    ChatInst,
+    /// `<|im_start|>user ... <|im_start|>assistant`。
+    ChatML,
+    /// `<start_of_turn>user ... <start_of_turn>model`。
+    ChatGemma,
+    /// 没有对话模板，原样续写。
+    Plain,
+}
+
+/// 权重的命名，不含 `.weight`、`.bias` 后缀。
+///
+/// 除 `lm_head` 外，所有名字都带有 `prefixes` 中的某一个前缀，每层的权重名为 `{prefix}{layers}.{l}.{name}`。
+pub(super) struct TensorNames {
+    pub prefixes: &'static [&'static str],
+    pub embed_tokens: &'static str,
+    pub embed_positions: Option<&'static str>,
+    pub layers: &'static str,
+    pub input_layernorm: &'static str,
+    pub qkv: Qkv,
+    pub o_proj: &'static str,
+    pub post_attention_layernorm: &'static str,
+    /// Gemma2 在前馈网络前后各有一次归一化，此时 `post_attention_layernorm` 作用于注意力输出。
+    pub sandwich: Option<(&'static str, &'static str)>,
+    pub mlp: Mlp,
+    pub moe: Option<Moe>,
+    pub model_norm: &'static str,
+    pub lm_head: &'static str,
+    /// 线性层以 Conv1D 存储，权重为 `in x out`。
+    pub conv1d: bool,
+}
+
+/// q、k、v 投影的存储方式。
+pub(super) enum Qkv {
+    /// 分别存储，旋转位置编码按前后两半配对；本项目保存的模型以 `fused` 融合存储。
+    Split {
+        fused: &'static str,
+        q: &'static str,
+        k: &'static str,
+        v: &'static str,
+    },
+    /// 按 q、k、v 依次融合存储。
+    Fused(&'static str),
+    /// 按头交错融合存储为 `nh x 3 x dh`，旋转位置编码按前后两半配对。
+    Interleaved(&'static str),
+}
+
+/// 前馈网络的存储方式。
+pub(super) enum Mlp {
+    /// 带门控，本项目保存的模型以 `gate_up` 融合存储。
+    Gated {
+        gate_up: &'static str,
+        gate: &'static str,
+        up: &'static str,
+        down: &'static str,
+    },
+    /// 不带门控。
+    Dense {
+        up: &'static str,
+        down: &'static str,
+    },
+}
+
+/// 混合专家前馈网络，第 `i` 个专家的权重名为 `{experts}.{i}.{name}`。
+pub(super) struct Moe {
+    pub gate: &'static str,
+    pub experts: &'static str,
+    pub gate_proj: &'static str,
+    pub up_proj: &'static str,
+    pub down_proj: &'static str,
+}
+
+impl Architecture {
+    /// 按 config.json 的 `architectures` 或 `model_type` 查找模型族，都不认识时视为 Llama。
+    pub fn find(config: &Value) -> &'static Self {
+        let architectures = config["architectures"].as_array().into_iter().flatten();
+        let model_type = config["model_type"].as_str();
+        architectures
+            .filter_map(Value::as_str)
+            .find_map(|name| REGISTRY.iter().find(|a| a.architectures.contains(&name)))
+            .unwrap_or_else(|| Self::find_model_type(model_type.unwrap_or_default()))
+    }
+
+    /// 按 `model_type` 查找模型族，不认识时视为 Llama。
+    pub fn find_model_type(model_type: &str) -> &'static Self {
+        REGISTRY
+            .iter()
+            .find(|a| a.model_types.contains(&model_type))
+            .unwrap_or(&REGISTRY[0])
+    }
+
+    /// 前馈网络是否带门控。
+    #[inline]
+    pub fn gated_mlp(&self) -> bool {
+        matches!(self.tensors.mlp, Mlp::Gated { .. })
+    }
+}
+
+impl TensorNames {
+    /// 以词嵌入表是否存在确定权重名的前缀。
+    pub fn prefix(&self, contains: impl Fn(&str) -> bool) -> Option<&'static str> {
+        self.prefixes
+            .iter()
+            .find(|prefix| contains(&format!("{prefix}{}.weight", self.embed_tokens)))
+            .copied()
+    }
+}
+
+const LLAMA: TensorNames = TensorNames {
+    prefixes: &["model."],
+    embed_tokens: "embed_tokens",
+    embed_positions: Some("embed_positions"),
+    layers: "layers",
+    input_layernorm: "input_layernorm",
+    qkv: Qkv::Split {
+        fused: "self_attn.qkv_proj",
+        q: "self_attn.q_proj",
+        k: "self_attn.k_proj",
+        v: "self_attn.v_proj",
+    },
+    o_proj: "self_attn.o_proj",
+    post_attention_layernorm: "post_attention_layernorm",
+    sandwich: Some(("pre_feedforward_layernorm", "post_feedforward_layernorm")),
+    mlp: Mlp::Gated {
+        gate_up: "mlp.gate_up_proj",
+        gate: "mlp.gate_proj",
+        up: "mlp.up_proj",
+        down: "mlp.down_proj",
+    },
+    moe: Some(Moe {
+        gate: "block_sparse_moe.gate",
+        experts: "block_sparse_moe.experts",
+        gate_proj: "w1",
+        up_proj: "w3",
+        down_proj: "w2",
+    }),
+    model_norm: "norm",
+    lm_head: "lm_head",
+    conv1d: false,
+};
+
+/// 本项目保存的模型总是使用 Llama 的命名。
+pub(super) const SAVED: &TensorNames = &LLAMA;
+
+/// 已知的模型族，第一项是无法识别时的默认值。
+static REGISTRY: &[Architecture] = &[
+    Architecture {
+        name: "llama",
+        architectures: &["LlamaForCausalLM"],
+        model_types: &["llama"],
+        tensors: LLAMA,
+        adapt: |_| {},
+        template: ChatTemplate::ChatTinyLlama,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "minicpm",
+        architectures: &["MiniCPMForCausalLM"],
+        model_types: &["minicpm"],
+        tensors: LLAMA,
+        adapt: |_| {},
+        template: ChatTemplate::ChatCPM,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "mistral",
+        architectures: &["MistralForCausalLM"],
+        model_types: &["mistral"],
+        tensors: LLAMA,
+        adapt: |_| {},
+        template: ChatTemplate::ChatInst,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "mixtral",
+        architectures: &["MixtralForCausalLM"],
+        model_types: &["mixtral"],
+        tensors: LLAMA,
+        adapt: |_| {},
+        template: ChatTemplate::ChatInst,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "qwen2",
+        architectures: &["Qwen2ForCausalLM"],
+        model_types: &["qwen2"],
+        tensors: LLAMA,
+        adapt: |_| {},
+        template: ChatTemplate::ChatML,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "gemma",
+        architectures: &["GemmaForCausalLM"],
+        model_types: &["gemma"],
+        tensors: LLAMA,
+        adapt: adapt_gemma,
+        template: ChatTemplate::ChatGemma,
+        scale_embed: true,
+        norm_offset: 1.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "gemma2",
+        architectures: &["Gemma2ForCausalLM"],
+        model_types: &["gemma2"],
+        tensors: LLAMA,
+        adapt: adapt_gemma,
+        template: ChatTemplate::ChatGemma,
+        scale_embed: true,
+        norm_offset: 1.,
+        alternating_window: true,
+    },
+    Architecture {
+        name: "gpt2",
+        architectures: &["GPT2LMHeadModel"],
+        model_types: &["gpt2"],
+        tensors: TensorNames {
+            // 完整保存的 `GPT2LMHeadModel` 带有 `transformer.` 前缀
+            prefixes: &["", "transformer."],
+            embed_tokens: "wte",
+            embed_positions: Some("wpe"),
+            layers: "h",
+            input_layernorm: "ln_1",
+            qkv: Qkv::Fused("attn.c_attn"),
+            o_proj: "attn.c_proj",
+            post_attention_layernorm: "ln_2",
+            sandwich: None,
+            mlp: Mlp::Dense {
+                up: "mlp.c_fc",
+                down: "mlp.c_proj",
+            },
+            moe: None,
+            model_norm: "ln_f",
+            lm_head: "lm_head",
+            conv1d: true,
+        },
+        adapt: |config| {
+            rename(
+                config,
+                &[
+                    ("n_embd", "hidden_size"),
+                    ("n_inner", "intermediate_size"),
+                    ("n_head", "num_attention_heads"),
+                    ("n_layer", "num_hidden_layers"),
+                    ("n_positions", "max_position_embeddings"),
+                    ("layer_norm_epsilon", "rms_norm_eps"),
+                    ("activation_function", "hidden_act"),
+                ],
+            );
+            config.entry("partial_rotary_factor").or_insert(json!(0));
+            config.entry("torch_dtype").or_insert(json!("float32"));
+        },
+        template: ChatTemplate::Plain,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+    Architecture {
+        name: "gpt_neox",
+        architectures: &["GPTNeoXForCausalLM"],
+        model_types: &["gpt_neox"],
+        tensors: TensorNames {
+            prefixes: &["gpt_neox."],
+            embed_tokens: "embed_in",
+            embed_positions: None,
+            layers: "layers",
+            input_layernorm: "input_layernorm",
+            qkv: Qkv::Interleaved("attention.query_key_value"),
+            o_proj: "attention.dense",
+            post_attention_layernorm: "post_attention_layernorm",
+            sandwich: None,
+            mlp: Mlp::Dense {
+                up: "mlp.dense_h_to_4h",
+                down: "mlp.dense_4h_to_h",
+            },
+            moe: None,
+            model_norm: "final_layer_norm",
+            lm_head: "embed_out",
+            conv1d: false,
+        },
+        adapt: |config| {
+            rename(
+                config,
+                &[
+                    ("layer_norm_eps", "rms_norm_eps"),
+                    ("rotary_emb_base", "rope_theta"),
+                    ("rotary_pct", "partial_rotary_factor"),
+                    ("use_parallel_residual", "parallel_residual"),
+                ],
+            );
+            config.entry("parallel_residual").or_insert(json!(true));
+        },
+        template: ChatTemplate::Plain,
+        scale_embed: false,
+        norm_offset: 0.,
+        alternating_window: false,
+    },
+];
+
+fn adapt_gemma(config: &mut Map<String, Value>) {
+    // `hidden_activation` 覆盖 `hidden_act`
+    if let Some(act) = config.remove("hidden_activation").filter(|v| !v.is_null()) {
+        config.insert("hidden_act".into(), act);
+    }
+    // 配置中声明 `gelu`，但实际以 tanh 近似训练
+    if config.get("hidden_act").and_then(Value::as_str) == Some("gelu") {
+        config.insert("hidden_act".into(), json!("gelu_pytorch_tanh"));
+    }
+}
+
+/// 把字段改为 Llama 的命名，已有同名字段时保留原字段。
+fn rename(config: &mut Map<String, Value>, renames: &[(&str, &str)]) {
+    for (from, to) in renames {
+        if !config.contains_key(*to) {
+            if let Some(value) = config.remove(*from) {
+                config.insert(to.to_string(), value);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_find() {
+    let find = |config: Value| Architecture::find(&config).name;
+    assert_eq!(
+        find(json!({"architectures": ["Qwen2ForCausalLM"]})),
+        "qwen2"
+    );
+    assert_eq!(find(json!({"model_type": "gpt_neox"})), "gpt_neox");
+    assert_eq!(
+        find(json!({"architectures": ["MiniCPMForCausalLM"], "model_type": "llama"})),
+        "minicpm"
+    );
+    assert_eq!(find(json!({"model_type": "unknown"})), "llama");
+    assert_eq!(find(Value::Null), "llama");
+    // 加载时以模型族的名字作为 `model_type`，必须能找回同一个模型族
+    for arch in REGISTRY {
+        assert!(std::ptr::eq(Architecture::find_model_type(arch.name), arch));
+    }
+}
+
+#[test]
+fn test_flags() {
+    let gemma = Architecture::find_model_type("gemma2");
+    assert!(gemma.scale_embed && gemma.norm_offset == 1. && gemma.gated_mlp());
+    assert!(gemma.alternating_window);
+    assert!(!Architecture::find_model_type("mistral").alternating_window);
+    let gpt = Architecture::find_model_type("gpt2");
+    assert!(!gpt.scale_embed && gpt.norm_offset == 0. && !gpt.gated_mlp());
+    assert_eq!(Architecture::find_model_type("unknown").name, "llama");
+}
@@ -1,34 +1,92 @@
-﻿use super::{memory::Layer, ConfigJson, Llama2, Memory, Storage};
-use common::{bf16, f16, Blob};
+﻿use super::{memory::Layer, ConfigJson, LinearScales, Llama2, Memory, Storage};
+use common::{
+    bf16, f16, f8e4m3, f8e5m2,
+    quant::{Block, Q4_0, Q4_K, Q8_0},
+    Blob,
+};
 use std::sync::Arc;
-use tensor::{DataType, Tensor, Ty};
+use tensor::{udim, DataType, Tensor, Ty};
 
 impl Memory {
+    /// 转换数据类型。
+    ///
+    /// 目标为按块量化或 FP8 类型时只转换线性层的权重，其他权重保持原来的类型；
+    /// 目标为其他浮点类型时转换其他权重，已量化的线性层权重保持不变。
     pub fn cast(src: &(dyn Llama2), new_dtype: DataType) -> Self {
+        let (new_dtype, quantization) = if new_dtype.is_quantized() || new_dtype.is_fp8() {
+            (src.data_type(), Some(new_dtype))
+        } else {
+            (new_dtype, src.quantization())
+        };
+        let linear = |t, scale| linear(t, scale, quantization.unwrap_or(new_dtype));
+
+        let embed_tokens = cast(src.embed_tokens(), new_dtype);
+        let lm_head = if src.tie_word_embeddings() {
+            embed_tokens.clone()
+        } else {
+            cast(src.lm_head(), new_dtype)
+        };
         Self {
             config: ConfigJson {
                 torch_dtype: new_dtype,
+                quantization,
                 ..ConfigJson::from(src)
             },
-            embed_tokens: cast(src.embed_tokens(), new_dtype),
+            embed_tokens,
+            embed_positions: src.embed_positions().map(|t| cast(t, new_dtype)),
             layers: (0..src.num_hidden_layers())
-                .map(|l| Layer {
-                    input_layernorm: cast(src.input_layernorm(l), new_dtype),
-                    w_qkv: cast(src.w_qkv(l), new_dtype),
-                    self_attn_o_proj: cast(src.self_attn_o_proj(l), new_dtype),
-                    post_attention_layernorm: cast(src.post_attention_layernorm(l), new_dtype),
-                    mlp_gate_up: cast(src.mlp_gate_up(l), new_dtype),
-                    mlp_down: cast(src.mlp_down(l), new_dtype),
+                .map(|l| {
+                    let scales = src.linear_scales(l);
+                    let (w_qkv, qkv) = linear(src.w_qkv(l), scales.qkv);
+                    let (self_attn_o_proj, o) = linear(src.self_attn_o_proj(l), scales.o);
+                    let (mlp_gate_up, gate_up) = linear(src.mlp_gate_up(l), scales.gate_up);
+                    let (mlp_down, down) = linear(src.mlp_down(l), scales.down);
+                    Layer {
+                        input_layernorm: cast(src.input_layernorm(l), new_dtype),
+                        input_layernorm_bias: src
+                            .input_layernorm_bias(l)
+                            .map(|b| cast(b, new_dtype)),
+                        w_qkv,
+                        b_qkv: src.b_qkv(l).map(|b| cast(b, new_dtype)),
+                        self_attn_o_proj,
+                        b_o: src.b_o(l).map(|b| cast(b, new_dtype)),
+                        attn_output_layernorm: src
+                            .attn_output_layernorm(l)
+                            .map(|w| cast(w, new_dtype)),
+                        post_attention_layernorm: cast(src.post_attention_layernorm(l), new_dtype),
+                        post_attention_layernorm_bias: src
+                            .post_attention_layernorm_bias(l)
+                            .map(|b| cast(b, new_dtype)),
+                        moe_gate: src.moe_gate(l).map(|g| cast(g, new_dtype)),
+                        mlp_gate_up,
+                        b_mlp_gate_up: src.b_mlp_gate_up(l).map(|b| cast(b, new_dtype)),
+                        mlp_down,
+                        b_mlp_down: src.b_mlp_down(l).map(|b| cast(b, new_dtype)),
+                        mlp_output_layernorm: src
+                            .mlp_output_layernorm(l)
+                            .map(|w| cast(w, new_dtype)),
+                        scales: LinearScales {
+                            qkv,
+                            o,
+                            gate_up,
+                            down,
+                        },
+                    }
                 })
                 .collect(),
             model_norm: cast(src.model_norm(), new_dtype),
-            lm_head: cast(src.lm_head(), new_dtype),
+            model_norm_bias: src.model_norm_bias().map(|b| cast(b, new_dtype)),
+            lm_head,
         }
     }
 }
 
-fn cast(src: Tensor<Storage>, new_dtype: DataType) -> Tensor<Storage> {
+pub(super) fn cast(src: Tensor<Storage>, new_dtype: DataType) -> Tensor<Storage> {
     match (src.data_type(), new_dtype) {
+        (a, b) if a == b => src,
+        (q, _) if q.is_quantized() => dequantize(src, new_dtype),
+        (_, q) if q.is_quantized() => quantize(src, q),
+        (f, _) if f.is_fp8() => cast(scaled(src, 1.), new_dtype),
         (DataType::F16, DataType::BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
         (DataType::F16, DataType::F32) => typed(src, |x: &f16| x.to_f32()),
         (DataType::BF16, DataType::F16) => typed(src, |x: &bf16| f16::from_f32(x.to_f32())),
@@ -61,3 +119,201 @@ fn typed<T: Ty + Sync, U: Ty + Send>(
 
     ans.map_physical(|b| Storage::Others(Arc::new(b)))
 }
+
+/// 转换线性层的权重，返回转换后的权重和逐张量的缩放系数。
+fn linear(src: Tensor<Storage>, scale: f32, dt: DataType) -> (Tensor<Storage>, f32) {
+    if src.data_type() == dt {
+        (src, scale)
+    } else if dt.is_fp8() {
+        // 按绝对值最大值确定缩放系数
+        let x = scaled(src, scale);
+        let amax = tensor::reslice::<u8, f32>(x.as_slice())
+            .iter()
+            .fold(0f32, |m, x| m.max(x.abs()));
+        let max = match dt {
+            DataType::F8_E4M3 => f8e4m3::MAX,
+            DataType::F8_E5M2 => f8e5m2::MAX,
+            _ => unreachable!(),
+        };
+        let scale = if amax > 0. { amax / max } else { 1. };
+        (encode_fp8(&x, 1. / scale, dt), scale)
+    } else {
+        (cast(scaled(src, scale), dt), 1.)
+    }
+}
+
+/// 把 FP8 权重乘以 `k` 后重新编码，用于把多个权重统一到相同的缩放系数，要求 `k` 不大于 1。
+pub(super) fn rescale_fp8(src: Tensor<Storage>, k: f32) -> Tensor<Storage> {
+    let dt = src.data_type();
+    assert!(dt.is_fp8());
+    encode_fp8(&scaled(src, 1.), k, dt)
+}
+
+/// 转换为 F32 并乘以缩放系数。
+fn scaled(src: Tensor<Storage>, scale: f32) -> Tensor<Storage> {
+    use rayon::prelude::*;
+    use tensor::{reslice, reslice_mut};
+
+    fn typed<T: Sync>(x: &[u8], y: &mut [f32], f: impl Fn(&T) -> f32 + Sync) {
+        y.par_iter_mut()
+            .zip(reslice::<u8, T>(x))
+            .for_each(|(y, x)| *y = f(x));
+    }
+
+    let x = match src.data_type() {
+        DataType::F8_E4M3 | DataType::F8_E5M2 => src,
+        _ if scale == 1. => return cast(src, DataType::F32),
+        _ => cast(src, DataType::F32),
+    };
+    assert!(x.is_contiguous());
+    let mut ans = Tensor::alloc(DataType::F32, x.shape(), Blob::new);
+    let y = reslice_mut(ans.physical_mut());
+    match x.data_type() {
+        DataType::F8_E4M3 => typed(x.as_slice(), y, |x: &f8e4m3| x.to_f32() * scale),
+        DataType::F8_E5M2 => typed(x.as_slice(), y, |x: &f8e5m2| x.to_f32() * scale),
+        DataType::F32 => typed(x.as_slice(), y, |x: &f32| x * scale),
+        _ => unreachable!(),
+    }
+    ans.map_physical(|b| Storage::Others(Arc::new(b)))
+}
+
+/// 把 F32 的 `x` 乘以 `k` 后编码为 FP8。
+fn encode_fp8(x: &Tensor<Storage>, k: f32, dt: DataType) -> Tensor<Storage> {
+    use rayon::prelude::*;
+
+    assert_eq!(x.data_type(), DataType::F32);
+    assert!(x.is_contiguous());
+    let encode: fn(f32) -> u8 = match dt {
+        DataType::F8_E4M3 => |x: f32| f8e4m3::from_f32(x).to_bits(),
+        DataType::F8_E5M2 => |x: f32| f8e5m2::from_f32(x).to_bits(),
+        _ => unreachable!(),
+    };
+    let mut ans = Tensor::alloc(dt, x.shape(), Blob::new);
+    ans.physical_mut()
+        .par_iter_mut()
+        .zip(tensor::reslice::<u8, f32>(x.as_slice()))
+        .for_each(|(y, x)| *y = encode(x * k));
+    ans.map_physical(|b| Storage::Others(Arc::new(b)))
+}
+
+/// 沿最后一维按块量化，最后一维缩小为块数。
+fn quantize(src: Tensor<Storage>, dt: DataType) -> Tensor<Storage> {
+    let n = dt.block_size() as udim;
+    let mut shape = src.shape().to_vec();
+    let k = shape.last_mut().unwrap();
+    assert_eq!(
+        *k % n,
+        0,
+        "last dimension {k} cannot be divided into {dt:?} blocks"
+    );
+    *k /= n;
+
+    let src = cast(src, DataType::F32);
+    assert!(src.is_contiguous());
+    let mut ans = Tensor::alloc(dt, &shape, Blob::new);
+    fn blockwise<B: Block>(x: &[f32], y: &mut [u8]) {
+        use rayon::prelude::*;
+        let y = unsafe { blocks_mut::<B>(y) };
+        y.par_iter_mut()
+            .zip(x.par_chunks_exact(B::N))
+            .for_each(|(y, x)| *y = B::quantize(x));
+    }
+    let x = tensor::reslice(src.as_slice());
+    match dt {
+        DataType::Q8_0 => blockwise::<Q8_0>(x, ans.physical_mut()),
+        DataType::Q4_0 => blockwise::<Q4_0>(x, ans.physical_mut()),
+        DataType::Q4_K => blockwise::<Q4_K>(x, ans.physical_mut()),
+        _ => unreachable!(),
+    }
+    ans.map_physical(|b| Storage::Others(Arc::new(b)))
+}
+
+/// 把按块量化的数据恢复为浮点数。
+fn dequantize(src: Tensor<Storage>, new_dtype: DataType) -> Tensor<Storage> {
+    let dt = src.data_type();
+    let mut shape = src.shape().to_vec();
+    *shape.last_mut().unwrap() *= dt.block_size() as udim;
+
+    assert!(src.is_contiguous());
+    let mut ans = Tensor::alloc(DataType::F32, &shape, Blob::new);
+    fn blockwise<B: Block>(x: &[u8], y: &mut [f32]) {
+        use rayon::prelude::*;
+        let x = unsafe { blocks::<B>(x) };
+        x.par_iter()
+            .zip(y.par_chunks_exact_mut(B::N))
+            .for_each(|(x, y)| x.dequantize(y));
+    }
+    let y = tensor::reslice_mut(ans.physical_mut());
+    match dt {
+        DataType::Q8_0 => blockwise::<Q8_0>(src.as_slice(), y),
+        DataType::Q4_0 => blockwise::<Q4_0>(src.as_slice(), y),
+        DataType::Q4_K => blockwise::<Q4_K>(src.as_slice(), y),
+        _ => unreachable!(),
+    }
+    cast(
+        ans.map_physical(|b| Storage::Others(Arc::new(b))),
+        new_dtype,
+    )
+}
+
+/// # Safety
+///
+/// `x` 的长度必须是块大小的整数倍。块以字节数组组成，不要求对齐。
+unsafe fn blocks<B: Block>(x: &[u8]) -> &[B] {
+    std::slice::from_raw_parts(x.as_ptr().cast(), x.len() / std::mem::size_of::<B>())
+}
+
+/// # Safety
+///
+/// 见 [blocks]。
+unsafe fn blocks_mut<B: Block>(x: &mut [u8]) -> &mut [B] {
+    std::slice::from_raw_parts_mut(x.as_mut_ptr().cast(), x.len() / std::mem::size_of::<B>())
+}
+
+#[test]
+fn test_quantize() {
+    let x = (0..2 * 256)
+        .flat_map(|i| f16::from_f32((i as f32 * 0.1).cos()).to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut blob = Blob::new(x.len());
+    blob.copy_from_slice(&x);
+    let src = Tensor::new(DataType::F16, &[2, 256], Storage::Others(Arc::new(blob)));
+    for dt in [DataType::Q8_0, DataType::Q4_0, DataType::Q4_K] {
+        let q = cast(src.clone(), dt);
+        assert_eq!(q.data_type(), dt);
+        assert_eq!(q.shape(), &[2, 256 / dt.block_size() as udim]);
+        assert_eq!(q.bytes_size(), 2 * 256 / dt.block_size() * dt.size());
+
+        let y = cast(q, DataType::F16);
+        assert_eq!(y.shape(), src.shape());
+        let x = tensor::reslice::<u8, f16>(src.as_slice());
+        let y = tensor::reslice::<u8, f16>(y.as_slice());
+        for (x, y) in x.iter().zip(y) {
+            assert!((x.to_f32() - y.to_f32()).abs() < 0.2);
+        }
+    }
+}
+
+#[test]
+fn test_fp8() {
+    let x = (0..64)
+        .flat_map(|i| ((i as f32 * 0.3).sin() * 3.).to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut blob = Blob::new(x.len());
+    blob.copy_from_slice(&x);
+    let src = Tensor::new(DataType::F32, &[2, 32], Storage::Others(Arc::new(blob)));
+    for dt in [DataType::F8_E4M3, DataType::F8_E5M2] {
+        let (q, scale) = linear(src.clone(), 1., dt);
+        assert_eq!(q.data_type(), dt);
+        assert_eq!(q.bytes_size(), 64);
+        assert!(scale > 0.);
+
+        let (y, one) = linear(q, scale, DataType::F32);
+        assert_eq!(one, 1.);
+        let x = tensor::reslice::<u8, f32>(src.as_slice());
+        let y = tensor::reslice::<u8, f32>(y.as_slice());
+        for (x, y) in x.iter().zip(y) {
+            assert!((x - y).abs() <= x.abs() / 8. + 1e-6, "{x} vs {y}");
+        }
+    }
+}
@@ -37,6 +37,21 @@ impl DistributedLayer {
         )
     }
 
+    #[inline]
+    pub fn b_qkv(&self) -> Option<Tensor<&[u8]>> {
+        let nh = self.scheme.nh;
+        let nkvh = self.scheme.nkvh;
+        let dh = self.scheme.dh;
+        let n = self.scheme.n as udim;
+        self.scheme.b_qkv.map(|offset| {
+            Tensor::new(
+                self.scheme.dt,
+                &[(nh + nkvh + nkvh) / n * dh],
+                &self.blob[offset..],
+            )
+        })
+    }
+
     #[inline]
     pub fn w_o(&self) -> Tensor<&[u8]> {
         let d = self.scheme.nh * self.scheme.dh;
@@ -160,6 +175,22 @@ impl<'a> Distributer<'a> {
                         .slice(&[slice![d/n + w =>=> w], slice![=>]]),
                 );
         }
+        // bqkv
+        if let (Some(offset), Some(b_qkv)) = (self.scheme.b_qkv, self.model.b_qkv(layer)) {
+            let wq = nh / n * dh;
+            let wkv = nkvh / n * dh;
+            let dkv = nkvh * dh;
+            let shape_b = &[(nh + nkvh + nkvh) * dh / n];
+            for (src, dst, w) in [
+                (wq * i, 0, wq),
+                (d + wkv * i, wq, wkv),
+                (d + dkv + wkv * i, wq + wkv, wkv),
+            ] {
+                b_qkv.clone().slice(&[slice![src =>=> w]]).reform_to(
+                    &mut Tensor::new(dt, shape_b, &mut blob[offset..]).slice(&[slice![dst =>=> w]]),
+                );
+            }
+        }
         // wo
         {
             let w = nh / n * dh;
@@ -227,6 +258,8 @@ pub struct DistributeScheme {
     pub n: usize,
     pub input_layernorm: usize,
     pub w_qkv: usize,
+    /// 模型没有 q、k、v 偏置时为 `None`。
+    pub b_qkv: Option<usize>,
     pub w_o: usize,
     pub post_att_layernorm: usize,
     pub mlp_gate_up: usize,
@@ -237,6 +270,30 @@ pub struct DistributeScheme {
 impl DistributeScheme {
     #[inline]
     fn new(model: &dyn Llama2, n: usize, align: usize) -> Arc<Self> {
+        assert_eq!(
+            model.num_experts(),
+            0,
+            "mixture of experts cannot be distributed"
+        );
+        assert!(
+            model.is_llama_like(),
+            "only llama-style layers can be distributed"
+        );
+        assert!(!model.alibi(), "alibi cannot be distributed");
+        assert!(
+            model.sliding_window().is_none(),
+            "sliding window cannot be distributed"
+        );
+        assert!(
+            model.quantization().is_none(),
+            "quantized model cannot be distributed"
+        );
+        assert!(
+            model.scale_emb().is_none()
+                && model.scale_depth().is_none()
+                && model.dim_model_base().is_none(),
+            "muP scaling cannot be distributed"
+        );
         assert_eq!(model.num_key_value_heads() % n, 0);
         assert_eq!(model.intermediate_size() % n, 0);
         assert!(align.is_power_of_two());
@@ -256,6 +313,9 @@ impl DistributeScheme {
 
         let offset_input_layernorm = align(d);
         let offset_w_qkv = align((nh + nkvh + nkvh) * dh * d / n);
+        let offset_b_qkv = model
+            .attention_bias()
+            .then(|| align((nh + nkvh + nkvh) * dh / n));
         let offset_w_o = align(d * d / n);
         let offset_post_att_layernorm = align(d);
         let offset_mlp_gate_up = align((di + di) * d / n);
@@ -272,6 +332,7 @@ impl DistributeScheme {
             n,
             input_layernorm: offset_input_layernorm,
             w_qkv: offset_w_qkv,
+            b_qkv: offset_b_qkv,
             w_o: offset_w_o,
             post_att_layernorm: offset_post_att_layernorm,
             mlp_gate_up: offset_mlp_gate_up,
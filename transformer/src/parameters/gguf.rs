@@ -0,0 +1,551 @@
+﻿use super::{
+    cast::cast, memory::Layer, safe_tensors::concat0, ConfigJson, LinearScales, Llama2, Memory,
+    Storage,
+};
+use crate::RopeScaling;
+use common::{
+    f16,
+    gguf::{self, GgmlType, Gguf, GgufTensor, GgufTensorRef, GgufValue},
+    quant::{Q5_K, Q6_K},
+    Blob,
+};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Error, ErrorKind::InvalidData, Result},
+    path::Path,
+    sync::Arc,
+};
+use tensor::{slice, udim, DataType, Tensor};
+
+impl Memory {
+    /// 加载 llama.cpp 导出的 `.gguf` 文件，支持 Llama 和 Qwen2 结构的浮点模型和 Q8_0、Q4_0、Q4_K 量化模型。
+    ///
+    /// 混合量化文件中的 Q5_K、Q6_K 张量在加载时反量化为 F16。
+    pub fn load_gguf(path: impl AsRef<Path>) -> Result<Self> {
+        let gguf = Gguf::load(path)?.share();
+        let invalid = |msg: String| Error::new(InvalidData, msg);
+
+        let arch = gguf
+            .get_str("general.architecture")
+            .ok_or_else(|| invalid("missing general.architecture".into()))?;
+        // llama.cpp 已把 Llama 的 q、k 重排为相邻两两成对，其他结构仍按前后两半配对
+        let rotate_half = match arch {
+            "llama" => false,
+            "qwen2" => true,
+            _ => return Err(invalid(format!("unsupported gguf architecture: {arch}"))),
+        };
+        let get = |key: &str| gguf.get(&format!("{arch}.{key}"));
+        let uint = |key: &str| get(key).and_then(GgufValue::as_u64).map(|v| v as usize);
+        let float = |key: &str| get(key).and_then(GgufValue::as_f32);
+        let required =
+            |key: &str| uint(key).ok_or_else(|| invalid(format!("missing {arch}.{key}")));
+        let token = |key: &str| gguf.get(key).and_then(GgufValue::as_u64);
+
+        // 在读取任何张量之前拒绝不支持的类型
+        if let Some((name, info)) = gguf
+            .tensors()
+            .find(|(_, info)| ggml_type(info.ty).is_none() && !dequantized(info.ty))
+        {
+            return Err(invalid(format!(
+                "unsupported gguf tensor type of {name}: {:?}",
+                info.ty
+            )));
+        }
+        let Some(embd) = gguf.tensor_info("token_embd.weight") else {
+            return Err(invalid("missing tensor: token_embd.weight".into()));
+        };
+        // 词嵌入表量化时以 F16 计算，线性层权重以第一层 q 的类型量化
+        let data_type = match ggml_type(embd.ty) {
+            Some(dt) if !dt.is_quantized() => dt,
+            _ => DataType::F16,
+        };
+        let quantization = gguf
+            .tensor_info("blk.0.attn_q.weight")
+            .and_then(|info| ggml_type(info.ty))
+            .filter(DataType::is_quantized);
+        let d = required("embedding_length")?;
+        let nh = required("attention.head_count")?;
+        let dh = uint("attention.key_length").unwrap_or(d / nh);
+        let mut config = json!({
+            "model_type": arch,
+            "bos_token_id": token("tokenizer.ggml.bos_token_id").unwrap_or(1),
+            "eos_token_id": token("tokenizer.ggml.eos_token_id").unwrap_or(2),
+            "hidden_size": d,
+            "intermediate_size": required("feed_forward_length")?,
+            "max_position_embeddings": required("context_length")?,
+            "num_attention_heads": nh,
+            "num_hidden_layers": required("block_count")?,
+            "num_key_value_heads": uint("attention.head_count_kv").unwrap_or(nh),
+            "vocab_size": uint("vocab_size").unwrap_or(embd.shape[0]),
+            "rms_norm_eps": float("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+            "rope_theta": float("rope.freq_base").unwrap_or(1e4),
+            "tie_word_embeddings": !gguf.contains("output.weight"),
+            "attention_bias": gguf.contains("blk.0.attn_q.bias"),
+            "num_local_experts": uint("expert_count").unwrap_or(0),
+            "num_experts_per_tok": uint("expert_used_count").unwrap_or(0),
+            "partial_rotary_factor": uint("rope.dimension_count").map_or(1., |rot| rot as f32 / dh as f32),
+            "torch_dtype": data_type,
+            "quantization": quantization,
+        });
+        if dh * nh != d {
+            config["head_dim"] = json!(dh);
+        }
+        if get("rope.scaling.type").and_then(GgufValue::as_str) == Some("linear") {
+            config["rope_scaling"] = json!({
+                "type": "linear",
+                "factor": float("rope.scaling.factor").unwrap_or(1.),
+            });
+        }
+        let config =
+            serde_json::from_value::<ConfigJson>(config).map_err(|e| invalid(e.to_string()))?;
+
+        let load = |name: &str, target: DataType| {
+            let shared = gguf
+                .share_tensor(name)
+                .ok_or_else(|| invalid(format!("missing tensor: {name}")))?;
+            let Some(dt) = ggml_type(shared.ty()) else {
+                let t = dequantize(&shared).ok_or_else(|| {
+                    invalid(format!(
+                        "unsupported gguf tensor type of {name}: {:?}",
+                        shared.ty()
+                    ))
+                })?;
+                return Ok(cast(t, target));
+            };
+            let mut shape = shared
+                .shape()
+                .iter()
+                .map(|&d| d as udim)
+                .collect::<Vec<_>>();
+            match shape.last_mut() {
+                Some(last) if *last % dt.block_size() as udim == 0 => {
+                    *last /= dt.block_size() as udim
+                }
+                _ => {
+                    return Err(invalid(format!(
+                        "invalid shape of {name}: {:?}",
+                        shared.shape()
+                    )))
+                }
+            }
+            let t = Tensor::new(dt, &shape, Storage::Others(Arc::new(shared)));
+            Ok(cast(t, target))
+        };
+        // 归一化等小张量通常以 F32 保存，统一转换为模型的数据类型
+        let tensor = |name: &str| load(name, data_type);
+        // 个别层的线性层权重可能使用不同的量化类型，统一转换为第一层的类型
+        let linear = |name: &str| load(name, quantization.unwrap_or(data_type));
+
+        let embed_tokens = tensor("token_embd.weight")?;
+        let lm_head = if config.tie_word_embeddings {
+            embed_tokens.clone()
+        } else {
+            tensor("output.weight")?
+        };
+
+        let nh = nh as udim;
+        let nkvh = config.num_key_value_heads as udim;
+        let dh = dh as udim;
+        let ne = config.num_local_experts;
+        let layers = (0..config.num_hidden_layers)
+            .map(|l| {
+                let name = |name: &str| format!("blk.{l}.{name}");
+                let qkv = |kind: &str| {
+                    let proj = |proj: &str| {
+                        let name = name(&format!("attn_{proj}.{kind}"));
+                        if kind == "bias" {
+                            tensor(&name)
+                        } else {
+                            linear(&name)
+                        }
+                    };
+                    let (q, k, v) = (proj("q")?, proj("k")?, proj("v")?);
+                    let cols = q.shape()[1..].to_vec();
+                    if k.shape()[1..] != cols[..] || v.shape()[1..] != cols[..] {
+                        return Err(invalid(format!("mismatched attention shapes in layer {l}")));
+                    }
+                    let q = split_heads(q, nh, dh, rotate_half)?;
+                    let k = split_heads(k, nkvh, dh, rotate_half)?;
+                    let v = split_heads(v, nkvh, dh, false)?;
+                    let qkv = concat0(&[&q, &k, &v], Blob::new);
+                    let shape = [&[(nh + nkvh + nkvh) * dh], &cols[..]].concat();
+                    Ok(qkv.reshape(&shape))
+                };
+                let (moe_gate, mlp_gate_up, mlp_down) = if ne > 0 {
+                    // 专家按 `num_experts x rows x cols` 堆叠，gate 和 up 逐专家拼接
+                    let gate = linear(&name("ffn_gate_exps.weight"))?;
+                    let up = linear(&name("ffn_up_exps.weight"))?;
+                    let &[n, di, cols] = gate.shape() else {
+                        return Err(invalid(format!("invalid expert shape: {:?}", gate.shape())));
+                    };
+                    if n as usize != ne || up.shape() != gate.shape() {
+                        return Err(invalid(format!("mismatched expert shapes in layer {l}")));
+                    }
+                    let expert = |t: &Tensor<Storage>, i: usize| {
+                        t.clone().slice(&[slice![=i], slice![=>], slice![=>]])
+                    };
+                    let gate_up = (0..ne)
+                        .flat_map(|i| [expert(&gate, i), expert(&up, i)])
+                        .collect::<Vec<_>>();
+                    (
+                        Some(tensor(&name("ffn_gate_inp.weight"))?),
+                        concat0(&gate_up.iter().collect::<Vec<_>>(), Blob::new).reshape(&[
+                            ne as _,
+                            di + di,
+                            cols,
+                        ]),
+                        linear(&name("ffn_down_exps.weight"))?,
+                    )
+                } else {
+                    let gate = linear(&name("ffn_gate.weight"))?;
+                    let up = linear(&name("ffn_up.weight"))?;
+                    if up.shape() != gate.shape() {
+                        return Err(invalid(format!("mismatched mlp shapes in layer {l}")));
+                    }
+                    (
+                        None,
+                        concat0(&[&gate, &up], Blob::new),
+                        linear(&name("ffn_down.weight"))?,
+                    )
+                };
+                Ok(Layer {
+                    input_layernorm: tensor(&name("attn_norm.weight"))?,
+                    input_layernorm_bias: None,
+                    w_qkv: qkv("weight")?,
+                    b_qkv: config.attention_bias.then(|| qkv("bias")).transpose()?,
+                    self_attn_o_proj: linear(&name("attn_output.weight"))?,
+                    b_o: None,
+                    attn_output_layernorm: None,
+                    post_attention_layernorm: tensor(&name("ffn_norm.weight"))?,
+                    post_attention_layernorm_bias: None,
+                    moe_gate,
+                    mlp_gate_up,
+                    b_mlp_gate_up: None,
+                    mlp_down,
+                    b_mlp_down: None,
+                    mlp_output_layernorm: None,
+                    scales: LinearScales::default(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            embed_tokens,
+            embed_positions: None,
+            layers,
+            model_norm: tensor("output_norm.weight")?,
+            model_norm_bias: None,
+            lm_head,
+            config,
+        })
+    }
+}
+
+/// gguf 张量类型对应的数据类型，量化类型以块为元素。
+fn ggml_type(ty: GgmlType) -> Option<DataType> {
+    match ty {
+        GgmlType::F32 => Some(DataType::F32),
+        GgmlType::F16 => Some(DataType::F16),
+        GgmlType::BF16 => Some(DataType::BF16),
+        GgmlType::Q8_0 => Some(DataType::Q8_0),
+        GgmlType::Q4_0 => Some(DataType::Q4_0),
+        GgmlType::Q4_K => Some(DataType::Q4_K),
+        _ => None,
+    }
+}
+
+/// 不能直接计算、加载时反量化为 F16 的 gguf 张量类型。
+///
+/// llama.cpp 的 Q4_K_M 等混合量化文件以这些类型保存 `output.weight` 和部分 `ffn_down`、`attn_v`。
+#[inline]
+fn dequantized(ty: GgmlType) -> bool {
+    matches!(ty, GgmlType::Q5_K | GgmlType::Q6_K)
+}
+
+/// 把 [dequantized] 类型的张量反量化为 F16。
+fn dequantize(t: &GgufTensor) -> Option<Tensor<Storage>> {
+    fn blockwise<B: Sync>(x: &[u8], y: &mut [f16], n: usize, dequantize: fn(&B, &mut [f32])) {
+        use rayon::prelude::*;
+        // 块以字节数组组成，不要求对齐；gguf 加载时已检查数据长度
+        let x = unsafe {
+            std::slice::from_raw_parts(x.as_ptr().cast::<B>(), x.len() / std::mem::size_of::<B>())
+        };
+        x.par_iter()
+            .zip(y.par_chunks_exact_mut(n))
+            .for_each(|(x, y)| {
+                let mut buf = [0.; 256];
+                dequantize(x, &mut buf[..n]);
+                for (y, &x) in y.iter_mut().zip(&buf) {
+                    *y = f16::from_f32(x);
+                }
+            });
+    }
+
+    let shape = t.shape().iter().map(|&d| d as udim).collect::<Vec<_>>();
+    let mut ans = Tensor::alloc(DataType::F16, &shape, Blob::new);
+    let y = tensor::reslice_mut(ans.physical_mut());
+    match t.ty() {
+        GgmlType::Q5_K => blockwise(t, y, Q5_K::N, Q5_K::dequantize),
+        GgmlType::Q6_K => blockwise(t, y, Q6_K::N, Q6_K::dequantize),
+        _ => return None,
+    }
+    Some(ans.map_physical(|b| Storage::Others(Arc::new(b))))
+}
+
+/// 把 q、k、v 的权重或偏置按头拆开，以便在第 0 维拼接；
+/// `rotate_half` 时同时把旋转位置编码从前后两半改为相邻两两成对。
+fn split_heads(
+    t: Tensor<Storage>,
+    n: udim,
+    dh: udim,
+    rotate_half: bool,
+) -> Result<Tensor<Storage>> {
+    Ok(match *t.shape() {
+        [rows, d] if rows == n * dh && rotate_half => {
+            t.reshape(&[n, 2, dh / 2, d]).transpose(&[0, 2, 1, 3])
+        }
+        [rows] if rows == n * dh && rotate_half => t.reshape(&[n, 2, dh / 2]).transpose(&[0, 2, 1]),
+        [rows, d] if rows == n * dh => t.reshape(&[n, dh, d]),
+        [rows] if rows == n * dh => t.reshape(&[n, dh]),
+        _ => {
+            return Err(Error::new(
+                InvalidData,
+                format!("invalid attention shape: {:?}", t.shape()),
+            ))
+        }
+    })
+}
+
+/// 把模型保存为 `.gguf` 文件，`tokenizer` 是分词器的元数据。
+///
+/// 支持 Llama、Mistral、Mixtral 和 Qwen2 结构的浮点模型和量化模型，
+/// 张量和元数据按 llama.cpp 的命名保存，可以由 [Memory::load_gguf] 重新加载。
+pub fn save_gguf(
+    model: &dyn Llama2,
+    tokenizer: Vec<(String, GgufValue)>,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let invalid = |msg: String| Error::new(InvalidData, msg);
+    let config = ConfigJson::from(model);
+
+    let (arch, rotate_half) = match config.model_type.as_str() {
+        "llama" | "mistral" | "mixtral" => ("llama", false),
+        "qwen2" => ("qwen2", true),
+        ty => return Err(invalid(format!("unsupported model type: {ty}"))),
+    };
+    // llama.cpp 的 `general.file_type`
+    let file_type = match config.quantization.unwrap_or(config.torch_dtype) {
+        DataType::F32 => 0u32,
+        DataType::F16 => 1,
+        DataType::Q4_0 => 2,
+        DataType::Q8_0 => 7,
+        DataType::Q4_K => 14,
+        DataType::BF16 => 32,
+        dt => return Err(invalid(format!("unsupported data type: {dt:?}"))),
+    };
+
+    let mut metadata = vec![
+        (
+            "general.architecture".into(),
+            GgufValue::String(arch.into()),
+        ),
+        (
+            "general.name".into(),
+            GgufValue::String(config.model_type.clone()),
+        ),
+        ("general.file_type".into(), GgufValue::U32(file_type)),
+    ];
+    let mut arch_meta = |key: &str, value| metadata.push((format!("{arch}.{key}"), value));
+    let uint = |n: usize| GgufValue::U32(n as _);
+    arch_meta("context_length", uint(config.max_position_embeddings));
+    arch_meta("embedding_length", uint(config.hidden_size));
+    arch_meta("block_count", uint(config.num_hidden_layers));
+    arch_meta("feed_forward_length", uint(config.intermediate_size));
+    arch_meta("attention.head_count", uint(config.num_attention_heads));
+    arch_meta("attention.head_count_kv", uint(config.num_key_value_heads));
+    arch_meta(
+        "attention.layer_norm_rms_epsilon",
+        GgufValue::F32(config.rms_norm_eps),
+    );
+    arch_meta("rope.freq_base", GgufValue::F32(config.rope_theta));
+    arch_meta("rope.dimension_count", uint(config.rotary_dim()));
+    arch_meta("vocab_size", uint(config.vocab_size));
+    if let Some(dh) = config.head_dim {
+        arch_meta("attention.key_length", uint(dh));
+        arch_meta("attention.value_length", uint(dh));
+    }
+    if config.num_local_experts > 0 {
+        arch_meta("expert_count", uint(config.num_local_experts));
+        arch_meta("expert_used_count", uint(config.num_experts_per_tok));
+    }
+    match &config.rope_scaling {
+        None => {}
+        Some(RopeScaling::Linear { factor }) => {
+            arch_meta("rope.scaling.type", GgufValue::String("linear".into()));
+            arch_meta("rope.scaling.factor", GgufValue::F32(*factor));
+        }
+        Some(scaling) => return Err(invalid(format!("unsupported rope scaling: {scaling:?}"))),
+    }
+    metadata.push((
+        "tokenizer.ggml.bos_token_id".into(),
+        GgufValue::U32(config.bos_token_id),
+    ));
+    metadata.push((
+        "tokenizer.ggml.eos_token_id".into(),
+        GgufValue::U32(model.eos_token_id()),
+    ));
+    metadata.extend(tokenizer);
+
+    let contiguous = |t: Tensor<Storage>| {
+        if t.is_contiguous() {
+            t
+        } else {
+            concat0(&[&t], Blob::new)
+        }
+    };
+    // 归一化和偏置按 llama.cpp 的习惯以 F32 保存
+    let to_f32 = |t: Tensor<Storage>| {
+        if t.data_type() == DataType::F32 {
+            t
+        } else {
+            cast(contiguous(t), DataType::F32)
+        }
+    };
+    // 非 Llama 结构的 q、k 恢复为前后两半配对
+    let heads = |t: Tensor<Storage>, n: usize| {
+        if !rotate_half {
+            return Ok(t);
+        }
+        let shape = t.shape().to_vec();
+        let (n, dh) = (n as udim, model.head_dim() as udim);
+        let t = match *shape.as_slice() {
+            [rows, d] if rows == n * dh => t.reshape(&[n, dh / 2, 2, d]).transpose(&[0, 2, 1, 3]),
+            [rows] if rows == n * dh => t.reshape(&[n, dh / 2, 2]).transpose(&[0, 2, 1]),
+            _ => return Err(invalid(format!("invalid attention shape: {shape:?}"))),
+        };
+        Ok(concat0(&[&t], Blob::new).reshape(&shape))
+    };
+
+    let nh = config.num_attention_heads;
+    let nkvh = config.num_key_value_heads;
+    let dq = model.q_hidden_size() as udim;
+    let dkv = model.kv_hidden_size() as udim;
+    let di = config.intermediate_size as udim;
+
+    let mut tensors = vec![("token_embd.weight".to_string(), model.embed_tokens())];
+    for l in 0..config.num_hidden_layers {
+        let mut push =
+            |name: &str, t: Tensor<Storage>| tensors.push((format!("blk.{l}.{name}"), t));
+        push("attn_norm.weight", to_f32(model.input_layernorm(l)));
+        push(
+            "attn_q.weight",
+            heads(contiguous(model.self_attn_q_proj(l)), nh)?,
+        );
+        push(
+            "attn_k.weight",
+            heads(contiguous(model.self_attn_k_proj(l)), nkvh)?,
+        );
+        push("attn_v.weight", contiguous(model.self_attn_v_proj(l)));
+        if let Some(b) = model.b_qkv(l) {
+            let bias = |start: udim, len: udim| {
+                let t = b.clone();
+                to_f32(t.slice(&[slice![start =>=> len]]))
+            };
+            push("attn_q.bias", heads(bias(0, dq), nh)?);
+            push("attn_k.bias", heads(bias(dq, dkv), nkvh)?);
+            push("attn_v.bias", bias(dq + dkv, dkv));
+        }
+        push("attn_output.weight", model.self_attn_o_proj(l));
+        push("ffn_norm.weight", to_f32(model.post_attention_layernorm(l)));
+        match model.moe_gate(l) {
+            Some(moe_gate) => {
+                // 专家按 `num_experts x rows x cols` 堆叠保存
+                let gate_up = model.mlp_gate_up(l);
+                let half = |start: udim| {
+                    let t = gate_up.clone();
+                    contiguous(t.slice(&[slice![=>], slice![start =>=> di], slice![=>]]))
+                };
+                push("ffn_gate_inp.weight", moe_gate);
+                push("ffn_gate_exps.weight", half(0));
+                push("ffn_up_exps.weight", half(di));
+                push("ffn_down_exps.weight", model.mlp_down(l));
+            }
+            None => {
+                push("ffn_gate.weight", contiguous(model.mlp_gate(l)));
+                push("ffn_up.weight", contiguous(model.mlp_up(l)));
+                push("ffn_down.weight", model.mlp_down(l));
+            }
+        }
+    }
+    tensors.push(("output_norm.weight".into(), to_f32(model.model_norm())));
+    if !config.tie_word_embeddings {
+        tensors.push(("output.weight".into(), model.lm_head()));
+    }
+
+    // 量化张量以块为元素，保存时恢复为元素数
+    let shapes = tensors
+        .iter()
+        .map(|(_, t)| {
+            let mut shape = t.shape().iter().map(|&d| d as usize).collect::<Vec<_>>();
+            *shape.last_mut().unwrap() *= t.data_type().block_size();
+            shape
+        })
+        .collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .zip(&shapes)
+        .map(|((name, t), shape)| {
+            let ty = match t.data_type() {
+                DataType::F32 => GgmlType::F32,
+                DataType::F16 => GgmlType::F16,
+                DataType::BF16 => GgmlType::BF16,
+                DataType::Q8_0 => GgmlType::Q8_0,
+                DataType::Q4_0 => GgmlType::Q4_0,
+                DataType::Q4_K => GgmlType::Q4_K,
+                dt => return Err(invalid(format!("unsupported data type of {name}: {dt:?}"))),
+            };
+            Ok(GgufTensorRef {
+                name,
+                ty,
+                shape,
+                data: t.as_slice(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    gguf::write(BufWriter::new(File::create(path)?), &metadata, &tensors)
+}
+
+#[test]
+fn test_load_k_quants() {
+    // 8 x 256 的 Q6_K 张量，每行一块
+    let block = [
+        &[0x21; 128][..],
+        &[0b11_10_01_00; 64],
+        &std::array::from_fn::<u8, 16, _>(|i| i as u8 + 1),
+        &f16::ONE.to_le_bytes(),
+    ]
+    .concat();
+    let data = block.repeat(8);
+    let output = GgufTensorRef {
+        name: "output.weight",
+        ty: GgmlType::Q6_K,
+        shape: &[8, 256],
+        data: &data,
+    };
+    let path = common::test_model::tiny_gguf("transformer-test-k-quants", &[output]);
+    let model = Memory::load_gguf(&path);
+    std::fs::remove_file(path).unwrap();
+
+    let model = model.unwrap();
+    assert!(!model.tie_word_embeddings());
+    let lm_head = model.lm_head();
+    assert_eq!(lm_head.shape(), &[8, 256]);
+    assert_eq!(lm_head.data_type(), DataType::F32);
+    let lm_head: &[f32] = tensor::reslice(lm_head.as_slice());
+    for (i, &y) in lm_head[..256].iter().enumerate() {
+        let q = [1, 17, 34, 50][i % 128 / 32] - 32;
+        assert_eq!(y, ((i / 16 + 1) as i32 * q) as f32, "{i}");
+    }
+}
@@ -0,0 +1,270 @@
+﻿use super::{cast::cast, Storage};
+use common::{
+    bf16, f16,
+    safe_tensors::{Dtype, SafeTensors},
+    Blob,
+};
+use serde::Deserialize;
+use std::{pin::Pin, sync::Arc};
+use tensor::{DataType, Tensor};
+
+/// config.json 中的 `quantization_config`，只有 GPTQ 和 AWQ 的权重需要在加载时反量化。
+#[derive(Deserialize, Debug)]
+#[serde(tag = "quant_method", rename_all = "lowercase")]
+pub(super) enum QuantizationConfig {
+    Gptq {
+        bits: u32,
+        /// `-1` 表示每个输出通道只有一组。
+        group_size: i64,
+        #[serde(default)]
+        checkpoint_format: GptqFormat,
+    },
+    Awq {
+        bits: u32,
+        group_size: i64,
+        #[serde(default = "default_zero_point")]
+        zero_point: bool,
+        #[serde(default = "default_awq_version")]
+        version: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// GPTQ 的存储格式，早期格式保存的零点比实际值小 1。
+#[derive(Deserialize, Default, Debug)]
+pub(super) enum GptqFormat {
+    #[default]
+    #[serde(rename = "gptq")]
+    V1,
+    #[serde(rename = "gptq_v2")]
+    V2,
+}
+
+#[inline(always)]
+const fn default_zero_point() -> bool {
+    true
+}
+
+#[inline(always)]
+fn default_awq_version() -> String {
+    "gemm".into()
+}
+
+impl QuantizationConfig {
+    /// 是否需要反量化 `qweight`。
+    #[inline]
+    pub fn is_packed(&self) -> bool {
+        !matches!(self, Self::Other)
+    }
+
+    /// 把 `{base}.qweight`、`{base}.qzeros`、`{base}.scales` 和 `{base}.g_idx` 反量化为 `dt` 类型，
+    /// 形状为 `out x in`。
+    pub fn dequantize(
+        &self,
+        model: &Pin<Arc<SafeTensors>>,
+        base: &str,
+        dt: DataType,
+    ) -> Tensor<Storage> {
+        let get = |name: &str| model.share_tensor(&format!("{base}.{name}"));
+        let int = |name: &str| {
+            let t = get(name).unwrap_or_else(|| panic!("missing tensor: {base}.{name}"));
+            assert_eq!(t.dtype(), Dtype::I32, "invalid type of {base}.{name}");
+            let shape = t.shape().to_vec();
+            let data = t
+                .data()
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect::<Vec<_>>();
+            (shape, data)
+        };
+        let (shape, qweight) = int("qweight");
+        let (_, qzeros) = int("qzeros");
+        let scales = get("scales").unwrap_or_else(|| panic!("missing tensor: {base}.scales"));
+        let &[groups, n] = scales.shape() else {
+            panic!("invalid shape of {base}.scales: {:?}", scales.shape());
+        };
+        let scales = match scales.dtype() {
+            Dtype::F16 => to_f32::<f16>(scales.data(), f16::to_f32),
+            Dtype::BF16 => to_f32::<bf16>(scales.data(), bf16::to_f32),
+            Dtype::F32 => to_f32::<f32>(scales.data(), |x| x),
+            dtype => panic!("invalid type of {base}.scales: {dtype:?}"),
+        };
+        let g_idx = get("g_idx").map(|_| int("g_idx").1);
+
+        let packed = Packed {
+            qweight: &qweight,
+            qzeros: &qzeros,
+            scales: &scales,
+            g_idx: g_idx.as_deref(),
+            groups,
+            n,
+        };
+        let (k, w) = match self {
+            &Self::Gptq {
+                bits,
+                group_size,
+                ref checkpoint_format,
+            } => {
+                assert!(matches!(bits, 2 | 4 | 8), "unsupported gptq bits: {bits}");
+                let k = shape[0] * (32 / bits) as usize;
+                let offset = match checkpoint_format {
+                    GptqFormat::V1 => 1,
+                    GptqFormat::V2 => 0,
+                };
+                (k, packed.gptq(bits, group(group_size, k), k, offset))
+            }
+            &Self::Awq {
+                bits,
+                group_size,
+                zero_point,
+                ref version,
+            } => {
+                assert_eq!(bits, 4, "unsupported awq bits: {bits}");
+                assert!(zero_point, "awq without zero point is not supported");
+                assert!(
+                    version.eq_ignore_ascii_case("gemm"),
+                    "unsupported awq version: {version}"
+                );
+                let k = shape[0];
+                (k, packed.awq(group(group_size, k), k))
+            }
+            Self::Other => unreachable!(),
+        };
+
+        let mut blob = Blob::new(w.len() * 4);
+        for (dst, x) in blob.chunks_exact_mut(4).zip(w) {
+            dst.copy_from_slice(&x.to_le_bytes());
+        }
+        let t = Tensor::new(
+            DataType::F32,
+            &[n as _, k as _],
+            Storage::Others(Arc::new(blob)),
+        );
+        cast(t, dt)
+    }
+}
+
+#[inline]
+fn group(group_size: i64, k: usize) -> usize {
+    if group_size > 0 {
+        group_size as usize
+    } else {
+        k
+    }
+}
+
+fn to_f32<T>(data: &[u8], f: impl Fn(T) -> f32) -> Vec<f32> {
+    data.chunks_exact(std::mem::size_of::<T>())
+        .map(|b| f(unsafe { b.as_ptr().cast::<T>().read_unaligned() }))
+        .collect()
+}
+
+/// 打包的整数权重，`n` 是输出通道数。
+struct Packed<'a> {
+    qweight: &'a [u32],
+    qzeros: &'a [u32],
+    scales: &'a [f32],
+    g_idx: Option<&'a [u32]>,
+    groups: usize,
+    n: usize,
+}
+
+impl Packed<'_> {
+    /// GPTQ 的权重沿输入通道打包，零点沿输出通道打包，`g_idx` 给出每个输入通道的组。
+    fn gptq(&self, bits: u32, group_size: usize, k: usize, offset: u32) -> Vec<f32> {
+        let n = self.n;
+        let per = (32 / bits) as usize;
+        let mask = (1 << bits) - 1;
+        let unpack = |word: u32, i: usize| (word >> (bits as usize * i)) & mask;
+        self.dequantize(k, |i, o| {
+            let g = self.g_idx.map_or(i / group_size, |g_idx| g_idx[i] as usize);
+            assert!(g < self.groups);
+            let q = unpack(self.qweight[i / per * n + o], i % per);
+            let z = unpack(self.qzeros[g * (n / per) + o / per], o % per) + offset;
+            (g, q as f32 - z as f32)
+        })
+    }
+
+    /// AWQ 的权重和零点都沿输出通道打包，每 8 个通道按 `[0, 2, 4, 6, 1, 3, 5, 7]` 交错。
+    fn awq(&self, group_size: usize, k: usize) -> Vec<f32> {
+        const POS: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+        let n = self.n;
+        let unpack = |word: u32, o: usize| (word >> (4 * POS[o % 8])) & 0xf;
+        self.dequantize(k, |i, o| {
+            let g = i / group_size;
+            let q = unpack(self.qweight[i * (n / 8) + o / 8], o);
+            let z = unpack(self.qzeros[g * (n / 8) + o / 8], o);
+            (g, q as f32 - z as f32)
+        })
+    }
+
+    /// 按输出通道并行，`f` 返回第 `i` 个输入通道、第 `o` 个输出通道的组和去掉零点的整数值。
+    fn dequantize(&self, k: usize, f: impl Fn(usize, usize) -> (usize, f32) + Sync) -> Vec<f32> {
+        use rayon::prelude::*;
+        let mut w = vec![0.; self.n * k];
+        w.par_chunks_exact_mut(k).enumerate().for_each(|(o, row)| {
+            for (i, w) in row.iter_mut().enumerate() {
+                let (g, q) = f(i, o);
+                *w = q * self.scales[g * self.n + o];
+            }
+        });
+        w
+    }
+}
+
+#[test]
+fn test_unpack() {
+    let (k, n, group_size) = (16, 8, 8);
+    let q = |i: usize, o: usize| ((i * 7 + o * 3) % 16) as u32;
+    let z = |g: usize, o: usize| ((g + o) % 16) as u32;
+    let scales = (0..2 * n).map(|i| i as f32 * 0.5 + 1.).collect::<Vec<_>>();
+    let ans = |i: usize, o: usize| {
+        let g = i / group_size;
+        (q(i, o) as f32 - z(g, o) as f32) * scales[g * n + o]
+    };
+
+    // GPTQ：8 个输入通道打包为一个整数，零点减 1 保存
+    let qweight = (0..k / 8)
+        .flat_map(|r| (0..n).map(move |o| (0..8).fold(0, |w, j| w | q(r * 8 + j, o) << (4 * j))))
+        .collect::<Vec<_>>();
+    let qzeros = (0..2)
+        .map(|g| (0..8).fold(0, |w, j| w | (z(g, j).wrapping_sub(1) & 0xf) << (4 * j)))
+        .collect::<Vec<_>>();
+    let packed = Packed {
+        qweight: &qweight,
+        qzeros: &qzeros,
+        scales: &scales,
+        g_idx: None,
+        groups: 2,
+        n,
+    };
+    let w = packed.gptq(4, group_size, k, 1);
+    for o in 0..n {
+        for i in 0..k {
+            if z(i / group_size, o) > 0 {
+                assert_eq!(w[o * k + i], ans(i, o));
+            }
+        }
+    }
+
+    // AWQ：8 个输出通道交错打包为一个整数
+    const ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+    let qweight = (0..k)
+        .map(|i| (0..8).fold(0, |w, j| w | q(i, ORDER[j]) << (4 * j)))
+        .collect::<Vec<_>>();
+    let qzeros = (0..2)
+        .map(|g| (0..8).fold(0, |w, j| w | z(g, ORDER[j]) << (4 * j)))
+        .collect::<Vec<_>>();
+    let packed = Packed {
+        qweight: &qweight,
+        qzeros: &qzeros,
+        ..packed
+    };
+    let w = packed.awq(group_size, k);
+    for o in 0..n {
+        for i in 0..k {
+            assert_eq!(w[o * k + i], ans(i, o));
+        }
+    }
+}
@@ -1,33 +1,51 @@
-﻿use super::{ConfigJson, DataType, Llama2, Storage};
+﻿use super::{Activation, ConfigJson, DataType, LinearScales, Llama2, Storage};
+use crate::RopeScaling;
 use common::utok;
 use tensor::{slice, Tensor};
 
 pub struct Memory {
     pub(super) config: ConfigJson,
     pub(super) embed_tokens: Tensor<Storage>,
+    pub(super) embed_positions: Option<Tensor<Storage>>,
     pub(super) layers: Vec<Layer>,
     pub(super) model_norm: Tensor<Storage>,
+    pub(super) model_norm_bias: Option<Tensor<Storage>>,
     pub(super) lm_head: Tensor<Storage>,
 }
 
 pub(super) struct Layer {
     pub input_layernorm: Tensor<Storage>,
+    pub input_layernorm_bias: Option<Tensor<Storage>>,
     pub w_qkv: Tensor<Storage>,
+    pub b_qkv: Option<Tensor<Storage>>,
     pub self_attn_o_proj: Tensor<Storage>,
+    pub b_o: Option<Tensor<Storage>>,
+    pub attn_output_layernorm: Option<Tensor<Storage>>,
     pub post_attention_layernorm: Tensor<Storage>,
+    pub post_attention_layernorm_bias: Option<Tensor<Storage>>,
+    pub moe_gate: Option<Tensor<Storage>>,
     pub mlp_gate_up: Tensor<Storage>,
+    pub b_mlp_gate_up: Option<Tensor<Storage>>,
     pub mlp_down: Tensor<Storage>,
+    pub b_mlp_down: Option<Tensor<Storage>>,
+    pub mlp_output_layernorm: Option<Tensor<Storage>>,
+    pub scales: LinearScales,
 }
 
 impl Llama2 for Memory {
+    #[inline]
+    fn model_type(&self) -> &str {
+        &self.config.model_type
+    }
+
     #[inline]
     fn bos_token_id(&self) -> utok {
         self.config.bos_token_id
     }
 
     #[inline]
-    fn eos_token_id(&self) -> utok {
-        self.config.eos_token_id
+    fn eos_token_ids(&self) -> &[utok] {
+        &self.config.eos_token_id
     }
 
     #[inline]
@@ -60,6 +78,11 @@ impl Llama2 for Memory {
         self.config.num_key_value_heads
     }
 
+    #[inline]
+    fn head_dim(&self) -> usize {
+        self.config.head_dim()
+    }
+
     #[inline]
     fn vocab_size(&self) -> usize {
         self.config.vocab_size
@@ -75,53 +98,155 @@ impl Llama2 for Memory {
         self.config.rope_theta
     }
 
+    #[inline]
+    fn rope_scaling(&self) -> Option<RopeScaling> {
+        self.config.rope_scaling
+    }
+
+    #[inline]
+    fn tie_word_embeddings(&self) -> bool {
+        self.config.tie_word_embeddings
+    }
+
+    #[inline]
+    fn attention_bias(&self) -> bool {
+        self.config.attention_bias
+    }
+
+    #[inline]
+    fn sliding_window(&self) -> Option<usize> {
+        self.config.sliding_window
+    }
+
+    #[inline]
+    fn num_experts(&self) -> usize {
+        self.config.num_local_experts
+    }
+
+    #[inline]
+    fn num_experts_per_tok(&self) -> usize {
+        self.config.num_experts_per_tok
+    }
+
+    #[inline]
+    fn hidden_act(&self) -> Activation {
+        self.config.hidden_act
+    }
+
+    #[inline]
+    fn query_pre_attn_scalar(&self) -> usize {
+        self.config
+            .query_pre_attn_scalar
+            .unwrap_or_else(|| self.head_dim())
+    }
+
+    #[inline]
+    fn attn_logit_softcapping(&self) -> Option<f32> {
+        self.config.attn_logit_softcapping
+    }
+
+    #[inline]
+    fn final_logit_softcapping(&self) -> Option<f32> {
+        self.config.final_logit_softcapping
+    }
+
+    #[inline]
+    fn rotary_dim(&self) -> usize {
+        self.config.rotary_dim()
+    }
+
+    #[inline]
+    fn parallel_residual(&self) -> bool {
+        self.config.parallel_residual
+    }
+
+    #[inline]
+    fn alibi(&self) -> bool {
+        self.config.alibi
+    }
+
+    #[inline]
+    fn scale_emb(&self) -> Option<f32> {
+        self.config.scale_emb
+    }
+
+    #[inline]
+    fn scale_depth(&self) -> Option<f32> {
+        self.config.scale_depth
+    }
+
+    #[inline]
+    fn dim_model_base(&self) -> Option<usize> {
+        self.config.dim_model_base
+    }
+
     #[inline]
     fn data_type(&self) -> DataType {
         self.config.torch_dtype
     }
 
+    #[inline]
+    fn quantization(&self) -> Option<DataType> {
+        self.config.quantization
+    }
+
     #[inline]
     fn embed_tokens(&self) -> Tensor<Storage> {
         self.embed_tokens.clone()
     }
 
+    #[inline]
+    fn embed_positions(&self) -> Option<Tensor<Storage>> {
+        self.embed_positions.clone()
+    }
+
     #[inline]
     fn input_layernorm(&self, layer: usize) -> Tensor<Storage> {
         self.layers[layer].input_layernorm.clone()
     }
 
+    #[inline]
+    fn input_layernorm_bias(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].input_layernorm_bias.clone()
+    }
+
     #[inline]
     fn w_qkv(&self, layer: usize) -> Tensor<Storage> {
         self.layers[layer].w_qkv.clone()
     }
 
+    #[inline]
+    fn b_qkv(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].b_qkv.clone()
+    }
+
     #[inline]
     fn self_attn_q_proj(&self, layer: usize) -> Tensor<Storage> {
-        let d = self.config.hidden_size;
+        let dq = self.q_hidden_size();
         self.layers[layer]
             .w_qkv
             .clone()
-            .slice(&[slice![=>d], slice![=>]])
+            .slice(&[slice![=>dq], slice![=>]])
     }
 
     #[inline]
     fn self_attn_k_proj(&self, layer: usize) -> Tensor<Storage> {
-        let d = self.config.hidden_size;
+        let dq = self.q_hidden_size();
         let dkv = self.kv_hidden_size();
         self.layers[layer]
             .w_qkv
             .clone()
-            .slice(&[slice![d =>=> dkv], slice![=>]])
+            .slice(&[slice![dq =>=> dkv], slice![=>]])
     }
 
     #[inline]
     fn self_attn_v_proj(&self, layer: usize) -> Tensor<Storage> {
-        let d = self.config.hidden_size;
+        let dq = self.q_hidden_size();
         let dkv = self.kv_hidden_size();
         self.layers[layer]
             .w_qkv
             .clone()
-            .slice(&[slice![d + dkv =>=> dkv], slice![=>]])
+            .slice(&[slice![dq + dkv =>=> dkv], slice![=>]])
     }
 
     #[inline]
@@ -129,16 +254,41 @@ impl Llama2 for Memory {
         self.layers[layer].self_attn_o_proj.clone()
     }
 
+    #[inline]
+    fn b_o(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].b_o.clone()
+    }
+
+    #[inline]
+    fn attn_output_layernorm(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].attn_output_layernorm.clone()
+    }
+
     #[inline]
     fn post_attention_layernorm(&self, layer: usize) -> Tensor<Storage> {
         self.layers[layer].post_attention_layernorm.clone()
     }
 
+    #[inline]
+    fn post_attention_layernorm_bias(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].post_attention_layernorm_bias.clone()
+    }
+
+    #[inline]
+    fn moe_gate(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].moe_gate.clone()
+    }
+
     #[inline]
     fn mlp_gate_up(&self, layer: usize) -> Tensor<Storage> {
         self.layers[layer].mlp_gate_up.clone()
     }
 
+    #[inline]
+    fn b_mlp_gate_up(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].b_mlp_gate_up.clone()
+    }
+
     #[inline]
     fn mlp_gate(&self, layer: usize) -> Tensor<Storage> {
         let di = self.config.intermediate_size;
@@ -153,6 +303,11 @@ impl Llama2 for Memory {
         self.layers[layer].mlp_down.clone()
     }
 
+    #[inline]
+    fn b_mlp_down(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].b_mlp_down.clone()
+    }
+
     #[inline]
     fn mlp_up(&self, layer: usize) -> Tensor<Storage> {
         let di = self.config.intermediate_size;
@@ -162,15 +317,30 @@ impl Llama2 for Memory {
             .slice(&[slice![di =>=> di], slice![=>]])
     }
 
+    #[inline]
+    fn mlp_output_layernorm(&self, layer: usize) -> Option<Tensor<Storage>> {
+        self.layers[layer].mlp_output_layernorm.clone()
+    }
+
     #[inline]
     fn model_norm(&self) -> Tensor<Storage> {
         self.model_norm.clone()
     }
 
+    #[inline]
+    fn model_norm_bias(&self) -> Option<Tensor<Storage>> {
+        self.model_norm_bias.clone()
+    }
+
     #[inline]
     fn lm_head(&self) -> Tensor<Storage> {
         self.lm_head.clone()
     }
+
+    #[inline]
+    fn linear_scales(&self, layer: usize) -> LinearScales {
+        self.layers[layer].scales
+    }
 }
 
 #[test]
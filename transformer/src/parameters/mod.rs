@@ -1,122 +1,452 @@
+mod architecture;
 mod cast;
+mod gguf;
+mod gptq;
 mod memory;
 mod safe_tensors;
 mod save;
 mod storage;
 
+use crate::{alibi_slopes, RopeScaling};
 use common::utok;
-use tensor::{DataType, Tensor};
+use tensor::{slice, udim, DataType, Tensor};
 mod distribute;
 
+pub use architecture::{Architecture, ChatTemplate};
 pub use distribute::{DistributeScheme, DistributedLayer, Distributer};
+pub use gguf::save_gguf;
 pub use memory::Memory;
 pub use save::save;
 pub use storage::Storage;
 
 pub trait Llama2 {
+    /// config.json 中的 `model_type`。
+    fn model_type(&self) -> &str;
     fn bos_token_id(&self) -> utok;
-    fn eos_token_id(&self) -> utok;
+    /// 所有句子结束符，至少有一个。
+    fn eos_token_ids(&self) -> &[utok];
     fn hidden_size(&self) -> usize;
     fn intermediate_size(&self) -> usize;
     fn max_position_embeddings(&self) -> usize;
     fn num_attention_heads(&self) -> usize;
     fn num_hidden_layers(&self) -> usize;
     fn num_key_value_heads(&self) -> usize;
+    /// 每个注意力头的维度，Gemma 等模型不等于 `hidden_size / num_attention_heads`。
+    fn head_dim(&self) -> usize;
     fn vocab_size(&self) -> usize;
     fn rms_norm_eps(&self) -> f32;
     fn rope_theta(&self) -> f32;
+    fn rope_scaling(&self) -> Option<RopeScaling>;
+    /// `lm_head` 是否与 `embed_tokens` 共享权重。
+    fn tie_word_embeddings(&self) -> bool;
+    /// q、k、v 投影是否带有偏置。
+    fn attention_bias(&self) -> bool;
+    /// 每个位置只关注包括自身在内的最后若干个位置，`None` 表示关注全部位置。
+    fn sliding_window(&self) -> Option<usize>;
+    /// 混合专家模型每层的专家数，稠密模型为 0。
+    fn num_experts(&self) -> usize;
+    /// 混合专家模型每个 token 选择的专家数。
+    fn num_experts_per_tok(&self) -> usize;
+    /// 前馈网络门控的激活函数。
+    fn hidden_act(&self) -> Activation;
+    /// 注意力分数按 `1 / sqrt(query_pre_attn_scalar)` 缩放，通常等于 `head_dim`。
+    fn query_pre_attn_scalar(&self) -> usize;
+    /// 注意力分数在 softmax 之前的软上限，`cap * tanh(x / cap)`。
+    fn attn_logit_softcapping(&self) -> Option<f32>;
+    /// 输出 logits 的软上限。
+    fn final_logit_softcapping(&self) -> Option<f32>;
+    /// 每个注意力头中做旋转位置编码的维度，使用绝对位置编码或 ALiBi 的模型为 0。
+    fn rotary_dim(&self) -> usize;
+    /// 是否以 ALiBi 线性偏置代替旋转位置编码。
+    fn alibi(&self) -> bool;
+    /// 注意力与前馈网络是否以同一输入并行计算并一起加回残差。
+    fn parallel_residual(&self) -> bool;
+    /// MiniCPM 词嵌入的缩放倍数。
+    fn scale_emb(&self) -> Option<f32>;
+    /// MiniCPM 残差分支的缩放系数，实际倍数为 `scale_depth / sqrt(num_hidden_layers)`。
+    fn scale_depth(&self) -> Option<f32>;
+    /// MiniCPM 输出层之前隐藏状态按 `dim_model_base / hidden_size` 缩放。
+    fn dim_model_base(&self) -> Option<usize>;
     fn data_type(&self) -> DataType;
+    /// 线性层权重的量化类型，按块量化的权重以一块为一个元素，最后一维缩小为块数；
+    /// FP8 权重另有逐张量的缩放系数，见 [Llama2::linear_scales]。
+    fn quantization(&self) -> Option<DataType>;
+
+    /// `model_type` 对应的模型族。
+    #[inline]
+    fn architecture(&self) -> &'static Architecture {
+        Architecture::find_model_type(self.model_type())
+    }
+
+    /// 第 `layer` 层的滑动窗口，Gemma2 只在偶数层使用滑动窗口。
+    #[inline]
+    fn layer_window(&self, layer: usize) -> Option<usize> {
+        let alternating = self.architecture().alternating_window;
+        self.sliding_window()
+            .filter(|_| !alternating || layer.is_multiple_of(2))
+    }
+
+    /// 所有层共用的滑动窗口，只有这时窗口之前的 K-V 缓存才可以释放。
+    #[inline]
+    fn cache_window(&self) -> Option<usize> {
+        self.sliding_window()
+            .filter(|_| !self.architecture().alternating_window)
+    }
+
+    /// 前馈网络是否带门控，GPT 系列只有 up 和 down 两个投影。
+    #[inline]
+    fn gated_mlp(&self) -> bool {
+        self.architecture().gated_mlp()
+    }
+
+    /// 是否只包含 Llama 的计算：RMSNorm、带 SiLU 门控的前馈网络、旋转位置编码，
+    /// 没有 Gemma 和 GPT 系列的额外变换。
+    fn is_llama_like(&self) -> bool {
+        let arch = self.architecture();
+        !arch.scale_embed
+            && arch.norm_offset == 0.
+            && self.gated_mlp()
+            && self.hidden_act() == Activation::Silu
+            && self.attn_logit_softcapping().is_none()
+            && self.final_logit_softcapping().is_none()
+            && self.query_pre_attn_scalar() == self.head_dim()
+            && (self.alibi() || self.rotary_dim() == self.head_dim())
+            && !self.parallel_residual()
+            && self.embed_positions().is_none()
+            && self.model_norm_bias().is_none()
+            && (0..self.num_hidden_layers()).all(|l| self.attn_output_layernorm(l).is_none())
+    }
+
+    /// 使用 ALiBi 时每个注意力头的斜率。
+    #[inline]
+    fn alibi_slopes(&self) -> Option<Vec<f32>> {
+        self.alibi()
+            .then(|| alibi_slopes(self.num_attention_heads()))
+    }
+
+    /// 词嵌入的缩放倍数，Gemma 为 `sqrt(hidden_size)`，MiniCPM 为 `scale_emb`。
+    #[inline]
+    fn embed_scale(&self) -> f32 {
+        if self.architecture().scale_embed {
+            (self.hidden_size() as f32).sqrt()
+        } else {
+            self.scale_emb().unwrap_or(1.)
+        }
+    }
+
+    /// 注意力和前馈网络的输出加回残差前的缩放倍数。
+    #[inline]
+    fn residual_scale(&self) -> f32 {
+        self.scale_depth()
+            .map_or(1., |s| s / (self.num_hidden_layers() as f32).sqrt())
+    }
+
+    /// 输出层计算 logits 时的缩放倍数。
+    #[inline]
+    fn logits_scale(&self) -> f32 {
+        self.dim_model_base()
+            .map_or(1., |base| base as f32 / self.hidden_size() as f32)
+    }
+
+    /// 归一化权重的偏移，Gemma 以 `1 + w` 作为权重。
+    #[inline]
+    fn rms_norm_offset(&self) -> f32 {
+        self.architecture().norm_offset
+    }
+
+    /// 主要的句子结束符。
+    #[inline]
+    fn eos_token_id(&self) -> utok {
+        self.eos_token_ids()[0]
+    }
+
+    /// 所有注意力头的总维度。
+    #[inline]
+    fn q_hidden_size(&self) -> usize {
+        self.num_attention_heads() * self.head_dim()
+    }
 
     #[inline]
     fn kv_hidden_size(&self) -> usize {
-        self.hidden_size() * self.num_key_value_heads() / self.num_attention_heads()
+        self.num_key_value_heads() * self.head_dim()
     }
 
     fn size(&self) -> usize {
         let d = self.hidden_size();
         let dv = self.vocab_size();
+        let dq = self.q_hidden_size();
         let dkv = self.kv_hidden_size();
         let di = self.intermediate_size();
         let l = self.num_hidden_layers();
+        let ne = self.num_experts();
+        let sandwich = self.num_hidden_layers() > 0 && self.attn_output_layernorm(0).is_some();
+        let norm_bias = self.model_norm_bias().is_some();
+        let dense_bias = self.num_hidden_layers() > 0 && self.b_o(0).is_some();
+        let gated = if self.gated_mlp() { 2 } else { 1 };
 
         (d * dv      // embed_tokens
+       + self.embed_positions().map_or(0, |t| t.size()) // embed_positions
        + l * d       // input_layernorm
-       + l * d * d   // self_attn_q_proj
+       + if norm_bias { l * d } else { 0 } // input_layernorm_bias
+       + l * dq * d  // self_attn_q_proj
        + l * dkv * d // self_attn_k_proj
        + l * dkv * d // self_attn_v_proj
-       + l * d * d   // self_attn_o_proj
+       + if self.attention_bias() { l * (dq + dkv + dkv) } else { 0 } // self_attn_qkv_bias
+       + l * d * dq  // self_attn_o_proj
+       + if dense_bias { l * d } else { 0 } // b_o
+       + if sandwich { l * d } else { 0 } // attn_output_layernorm
        + l * d       // post_attention_layernorm
-       + l * di * d  // mlp_gate
-       + l * d * di  // mlp_down
-       + l * di * d  // mlp_up
+       + if norm_bias { l * d } else { 0 } // post_attention_layernorm_bias
+       + l * ne * d  // moe_gate
+       + l * ne.max(1) * di * d * gated // mlp_gate_up
+       + if dense_bias { l * (di * gated + d) } else { 0 } // b_mlp_gate_up + b_mlp_down
+       + l * ne.max(1) * d * di // mlp_down
+       + if sandwich { l * d } else { 0 } // mlp_output_layernorm
        + d           // model_norm
-       + dv * d)     // lm_head
+       + if norm_bias { d } else { 0 } // model_norm_bias
+       + if self.tie_word_embeddings() { 0 } else { dv * d }) // lm_head
        * self.data_type().size()
     }
 
     /// Shape = `vocab_size x hidden_size`.
     fn embed_tokens(&self) -> Tensor<Storage>;
+    /// Shape = `max_position_embeddings x hidden_size`，GPT-2 可学习的绝对位置编码。
+    fn embed_positions(&self) -> Option<Tensor<Storage>>;
     /// Shape = `hidden_size`.
     fn input_layernorm(&self, layer: usize) -> Tensor<Storage>;
+    /// Shape = `hidden_size`，LayerNorm 的偏置，RMSNorm 为 `None`。
+    fn input_layernorm_bias(&self, layer: usize) -> Option<Tensor<Storage>>;
     /// Shape = `(((num_head + num_kv_head + num_kv_head) x head_dim) x hidden_size`.
     fn w_qkv(&self, layer: usize) -> Tensor<Storage>;
+    /// Shape = `((num_head + num_kv_head + num_kv_head) x head_dim)`，与 `w_qkv` 的行对应。
+    fn b_qkv(&self, layer: usize) -> Option<Tensor<Storage>>;
     /// Shape = `(num_kv_head x head_group x head_dim) x hidden_size`.
     fn self_attn_q_proj(&self, layer: usize) -> Tensor<Storage>;
     /// Shape = `(num_kv_head x head_dim) x hidden_size`.
     fn self_attn_k_proj(&self, layer: usize) -> Tensor<Storage>;
     /// Shape = `(num_kv_head x head_dim) x hidden_size`.
     fn self_attn_v_proj(&self, layer: usize) -> Tensor<Storage>;
-    /// Shape = `(num_kv_head x head_group x head_dim) x hidden_size`.
+    /// Shape = `hidden_size x (num_kv_head x head_group x head_dim)`.
     fn self_attn_o_proj(&self, layer: usize) -> Tensor<Storage>;
     /// Shape = `hidden_size`.
+    fn b_o(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `hidden_size`，Gemma2 在加回残差之前对注意力输出做的归一化。
+    fn attn_output_layernorm(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `hidden_size`.
     fn post_attention_layernorm(&self, layer: usize) -> Tensor<Storage>;
-    /// Shape = `(intermediate_size + intermediate_size) x hidden_size`.
+    /// Shape = `hidden_size`.
+    fn post_attention_layernorm_bias(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `num_experts x hidden_size`，稠密模型为 `None`。
+    fn moe_gate(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `(intermediate_size + intermediate_size) x hidden_size`，
+    /// 混合专家模型为 `num_experts x (intermediate_size + intermediate_size) x hidden_size`，
+    /// 不带门控的模型为 `intermediate_size x hidden_size`。
     fn mlp_gate_up(&self, layer: usize) -> Tensor<Storage>;
-    /// Shape = `intermediate_size x hidden_size`.
+    /// 与 `mlp_gate_up` 的行对应。
+    fn b_mlp_gate_up(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `intermediate_size x hidden_size`，仅稠密模型。
     fn mlp_gate(&self, layer: usize) -> Tensor<Storage>;
-    /// Shape = `intermediate_size x hidden_size`.
+    /// Shape = `intermediate_size x hidden_size`，仅稠密模型。
     fn mlp_up(&self, layer: usize) -> Tensor<Storage>;
-    /// Shape = `hidden_size x intermediate_size`.
+    /// Shape = `hidden_size x intermediate_size`，
+    /// 混合专家模型为 `num_experts x hidden_size x intermediate_size`。
     fn mlp_down(&self, layer: usize) -> Tensor<Storage>;
     /// Shape = `hidden_size`.
+    fn b_mlp_down(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `(intermediate_size + intermediate_size) x hidden_size`.
+    fn expert_gate_up(&self, layer: usize, expert: usize) -> Tensor<Storage> {
+        slice_expert(self.mlp_gate_up(layer), expert)
+    }
+    /// Shape = `hidden_size x intermediate_size`.
+    fn expert_down(&self, layer: usize, expert: usize) -> Tensor<Storage> {
+        slice_expert(self.mlp_down(layer), expert)
+    }
+    /// Shape = `hidden_size`，Gemma2 在加回残差之前对前馈网络输出做的归一化。
+    fn mlp_output_layernorm(&self, layer: usize) -> Option<Tensor<Storage>>;
+    /// Shape = `hidden_size`.
     fn model_norm(&self) -> Tensor<Storage>;
+    /// Shape = `hidden_size`.
+    fn model_norm_bias(&self) -> Option<Tensor<Storage>>;
     /// Shape = `vocab_size x hidden_size`.
     fn lm_head(&self) -> Tensor<Storage>;
+    /// 线性层权重的逐张量缩放系数，混合专家模型的所有专家共用。
+    fn linear_scales(&self, layer: usize) -> LinearScales;
 
     fn tensors(&self) -> Vec<Tensor<Storage>> {
         let mut tensors = Vec::with_capacity(self.num_hidden_layers() * 6 + 3);
         tensors.push(self.embed_tokens());
         tensors.push(self.embed_tokens());
+        tensors.extend(self.embed_positions());
         for layer in 0..self.num_hidden_layers() {
             tensors.push(self.input_layernorm(layer));
+            tensors.extend(self.input_layernorm_bias(layer));
             tensors.push(self.w_qkv(layer));
+            tensors.extend(self.b_qkv(layer));
             tensors.push(self.self_attn_o_proj(layer));
+            tensors.extend(self.b_o(layer));
+            tensors.extend(self.attn_output_layernorm(layer));
             tensors.push(self.post_attention_layernorm(layer));
+            tensors.extend(self.post_attention_layernorm_bias(layer));
+            tensors.extend(self.moe_gate(layer));
             tensors.push(self.mlp_gate_up(layer));
+            tensors.extend(self.b_mlp_gate_up(layer));
             tensors.push(self.mlp_down(layer));
+            tensors.extend(self.b_mlp_down(layer));
+            tensors.extend(self.mlp_output_layernorm(layer));
         }
         tensors.push(self.model_norm());
+        tensors.extend(self.model_norm_bias());
         tensors.push(self.lm_head());
         tensors
     }
 }
 
+/// 线性层权重的逐张量缩放系数，权重乘以缩放系数才是实际的值，只有 FP8 权重不为 1。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LinearScales {
+    pub qkv: f32,
+    pub o: f32,
+    pub gate_up: f32,
+    pub down: f32,
+}
+
+impl Default for LinearScales {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            qkv: 1.,
+            o: 1.,
+            gate_up: 1.,
+            down: 1.,
+        }
+    }
+}
+
+/// 前馈网络的激活函数，带门控时作用于 gate。
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Activation {
+    /// SwiGLU，`silu(gate) * up`。
+    #[default]
+    #[serde(rename = "silu", alias = "swish")]
+    Silu,
+    /// GeGLU，`gelu(gate) * up`，GELU 采用 tanh 近似。
+    #[serde(rename = "gelu_pytorch_tanh", alias = "gelu_new")]
+    GeluTanh,
+    /// 精确的 GELU，`x * Φ(x)`。
+    #[serde(rename = "gelu")]
+    Gelu,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 struct ConfigJson {
+    #[serde(default = "default_model_type")]
+    pub model_type: String,
     pub bos_token_id: utok,
-    pub eos_token_id: utok,
+    #[serde(with = "common::one_or_many")]
+    pub eos_token_id: Vec<utok>,
     pub hidden_size: usize,
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
     pub num_attention_heads: usize,
     pub num_hidden_layers: usize,
     pub num_key_value_heads: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_dim: Option<usize>,
     pub vocab_size: usize,
     #[serde(default = "default_rms_norm_eps")]
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    #[serde(
+        default,
+        deserialize_with = "RopeScaling::deserialize_config",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rope_scaling: Option<RopeScaling>,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub attention_bias: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window: Option<usize>,
+    /// Qwen2 等模型配置了 `sliding_window` 但并不使用。
+    #[serde(default = "default_use_sliding_window", skip_serializing)]
+    pub use_sliding_window: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub num_local_experts: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub num_experts_per_tok: usize,
+    #[serde(default)]
+    pub hidden_act: Activation,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_pre_attn_scalar: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_logit_softcapping: Option<f32>,
+    #[serde(
+        default = "default_partial_rotary_factor",
+        skip_serializing_if = "is_one"
+    )]
+    pub partial_rotary_factor: f32,
+    #[serde(default)]
+    pub parallel_residual: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub alibi: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale_emb: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale_depth: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dim_model_base: Option<usize>,
     pub torch_dtype: DataType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization: Option<DataType>,
+    #[serde(default, skip_serializing)]
+    pub quantization_config: Option<gptq::QuantizationConfig>,
+}
+
+impl ConfigJson {
+    #[inline]
+    fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    #[inline]
+    fn rotary_dim(&self) -> usize {
+        if self.alibi {
+            return 0;
+        }
+        (self.head_dim() as f32 * self.partial_rotary_factor) as usize & !1
+    }
+
+    /// 解析 config.json，先由模型族把字段统一为 Llama 的命名。
+    fn from_json(
+        mut json: serde_json::Value,
+        adapt: fn(&mut serde_json::Map<String, serde_json::Value>),
+    ) -> serde_json::Result<Self> {
+        use serde_json::{json, Value};
+
+        if let Value::Object(map) = &mut json {
+            adapt(map);
+            // GPT-2 的 `n_inner` 为空时前馈网络宽度是隐藏层的 4 倍
+            if map.get("intermediate_size").is_none_or(Value::is_null) {
+                let d = map.get("hidden_size").and_then(Value::as_u64).unwrap_or(0);
+                map.insert("intermediate_size".into(), json!(4 * d));
+            }
+            if !map.contains_key("num_key_value_heads") {
+                let nh = map.get("num_attention_heads").cloned().unwrap_or_default();
+                map.insert("num_key_value_heads".into(), nh);
+            }
+        }
+        serde_json::from_value(json)
+    }
+}
+
+#[inline(always)]
+fn default_model_type() -> String {
+    "llama".into()
 }
 
 #[inline(always)]
@@ -129,21 +459,80 @@ const fn default_rope_theta() -> f32 {
     1e4
 }
 
+#[inline(always)]
+const fn default_use_sliding_window() -> bool {
+    true
+}
+
+#[inline(always)]
+const fn default_partial_rotary_factor() -> f32 {
+    1.
+}
+
+#[inline(always)]
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[inline(always)]
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+#[inline(always)]
+fn is_one(x: &f32) -> bool {
+    *x == 1.
+}
+
+/// 从按专家堆叠的权重中取出一个专家。
+fn slice_expert(stacked: Tensor<Storage>, expert: usize) -> Tensor<Storage> {
+    let &[ne, rows, cols] = stacked.shape() else {
+        panic!("not a mixture of experts: {:?}", stacked.shape());
+    };
+    assert!((expert as udim) < ne);
+    stacked
+        .slice(&[slice![=expert], slice![=>], slice![=>]])
+        .reshape(&[rows, cols])
+}
+
 impl From<&dyn Llama2> for ConfigJson {
     fn from(model: &dyn Llama2) -> Self {
         Self {
+            model_type: model.model_type().into(),
             bos_token_id: model.bos_token_id(),
-            eos_token_id: model.eos_token_id(),
+            eos_token_id: model.eos_token_ids().to_vec(),
             hidden_size: model.hidden_size(),
             intermediate_size: model.intermediate_size(),
             max_position_embeddings: model.max_position_embeddings(),
             num_attention_heads: model.num_attention_heads(),
             num_hidden_layers: model.num_hidden_layers(),
             num_key_value_heads: model.num_key_value_heads(),
+            head_dim: Some(model.head_dim())
+                .filter(|&dh| dh * model.num_attention_heads() != model.hidden_size()),
             vocab_size: model.vocab_size(),
             rms_norm_eps: model.rms_norm_eps(),
             rope_theta: model.rope_theta(),
+            rope_scaling: model.rope_scaling(),
+            tie_word_embeddings: model.tie_word_embeddings(),
+            attention_bias: model.attention_bias(),
+            sliding_window: model.sliding_window(),
+            use_sliding_window: true,
+            num_local_experts: model.num_experts(),
+            num_experts_per_tok: model.num_experts_per_tok(),
+            hidden_act: model.hidden_act(),
+            query_pre_attn_scalar: Some(model.query_pre_attn_scalar())
+                .filter(|&s| s != model.head_dim()),
+            attn_logit_softcapping: model.attn_logit_softcapping(),
+            final_logit_softcapping: model.final_logit_softcapping(),
+            partial_rotary_factor: model.rotary_dim() as f32 / model.head_dim() as f32,
+            parallel_residual: model.parallel_residual(),
+            alibi: model.alibi(),
+            scale_emb: model.scale_emb(),
+            scale_depth: model.scale_depth(),
+            dim_model_base: model.dim_model_base(),
             torch_dtype: model.data_type(),
+            quantization: model.quantization(),
+            quantization_config: None,
         }
     }
 }
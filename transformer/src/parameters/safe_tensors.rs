@@ -1,5 +1,12 @@
-﻿use super::{memory::Layer, storage::HostMem, ConfigJson, Memory, Storage};
+﻿use super::{
+    architecture::{Mlp, Qkv, SAVED},
+    cast::rescale_fp8,
+    memory::Layer,
+    storage::HostMem,
+    Architecture, ConfigJson, LinearScales, Memory, Storage,
+};
 use common::{
+    bf16, f16,
     safe_tensors::{
         Dtype, SafeTensors,
         SafeTensorsError::{self, Io, Json},
@@ -19,13 +26,63 @@ impl Memory {
         mut realloc: Option<impl FnMut(usize) -> T>,
     ) -> Result<Self, SafeTensorsError> {
         let config = File::open(model_dir.as_ref().join("config.json")).map_err(Io)?;
-        let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
+        let config = serde_json::from_reader(&config).map_err(Json)?;
+        let arch = Architecture::find(&config);
+        let mut config = ConfigJson::from_json(config, arch.adapt).map_err(Json)?;
+        // 模型族优先按 `architectures` 确定，写回 `model_type` 使 `Llama2::architecture` 与之一致
+        config.model_type = arch.name.into();
+        if !config.use_sliding_window {
+            config.sliding_window = None;
+        }
         let model = SafeTensors::load_from_dir(model_dir)?.share();
+        // 本项目保存的模型总是使用 Llama 的命名
+        let (names, prefix) = [&arch.tensors, SAVED]
+            .into_iter()
+            .find_map(|names| Some((names, names.prefix(|name| model.contains(name))?)))
+            .unwrap_or_else(|| panic!("missing tensor: {}.weight", arch.tensors.embed_tokens));
+        // Qwen2 等模型不在配置中声明偏置，以权重中是否存在偏置为准
+        let has_bias =
+            |name: &str| model.contains(&format!("{prefix}{}.0.{name}.bias", names.layers));
+        config.attention_bias |= match names.qkv {
+            Qkv::Split { fused, q, .. } => has_bias(fused) || has_bias(q),
+            Qkv::Fused(qkv) | Qkv::Interleaved(qkv) => has_bias(qkv),
+        };
+        // Gemma 等模型默认共享权重且不在配置中声明
+        config.tie_word_embeddings |= !model.contains(&format!("{}.weight", names.lm_head));
 
+        let torch_dtype = config.torch_dtype;
+        let quantization = config.quantization;
+        let packed = config
+            .quantization_config
+            .take()
+            .filter(|config| config.is_packed());
         let tensor = |name: &str| {
+            // GPTQ 和 AWQ 的线性层权重在加载时反量化，形状与 `nn.Linear` 一致
+            if let Some((packed, base)) =
+                packed
+                    .as_ref()
+                    .zip(name.strip_suffix(".weight"))
+                    .filter(|(_, base)| {
+                        !model.contains(name) && model.contains(&format!("{base}.qweight"))
+                    })
+            {
+                let w = packed.dequantize(&model, base, torch_dtype);
+                return if names.conv1d {
+                    w.transpose(&[1, 0])
+                } else {
+                    w
+                };
+            }
             let shared = model
                 .share_tensor(name)
                 .unwrap_or_else(|| panic!("missing tensor: {name}"));
+            let shape = shared.shape().iter().map(|&d| d as udim).collect::<Shape>();
+            // 量化的块保存为字节，最后一维是块的字节数
+            if let (Some(dt), Dtype::U8) = (quantization, shared.dtype()) {
+                let (block, shape) = shape.split_last().unwrap();
+                assert_eq!(*block as usize, dt.size());
+                return Tensor::new(dt, shape, Storage::SafeTensor(shared));
+            }
             let data_type = match shared.dtype() {
                 Dtype::BOOL => DataType::Bool,
                 Dtype::I8 => DataType::I8,
@@ -40,78 +97,304 @@ impl Memory {
                 Dtype::BF16 => DataType::BF16,
                 Dtype::F32 => DataType::F32,
                 Dtype::F64 => DataType::F64,
+                Dtype::F8_E4M3 => DataType::F8_E4M3,
+                Dtype::F8_E5M2 => DataType::F8_E5M2,
                 _ => unreachable!(),
             };
-            assert_eq!(data_type, config.torch_dtype);
-            Tensor::new(
-                data_type,
-                &shared.shape().iter().map(|&d| d as udim).collect::<Shape>(),
-                Storage::SafeTensor(shared),
-            )
+            // FP8 的线性层权重与其他参数的类型不同
+            assert!(data_type == torch_dtype || data_type.is_fp8());
+            Tensor::new(data_type, &shape, Storage::SafeTensor(shared))
+        };
+        let optional = |name: &str| model.contains(name).then(|| tensor(name));
+        // FP8 权重的逐张量缩放系数，其他权重为 1
+        let scale = |name: &str| {
+            assert!(
+                !model.contains(&format!("{name}_scale_inv")),
+                "block-wise fp8 scales are not supported: {name}"
+            );
+            let Some(shared) = model.share_tensor(&format!("{name}_scale")) else {
+                return 1.;
+            };
+            assert_eq!(
+                shared.shape().iter().product::<usize>(),
+                1,
+                "only per-tensor fp8 scales are supported: {name}"
+            );
+            let data = shared.data();
+            match shared.dtype() {
+                Dtype::F32 => f32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                Dtype::BF16 => bf16::from_le_bytes([data[0], data[1]]).to_f32(),
+                Dtype::F16 => f16::from_le_bytes([data[0], data[1]]).to_f32(),
+                dtype => panic!("invalid scale type of {name}: {dtype:?}"),
+            }
         };
+        let scaled = |name: &str| (tensor(name), scale(name));
 
-        Ok(Self {
-            embed_tokens: tensor("model.embed_tokens.weight"),
-            layers: (0..config.num_hidden_layers)
-                .map(|l| {
-                    let name = |name: &str| format!("model.layers.{l}.{name}.weight");
-                    Layer {
-                        input_layernorm: tensor(&name("input_layernorm")),
-                        w_qkv: {
-                            let qkv = name("self_attn.qkv_proj");
-                            if model.contains(&qkv) {
-                                tensor(&qkv)
-                            } else if let Some(realloc) = realloc.as_mut() {
-                                let d = config.hidden_size as udim;
-                                let nkvh = config.num_key_value_heads as udim;
-                                let nh = config.num_attention_heads as udim;
-                                let dkv = d * nkvh / nh;
-                                let sq = &[nh, 2, d / nh / 2, d];
-                                let skv = &[nkvh, 2, dkv / nkvh / 2, d];
-                                let perm = &[0, 2, 1, 3];
-
-                                let q = tensor(&name("self_attn.q_proj"))
-                                    .reshape(sq)
-                                    .transpose(perm);
-                                let k = tensor(&name("self_attn.k_proj"))
-                                    .reshape(skv)
-                                    .transpose(perm);
-                                let v = tensor(&name("self_attn.v_proj")).reshape(skv);
-                                concat0(&[&q, &k, &v], realloc).reshape(&[d + dkv + dkv, d])
-                            } else {
-                                panic!("missing concat tensor: {qkv}");
-                            }
-                        },
-                        self_attn_o_proj: tensor(&name("self_attn.o_proj")),
-                        post_attention_layernorm: tensor(&name("post_attention_layernorm")),
-                        mlp_gate_up: {
-                            let gate_up = name("mlp.gate_up_proj");
-                            if model.contains(&gate_up) {
-                                tensor(&gate_up)
-                            } else if let Some(realloc) = realloc.as_mut() {
-                                concat0(
-                                    &[
-                                        &tensor(&name("mlp.gate_proj")),
-                                        &tensor(&name("mlp.up_proj")),
-                                    ],
-                                    realloc,
-                                )
+        let embed_tokens = tensor(&format!("{prefix}{}.weight", names.embed_tokens));
+        // 共享权重的模型直接以词嵌入表作为输出层
+        let lm_head = if config.tie_word_embeddings {
+            embed_tokens.clone()
+        } else {
+            tensor(&format!("{}.weight", names.lm_head))
+        };
+
+        let d = config.hidden_size as udim;
+        let nkvh = config.num_key_value_heads as udim;
+        let nh = config.num_attention_heads as udim;
+        let dh = config.head_dim() as udim;
+        let dq = nh * dh;
+        let dkv = nkvh * dh;
+        let di = config.intermediate_size as udim;
+        let ne = config.num_local_experts;
+        // 按头交错存储的 qkv 重排为 q、k、v 依次排列，
+        // 同时把 q、k 前 `rot` 维的旋转位置编码从前后两半改为相邻两两成对
+        let rows: Vec<usize> = if let Qkv::Interleaved(_) = names.qkv {
+            let (nh, dh, rot) = (nh as usize, dh as usize, config.rotary_dim());
+            (0..3)
+                .flat_map(|i| {
+                    (0..nh).flat_map(move |h| {
+                        (0..dh).map(move |j| {
+                            let j = if i < 2 && j < rot {
+                                j % 2 * (rot / 2) + j / 2
                             } else {
-                                panic!("missing concat tensor: {gate_up}");
-                            }
-                        },
-                        mlp_down: tensor(&name("mlp.down_proj")),
-                    }
+                                j
+                            };
+                            (h * 3 + i) * dh + j
+                        })
+                    })
                 })
-                .collect(),
-            model_norm: tensor("model.norm.weight"),
-            lm_head: tensor("lm_head.weight"),
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let mut layers = Vec::with_capacity(config.num_hidden_layers);
+        for l in 0..config.num_hidden_layers {
+            let name = |name: &str| format!("{prefix}{}.{l}.{name}.weight", names.layers);
+            let bias = |name: &str| format!("{prefix}{}.{l}.{name}.bias", names.layers);
+            let ((w_qkv, qkv_scale), b_qkv) = match names.qkv {
+                Qkv::Split { fused, q, k, v } => {
+                    let w_qkv = if model.contains(&name(fused)) {
+                        scaled(&name(fused))
+                    } else if let Some(realloc) = realloc.as_mut() {
+                        let sq = &[nh, 2, dh / 2, d];
+                        let skv = &[nkvh, 2, dh / 2, d];
+                        let perm = &[0, 2, 1, 3];
+
+                        let (qkv, scale) = unify_scales(vec![
+                            scaled(&name(q)),
+                            scaled(&name(k)),
+                            scaled(&name(v)),
+                        ]);
+                        let Ok([q, k, v]) = <[_; 3]>::try_from(qkv) else {
+                            unreachable!()
+                        };
+                        let q = q.reshape(sq).transpose(perm);
+                        let k = k.reshape(skv).transpose(perm);
+                        let v = v.reshape(skv);
+                        let qkv = concat0(&[&q, &k, &v], realloc).reshape(&[dq + dkv + dkv, d]);
+                        (qkv, scale)
+                    } else {
+                        panic!("missing concat tensor: {}", name(fused));
+                    };
+                    let b_qkv = config.attention_bias.then(|| {
+                        if model.contains(&bias(fused)) {
+                            tensor(&bias(fused))
+                        } else if let Some(realloc) = realloc.as_mut() {
+                            // 偏置与权重的行做相同的重排
+                            let sq = &[nh, 2, dh / 2];
+                            let skv = &[nkvh, 2, dh / 2];
+                            let perm = &[0, 2, 1];
+
+                            let q = tensor(&bias(q)).reshape(sq).transpose(perm);
+                            let k = tensor(&bias(k)).reshape(skv).transpose(perm);
+                            let v = tensor(&bias(v)).reshape(skv);
+                            concat0(&[&q, &k, &v], realloc).reshape(&[dq + dkv + dkv])
+                        } else {
+                            panic!("missing concat tensor: {}", bias(fused));
+                        }
+                    });
+                    (w_qkv, b_qkv)
+                }
+                Qkv::Fused(qkv) => (
+                    (
+                        linear(tensor(&name(qkv)), names.conv1d, realloc.as_mut()),
+                        scale(&name(qkv)),
+                    ),
+                    optional(&bias(qkv)),
+                ),
+                Qkv::Interleaved(qkv) => {
+                    let realloc = realloc.as_mut().expect("interleaved qkv must be reordered");
+                    (
+                        (
+                            gather0(&tensor(&name(qkv)), &rows, &mut *realloc),
+                            scale(&name(qkv)),
+                        ),
+                        optional(&bias(qkv)).map(|b| gather0(&b, &rows, realloc)),
+                    )
+                }
+            };
+            // Gemma2 的 `post_attention_layernorm` 作用于注意力输出，前馈网络之前另有归一化
+            let sandwich = names.sandwich.filter(|(pre, _)| model.contains(&name(pre)));
+            let post_attention_layernorm =
+                sandwich.map_or(names.post_attention_layernorm, |(pre, _)| pre);
+            let (
+                moe_gate,
+                (mlp_gate_up, gate_up_scale),
+                b_mlp_gate_up,
+                (mlp_down, down_scale),
+                b_mlp_down,
+            ) = match (&names.moe, &names.mlp) {
+                (Some(moe), _) if ne > 0 => {
+                    let expert =
+                        |i: usize, w: &str| scaled(&name(&format!("{}.{i}.{w}", moe.experts)));
+                    let gate_up = name(&format!("{}.gate_up_proj", moe.experts));
+                    let down = name(&format!("{}.down_proj", moe.experts));
+                    let (gate_up, down) = if model.contains(&gate_up) {
+                        (scaled(&gate_up), scaled(&down))
+                    } else if let Some(realloc) = realloc.as_mut() {
+                        // 所有专家共用一个缩放系数
+                        let (gate_up, gate_up_scale) = unify_scales(
+                            (0..ne)
+                                .flat_map(|i| [expert(i, moe.gate_proj), expert(i, moe.up_proj)])
+                                .collect(),
+                        );
+                        let (down, down_scale) =
+                            unify_scales((0..ne).map(|i| expert(i, moe.down_proj)).collect());
+                        (
+                            (
+                                concat0(&gate_up.iter().collect::<Vec<_>>(), &mut *realloc)
+                                    .reshape(&[ne as _, di + di, d]),
+                                gate_up_scale,
+                            ),
+                            (
+                                concat0(&down.iter().collect::<Vec<_>>(), &mut *realloc)
+                                    .reshape(&[ne as _, d, di]),
+                                down_scale,
+                            ),
+                        )
+                    } else {
+                        panic!("missing concat tensor: {gate_up}");
+                    };
+                    (Some(tensor(&name(moe.gate))), gate_up, None, down, None)
+                }
+                (
+                    _,
+                    &Mlp::Gated {
+                        gate_up,
+                        gate,
+                        up,
+                        down,
+                    },
+                ) => {
+                    let w_gate_up = if model.contains(&name(gate_up)) {
+                        scaled(&name(gate_up))
+                    } else if let Some(realloc) = realloc.as_mut() {
+                        let (gate_up, scale) =
+                            unify_scales(vec![scaled(&name(gate)), scaled(&name(up))]);
+                        (concat0(&[&gate_up[0], &gate_up[1]], realloc), scale)
+                    } else {
+                        panic!("missing concat tensor: {}", name(gate_up));
+                    };
+                    (
+                        None,
+                        w_gate_up,
+                        optional(&bias(gate_up)),
+                        scaled(&name(down)),
+                        optional(&bias(down)),
+                    )
+                }
+                (_, &Mlp::Dense { up, down }) => (
+                    None,
+                    (
+                        linear(tensor(&name(up)), names.conv1d, realloc.as_mut()),
+                        scale(&name(up)),
+                    ),
+                    optional(&bias(up)),
+                    (
+                        linear(tensor(&name(down)), names.conv1d, realloc.as_mut()),
+                        scale(&name(down)),
+                    ),
+                    optional(&bias(down)),
+                ),
+            };
+            layers.push(Layer {
+                input_layernorm: tensor(&name(names.input_layernorm)),
+                input_layernorm_bias: optional(&bias(names.input_layernorm)),
+                w_qkv,
+                b_qkv,
+                self_attn_o_proj: linear(
+                    tensor(&name(names.o_proj)),
+                    names.conv1d,
+                    realloc.as_mut(),
+                ),
+                b_o: optional(&bias(names.o_proj)),
+                attn_output_layernorm: sandwich
+                    .map(|_| tensor(&name(names.post_attention_layernorm))),
+                post_attention_layernorm: tensor(&name(post_attention_layernorm)),
+                post_attention_layernorm_bias: optional(&bias(post_attention_layernorm)),
+                moe_gate,
+                mlp_gate_up,
+                b_mlp_gate_up,
+                mlp_down,
+                b_mlp_down,
+                mlp_output_layernorm: sandwich.map(|(_, post)| tensor(&name(post))),
+                scales: LinearScales {
+                    qkv: qkv_scale,
+                    o: scale(&name(names.o_proj)),
+                    gate_up: gate_up_scale,
+                    down: down_scale,
+                },
+            });
+        }
+        // FP8 的线性层权重按量化类型记录在配置中
+        if let Some(dt) = layers
+            .first()
+            .map(|l| l.w_qkv.data_type())
+            .filter(DataType::is_fp8)
+        {
+            config.quantization = Some(dt);
+        }
+
+        Ok(Self {
+            embed_tokens,
+            embed_positions: names
+                .embed_positions
+                .and_then(|name| optional(&format!("{prefix}{name}.weight"))),
+            layers,
+            model_norm: tensor(&format!("{prefix}{}.weight", names.model_norm)),
+            model_norm_bias: optional(&format!("{prefix}{}.bias", names.model_norm)),
+            lm_head,
             config,
         })
     }
 }
 
-fn concat0<T: HostMem + DerefMut<Target = [u8]>>(
+/// 拼接之前把各个 FP8 权重统一到最大的缩放系数，非 FP8 权重的缩放系数都是 1。
+fn unify_scales(tensors: Vec<(Tensor<Storage>, f32)>) -> (Vec<Tensor<Storage>>, f32) {
+    let max = tensors.iter().map(|(_, s)| *s).fold(0., f32::max);
+    let tensors = tensors
+        .into_iter()
+        .map(|(t, s)| if s == max { t } else { rescale_fp8(t, s / max) })
+        .collect();
+    (tensors, max)
+}
+
+/// Conv1D 的权重按 `in x out` 存储，转置为 `out x in`。
+fn linear<T: HostMem + DerefMut<Target = [u8]>>(
+    w: Tensor<Storage>,
+    conv1d: bool,
+    realloc: Option<&mut impl FnMut(usize) -> T>,
+) -> Tensor<Storage> {
+    if conv1d {
+        let realloc = realloc.expect("conv1d weights must be transposed");
+        concat0(&[&w.transpose(&[1, 0])], realloc)
+    } else {
+        w
+    }
+}
+
+pub(super) fn concat0<T: HostMem + DerefMut<Target = [u8]>>(
     tensors: &[&Tensor<Storage>],
     realloc: impl FnOnce(usize) -> T,
 ) -> Tensor<Storage> {
@@ -133,3 +416,23 @@ fn concat0<T: HostMem + DerefMut<Target = [u8]>>(
     }
     ans.map_physical(|b| Storage::Others(Arc::new(b)))
 }
+
+/// 按行号从第 0 维取出若干行，依次排列为新的张量。
+fn gather0<T: HostMem + DerefMut<Target = [u8]>>(
+    tensor: &Tensor<Storage>,
+    rows: &[usize],
+    realloc: impl FnOnce(usize) -> T,
+) -> Tensor<Storage> {
+    assert!(tensor.is_contiguous());
+
+    let mut shape = Shape::from_slice(tensor.shape());
+    let len = tensor.bytes_size() / shape[0] as usize;
+    shape[0] = rows.len() as _;
+
+    let src = tensor.as_slice();
+    let mut ans = Tensor::alloc(tensor.data_type(), &shape, realloc);
+    for (dst, &row) in ans.physical_mut().chunks_exact_mut(len).zip(rows) {
+        dst.copy_from_slice(&src[row * len..][..len]);
+    }
+    ans.map_physical(|b| Storage::Others(Arc::new(b)))
+}
@@ -1,10 +1,14 @@
 ﻿use super::{ConfigJson, Llama2, Storage};
-use common::safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo};
+use common::{
+    safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo},
+    Blob,
+};
 use std::{
     collections::HashMap,
     fs,
     io::{self, BufWriter, Write},
     path::Path,
+    sync::Arc,
 };
 use tensor::{DataType, Tensor};
 
@@ -22,8 +26,9 @@ pub fn save(model: &dyn Llama2, dir: impl AsRef<Path>) -> io::Result<()> {
         },
     };
 
-    let mut tensor_info = |tensor: Tensor<Storage>| TensorInfo {
-        dtype: match tensor.data_type() {
+    let mut tensor_info = |tensor: Tensor<Storage>| {
+        let mut shape = tensor.shape().iter().map(|&d| d as _).collect::<Vec<_>>();
+        let dtype = match tensor.data_type() {
             DataType::Bool => Dtype::BOOL,
             DataType::I8 => Dtype::I8,
             DataType::I16 => Dtype::I16,
@@ -37,51 +42,165 @@ pub fn save(model: &dyn Llama2, dir: impl AsRef<Path>) -> io::Result<()> {
             DataType::BF16 => Dtype::BF16,
             DataType::F32 => Dtype::F32,
             DataType::F64 => Dtype::F64,
-        },
-        shape: tensor.shape().iter().map(|&d| d as _).collect(),
-        data_offsets: {
-            let start = offset;
-            offset += tensor.bytes_size();
-            (start, offset)
-        },
+            DataType::F8_E4M3 => Dtype::F8_E4M3,
+            DataType::F8_E5M2 => Dtype::F8_E5M2,
+            // 量化的块保存为字节，块的类型记录在配置中
+            dt @ (DataType::Q8_0 | DataType::Q4_0 | DataType::Q4_K) => {
+                shape.push(dt.size());
+                Dtype::U8
+            }
+        };
+        TensorInfo {
+            dtype,
+            shape,
+            data_offsets: {
+                let start = offset;
+                offset += tensor.bytes_size();
+                (start, offset)
+            },
+        }
     };
 
     header.tensors.insert(
         "model.embed_tokens.weight".into(),
         tensor_info(model.embed_tokens()),
     );
+    if let Some(embed_positions) = model.embed_positions() {
+        header.tensors.insert(
+            "model.embed_positions.weight".into(),
+            tensor_info(embed_positions),
+        );
+    }
     for layer in 0..model.num_hidden_layers() {
         header.tensors.insert(
             format!("model.layers.{layer}.input_layernorm.weight"),
             tensor_info(model.input_layernorm(layer)),
         );
+        if let Some(bias) = model.input_layernorm_bias(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.input_layernorm.bias"),
+                tensor_info(bias),
+            );
+        }
+        let scales = model.linear_scales(layer);
         header.tensors.insert(
             format!("model.layers.{layer}.self_attn.qkv_proj.weight"),
             tensor_info(model.w_qkv(layer)),
         );
+        if let Some(scale) = weight_scale(&model.w_qkv(layer), scales.qkv) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.self_attn.qkv_proj.weight_scale"),
+                tensor_info(scale),
+            );
+        }
+        if let Some(b_qkv) = model.b_qkv(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.self_attn.qkv_proj.bias"),
+                tensor_info(b_qkv),
+            );
+        }
         header.tensors.insert(
             format!("model.layers.{layer}.self_attn.o_proj.weight"),
             tensor_info(model.self_attn_o_proj(layer)),
         );
+        if let Some(scale) = weight_scale(&model.self_attn_o_proj(layer), scales.o) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.self_attn.o_proj.weight_scale"),
+                tensor_info(scale),
+            );
+        }
+        if let Some(b_o) = model.b_o(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.self_attn.o_proj.bias"),
+                tensor_info(b_o),
+            );
+        }
+        // Gemma2 的注意力输出归一化沿用 `post_attention_layernorm` 的名字
+        let post_attention_layernorm = match model.attn_output_layernorm(layer) {
+            Some(attn_output_layernorm) => {
+                header.tensors.insert(
+                    format!("model.layers.{layer}.post_attention_layernorm.weight"),
+                    tensor_info(attn_output_layernorm),
+                );
+                "pre_feedforward_layernorm"
+            }
+            None => "post_attention_layernorm",
+        };
         header.tensors.insert(
-            format!("model.layers.{layer}.post_attention_layernorm.weight"),
+            format!("model.layers.{layer}.{post_attention_layernorm}.weight"),
             tensor_info(model.post_attention_layernorm(layer)),
         );
+        if let Some(bias) = model.post_attention_layernorm_bias(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.{post_attention_layernorm}.bias"),
+                tensor_info(bias),
+            );
+        }
+        // 混合专家模型的专家权重按专家堆叠保存
+        let mlp = match model.moe_gate(layer) {
+            Some(moe_gate) => {
+                header.tensors.insert(
+                    format!("model.layers.{layer}.block_sparse_moe.gate.weight"),
+                    tensor_info(moe_gate),
+                );
+                "block_sparse_moe.experts"
+            }
+            None => "mlp",
+        };
         header.tensors.insert(
-            format!("model.layers.{layer}.mlp.gate_up_proj.weight"),
+            format!("model.layers.{layer}.{mlp}.gate_up_proj.weight"),
             tensor_info(model.mlp_gate_up(layer)),
         );
+        if let Some(scale) = weight_scale(&model.mlp_gate_up(layer), scales.gate_up) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.{mlp}.gate_up_proj.weight_scale"),
+                tensor_info(scale),
+            );
+        }
+        if let Some(bias) = model.b_mlp_gate_up(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.{mlp}.gate_up_proj.bias"),
+                tensor_info(bias),
+            );
+        }
         header.tensors.insert(
-            format!("model.layers.{layer}.mlp.down_proj.weight"),
+            format!("model.layers.{layer}.{mlp}.down_proj.weight"),
             tensor_info(model.mlp_down(layer)),
         );
+        if let Some(scale) = weight_scale(&model.mlp_down(layer), scales.down) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.{mlp}.down_proj.weight_scale"),
+                tensor_info(scale),
+            );
+        }
+        if let Some(bias) = model.b_mlp_down(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.{mlp}.down_proj.bias"),
+                tensor_info(bias),
+            );
+        }
+        if let Some(mlp_output_layernorm) = model.mlp_output_layernorm(layer) {
+            header.tensors.insert(
+                format!("model.layers.{layer}.post_feedforward_layernorm.weight"),
+                tensor_info(mlp_output_layernorm),
+            );
+        }
     }
     header
         .tensors
         .insert("model.norm.weight".into(), tensor_info(model.model_norm()));
-    header
-        .tensors
-        .insert("lm_head.weight".into(), tensor_info(model.lm_head()));
+    if let Some(bias) = model.model_norm_bias() {
+        header
+            .tensors
+            .insert("model.norm.bias".into(), tensor_info(bias));
+    }
+    // 共享权重的模型不保存输出层
+    let tied = model.tie_word_embeddings();
+    if !tied {
+        header
+            .tensors
+            .insert("lm_head.weight".into(), tensor_info(model.lm_head()));
+    }
 
     let header = {
         let str = serde_json::to_string(&header)?;
@@ -103,15 +222,72 @@ pub fn save(model: &dyn Llama2, dir: impl AsRef<Path>) -> io::Result<()> {
     let mut file = fs::File::create(dir.join("model.safetensors"))?;
     file.write_all(&header)?;
     file.write_all(model.embed_tokens().as_slice())?;
+    if let Some(embed_positions) = model.embed_positions() {
+        file.write_all(embed_positions.as_slice())?;
+    }
     for layer in 0..model.num_hidden_layers() {
         file.write_all(model.input_layernorm(layer).as_slice())?;
+        if let Some(bias) = model.input_layernorm_bias(layer) {
+            file.write_all(bias.as_slice())?;
+        }
+        let scales = model.linear_scales(layer);
         file.write_all(model.w_qkv(layer).as_slice())?;
+        if let Some(scale) = weight_scale(&model.w_qkv(layer), scales.qkv) {
+            file.write_all(scale.as_slice())?;
+        }
+        if let Some(b_qkv) = model.b_qkv(layer) {
+            file.write_all(b_qkv.as_slice())?;
+        }
         file.write_all(model.self_attn_o_proj(layer).as_slice())?;
+        if let Some(scale) = weight_scale(&model.self_attn_o_proj(layer), scales.o) {
+            file.write_all(scale.as_slice())?;
+        }
+        if let Some(b_o) = model.b_o(layer) {
+            file.write_all(b_o.as_slice())?;
+        }
+        if let Some(attn_output_layernorm) = model.attn_output_layernorm(layer) {
+            file.write_all(attn_output_layernorm.as_slice())?;
+        }
         file.write_all(model.post_attention_layernorm(layer).as_slice())?;
+        if let Some(bias) = model.post_attention_layernorm_bias(layer) {
+            file.write_all(bias.as_slice())?;
+        }
+        if let Some(moe_gate) = model.moe_gate(layer) {
+            file.write_all(moe_gate.as_slice())?;
+        }
         file.write_all(model.mlp_gate_up(layer).as_slice())?;
+        if let Some(scale) = weight_scale(&model.mlp_gate_up(layer), scales.gate_up) {
+            file.write_all(scale.as_slice())?;
+        }
+        if let Some(bias) = model.b_mlp_gate_up(layer) {
+            file.write_all(bias.as_slice())?;
+        }
         file.write_all(model.mlp_down(layer).as_slice())?;
+        if let Some(scale) = weight_scale(&model.mlp_down(layer), scales.down) {
+            file.write_all(scale.as_slice())?;
+        }
+        if let Some(bias) = model.b_mlp_down(layer) {
+            file.write_all(bias.as_slice())?;
+        }
+        if let Some(mlp_output_layernorm) = model.mlp_output_layernorm(layer) {
+            file.write_all(mlp_output_layernorm.as_slice())?;
+        }
     }
     file.write_all(model.model_norm().as_slice())?;
-    file.write_all(model.lm_head().as_slice())?;
+    if let Some(bias) = model.model_norm_bias() {
+        file.write_all(bias.as_slice())?;
+    }
+    if !tied {
+        file.write_all(model.lm_head().as_slice())?;
+    }
     Ok(())
 }
+
+/// FP8 权重的逐张量缩放系数，以 F32 标量保存在权重之后。
+fn weight_scale(weight: &Tensor<Storage>, scale: f32) -> Option<Tensor<Storage>> {
+    weight.data_type().is_fp8().then(|| {
+        let mut blob = Blob::new(4);
+        blob.copy_from_slice(&scale.to_le_bytes());
+        Tensor::new(DataType::F32, &[], Storage::Others(Arc::new(blob)))
+    })
+}
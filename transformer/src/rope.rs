@@ -0,0 +1,350 @@
+//! 旋转位置编码的频率。
+
+use crate::Llama2;
+use serde::{Deserialize, Deserializer};
+use std::f32::consts::PI;
+
+/// config.json 中 `rope_scaling` 描述的旋转位置编码缩放方式。
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize)]
+#[serde(into = "RopeScalingJson")]
+pub enum RopeScaling {
+    /// 位置线性内插，所有频率缩小 `factor` 倍。
+    Linear { factor: f32 },
+    /// 动态 NTK，位置超出原始上下文长度后随位置放大底数。
+    Dynamic {
+        factor: f32,
+        original_max_position_embeddings: Option<usize>,
+    },
+    /// YaRN，高频维度保持不变、低频维度线性内插，并放大注意力分数。
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: Option<usize>,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: Option<f32>,
+    },
+    /// Llama-3，按波长分段缩放频率，两段之间平滑过渡。
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+}
+
+/// `rope_scaling` 在 config.json 中的格式，新旧版本分别用 `rope_type` 和 `type` 表示类型。
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
+struct RopeScalingJson {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rope_type: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    type_: Option<String>,
+    #[serde(default = "default_factor")]
+    factor: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_max_position_embeddings: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    low_freq_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    high_freq_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beta_fast: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    beta_slow: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attention_factor: Option<f32>,
+}
+
+#[inline(always)]
+const fn default_factor() -> f32 {
+    1.
+}
+
+impl RopeScaling {
+    /// 解析 config.json 中的 `rope_scaling`，`default` 类型表示不缩放。
+    pub(crate) fn deserialize_config<'de, D>(d: D) -> Result<Option<Self>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let Some(json) = Option::<RopeScalingJson>::deserialize(d)? else {
+            return Ok(None);
+        };
+        let missing = |field| D::Error::missing_field(field);
+        let factor = json.factor;
+        let original_max_position_embeddings = json.original_max_position_embeddings;
+        match json.rope_type.or(json.type_).as_deref() {
+            None | Some("default") => Ok(None),
+            Some("linear") => Ok(Some(Self::Linear { factor })),
+            Some("dynamic") => Ok(Some(Self::Dynamic {
+                factor,
+                original_max_position_embeddings,
+            })),
+            Some("yarn") => Ok(Some(Self::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast: json.beta_fast.unwrap_or(32.),
+                beta_slow: json.beta_slow.unwrap_or(1.),
+                attention_factor: json.attention_factor,
+            })),
+            Some("llama3") => Ok(Some(Self::Llama3 {
+                factor,
+                low_freq_factor: json
+                    .low_freq_factor
+                    .ok_or_else(|| missing("low_freq_factor"))?,
+                high_freq_factor: json
+                    .high_freq_factor
+                    .ok_or_else(|| missing("high_freq_factor"))?,
+                original_max_position_embeddings: original_max_position_embeddings
+                    .ok_or_else(|| missing("original_max_position_embeddings"))?,
+            })),
+            Some(ty) => Err(D::Error::unknown_variant(
+                ty,
+                &["default", "linear", "dynamic", "yarn", "llama3"],
+            )),
+        }
+    }
+
+    /// 注意力分数额外的缩放倍数。YaRN 将 q、k 各放大 `attention_factor` 倍。
+    pub fn attention_scale(&self) -> f32 {
+        match *self {
+            Self::Yarn {
+                factor,
+                attention_factor,
+                ..
+            } => {
+                let a = attention_factor.unwrap_or(if factor <= 1. {
+                    1.
+                } else {
+                    0.1 * factor.ln() + 1.
+                });
+                a * a
+            }
+            _ => 1.,
+        }
+    }
+}
+
+impl From<RopeScaling> for RopeScalingJson {
+    fn from(value: RopeScaling) -> Self {
+        let mut json = Self {
+            rope_type: None,
+            type_: None,
+            factor: 1.,
+            original_max_position_embeddings: None,
+            low_freq_factor: None,
+            high_freq_factor: None,
+            beta_fast: None,
+            beta_slow: None,
+            attention_factor: None,
+        };
+        let ty = match value {
+            RopeScaling::Linear { factor } => {
+                json.factor = factor;
+                "linear"
+            }
+            RopeScaling::Dynamic {
+                factor,
+                original_max_position_embeddings,
+            } => {
+                json.factor = factor;
+                json.original_max_position_embeddings = original_max_position_embeddings;
+                "dynamic"
+            }
+            RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                attention_factor,
+            } => {
+                json.factor = factor;
+                json.original_max_position_embeddings = original_max_position_embeddings;
+                json.beta_fast = Some(beta_fast);
+                json.beta_slow = Some(beta_slow);
+                json.attention_factor = attention_factor;
+                "yarn"
+            }
+            RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => {
+                json.factor = factor;
+                json.low_freq_factor = Some(low_freq_factor);
+                json.high_freq_factor = Some(high_freq_factor);
+                json.original_max_position_embeddings = Some(original_max_position_embeddings);
+                "llama3"
+            }
+        };
+        json.rope_type = Some(ty.into());
+        json
+    }
+}
+
+/// 旋转位置编码每对维度的角频率。
+#[derive(Clone, Debug)]
+pub struct RopeTable {
+    inv_freq: Vec<f32>,
+    dynamic: Option<(f32, f32)>,
+}
+
+impl RopeTable {
+    #[inline]
+    pub fn new(model: &dyn Llama2) -> Self {
+        Self::build(
+            model.rope_theta(),
+            model.rotary_dim(),
+            model.max_position_embeddings(),
+            model.rope_scaling(),
+        )
+    }
+
+    fn build(theta: f32, dh: usize, max_pos: usize, scaling: Option<RopeScaling>) -> Self {
+        let half = dh / 2;
+        let mut inv_freq = (0..half)
+            .map(|k| theta.powf(-(k as f32) / half as f32))
+            .collect::<Vec<_>>();
+        let mut dynamic = None;
+        match scaling {
+            None => {}
+            Some(RopeScaling::Linear { factor }) => {
+                inv_freq.iter_mut().for_each(|f| *f /= factor);
+            }
+            Some(RopeScaling::Dynamic {
+                factor,
+                original_max_position_embeddings,
+            }) => {
+                let original = original_max_position_embeddings.unwrap_or(max_pos);
+                dynamic = Some((factor, original as f32));
+            }
+            Some(RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                ..
+            }) => {
+                let original = original_max_position_embeddings.unwrap_or(max_pos) as f32;
+                let dim = dh as f32;
+                // 旋转 `n` 圈的维度
+                let correction = |n: f32| dim * (original / (n * 2. * PI)).ln() / (2. * theta.ln());
+                let low = correction(beta_fast).floor().max(0.);
+                let high = correction(beta_slow).ceil().min(dim - 1.);
+                let high = if low == high { high + 1e-3 } else { high };
+                for (k, f) in inv_freq.iter_mut().enumerate() {
+                    let extrapolation = 1. - ((k as f32 - low) / (high - low)).clamp(0., 1.);
+                    *f = *f / factor * (1. - extrapolation) + *f * extrapolation;
+                }
+            }
+            Some(RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            }) => {
+                let original = original_max_position_embeddings as f32;
+                let low_freq_wavelen = original / low_freq_factor;
+                let high_freq_wavelen = original / high_freq_factor;
+                for f in &mut inv_freq {
+                    let wavelen = 2. * PI / *f;
+                    if wavelen > low_freq_wavelen {
+                        *f /= factor;
+                    } else if wavelen >= high_freq_wavelen {
+                        let smooth = (original / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        *f = (1. - smooth) * *f / factor + smooth * *f;
+                    }
+                }
+            }
+        }
+        Self { inv_freq, dynamic }
+    }
+
+    /// 每对维度的角频率，长度为 `head_dim / 2`。
+    #[inline]
+    pub fn inv_freq(&self) -> &[f32] {
+        &self.inv_freq
+    }
+
+    /// 动态 NTK 缩放的倍数和原始上下文长度。
+    #[inline]
+    pub fn dynamic(&self) -> Option<(f32, f32)> {
+        self.dynamic
+    }
+
+    /// 第 `k` 对维度在位置 `pos` 处旋转的角度。
+    pub fn angle(&self, k: usize, pos: f32) -> f32 {
+        let mut freq = self.inv_freq[k];
+        if let Some((factor, original)) = self.dynamic {
+            let len = pos + 1.;
+            if len > original {
+                // 底数放大 `s^(dim/(dim-2))` 倍
+                let s = factor * len / original - (factor - 1.);
+                freq *= s.powf(-(k as f32) / (self.inv_freq.len() - 1) as f32);
+            }
+        }
+        pos * freq
+    }
+}
+
+#[test]
+fn test_rope() {
+    #[derive(serde::Deserialize)]
+    struct Config {
+        #[serde(deserialize_with = "RopeScaling::deserialize_config")]
+        rope_scaling: Option<RopeScaling>,
+    }
+    let parse = |json: &str| {
+        serde_json::from_str::<Config>(&format!(r#"{{"rope_scaling":{json}}}"#))
+            .unwrap()
+            .rope_scaling
+    };
+    assert_eq!(parse("null"), None);
+    assert_eq!(parse(r#"{"rope_type":"default"}"#), None);
+    assert_eq!(
+        parse(r#"{"type":"linear","factor":2.0}"#),
+        Some(RopeScaling::Linear { factor: 2. }),
+    );
+    let llama3 = parse(
+        r#"{"factor":8.0,"low_freq_factor":1.0,"high_freq_factor":4.0,"original_max_position_embeddings":8192,"rope_type":"llama3"}"#,
+    );
+    assert!(matches!(llama3, Some(RopeScaling::Llama3 { .. })));
+    let json = serde_json::to_string(&llama3.unwrap()).unwrap();
+    assert_eq!(parse(&json), llama3);
+
+    let base = RopeTable::build(5e5, 128, 131072, None);
+    let linear = RopeTable::build(5e5, 128, 131072, Some(RopeScaling::Linear { factor: 2. }));
+    for (a, b) in base.inv_freq().iter().zip(linear.inv_freq()) {
+        assert_eq!(a / 2., *b);
+    }
+    // Llama-3 只缩放低频部分
+    let llama3 = RopeTable::build(5e5, 128, 131072, llama3);
+    assert_eq!(llama3.inv_freq()[0], base.inv_freq()[0]);
+    assert_eq!(llama3.inv_freq()[63], base.inv_freq()[63] / 8.);
+    // YaRN 同样保持高频、内插低频，并放大注意力分数
+    let yarn = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: Some(32768),
+        beta_fast: 32.,
+        beta_slow: 1.,
+        attention_factor: None,
+    };
+    assert!(yarn.attention_scale() > 1.);
+    let yarn = RopeTable::build(1e6, 128, 131072, Some(yarn));
+    let base = RopeTable::build(1e6, 128, 131072, None);
+    assert_eq!(yarn.inv_freq()[0], base.inv_freq()[0]);
+    assert_eq!(yarn.inv_freq()[63], base.inv_freq()[63] / 4.);
+    // 动态 NTK 在原始上下文长度内不改变频率
+    let dynamic = RopeScaling::Dynamic {
+        factor: 2.,
+        original_max_position_embeddings: None,
+    };
+    let dynamic = RopeTable::build(1e4, 64, 2048, Some(dynamic));
+    let base = RopeTable::build(1e4, 64, 2048, None);
+    assert_eq!(dynamic.angle(5, 100.), base.angle(5, 100.));
+    assert_eq!(dynamic.angle(0, 4000.), base.angle(0, 4000.));
+    assert!(dynamic.angle(5, 4000.) < base.angle(5, 4000.));
+}
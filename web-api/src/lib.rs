@@ -8,9 +8,10 @@ use actix_web::{web, App, HttpResponse, HttpServer};
 use causal_lm::CausalLM;
 use futures::StreamExt;
 use manager::ServiceManager;
-use schemas::{Drop, Fork, Infer};
+use schemas::{Drop, Fork, Infer, Load, Save};
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
     sync::Arc,
 };
 
@@ -23,7 +24,11 @@ struct AppState<M: CausalLM> {
     service_manager: Arc<ServiceManager<M>>,
 }
 
-pub async fn start_infer_service<M>(service: service::Service<M>, port: u16) -> std::io::Result<()>
+pub async fn start_infer_service<M>(
+    service: service::Service<M>,
+    port: u16,
+    session_dir: Option<PathBuf>,
+) -> std::io::Result<()>
 where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
@@ -31,7 +36,7 @@ where
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
     info!("start service at {addr}");
     let app_state = web::Data::new(AppState {
-        service_manager: Arc::new(service.into()),
+        service_manager: Arc::new(ServiceManager::new(service, session_dir)),
     });
     HttpServer::new(move || {
         App::new()
@@ -39,6 +44,8 @@ where
             .route("/infer", web::post().to(infer::<M>))
             .route("/fork", web::post().to(fork::<M>))
             .route("/drop", web::post().to(drop::<M>))
+            .route("/save", web::post().to(save::<M>))
+            .route("/load", web::post().to(load::<M>))
     })
     .bind(addr)?
     .run()
@@ -80,3 +87,27 @@ where
         Err(e) => response::error(e),
     }
 }
+
+async fn save<M>(app_state: web::Data<AppState<M>>, request: web::Json<Save>) -> HttpResponse
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    info!("Request from {}: save", request.session_id);
+    match app_state.service_manager.save(request.into_inner()) {
+        Ok(s) => response::success(s),
+        Err(e) => response::error(e),
+    }
+}
+
+async fn load<M>(app_state: web::Data<AppState<M>>, request: web::Json<Load>) -> HttpResponse
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    info!("Request from {}: load", request.session_id);
+    match app_state.service_manager.load(request.into_inner()) {
+        Ok(s) => response::success(s),
+        Err(e) => response::error(e),
+    }
+}
@@ -1,4 +1,6 @@
-use crate::schemas::{Drop, DropSuccess, Error, Fork, ForkSuccess, Infer};
+use crate::schemas::{
+    Drop, DropSuccess, Error, Fork, ForkSuccess, Infer, Load, LoadSuccess, Save, SaveSuccess,
+};
 use causal_lm::CausalLM;
 use futures::{
     channel::mpsc::{self, Receiver},
@@ -7,6 +9,8 @@ use futures::{
 use service::{Service, Session};
 use std::{
     collections::{hash_map::Entry, HashMap},
+    io::ErrorKind::NotFound,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
@@ -20,16 +24,30 @@ pub struct ServiceManager<M: CausalLM> {
     /// so that a new request with the same id will not be double-served.
     /// A session must be re-inserted after being served.
     pending_sessions: Mutex<HashMap<String, Option<Session<M>>>>,
+
+    /// Directory to save sessions to, session storage is disabled if not set.
+    session_dir: Option<PathBuf>,
 }
 
-impl<M: CausalLM> From<Service<M>> for ServiceManager<M> {
+impl<M: CausalLM> ServiceManager<M> {
     #[inline]
-    fn from(infer_service: Service<M>) -> Self {
+    pub fn new(infer_service: Service<M>, session_dir: Option<PathBuf>) -> Self {
         Self {
             infer_service,
             pending_sessions: Default::default(),
+            session_dir,
         }
     }
+
+    /// Archive path of a session, with the session id hex-encoded as file name.
+    fn archive_path(&self, session_id: &str) -> Result<PathBuf, Error> {
+        let dir = self.session_dir.as_ref().ok_or(Error::StorageDisabled)?;
+        let name = session_id
+            .bytes()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        Ok(dir.join(format!("{name}.session")))
+    }
 }
 
 impl<M> ServiceManager<M>
@@ -83,6 +101,9 @@ where
                         break;
                     }
                 }
+                if let Some(e) = busy.error() {
+                    warn!("Failed to infer for {session_id} with error \"{e}\"");
+                }
             }
             if let Some(container) = self_.pending_sessions.lock().unwrap().get_mut(&session_id) {
                 container.get_or_insert(session);
@@ -114,6 +135,62 @@ where
         Ok(ForkSuccess)
     }
 
+    pub fn save(&self, Save { session_id }: Save) -> Result<SaveSuccess, Error> {
+        let path = self.archive_path(&session_id)?;
+        // Take the session out so that other requests are not blocked while saving
+        let session = self
+            .pending_sessions
+            .lock()
+            .unwrap()
+            .get_mut(&session_id)
+            .ok_or(Error::SessionNotFound)?
+            .take()
+            .ok_or(Error::SessionBusy)?;
+        let result = session.save(&path, true);
+        if let Some(container) = self.pending_sessions.lock().unwrap().get_mut(&session_id) {
+            container.get_or_insert(session);
+        }
+        result.map(|_| SaveSuccess).map_err(|e| {
+            warn!(
+                "Failed to save \"{session_id}\" to {} with error \"{e}\"",
+                path.display()
+            );
+            Error::ArchiveFailed
+        })
+    }
+
+    pub fn load(&self, Load { session_id }: Load) -> Result<LoadSuccess, Error> {
+        let path = self.archive_path(&session_id)?;
+        if self
+            .pending_sessions
+            .lock()
+            .unwrap()
+            .contains_key(&session_id)
+        {
+            warn!("Failed to load because \"{session_id}\" already exists");
+            return Err(Error::SessionDuplicate);
+        }
+        let mut session = self.infer_service.launch();
+        match session.load(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == NotFound => return Err(Error::SessionNotFound),
+            Err(e) => {
+                warn!(
+                    "Failed to load \"{session_id}\" from {} with error \"{e}\"",
+                    path.display()
+                );
+                return Err(Error::ArchiveFailed);
+            }
+        }
+        match self.pending_sessions.lock().unwrap().entry(session_id) {
+            Entry::Occupied(_) => Err(Error::SessionDuplicate),
+            Entry::Vacant(e) => {
+                e.insert(Some(session));
+                Ok(LoadSuccess)
+            }
+        }
+    }
+
     pub fn drop_(&self, Drop { session_id }: Drop) -> Result<DropSuccess, Error> {
         self.pending_sessions
             .lock()
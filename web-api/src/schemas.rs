@@ -25,8 +25,20 @@ pub(crate) struct Drop {
     pub session_id: String,
 }
 
+#[derive(serde::Deserialize)]
+pub(crate) struct Save {
+    pub session_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Load {
+    pub session_id: String,
+}
+
 pub(crate) struct ForkSuccess;
 pub(crate) struct DropSuccess;
+pub(crate) struct SaveSuccess;
+pub(crate) struct LoadSuccess;
 
 pub(crate) trait Success {
     fn msg(&self) -> &str;
@@ -45,6 +57,16 @@ impl Success for DropSuccess {
         "drop success"
     }
 }
+impl Success for SaveSuccess {
+    fn msg(&self) -> &str {
+        "save success"
+    }
+}
+impl Success for LoadSuccess {
+    fn msg(&self) -> &str {
+        "load success"
+    }
+}
 
 #[derive(Debug)]
 pub(crate) enum Error {
@@ -53,6 +75,8 @@ pub(crate) enum Error {
     SessionNotFound,
     EmptyInput,
     InvalidDialogPos(usize),
+    StorageDisabled,
+    ArchiveFailed,
 }
 
 #[derive(serde::Serialize)]
@@ -71,6 +95,8 @@ impl Error {
             Self::SessionDuplicate => StatusCode::CONFLICT,
             Self::EmptyInput => StatusCode::BAD_REQUEST,
             Self::InvalidDialogPos(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::StorageDisabled => StatusCode::NOT_IMPLEMENTED,
+            Self::ArchiveFailed => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
@@ -96,6 +122,8 @@ impl Error {
             Self::SessionBusy => json(error!(0, "Session is busy")),
             Self::SessionDuplicate => json(error!(0, "Session ID already exists")),
             Self::EmptyInput => json(error!(0, "Input list is empty")),
+            Self::StorageDisabled => json(error!(0, "Session storage is not configured")),
+            Self::ArchiveFailed => json(error!(0, "Failed to access session archive")),
             &Self::InvalidDialogPos(current_dialog_pos) => {
                 #[derive(serde::Serialize)]
                 struct ErrorBodyExtra {
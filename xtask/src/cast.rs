@@ -21,6 +21,11 @@ impl CastArgs {
             Some("f32") | Some("float") | Some("float32") | None => DataType::F32,
             Some("f16") | Some("half") | Some("float16") => DataType::F16,
             Some("bf16") | Some("bfloat16") => DataType::BF16,
+            Some("q8_0") => DataType::Q8_0,
+            Some("q4_0") => DataType::Q4_0,
+            Some("q4_k") => DataType::Q4_K,
+            Some("f8_e4m3") | Some("float8_e4m3fn") => DataType::F8_E4M3,
+            Some("f8_e5m2") | Some("float8_e5m2") => DataType::F8_E5M2,
             Some(ty) => panic!("Unknown data type: \"{ty}\""),
         };
         let model_dir = PathBuf::from(self.model);
@@ -22,7 +22,7 @@ impl Task for ChatArgs {
         M::Error: Debug,
     {
         let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
+        self.inference.init_service(&mut service);
         Chatting {
             service,
             current: 0,
@@ -52,6 +52,8 @@ fn print_help() {
 /create         新建会话session
 /switch [0-9+]  切换至指定会话
 /drop [0-9+]    丢弃指定会话
+/save [path]    保存当前会话至文件
+/load [path]    从文件恢复当前会话
 /args           打印当前参数
 /args key value 设置指定参数
 /help           打印帮助信息
@@ -172,6 +174,14 @@ impl<M: CausalLM> Chatting<M> {
                 }
                 Err(_) => println!("Invalid drop command"),
             },
+            ["/save", path] => match self.session().save(path, true) {
+                Ok(()) => println!("Session {} is saved to {path}.", self.current),
+                Err(e) => println!("Failed to save session: {e}"),
+            },
+            ["/load", path] => match self.session_mut().load(path) {
+                Ok(()) => println!("Session {} is loaded from {path}.", self.current),
+                Err(e) => println!("Failed to load session: {e}"),
+            },
             ["/args"] => self.print_args(),
             ["/args", "temperature", t] => {
                 if let Ok(t) = t.parse() {
@@ -212,5 +222,8 @@ impl<M: CausalLM> Chatting<M> {
             }
         }
         println!();
+        if let Some(e) = busy.error() {
+            println!("{}", format!("Error: {e}").red());
+        }
     }
 }
@@ -0,0 +1,48 @@
+﻿use std::{path::PathBuf, time::Instant};
+use tokenizer::{TokenizerJson, BPE};
+use transformer::{save_gguf, Memory};
+
+#[derive(Args, Default)]
+pub(crate) struct ExportGgufArgs {
+    /// Original model directory.
+    #[clap(short, long)]
+    model: String,
+    /// Target gguf file.
+    #[clap(short, long)]
+    target: Option<String>,
+}
+
+impl ExportGgufArgs {
+    pub fn invoke(self) {
+        let model_dir = PathBuf::from(self.model);
+
+        // 先读取分词器，不支持的词表不必等待加载模型
+        let time = Instant::now();
+        let tokenizer = if let Ok(bpe) = BPE::from_model_file(model_dir.join("tokenizer.model")) {
+            bpe.gguf_metadata()
+        } else if model_dir.join("tokenizer.json").is_file() {
+            TokenizerJson::from_json_file(model_dir.join("tokenizer.json"))
+                .unwrap()
+                .gguf_metadata()
+        } else {
+            // vocabs.txt 按最长前缀匹配分词，gguf 的词表无法表示
+            panic!("Tokenizer file not found, tokenizer.model or tokenizer.json is required");
+        };
+        println!("load tokenizer ... {:?}", time.elapsed());
+
+        let time = Instant::now();
+        let model = Memory::load_safetensors(&model_dir).unwrap();
+        println!("load model ... {:?}", time.elapsed());
+
+        let target = self.target.map(PathBuf::from).unwrap_or_else(|| {
+            model_dir.with_file_name(format!(
+                "{}.gguf",
+                model_dir.file_name().unwrap().to_str().unwrap()
+            ))
+        });
+
+        let time = Instant::now();
+        save_gguf(&model, tokenizer, &target).unwrap();
+        println!("save {} ... {:?}", target.display(), time.elapsed());
+    }
+}
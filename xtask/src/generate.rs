@@ -26,12 +26,13 @@ impl Task for GenerateArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        self.inference.init_service(&mut service);
 
         print_now!("{}", self.prompt);
 
         let mut steps = self.max_steps.unwrap_or(usize::MAX);
-        let mut generator = service.generate(self.prompt, Some(self.inference.sample_args()));
+        let mut generator = service.generate(self.prompt, None);
         while let Some(s) = generator.decode().await {
             match &*s {
                 "\\n" => println!(),
@@ -43,5 +44,8 @@ impl Task for GenerateArgs {
             }
         }
         println!();
+        if let Some(e) = generator.error() {
+            println!("Error: {e}");
+        }
     }
 }
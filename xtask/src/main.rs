@@ -1,6 +1,7 @@
 mod cast;
 mod chat;
 mod deploy;
+mod export_gguf;
 mod generate;
 mod service;
 
@@ -18,6 +19,7 @@ fn main() {
     match Cli::parse().command {
         Deploy(deploy) => deploy.deploy(),
         Cast(cast) => cast.invode(),
+        ExportGguf(export) => export.invoke(),
         Generate(args) => args.run(),
         Chat(chat) => chat.run(),
         Service(service) => service.run(),
@@ -38,6 +40,8 @@ enum Commands {
     Deploy(DeployArgs),
     /// Cast model
     Cast(cast::CastArgs),
+    /// Export model as a gguf file
+    ExportGguf(export_gguf::ExportGgufArgs),
     /// Generate following text
     Generate(generate::GenerateArgs),
     /// Chat locally
@@ -64,6 +68,15 @@ struct InferenceArgs {
     /// Random sample top-p.
     #[clap(long)]
     top_p: Option<f32>,
+    /// Store kv cache as int8 on CPU.
+    #[clap(long)]
+    kv_int8: bool,
+    /// Max kv cache blocks allocated on CPU.
+    #[clap(long)]
+    cache_blocks: Option<usize>,
+    /// Max tokens computed in one inference step, longer prompts are prefilled in chunks.
+    #[clap(long)]
+    token_budget: Option<usize>,
 
     #[cfg(feature = "nvidia")]
     /// Use Nvidia GPU.
@@ -102,12 +115,20 @@ impl InferenceArgs {
             .collect()
     }
 
+    fn init_service<M: CausalLM>(&self, service: &mut ::service::Service<M>) {
+        service.default_sample = self.sample_args(&service.default_sample);
+        if let Some(budget) = self.token_budget {
+            service.set_token_budget(budget);
+        }
+    }
+
+    /// 用命令行指定的参数覆盖模型默认的采样参数。
     #[inline]
-    fn sample_args(&self) -> SampleArgs {
+    fn sample_args(&self, default: &SampleArgs) -> SampleArgs {
         SampleArgs {
-            temperature: self.temperature.unwrap_or(0.),
-            top_k: self.top_k.unwrap_or(usize::MAX),
-            top_p: self.top_p.unwrap_or(1.),
+            temperature: self.temperature.unwrap_or(default.temperature),
+            top_k: self.top_k.unwrap_or(default.top_k),
+            top_p: self.top_p.unwrap_or(default.top_p),
         }
     }
 }
@@ -131,8 +152,17 @@ trait Task: Sized {
         self.inference().init_log();
         match self.inference().nvidia().as_slice() {
             [] => {
-                use transformer_cpu::Transformer as M;
-                runtime.block_on(self.typed::<M>(()));
+                use transformer_cpu::{KVCacheType, Meta, Transformer as M};
+                let kv_cache = if self.inference().kv_int8 {
+                    KVCacheType::I8
+                } else {
+                    KVCacheType::F16
+                };
+                let cache_blocks = self.inference().cache_blocks;
+                runtime.block_on(self.typed::<M>(Meta {
+                    kv_cache,
+                    cache_blocks,
+                }));
             }
             #[cfg(detected_cuda)]
             &[n] => {
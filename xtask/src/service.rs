@@ -1,7 +1,7 @@
 ﻿use crate::{InferenceArgs, Task};
 use causal_lm::CausalLM;
 use service::Service;
-use std::fmt::Debug;
+use std::{fmt::Debug, path::PathBuf};
 use web_api::start_infer_service;
 
 #[derive(Args, Default)]
@@ -11,6 +11,12 @@ pub struct ServiceArgs {
     /// Port to bind the service to
     #[clap(short, long)]
     pub port: u16,
+    /// Number of prompt prefixes cached across sessions.
+    #[clap(long, default_value_t = 0)]
+    pub prefix_cache: usize,
+    /// Directory to save sessions to.
+    #[clap(long)]
+    pub session_dir: Option<PathBuf>,
 }
 
 impl Task for ServiceArgs {
@@ -25,7 +31,10 @@ impl Task for ServiceArgs {
         M::Error: Debug,
     {
         let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
-        start_infer_service(service, self.port).await.unwrap();
+        self.inference.init_service(&mut service);
+        service.set_prefix_cache(self.prefix_cache);
+        start_infer_service(service, self.port, self.session_dir)
+            .await
+            .unwrap();
     }
 }